@@ -0,0 +1,33 @@
+mod util;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const LOOP: &str = r#"
+(module
+  (func (export "loop") (param $n i32) (result i32)
+    (loop $next
+      (br_if $next (local.tee $n (i32.sub (local.get $n) (i32.const 1)))))
+    (local.get $n)))
+"#;
+
+fn run_tinywasm(twasm: &[u8], iterations: i32, fuel: Option<u64>) {
+    let (mut store, instance) = util::tinywasm(twasm);
+    store.set_fuel(fuel);
+    let func = instance.exported_func::<i32, i32>(&store, "loop").expect("exported_func");
+    func.call(&mut store, iterations).expect("call");
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let twasm = util::wasm_to_twasm(&wat::parse_str(LOOP).expect("wat::parse_str"));
+
+    let mut group = c.benchmark_group("fuel");
+    group.bench_function("unmetered", |b| b.iter(|| run_tinywasm(&twasm, black_box(10_000), None)));
+    group.bench_function("metered", |b| b.iter(|| run_tinywasm(&twasm, black_box(10_000), Some(u64::MAX))));
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default().significance_level(0.1);
+    targets = criterion_benchmark
+);
+
+criterion_main!(benches);
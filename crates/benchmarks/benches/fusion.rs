@@ -0,0 +1,53 @@
+mod util;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tinywasm::{parser::Parser, types::Instruction};
+
+// `local.get + i32.const + i32.add` is how rustc computes most addresses and loop counters,
+// `local.get + i32.const + i32.store` how it initializes memory
+const LOOP: &str = r#"
+(module
+  (memory 1)
+  (func (export "loop") (param $n i32) (result i32)
+    (local $i i32) (local $sum i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $n)))
+        (local.set $sum (i32.add (local.get $sum) (i32.const 3)))
+        (i32.store (local.get $sum) (i32.const 7))
+        (local.set $sum (i32.and (local.get $sum) (i32.const 0xfff)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (local.get $sum)))
+"#;
+
+fn parse(wasm: &[u8], fuse: bool) -> Vec<u8> {
+    let parser = Parser::new().fuse_instructions(fuse);
+    let module = parser.parse_module_bytes(wasm).expect("parse_module_bytes");
+    let instrs = &module.funcs[0].instructions;
+    assert_eq!(instrs.iter().any(|i| matches!(i, Instruction::I32LocalGetConstAdd(..))), fuse);
+    assert_eq!(instrs.iter().any(|i| matches!(i, Instruction::I32StoreLocal { .. })), fuse);
+    module.serialize_twasm().to_vec()
+}
+
+fn run_tinywasm(twasm: &[u8], iterations: i32) {
+    let (mut store, instance) = util::tinywasm(twasm);
+    let func = instance.exported_func::<i32, i32>(&store, "loop").expect("exported_func");
+    func.call(&mut store, iterations).expect("call");
+}
+
+fn criterion_benchmark(c: &mut Criterion) {
+    let wasm = wat::parse_str(LOOP).expect("wat::parse_str");
+    let (fused, unfused) = (parse(&wasm, true), parse(&wasm, false));
+
+    let mut group = c.benchmark_group("fusion");
+    group.bench_function("fused", |b| b.iter(|| run_tinywasm(&fused, black_box(10_000))));
+    group.bench_function("unfused", |b| b.iter(|| run_tinywasm(&unfused, black_box(10_000))));
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default().significance_level(0.1);
+    targets = criterion_benchmark
+);
+
+criterion_main!(benches);
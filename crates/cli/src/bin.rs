@@ -1,10 +1,18 @@
-use std::str::FromStr;
+use std::{path::Path, str::FromStr};
 
 use argh::FromArgs;
 use args::WasmArg;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
 use log::{debug, info};
-use tinywasm::{self, types::WasmValue, Module};
+use tinywasm::{
+    self,
+    parser::Parser,
+    types::{TinyWasmModule, WasmValue},
+    Module,
+};
+
+#[cfg(feature = "compression")]
+use tinywasm::types::archive::Compression;
 
 use crate::args::to_wasm_args;
 mod args;
@@ -28,6 +36,7 @@ struct TinyWasmCli {
 #[argh(subcommand)]
 enum TinyWasmSubcommand {
     Run(Run),
+    Compile(Compile),
 }
 
 enum Engine {
@@ -66,6 +75,47 @@ struct Run {
     engine: Engine,
 }
 
+#[derive(FromArgs)]
+/// precompile a wasm file into a .twasm archive
+#[argh(subcommand, name = "compile")]
+struct Compile {
+    /// wasm file to compile
+    #[argh(positional)]
+    wasm_file: String,
+
+    /// output file, defaults to the wasm file with a .twasm extension
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// optimization level of the translator: 0 keeps the original instructions, 1 fuses common sequences
+    #[argh(option, short = 'O', default = "1")]
+    opt_level: u8,
+
+    /// insert yield points at function entries and loop back-edges
+    #[argh(switch)]
+    yield_points: bool,
+
+    /// insert coverage probes at the start of every basic block
+    #[argh(switch)]
+    coverage: bool,
+
+    /// compress the archive with lz4
+    #[argh(switch)]
+    compress: bool,
+
+    /// compress the archive with DEFLATE at this level instead, from 0 (none) to 10 (smallest)
+    #[argh(option)]
+    compression_level: Option<u8>,
+
+    /// write a portable archive, which can also be loaded by later releases
+    #[argh(switch)]
+    portable: bool,
+
+    /// load the archive again and check that it matches the module
+    #[argh(switch)]
+    verify: bool,
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
 
@@ -87,23 +137,62 @@ fn main() -> Result<()> {
         TinyWasmSubcommand::Run(Run { wasm_file, engine, args, func }) => {
             debug!("args: {:?}", args);
 
-            let path = cwd.join(wasm_file.clone());
-            let module = match wasm_file.ends_with(".wat") {
-                #[cfg(feature = "wat")]
-                true => {
-                    let wat = std::fs::read_to_string(path)?;
-                    let wasm = wat::wat2wasm(&wat);
-                    tinywasm::Module::parse_bytes(&wasm)?
-                }
-                #[cfg(not(feature = "wat"))]
-                true => return Err(color_eyre::eyre::eyre!("wat support is not enabled in this build")),
-                false => tinywasm::Module::parse_file(path)?,
-            };
-
+            let module = Module::from(load(&Parser::new(), &cwd.join(&wasm_file))?);
             match engine {
                 Engine::Main => run(module, func, to_wasm_args(args)),
             }
         }
+        TinyWasmSubcommand::Compile(compile) => {
+            let output = match &compile.output {
+                Some(output) => cwd.join(output),
+                None => cwd.join(&compile.wasm_file).with_extension("twasm"),
+            };
+            let parser = Parser::new()
+                .fuse_instructions(compile.opt_level > 0)
+                .yield_points(compile.yield_points)
+                .coverage(compile.coverage);
+
+            let module = load(&parser, &cwd.join(&compile.wasm_file))?;
+            let archive = serialize(&module, &compile)?;
+            if compile.verify {
+                if TinyWasmModule::from_twasm_unaligned_verified(&archive)? != module {
+                    return Err(eyre!("the archive doesn't match the module"));
+                }
+            }
+
+            std::fs::write(&output, &archive)?;
+            info!("wrote {} ({} bytes)", output.display(), archive.len());
+            Ok(())
+        }
+    }
+}
+
+fn load(parser: &Parser, path: &Path) -> Result<TinyWasmModule> {
+    match path.extension().is_some_and(|ext| ext == "wat") {
+        #[cfg(feature = "wat")]
+        true => {
+            let wat = std::fs::read_to_string(path)?;
+            Ok(parser.parse_module_bytes(wat::wat2wasm(&wat))?)
+        }
+        #[cfg(not(feature = "wat"))]
+        true => Err(eyre!("wat support is not enabled in this build")),
+        false => Ok(parser.parse_module_file(path)?),
+    }
+}
+
+fn serialize(module: &TinyWasmModule, compile: &Compile) -> Result<Vec<u8>> {
+    let compress = compile.compress || compile.compression_level.is_some();
+    match (compress, compile.portable) {
+        (true, true) => Err(eyre!("portable archives can't be compressed")),
+        #[cfg(feature = "compression")]
+        (true, false) => Ok(match compile.compression_level {
+            Some(level) => module.serialize_twasm_compressed_with(Compression::Deflate { level }),
+            None => module.serialize_twasm_compressed(),
+        }),
+        #[cfg(not(feature = "compression"))]
+        (true, false) => Err(eyre!("compression is not enabled in this build")),
+        (false, true) => Ok(module.serialize_twasm_portable()),
+        (false, false) => Ok(module.serialize_twasm().to_vec()),
     }
 }
 
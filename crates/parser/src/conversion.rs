@@ -1,5 +1,5 @@
-use crate::Result;
-use crate::{module::Code, visit::process_operators};
+use crate::{module::Code, peephole::Constants, visit::process_operators};
+use crate::{Result, TranslateOptions};
 use alloc::{boxed::Box, format, string::ToString, vec::Vec};
 use tinywasm_types::*;
 use wasmparser::{FuncValidator, OperatorsReader, ValidatorResources};
@@ -156,9 +156,14 @@ pub(crate) fn convert_module_export(export: wasmparser::Export<'_>) -> Result<Ex
     Ok(Export { index: export.index, name: Box::from(export.name), kind })
 }
 
+// translate a function body, validating it if a validator is given
 pub(crate) fn convert_module_code(
     func: wasmparser::FunctionBody<'_>,
-    mut validator: FuncValidator<ValidatorResources>,
+    mut validator: Option<FuncValidator<ValidatorResources>>,
+    code_section_start: usize,
+    options: TranslateOptions,
+    memory_sizes: &[u64],
+    func_types: &[FuncType],
 ) -> Result<Code> {
     let locals_reader = func.get_locals_reader()?;
     let count = locals_reader.get_count();
@@ -167,15 +172,63 @@ pub(crate) fn convert_module_code(
     let mut locals = Vec::with_capacity(count as usize);
     for (i, local) in locals_reader.into_iter().enumerate() {
         let local = local?;
-        validator.define_locals(pos + i, local.0, local.1)?;
+        if let Some(validator) = validator.as_mut() {
+            validator.define_locals(pos + i, local.0, local.1)?;
+        }
         for _ in 0..local.0 {
             locals.push(convert_valtype(&local.1));
         }
     }
 
-    let body = process_operators(Some(&mut validator), &func)?;
+    let (body, offsets, br_table_targets, constants) =
+        process_operators(validator.as_mut(), &func, code_section_start, options, memory_sizes, func_types)?;
     let locals = locals.into_boxed_slice();
-    Ok((body, locals))
+    Ok((body, locals, offsets, br_table_targets, constants))
+}
+
+// malformed name sections must not fail parsing, so invalid entries are skipped
+pub(crate) fn convert_func_names(data: &[u8], offset: usize) -> Vec<(u32, Box<str>)> {
+    let mut names = Vec::new();
+    for subsection in wasmparser::NameSectionReader::new(data, offset) {
+        let Ok(wasmparser::Name::Function(map)) = subsection else {
+            continue;
+        };
+        names.extend(map.into_iter().filter_map(|naming| naming.ok()).map(|n| (n.index, Box::from(n.name))));
+    }
+    names
+}
+
+// the features a module is compiled with, see <https://github.com/WebAssembly/tool-conventions/blob/main/Linking.md#target-features-section>
+// like the name section, a malformed section must not fail parsing, so only the entries before an error are returned
+pub(crate) fn convert_target_features(mut data: &[u8]) -> Vec<Box<str>> {
+    fn read_u32(data: &mut &[u8]) -> Option<u32> {
+        let mut result = 0u32;
+        for shift in (0..35).step_by(7) {
+            let (byte, rest) = data.split_first()?;
+            *data = rest;
+            result |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    let mut features = Vec::new();
+    let Some(count) = read_u32(&mut data) else { return features };
+    for _ in 0..count {
+        let Some((prefix, rest)) = data.split_first() else { break };
+        data = rest;
+        let Some(len) = read_u32(&mut data).map(|len| len as usize).filter(|len| *len <= data.len()) else { break };
+        let (name, rest) = data.split_at(len);
+        data = rest;
+
+        // `+` marks features that are used, `=` features that are required by linked objects,
+        // and `-` features that must not be used
+        let (Ok(name), b'+' | b'=') = (core::str::from_utf8(name), *prefix) else { continue };
+        features.push(Box::from(name));
+    }
+    features
 }
 
 pub(crate) fn convert_module_type(ty: wasmparser::RecGroup) -> Result<FuncType> {
@@ -197,12 +250,31 @@ pub(crate) fn convert_module_type(ty: wasmparser::RecGroup) -> Result<FuncType>
     Ok(FuncType { params, results })
 }
 
-pub(crate) fn convert_blocktype(blocktype: wasmparser::BlockType) -> BlockArgs {
-    match blocktype {
+// blocks with a function type store their number of params and results, so entering them doesn't need to
+// look up the type. Arities above 255 don't fit in the instruction and are stored in the constant pool
+pub(crate) fn convert_blocktype(
+    blocktype: wasmparser::BlockType,
+    func_types: &[FuncType],
+    constants: &mut Constants,
+) -> Result<BlockArgsPacked> {
+    let args = match blocktype {
         wasmparser::BlockType::Empty => BlockArgs::Empty,
         wasmparser::BlockType::Type(ty) => BlockArgs::Type(convert_valtype(&ty)),
-        wasmparser::BlockType::FuncType(ty) => BlockArgs::FuncType(ty),
-    }
+        wasmparser::BlockType::FuncType(t) => {
+            let ty = func_types
+                .get(t as usize)
+                .ok_or_else(|| crate::ParseError::Other(format!("Block type {} not found", t)))?;
+            match (u8::try_from(ty.params.len()), u8::try_from(ty.results.len())) {
+                (Ok(params), Ok(results)) => BlockArgs::Arity { params, results },
+                _ => {
+                    let arity = BlockArgs::pack_arity(ty.params.len() as u32, ty.results.len() as u32);
+                    BlockArgs::ArityConst(constants.add(arity))
+                }
+            }
+        }
+    };
+    BlockArgsPacked::new(args)
+        .ok_or_else(|| crate::ParseError::UnsupportedOperator(format!("Unsupported block type: {:?}", blocktype)))
 }
 
 pub(crate) fn convert_reftype(reftype: &wasmparser::RefType) -> ValType {
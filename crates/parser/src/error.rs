@@ -3,7 +3,7 @@ use core::fmt::{Debug, Display};
 use alloc::string::{String, ToString};
 use wasmparser::Encoding;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Errors that can occur when parsing a WebAssembly module
 pub enum ParseError {
     /// An invalid type was encountered
@@ -16,6 +16,8 @@ pub enum ParseError {
     EmptySection(String),
     /// An unsupported operator was encountered
     UnsupportedOperator(String),
+    /// The module is compiled with a feature that is disabled or unsupported, see the `target_features` section
+    UnsupportedFeature(String),
     /// An error occurred while parsing the module
     ParseError {
         /// The error message
@@ -46,6 +48,9 @@ impl Display for ParseError {
             Self::DuplicateSection(section) => write!(f, "duplicate section: {}", section),
             Self::EmptySection(section) => write!(f, "empty section: {}", section),
             Self::UnsupportedOperator(operator) => write!(f, "unsupported operator: {}", operator),
+            Self::UnsupportedFeature(feature) => {
+                write!(f, "module requires {} which is disabled/unsupported", feature)
+            }
             Self::ParseError { message, offset } => {
                 write!(f, "error parsing module: {} at offset {}", message, offset)
             }
@@ -0,0 +1,543 @@
+//! Inlining of small functions
+//!
+//! Calls of functions with a short body that has no control flow and no locals besides its params,
+//! like the accessors and wrappers compilers leave uninlined at low optimization levels, are replaced
+//! by the body. The arguments are stored in locals of the caller that take the place of the
+//! callee's params, so the body runs unchanged apart from its local indices. Since the bodies
+//! don't nest, all call sites share these locals, and a caller gains at most `MAX_PARAMS` of them
+//! for each value type.
+
+use alloc::vec::Vec;
+use tinywasm_types::Instruction::{self, *};
+use tinywasm_types::{ConstAddr, ValType, WasmFunction};
+
+// the longest body that is inlined, without its `end`
+const MAX_INSTRUCTIONS: usize = 8;
+
+// the most params of an inlined function
+const MAX_PARAMS: usize = 4;
+
+// the local indices of fused instructions are smaller, see `SmallLocalAddr`
+const SMALL: usize = u16::MAX as usize;
+
+/// A function whose calls can be replaced by its body
+#[derive(Debug)]
+struct Callee {
+    params: Vec<ValType>,
+    // without the final `end`
+    body: Vec<Instruction>,
+    constants: Vec<u64>,
+}
+
+impl Callee {
+    fn new(func: &WasmFunction) -> Option<Self> {
+        let (EndFunc, body) = func.instructions.split_last()? else { return None };
+        let straight = body.iter().all(|instr| {
+            !matches!(
+                instr,
+                Unreachable
+                    | Yield
+                    | Probe
+                    | Block(..)
+                    | Loop(..)
+                    | If(..)
+                    | Else(_)
+                    | EndBlockFrame
+                    | EndFunc
+                    | Br(_)
+                    | BrIf(_)
+                    | BrTable(_)
+                    | I32EqzBrIf(_)
+                    | Return
+                    | Call(_)
+                    | CallIndirect(..)
+            )
+        });
+
+        let small = body.len() <= MAX_INSTRUCTIONS && func.locals.is_empty() && func.ty.params.len() <= MAX_PARAMS;
+        match straight && small {
+            true => {
+                Some(Self { params: func.ty.params.to_vec(), body: body.to_vec(), constants: func.constants.to_vec() })
+            }
+            false => None,
+        }
+    }
+
+    // the caller's locals that take the place of the params, adding them to `scratch` if there aren't enough
+    //
+    // `scratch` holds the types of the locals from `first` on. Returns `None` if a local doesn't fit.
+    fn locals(&self, first: usize, scratch: &mut Vec<ValType>) -> Option<Vec<u32>> {
+        let len = scratch.len();
+        let mut locals = Vec::with_capacity(self.params.len());
+        for (param, ty) in self.params.iter().enumerate() {
+            let nth = self.params[..param].iter().filter(|t| *t == ty).count();
+            let local = match scratch.iter().enumerate().filter(|(_, t)| *t == ty).nth(nth) {
+                Some((local, _)) => local,
+                None => {
+                    scratch.push(*ty);
+                    scratch.len() - 1
+                }
+            };
+            locals.push((first + local) as u32);
+        }
+
+        match locals.iter().all(|local| *local as usize <= SMALL) {
+            true => Some(locals),
+            false => {
+                scratch.truncate(len);
+                None
+            }
+        }
+    }
+
+    // append the body to `out`, with its params in the caller's `locals`
+    fn inline(&self, out: &mut Vec<Instruction>, locals: &[u32], constants: &mut Vec<u64>) {
+        locals.iter().rev().for_each(|local| out.push(LocalSet(*local)));
+
+        // the first param is usually read right away, so it can stay on the stack
+        let mut body = self.body.iter();
+        if let (Some(first), Some(last), Some(LocalGet(0))) = (locals.first(), out.last_mut(), self.body.first()) {
+            *last = LocalTee(*first);
+            body.next();
+        }
+
+        for instr in body {
+            let mut instr = instr.clone();
+            operands(&mut instr, |operand| match operand {
+                Operand::Local(local) => *local = locals[*local as usize],
+                // `locals` makes sure that the moved locals still fit
+                Operand::SmallLocal(local) => *local = locals[*local as usize] as u16,
+                Operand::Constant(addr) => *addr = add_constant(constants, self.constants[*addr as usize]),
+                Operand::Offset(_) => {}
+            });
+            out.push(instr);
+        }
+    }
+}
+
+fn add_constant(constants: &mut Vec<u64>, value: u64) -> ConstAddr {
+    match constants.iter().position(|v| *v == value) {
+        Some(addr) => addr as ConstAddr,
+        None => {
+            constants.push(value);
+            constants.len() as ConstAddr - 1
+        }
+    }
+}
+
+/// An operand of an instruction that changes when the instruction is moved to another function or position
+enum Operand<'a> {
+    Local(&'a mut u32),
+    // the local indices of fused instructions are smaller, see `SmallLocalAddr`
+    SmallLocal(&'a mut u16),
+    Constant(&'a mut ConstAddr),
+    // relative to the instruction
+    Offset(&'a mut u32),
+}
+
+// call `f` with every operand of `instr` that refers to locals, constants or other instructions
+//
+// The match is exhaustive, so new instructions have to be added here.
+fn operands(instr: &mut Instruction, mut f: impl FnMut(Operand<'_>)) {
+    match instr {
+        LocalGet(local) | LocalSet(local) | LocalTee(local) => f(Operand::Local(local)),
+        I32LocalGetConstAdd(local, _)
+        | I32StoreLocal { local, .. }
+        | I32LtSLocalConst(local, _)
+        | I32LtULocalConst(local, _) => f(Operand::SmallLocal(local)),
+        LocalTeeGet(a, b)
+        | LocalGet2(a, b)
+        | LocalGetSet(a, b)
+        | I32AddLocals(a, b)
+        | I32SubLocals(a, b)
+        | I32LtSLocals(a, b)
+        | I32LtULocals(a, b) => {
+            f(Operand::SmallLocal(a));
+            f(Operand::SmallLocal(b));
+        }
+        LocalGet3(a, b, c) => {
+            f(Operand::SmallLocal(a));
+            f(Operand::SmallLocal(b));
+            f(Operand::SmallLocal(c));
+        }
+
+        I64XorConstRotl(addr)
+        | I64AddConst(addr)
+        | I64SubConst(addr)
+        | I64Const(addr)
+        | F64Const(addr)
+        | I32Load { offset: addr, .. }
+        | I64Load { offset: addr, .. }
+        | F32Load { offset: addr, .. }
+        | F64Load { offset: addr, .. }
+        | I32Load8S { offset: addr, .. }
+        | I32Load8U { offset: addr, .. }
+        | I32Load16S { offset: addr, .. }
+        | I32Load16U { offset: addr, .. }
+        | I64Load8S { offset: addr, .. }
+        | I64Load8U { offset: addr, .. }
+        | I64Load16S { offset: addr, .. }
+        | I64Load16U { offset: addr, .. }
+        | I64Load32S { offset: addr, .. }
+        | I64Load32U { offset: addr, .. }
+        | I32Store { offset: addr, .. }
+        | I64Store { offset: addr, .. }
+        | F32Store { offset: addr, .. }
+        | F64Store { offset: addr, .. }
+        | I32Store8 { offset: addr, .. }
+        | I32Store16 { offset: addr, .. }
+        | I64Store8 { offset: addr, .. }
+        | I64Store16 { offset: addr, .. }
+        | I64Store32 { offset: addr, .. } => f(Operand::Constant(addr)),
+
+        Block(_, offset) | Loop(_, offset) | If(_, offset) | Else(offset) => f(Operand::Offset(offset)),
+
+        // branches refer to labels instead of instructions, so they stay the same
+        I32AddConst(..)
+        | I32SubConst(..)
+        | I32EqConst(..)
+        | I32NeConst(..)
+        | I32LtSConst(..)
+        | I32LtUConst(..)
+        | I32GtSConst(..)
+        | I32GtUConst(..)
+        | I32EqzBrIf(..)
+        | I32LoadStatic { .. }
+        | I64LoadStatic { .. }
+        | F32LoadStatic { .. }
+        | F64LoadStatic { .. }
+        | I32StoreStatic { .. }
+        | I64StoreStatic { .. }
+        | F32StoreStatic { .. }
+        | F64StoreStatic { .. }
+        | Unreachable
+        | Nop
+        | Yield
+        | Probe
+        | EndBlockFrame
+        | EndFunc
+        | Br(..)
+        | BrIf(..)
+        | BrTable(..)
+        | Return
+        | Call(..)
+        | CallIndirect(..)
+        | Drop
+        | Select(..)
+        | GlobalGet(..)
+        | GlobalSet(..)
+        | MemorySize(..)
+        | MemoryGrow(..)
+        | I32Const(..)
+        | F32Const(..)
+        | RefNull(..)
+        | RefFunc(..)
+        | RefIsNull
+        | I32Eqz
+        | I32Eq
+        | I32Ne
+        | I32LtS
+        | I32LtU
+        | I32GtS
+        | I32GtU
+        | I32LeS
+        | I32LeU
+        | I32GeS
+        | I32GeU
+        | I64Eqz
+        | I64Eq
+        | I64Ne
+        | I64LtS
+        | I64LtU
+        | I64GtS
+        | I64GtU
+        | I64LeS
+        | I64LeU
+        | I64GeS
+        | I64GeU
+        | F32Eq
+        | F32Ne
+        | F32Lt
+        | F32Gt
+        | F32Le
+        | F32Ge
+        | F64Eq
+        | F64Ne
+        | F64Lt
+        | F64Gt
+        | F64Le
+        | F64Ge
+        | I32Clz
+        | I32Ctz
+        | I32Popcnt
+        | I32Add
+        | I32Sub
+        | I32Mul
+        | I32DivS
+        | I32DivU
+        | I32RemS
+        | I32RemU
+        | I32And
+        | I32Or
+        | I32Xor
+        | I32Shl
+        | I32ShrS
+        | I32ShrU
+        | I32Rotl
+        | I32Rotr
+        | I64Clz
+        | I64Ctz
+        | I64Popcnt
+        | I64Add
+        | I64Sub
+        | I64Mul
+        | I64DivS
+        | I64DivU
+        | I64RemS
+        | I64RemU
+        | I64And
+        | I64Or
+        | I64Xor
+        | I64Shl
+        | I64ShrS
+        | I64ShrU
+        | I64Rotl
+        | I64Rotr
+        | F32Abs
+        | F32Neg
+        | F32Ceil
+        | F32Floor
+        | F32Trunc
+        | F32Nearest
+        | F32Sqrt
+        | F32Add
+        | F32Sub
+        | F32Mul
+        | F32Div
+        | F32Min
+        | F32Max
+        | F32Copysign
+        | F64Abs
+        | F64Neg
+        | F64Ceil
+        | F64Floor
+        | F64Trunc
+        | F64Nearest
+        | F64Sqrt
+        | F64Add
+        | F64Sub
+        | F64Mul
+        | F64Div
+        | F64Min
+        | F64Max
+        | F64Copysign
+        | I32WrapI64
+        | I32TruncF32S
+        | I32TruncF32U
+        | I32TruncF64S
+        | I32TruncF64U
+        | I32Extend8S
+        | I32Extend16S
+        | I64Extend8S
+        | I64Extend16S
+        | I64Extend32S
+        | I64ExtendI32S
+        | I64ExtendI32U
+        | I64TruncF32S
+        | I64TruncF32U
+        | I64TruncF64S
+        | I64TruncF64U
+        | F32ConvertI32S
+        | F32ConvertI32U
+        | F32ConvertI64S
+        | F32ConvertI64U
+        | F32DemoteF64
+        | F64ConvertI32S
+        | F64ConvertI32U
+        | F64ConvertI64S
+        | F64ConvertI64U
+        | F64PromoteF32
+        | I32ReinterpretF32
+        | I64ReinterpretF64
+        | F32ReinterpretI32
+        | F64ReinterpretI64
+        | I32TruncSatF32S
+        | I32TruncSatF32U
+        | I32TruncSatF64S
+        | I32TruncSatF64U
+        | I64TruncSatF32S
+        | I64TruncSatF32U
+        | I64TruncSatF64S
+        | I64TruncSatF64U
+        | TableInit(..)
+        | TableGet(..)
+        | TableSet(..)
+        | TableCopy { .. }
+        | TableGrow(..)
+        | TableSize(..)
+        | TableFill(..)
+        | MemoryInit(..)
+        | MemoryCopy(..)
+        | MemoryFill(..)
+        | DataDrop(..) => {}
+    }
+}
+
+/// Replace the calls of small functions of the module by their bodies
+///
+/// `imported_funcs` is the number of imported functions, which come before `funcs` in the function index space.
+pub(crate) fn inline_calls(funcs: &mut [WasmFunction], imported_funcs: usize) {
+    let callees: Vec<Option<Callee>> = funcs.iter().map(Callee::new).collect();
+    if callees.iter().any(Option::is_some) {
+        funcs.iter_mut().for_each(|func| inline_into(func, &callees, imported_funcs));
+    }
+}
+
+fn inline_into(func: &mut WasmFunction, callees: &[Option<Callee>], imported_funcs: usize) {
+    let callee = |instr: &Instruction| match instr {
+        Call(addr) => callees.get((*addr as usize).checked_sub(imported_funcs)?)?.as_ref(),
+        _ => None,
+    };
+    if !func.instructions.iter().any(|instr| callee(instr).is_some()) {
+        return;
+    }
+
+    let first = func.ty.params.len() + func.locals.len();
+    let mut scratch = Vec::new();
+    let mut constants = func.constants.to_vec();
+    let mut instructions = Vec::with_capacity(func.instructions.len());
+    let mut offsets = Vec::with_capacity(func.offsets.len());
+
+    // where each of the original instructions starts now, to move the block offsets
+    let mut moved = Vec::with_capacity(func.instructions.len() + 1);
+
+    for (ip, instr) in func.instructions.iter().enumerate() {
+        moved.push(instructions.len());
+        match callee(instr).and_then(|callee| Some((callee, callee.locals(first, &mut scratch)?))) {
+            Some((callee, locals)) => callee.inline(&mut instructions, &locals, &mut constants),
+            None => instructions.push(instr.clone()),
+        }
+
+        // inlined instructions have the offset of their call
+        if let Some(offset) = func.offsets.get(ip) {
+            offsets.resize(instructions.len(), *offset);
+        }
+    }
+    moved.push(instructions.len());
+
+    // inlined bodies have no blocks, so only the original instructions can have offsets
+    for ip in (0..func.instructions.len()).filter(|ip| moved[*ip] < moved[ip + 1]) {
+        operands(&mut instructions[moved[ip]], |operand| {
+            if let Operand::Offset(offset) = operand {
+                *offset = (moved[ip + *offset as usize] - moved[ip]) as u32;
+            }
+        });
+    }
+
+    func.instructions = instructions.into_boxed_slice();
+    func.locals = func.locals.iter().chain(&scratch).copied().collect();
+    func.offsets = offsets.into_boxed_slice();
+    func.constants = constants.into_boxed_slice();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{boxed::Box, vec};
+    use tinywasm_types::{BlockArgsPacked, FuncType};
+
+    fn func(params: &[ValType], locals: &[ValType], instructions: Vec<Instruction>, constants: &[u64]) -> WasmFunction {
+        WasmFunction {
+            offsets: (0..instructions.len() as u32).collect(),
+            instructions: instructions.into_boxed_slice(),
+            locals: locals.into(),
+            ty: FuncType { params: params.into(), results: Box::new([ValType::I32]) },
+            br_table_targets: Box::new([]),
+            constants: constants.into(),
+        }
+    }
+
+    #[test]
+    fn test_inline_calls() {
+        // `get` loads a field of the struct its param points to, `add` sums its params
+        let get = func(&[ValType::I32], &[], vec![LocalGet(0), I32Load { offset: 0, mem_addr: 0 }, EndFunc], &[8]);
+        let add = func(&[ValType::I32, ValType::I32], &[], vec![LocalGet2(0, 1), I32Add, EndFunc], &[]);
+        let caller = vec![
+            Block(BlockArgsPacked::EMPTY, 4),
+            LocalGet(0),
+            Call(2),
+            Drop,
+            EndBlockFrame,
+            LocalGet2(0, 1),
+            Call(3),
+            EndFunc,
+        ];
+        // the first function is imported
+        let caller = func(&[ValType::I32], &[ValType::I64], caller, &[8, 16]);
+        let mut funcs = [caller, get, add];
+        inline_calls(&mut funcs, 1);
+
+        let caller = &funcs[0];
+        assert_eq!(
+            &*caller.instructions,
+            [
+                Block(BlockArgsPacked::EMPTY, 5),
+                LocalGet(0),
+                LocalTee(2),
+                I32Load { offset: 0, mem_addr: 0 },
+                Drop,
+                EndBlockFrame,
+                LocalGet2(0, 1),
+                LocalSet(3),
+                LocalSet(2),
+                LocalGet2(2, 3),
+                I32Add,
+                EndFunc,
+            ]
+        );
+        // both calls share the local of the first param
+        assert_eq!(&*caller.locals, [ValType::I64, ValType::I32, ValType::I32]);
+        assert_eq!(&*caller.offsets, [0, 1, 2, 2, 3, 4, 5, 6, 6, 6, 6, 7]);
+        assert_eq!(&*caller.constants, [8, 16]);
+
+        // functions with control flow, calls or locals aren't inlined
+        let branch = func(&[], &[], vec![I32Const(1), Return, EndFunc], &[]);
+        let local = func(&[], &[ValType::I32], vec![LocalGet(0), EndFunc], &[]);
+        let mut funcs = [func(&[], &[], vec![Call(1), Call(2), Call(0), EndFunc], &[]), branch, local];
+        inline_calls(&mut funcs, 0);
+        assert_eq!(&*funcs[0].instructions, [Call(1), Call(2), Call(0), EndFunc]);
+    }
+
+    #[test]
+    fn test_reuse_locals() {
+        // calls of functions with the same param types add their locals only once
+        let wrap = func(&[ValType::I64], &[], vec![LocalGet(0), I32WrapI64, EndFunc], &[]);
+        let select = func(&[ValType::I32, ValType::I64], &[], vec![LocalGet(1), LocalGet(0), Drop, Drop, EndFunc], &[]);
+        let empty = func(&[], &[], vec![EndFunc], &[]);
+        let caller = vec![Call(1), Call(1), Call(2), Call(3), Block(BlockArgsPacked::EMPTY, 1), EndBlockFrame, EndFunc];
+        let mut funcs = [func(&[ValType::I64], &[], caller, &[]), wrap, select, empty];
+        inline_calls(&mut funcs, 0);
+
+        let caller = &funcs[0];
+        assert_eq!(
+            &*caller.instructions,
+            [
+                LocalTee(1),
+                I32WrapI64,
+                LocalTee(1),
+                I32WrapI64,
+                LocalSet(1),
+                LocalSet(2),
+                LocalGet(1),
+                LocalGet(2),
+                Drop,
+                Drop,
+                Block(BlockArgsPacked::EMPTY, 1),
+                EndBlockFrame,
+                EndFunc,
+            ]
+        );
+        assert_eq!(&*caller.locals, [ValType::I64, ValType::I32]);
+        assert_eq!(&*caller.offsets, [0, 0, 1, 1, 2, 2, 2, 2, 2, 2, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,100 @@
+//! Lazy translation of function bodies
+//!
+//! [`Parser::parse_module_bytes_lazy`](crate::Parser::parse_module_bytes_lazy) validates a module without
+//! translating its function bodies. They are translated independently of each other, either on the rayon
+//! thread pool in the background or by the first caller that needs a function before the background
+//! translation got to it. Each body is only translated once, a caller needing one that is being translated
+//! waits for it.
+
+use crate::{conversion, ParseError, Result, TranslateOptions};
+use alloc::{boxed::Box, format, sync::Arc, vec::Vec};
+use core::ops::Range;
+use std::sync::OnceLock;
+use tinywasm_types::{FuncType, WasmFunction};
+
+/// The function bodies of a module parsed with [`Parser::parse_module_bytes_lazy`](crate::Parser::parse_module_bytes_lazy)
+///
+/// The module has been validated, so translating a body only fails if it uses an instruction tinywasm
+/// doesn't support.
+pub struct LazyFunctions {
+    wasm: Arc<[u8]>,
+    // the range of each body in `wasm`, and the type of its function
+    bodies: Box<[(Range<usize>, FuncType)]>,
+    funcs: Box<[OnceLock<Result<WasmFunction>>]>,
+    code_section_start: usize,
+    options: TranslateOptions,
+    memory_sizes: Box<[u64]>,
+    func_types: Box<[FuncType]>,
+}
+
+impl LazyFunctions {
+    pub(crate) fn new(
+        wasm: Arc<[u8]>,
+        bodies: Vec<(Range<usize>, FuncType)>,
+        code_section_start: usize,
+        options: TranslateOptions,
+        memory_sizes: &[u64],
+        func_types: &[FuncType],
+    ) -> Self {
+        Self {
+            wasm,
+            funcs: bodies.iter().map(|_| OnceLock::new()).collect(),
+            bodies: bodies.into_boxed_slice(),
+            code_section_start,
+            options,
+            memory_sizes: memory_sizes.into(),
+            func_types: func_types.into(),
+        }
+    }
+
+    /// The number of functions, which excludes imported ones
+    pub fn len(&self) -> usize {
+        self.funcs.len()
+    }
+
+    /// Whether the module defines no functions
+    pub fn is_empty(&self) -> bool {
+        self.funcs.is_empty()
+    }
+
+    /// Whether all functions have been translated
+    pub fn is_translated(&self) -> bool {
+        self.funcs.iter().all(|func| func.get().is_some())
+    }
+
+    /// Get the function at `idx`, which excludes imported functions
+    ///
+    /// If the function hasn't been translated yet, it is translated on the calling thread,
+    /// or this waits for the translation if it is already in progress on another thread.
+    pub fn get(&self, idx: usize) -> Result<&WasmFunction> {
+        let func = self.funcs.get(idx).ok_or_else(|| ParseError::Other(format!("function {} not found", idx)))?;
+        func.get_or_init(|| self.translate(idx)).as_ref().map_err(Clone::clone)
+    }
+
+    fn translate(&self, idx: usize) -> Result<WasmFunction> {
+        let (range, ty) = &self.bodies[idx];
+        let body = wasmparser::FunctionBody::new(range.start, &self.wasm[range.clone()]);
+        let (options, memory_sizes, func_types) = (self.options, &self.memory_sizes, &self.func_types);
+        let code =
+            conversion::convert_module_code(body, None, self.code_section_start, options, memory_sizes, func_types);
+        let (instructions, locals, offsets, br_table_targets, constants) = code?;
+        Ok(WasmFunction { instructions, locals, offsets, br_table_targets, constants, ty: ty.clone() })
+    }
+
+    // translate the functions nobody asked for yet on the rayon thread pool
+    pub(crate) fn translate_in_background(self: &Arc<Self>) {
+        use rayon::prelude::*;
+
+        let lazy = self.clone();
+        rayon::spawn(move || {
+            (0..lazy.len()).into_par_iter().for_each(|idx| _ = lazy.get(idx));
+        });
+    }
+}
+
+impl core::fmt::Debug for LazyFunctions {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let translated = self.funcs.iter().filter(|func| func.get().is_some()).count();
+        f.debug_struct("LazyFunctions").field("len", &self.len()).field("translated", &translated).finish()
+    }
+}
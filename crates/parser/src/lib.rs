@@ -29,28 +29,100 @@ mod log {
 
 mod conversion;
 mod error;
+mod inline;
+#[cfg(feature = "parallel")]
+mod lazy;
 mod module;
+mod peephole;
 mod visit;
-use alloc::{string::ToString, vec::Vec};
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec::Vec,
+};
 pub use error::*;
 use module::ModuleReader;
-use tinywasm_types::WasmFunction;
+pub use peephole::FusionRules;
+use tinywasm_types::{ImportKind, ModuleFrontend, WasmFunction};
 use wasmparser::{Validator, WasmFeatures};
 
 pub use tinywasm_types::TinyWasmModule;
 
+#[cfg(feature = "parallel")]
+pub use lazy::LazyFunctions;
+
+#[cfg(feature = "wasm-encoder")]
+/// The version of `wasm-encoder` supported by [`Parser::parse_wasm_encoder_module`]
+pub use wasm_encoder;
+
 /// A WebAssembly parser
 #[derive(Default, Debug)]
-pub struct Parser {}
+pub struct Parser {
+    options: TranslateOptions,
+}
+
+// instrumentation and optimizations applied while translating function bodies
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TranslateOptions {
+    pub(crate) yield_points: bool,
+    pub(crate) coverage: bool,
+    pub(crate) fuse: bool,
+    pub(crate) rules: FusionRules,
+}
+
+impl Default for TranslateOptions {
+    fn default() -> Self {
+        Self { yield_points: false, coverage: false, fuse: true, rules: FusionRules::default() }
+    }
+}
 
 impl Parser {
     /// Create a new parser instance
     pub fn new() -> Self {
-        Self {}
+        Self::default()
     }
 
-    fn create_validator(&self) -> Validator {
-        let features = WasmFeatures {
+    /// Insert yield points at every function entry and loop back-edge
+    ///
+    /// Yield points let the runtime interrupt long running code that makes no calls,
+    /// at the cost of executing an additional instruction per loop iteration.
+    pub fn yield_points(mut self, enabled: bool) -> Self {
+        self.options.yield_points = enabled;
+        self
+    }
+
+    /// Insert coverage probes at the start of every function and basic block
+    ///
+    /// When coverage is enabled in the store, executed probes are recorded.
+    /// See `Store::enable_coverage` in the `tinywasm` crate.
+    pub fn coverage(mut self, enabled: bool) -> Self {
+        self.options.coverage = enabled;
+        self
+    }
+
+    /// Fuse common sequences of instructions into a single instruction, e.g. `local.get` pairs
+    ///
+    /// This also folds constant expressions, removes unreachable code and inlines calls of small functions
+    /// without control flow, which then don't show up in backtraces. It is enabled by default.
+    /// Disabling it keeps the instructions closer to the original WebAssembly, e.g. for tools that
+    /// analyze the translated bytecode, but makes execution slower.
+    pub fn fuse_instructions(mut self, enabled: bool) -> Self {
+        self.options.fuse = enabled;
+        self
+    }
+
+    /// Choose the rules used to fuse instructions, see [`FusionRules`]
+    ///
+    /// Only applies while [`Parser::fuse_instructions`] is enabled. Workloads that spend much of their time
+    /// in a sequence fused by one of the opt-in rules, e.g. according to the fusion candidates of the opcode
+    /// counts of the `tinywasm` crate, can enable the rule here. Only the built-in rules can be selected.
+    pub fn fusion_rules(mut self, rules: FusionRules) -> Self {
+        self.options.rules = rules;
+        self
+    }
+
+    fn features(&self) -> WasmFeatures {
+        WasmFeatures {
             bulk_memory: true,
             floats: true,
             multi_value: true,
@@ -73,18 +145,82 @@ impl Parser {
             tail_call: false,
             threads: false,
             multi_memory: false, // should be working mostly
-        };
-        Validator::new_with_features(features)
+        }
+    }
+
+    fn create_validator(&self) -> Validator {
+        Validator::new_with_features(self.features())
+    }
+
+    // check if a feature in the `target_features` section is known to be disabled
+    fn feature_enabled(&self, name: &str) -> Option<bool> {
+        let features = self.features();
+        Some(match name {
+            "atomics" | "shared-mem" => features.threads,
+            "bulk-memory" => features.bulk_memory,
+            "exception-handling" => features.exceptions,
+            "extended-const" => features.extended_const,
+            "gc" => features.gc,
+            "memory64" => features.memory64,
+            "multimemory" => features.multi_memory,
+            "multivalue" => features.multi_value,
+            "mutable-globals" => features.mutable_global,
+            "nontrapping-fptoint" => features.saturating_float_to_int,
+            "reference-types" => features.reference_types,
+            "relaxed-simd" => features.relaxed_simd,
+            "sign-ext" => features.sign_extension,
+            "simd128" => features.simd,
+            "tail-call" => features.tail_call,
+            _ => return None,
+        })
+    }
+
+    fn find_disabled(&self, features: &[Box<str>]) -> Option<String> {
+        features.iter().find(|name| self.feature_enabled(name) == Some(false)).map(|name| name.to_string())
+    }
+
+    // find a feature the module was compiled with that isn't enabled, to explain why parsing failed
+    fn find_unsupported_feature(&self, wasm: &[u8]) -> Option<String> {
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            match payload.ok()? {
+                wasmparser::Payload::CustomSection(reader) if reader.name() == "target_features" => {
+                    return self.find_disabled(&conversion::convert_target_features(reader.data()));
+                }
+                wasmparser::Payload::End(_) => return None,
+                _ => {}
+            }
+        }
+        None
     }
 
     /// Parse a [`TinyWasmModule`] from bytes
+    ///
+    /// With the `parallel` feature, the function bodies are translated in parallel using `rayon`.
     pub fn parse_module_bytes(&self, wasm: impl AsRef<[u8]>) -> Result<TinyWasmModule> {
         let wasm = wasm.as_ref();
         let mut validator = self.create_validator();
-        let mut reader = ModuleReader::new();
+        let mut reader = ModuleReader::new(self.options);
+
+        // with the `parallel` feature, function bodies are translated once the whole module has been read
+        #[cfg(feature = "parallel")]
+        let mut functions = Vec::new();
 
         for payload in wasmparser::Parser::new(0).parse_all(wasm) {
-            reader.process_payload(payload?, &mut validator)?;
+            let res = payload.map_err(ParseError::from).and_then(|payload| match payload {
+                #[cfg(feature = "parallel")]
+                wasmparser::Payload::CodeSectionEntry(function) => {
+                    reader.defer_code(function, &mut validator).map(|function| functions.push(function))
+                }
+                payload => reader.process_payload(payload, &mut validator),
+            });
+            if let Err(err) = res {
+                return Err(self.find_unsupported_feature(wasm).map_or(err, ParseError::UnsupportedFeature));
+            }
+        }
+
+        #[cfg(feature = "parallel")]
+        if let Err(err) = reader.translate_parallel(functions) {
+            return Err(self.find_unsupported_feature(wasm).map_or(err, ParseError::UnsupportedFeature));
         }
 
         if !reader.end_reached {
@@ -94,6 +230,85 @@ impl Parser {
         reader.try_into()
     }
 
+    /// Parse a [`TinyWasmModule`] from bytes, returning once it has been validated. Requires the `parallel` feature.
+    ///
+    /// The functions of the returned module have no instructions yet. Their bodies are translated by the returned
+    /// [`LazyFunctions`], which starts translating them on the `rayon` thread pool in the background.
+    /// Since the bodies are translated independently of each other, calls of small functions are not inlined.
+    #[cfg(feature = "parallel")]
+    pub fn parse_module_bytes_lazy(
+        &self,
+        wasm: impl Into<alloc::sync::Arc<[u8]>>,
+    ) -> Result<(TinyWasmModule, alloc::sync::Arc<LazyFunctions>)> {
+        let wasm = wasm.into();
+        let mut validator = self.create_validator();
+        let mut reader = ModuleReader::new(self.options);
+        let mut functions = Vec::new();
+
+        for payload in wasmparser::Parser::new(0).parse_all(&wasm) {
+            let res = payload.map_err(ParseError::from).and_then(|payload| match payload {
+                wasmparser::Payload::CodeSectionEntry(function) => {
+                    reader.defer_code(function, &mut validator).map(|function| functions.push(function))
+                }
+                payload => reader.process_payload(payload, &mut validator),
+            });
+            if let Err(err) = res {
+                return Err(self.find_unsupported_feature(&wasm).map_or(err, ParseError::UnsupportedFeature));
+            }
+        }
+
+        let bodies = match reader.validate_parallel(functions) {
+            Ok(bodies) => bodies,
+            Err(err) => return Err(self.find_unsupported_feature(&wasm).map_or(err, ParseError::UnsupportedFeature)),
+        };
+
+        if !reader.end_reached {
+            return Err(ParseError::EndNotReached);
+        }
+
+        let (start, memory_sizes, func_types) = (reader.code_section_start, &reader.memory_sizes, &reader.func_types);
+        let lazy = LazyFunctions::new(wasm.clone(), bodies, start, self.options, memory_sizes, func_types);
+        let lazy = alloc::sync::Arc::new(lazy);
+        let module = reader.try_into()?;
+        lazy.translate_in_background();
+        Ok((module, lazy))
+    }
+
+    /// Parse a [`TinyWasmModule`] from the payloads of a [`wasmparser::Parser`]
+    ///
+    /// This lets tools that already parse a module with `wasmparser` hand it over without
+    /// serializing it to bytes again. The payloads have to contain the whole module, including its `End` payload.
+    ///
+    /// The parser uses a fork of `wasmparser`, so the payloads have to come from the `tinywasm-wasmparser`
+    /// crate in the same version as the one this crate depends on.
+    pub fn parse_module_payloads<'a>(
+        &self,
+        payloads: impl IntoIterator<Item = wasmparser::Result<wasmparser::Payload<'a>>>,
+    ) -> Result<TinyWasmModule> {
+        let mut validator = self.create_validator();
+        let mut reader = ModuleReader::new(self.options);
+
+        for payload in payloads {
+            let res =
+                payload.map_err(ParseError::from).and_then(|payload| reader.process_payload(payload, &mut validator));
+            if let Err(err) = res {
+                return Err(self.find_disabled(&reader.target_features).map_or(err, ParseError::UnsupportedFeature));
+            }
+        }
+
+        if !reader.end_reached {
+            return Err(ParseError::EndNotReached);
+        }
+
+        reader.try_into()
+    }
+
+    #[cfg(feature = "wasm-encoder")]
+    /// Parse a [`TinyWasmModule`] from a [`wasm_encoder::Module`]. Requires `wasm-encoder` feature.
+    pub fn parse_wasm_encoder_module(&self, module: &wasm_encoder::Module) -> Result<TinyWasmModule> {
+        self.parse_module_bytes(module.as_slice())
+    }
+
     #[cfg(feature = "std")]
     /// Parse a [`TinyWasmModule`] from a file. Requires `std` feature.
     pub fn parse_module_file(&self, path: impl AsRef<crate::std::path::Path> + Clone) -> Result<TinyWasmModule> {
@@ -111,7 +326,7 @@ impl Parser {
         use alloc::format;
 
         let mut validator = self.create_validator();
-        let mut reader = ModuleReader::new();
+        let mut reader = ModuleReader::new(self.options);
         let mut buffer = Vec::new();
         let mut parser = wasmparser::Parser::new(0);
         let mut eof = false;
@@ -128,7 +343,12 @@ impl Parser {
                     eof = read_bytes == 0;
                 }
                 wasmparser::Chunk::Parsed { consumed, payload } => {
-                    reader.process_payload(payload, &mut validator)?;
+                    // only the target features seen so far can explain the error
+                    if let Err(err) = reader.process_payload(payload, &mut validator) {
+                        return Err(self
+                            .find_disabled(&reader.target_features)
+                            .map_or(err, ParseError::UnsupportedFeature));
+                    }
                     buffer.drain(..consumed);
                     if eof || reader.end_reached {
                         return reader.try_into();
@@ -139,6 +359,14 @@ impl Parser {
     }
 }
 
+impl ModuleFrontend for Parser {
+    type Error = ParseError;
+
+    fn parse_module_bytes(&self, bytes: &[u8]) -> Result<TinyWasmModule> {
+        Parser::parse_module_bytes(self, bytes)
+    }
+}
+
 impl TryFrom<ModuleReader> for TinyWasmModule {
     type Error = ParseError;
 
@@ -154,17 +382,26 @@ impl TryFrom<ModuleReader> for TinyWasmModule {
             return Err(ParseError::Other("Code and code type address count mismatch".to_string()));
         }
 
-        let funcs = reader
+        let mut funcs = reader
             .code
             .into_iter()
             .zip(code_type_addrs)
-            .map(|((instructions, locals), ty_idx)| WasmFunction {
+            .map(|((instructions, locals, offsets, br_table_targets, constants), ty_idx)| WasmFunction {
                 instructions,
                 locals,
+                offsets,
+                br_table_targets,
+                constants,
                 ty: reader.func_types.get(ty_idx as usize).expect("No func type for func, this is a bug").clone(),
             })
             .collect::<Vec<_>>();
 
+        // lazily translated functions are translated independently of each other, so calls aren't inlined
+        if reader.options.fuse && !reader.lazy_code {
+            let imported_funcs = reader.imports.iter().filter(|i| matches!(i.kind, ImportKind::Function(_))).count();
+            inline::inline_calls(&mut funcs, imported_funcs);
+        }
+
         let globals = reader.globals;
         let table_types = reader.table_types;
 
@@ -178,7 +415,181 @@ impl TryFrom<ModuleReader> for TinyWasmModule {
             data: reader.data.into_boxed_slice(),
             exports: reader.exports.into_boxed_slice(),
             elements: reader.elements.into_boxed_slice(),
+            func_names: reader.func_names.into_boxed_slice(),
+            target_features: reader.target_features.into_boxed_slice(),
             memory_types: reader.memory_types.into_boxed_slice(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use tinywasm_types::{BlockArgs, Instruction};
+
+    #[rustfmt::skip]
+    const WASM: [u8; 65] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+        // type 0: (i32) -> i32
+        0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x7f,
+        // three functions of type 0, the last one exported as "a"
+        0x03, 0x04, 0x03, 0x00, 0x00, 0x00,
+        0x07, 0x05, 0x01, 0x01, 0x61, 0x00, 0x02,
+        0x0a, 0x22, 0x03,
+        // local.get 0, i32.const 1, i32.add
+        0x07, 0x00, 0x20, 0x00, 0x41, 0x01, 0x6a, 0x0b,
+        // local.get 0, call 0, i32.const 2, i32.mul
+        0x09, 0x00, 0x20, 0x00, 0x10, 0x00, 0x41, 0x02, 0x6c, 0x0b,
+        // local.get 0, if (result i32) local.get 0, call 1, else i32.const 0, end
+        0x0e, 0x00, 0x20, 0x00, 0x04, 0x7f, 0x20, 0x00, 0x10, 0x01, 0x05, 0x41, 0x00, 0x0b, 0x0b,
+    ];
+
+    // `wasm` with a `target_features` section marking `features` as used, right after the header
+    fn with_target_features(wasm: &[u8], features: &[&str]) -> Vec<u8> {
+        let mut data = vec![features.len() as u8];
+        for feature in features {
+            data.extend([b'+', feature.len() as u8].iter().chain(feature.as_bytes()));
+        }
+
+        let name = b"target_features";
+        let section = [[name.len() as u8].as_slice(), name, &data].concat();
+        [&wasm[..8], &[0x00, section.len() as u8], &section, &wasm[8..]].concat()
+    }
+
+    #[test]
+    fn test_target_features() {
+        let parser = Parser::new();
+        let wasm = with_target_features(&WASM, &["bulk-memory", "mutable-globals"]);
+        let module = parser.parse_module_bytes(&wasm).expect("features are supported");
+        let features: Vec<_> = module.target_features.iter().map(|feature| &**feature).collect();
+        assert_eq!(features, ["bulk-memory", "mutable-globals"]);
+
+        #[rustfmt::skip]
+        let tail_call = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            // type 0: () -> ()
+            0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00,
+            // return_call 0
+            0x0a, 0x06, 0x01, 0x04, 0x00, 0x12, 0x00, 0x0b,
+        ];
+
+        // a module failing to parse because it uses a disabled feature reports the feature
+        let wasm = with_target_features(&tail_call, &["bulk-memory", "tail-call"]);
+        let err = parser.parse_module_bytes(&wasm).expect_err("tail calls are disabled");
+        assert!(matches!(err, ParseError::UnsupportedFeature(feature) if feature == "tail-call"));
+        let payloads = wasmparser::Parser::new(0).parse_all(&wasm);
+        let err = parser.parse_module_payloads(payloads).expect_err("tail calls are disabled");
+        assert!(matches!(err, ParseError::UnsupportedFeature(feature) if feature == "tail-call"));
+
+        // without the section, only the validation error is reported
+        let err = parser.parse_module_bytes(tail_call).expect_err("tail calls are disabled");
+        assert!(!matches!(err, ParseError::UnsupportedFeature(_)));
+    }
+
+    #[test]
+    fn test_truncated_payloads() {
+        let parser = Parser::new();
+        let payloads = wasmparser::Parser::new(0).parse_all(&WASM);
+        assert_eq!(parser.parse_module_payloads(payloads).expect("valid module").funcs.len(), 3);
+
+        // every payload but the `End` one
+        let payloads = wasmparser::Parser::new(0).parse_all(&WASM);
+        let truncated = payloads.take_while(|payload| !matches!(payload, Ok(wasmparser::Payload::End(_))));
+        assert!(matches!(parser.parse_module_payloads(truncated), Err(ParseError::EndNotReached)));
+    }
+
+    #[test]
+    fn test_wide_block_types() {
+        fn leb(mut value: u32, out: &mut Vec<u8>) {
+            while value >= 0x80 {
+                out.push(value as u8 | 0x80);
+                value >>= 7;
+            }
+            out.push(value as u8);
+        }
+        fn section(id: u8, content: &[u8], out: &mut Vec<u8>) {
+            out.push(id);
+            leb(content.len() as u32, out);
+            out.extend_from_slice(content);
+        }
+
+        // types 0 to 65535 are `() -> ()`, 65536 takes 300 i32s and 65537 returns one
+        let mut types = Vec::new();
+        leb(0x10002, &mut types);
+        (0..0x10000).for_each(|_| types.extend([0x60, 0x00, 0x00]));
+        types.extend([0x60, 0xac, 0x02].iter().chain(&[0x7f; 300]).chain(&[0x00]));
+        types.extend([0x60, 0x00, 0x01, 0x7f]);
+
+        // i32.const 0 (300 times), block (type 65536), drop (300 times), end,
+        // block (type 65537), i32.const 7, end, drop
+        let mut body = vec![0x00];
+        (0..300).for_each(|_| body.extend([0x41, 0x00]));
+        body.extend([0x02, 0x80, 0x80, 0x04]);
+        body.extend([0x1a; 300].iter().chain(&[0x0b]));
+        body.extend([0x02, 0x81, 0x80, 0x04, 0x41, 0x07, 0x0b, 0x1a, 0x0b]);
+        let mut code = vec![0x01];
+        leb(body.len() as u32, &mut code);
+        code.extend(body);
+
+        let mut wasm = vec![0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        section(0x01, &types, &mut wasm);
+        section(0x03, &[0x01, 0x00], &mut wasm);
+        section(0x0a, &code, &mut wasm);
+
+        let module = Parser::new().fuse_instructions(false).parse_module_bytes(&wasm).expect("valid module");
+        module.verify().expect("valid module");
+        let func = &module.funcs[0];
+        let blocks: Vec<_> = func
+            .instructions
+            .iter()
+            .filter_map(|instr| match instr {
+                Instruction::Block(args, _) => Some(args.unpack()),
+                _ => None,
+            })
+            .collect();
+
+        // the arity of the first block doesn't fit in the instruction
+        let BlockArgs::ArityConst(constant) = blocks[0] else { panic!("expected an arity constant: {:?}", blocks) };
+        assert_eq!(BlockArgs::unpack_arity(func.constants[constant as usize]), (300, 0));
+        assert_eq!(blocks[1], BlockArgs::Arity { params: 0, results: 1 });
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_parallel_translation() {
+        // payloads are always translated one function after another
+        for parser in [Parser::new(), Parser::new().fuse_instructions(false)] {
+            let parallel = parser.parse_module_bytes(&WASM).expect("valid module");
+            let payloads = wasmparser::Parser::new(0).parse_all(&WASM);
+            let sequential = parser.parse_module_payloads(payloads).expect("valid module");
+            assert_eq!(parallel.funcs.len(), 3);
+            assert_eq!(parallel, sequential);
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_lazy_translation() {
+        // lazily translated functions aren't inlined into each other
+        let parser = Parser::new().fuse_instructions(false);
+        let expected = parser.parse_module_bytes(&WASM).expect("valid module");
+        let (module, lazy) = parser.parse_module_bytes_lazy(&WASM[..]).expect("valid module");
+        assert_eq!(module.funcs.len(), 3);
+        assert_eq!(lazy.len(), 3);
+        assert!(module.funcs.iter().all(|func| func.instructions.is_empty()));
+
+        for (idx, func) in expected.funcs.iter().enumerate() {
+            assert_eq!(lazy.get(idx).expect("valid function"), func);
+        }
+        assert!(lazy.is_translated());
+        assert!(lazy.get(3).is_err());
+
+        // invalid bodies are rejected before the module is returned
+        let mut invalid = WASM.to_vec();
+        let end = invalid.len() - 1;
+        invalid[end - 1] = 0x00;
+        assert!(parser.parse_module_bytes_lazy(invalid).is_err());
+    }
+}
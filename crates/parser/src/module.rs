@@ -1,10 +1,17 @@
 use crate::log::debug;
-use crate::{conversion, ParseError, Result};
-use alloc::{boxed::Box, format, vec::Vec};
-use tinywasm_types::{Data, Element, Export, FuncType, Global, Import, Instruction, MemoryType, TableType, ValType};
+use crate::{conversion, ParseError, Result, TranslateOptions};
+use alloc::{boxed::Box, format, string::ToString, vec::Vec};
+use tinywasm_types::{
+    Data, Element, Export, FuncType, Global, Import, ImportKind, Instruction, MemoryArch, MemoryType, TableType,
+    ValType,
+};
 use wasmparser::{Payload, Validator};
 
-pub(crate) type Code = (Box<[Instruction]>, Box<[ValType]>);
+#[cfg(feature = "parallel")]
+pub(crate) type DeferredCode<'a> =
+    (wasmparser::FunctionBody<'a>, wasmparser::FuncValidator<wasmparser::ValidatorResources>);
+
+pub(crate) type Code = (Box<[Instruction]>, Box<[ValType]>, Box<[u32]>, Box<[u32]>, Box<[u64]>);
 
 #[derive(Default)]
 pub(crate) struct ModuleReader {
@@ -14,18 +21,25 @@ pub(crate) struct ModuleReader {
     pub(crate) code_type_addrs: Vec<u32>,
     pub(crate) exports: Vec<Export>,
     pub(crate) code: Vec<Code>,
+    pub(crate) code_section_start: usize,
+    pub(crate) memory_sizes: Vec<u64>,
     pub(crate) globals: Vec<Global>,
     pub(crate) table_types: Vec<TableType>,
     pub(crate) memory_types: Vec<MemoryType>,
     pub(crate) imports: Vec<Import>,
     pub(crate) data: Vec<Data>,
     pub(crate) elements: Vec<Element>,
+    pub(crate) func_names: Vec<(u32, Box<str>)>,
+    pub(crate) target_features: Vec<Box<str>>,
     pub(crate) end_reached: bool,
+    pub(crate) options: TranslateOptions,
+    // set when the code only holds placeholders for bodies translated by `LazyFunctions`
+    pub(crate) lazy_code: bool,
 }
 
 impl ModuleReader {
-    pub(crate) fn new() -> ModuleReader {
-        Self::default()
+    pub(crate) fn new(options: TranslateOptions) -> ModuleReader {
+        Self { options, ..Default::default() }
     }
 
     pub(crate) fn process_payload(&mut self, payload: Payload<'_>, validator: &mut Validator) -> Result<()> {
@@ -124,13 +138,30 @@ impl ModuleReader {
                     return Err(ParseError::DuplicateSection("Code section".into()));
                 }
                 self.code.reserve(count as usize);
+                self.code_section_start = range.start;
                 validator.code_section_start(count, &range)?;
+
+                // constant addresses below the minimum sizes of the memories don't need bounds checks,
+                // 64-bit memories are left out
+                let imported = self.imports.iter().filter_map(|import| match &import.kind {
+                    ImportKind::Memory(ty) => Some(ty),
+                    _ => None,
+                });
+                let size = |ty: &MemoryType| if ty.arch == MemoryArch::I32 { ty.initial_size() } else { 0 };
+                self.memory_sizes = imported.chain(self.memory_types.iter()).map(size).collect();
             }
             CodeSectionEntry(function) => {
                 debug!("Found code section entry");
                 let v = validator.code_section_entry(&function)?;
                 let func_validator = v.into_validator(Default::default());
-                self.code.push(conversion::convert_module_code(function, func_validator)?);
+                self.code.push(conversion::convert_module_code(
+                    function,
+                    Some(func_validator),
+                    self.code_section_start,
+                    self.options,
+                    &self.memory_sizes,
+                    &self.func_types,
+                )?);
             }
             ImportSection(reader) => {
                 if !self.imports.is_empty() {
@@ -160,6 +191,14 @@ impl ModuleReader {
                 validator.end(offset)?;
                 self.end_reached = true;
             }
+            CustomSection(reader) if reader.name() == "name" => {
+                debug!("Found name section");
+                self.func_names = conversion::convert_func_names(reader.data(), reader.data_offset());
+            }
+            CustomSection(reader) if reader.name() == "target_features" => {
+                debug!("Found target features section");
+                self.target_features = conversion::convert_target_features(reader.data());
+            }
             CustomSection(_reader) => {
                 debug!("Found custom section");
                 debug!("Skipping custom section: {:?}", _reader.name());
@@ -170,4 +209,60 @@ impl ModuleReader {
 
         Ok(())
     }
+
+    /// Validate the header of a function body, leaving its translation to [`ModuleReader::translate_parallel`]
+    #[cfg(feature = "parallel")]
+    pub(crate) fn defer_code<'a>(
+        &mut self,
+        function: wasmparser::FunctionBody<'a>,
+        validator: &mut Validator,
+    ) -> Result<DeferredCode<'a>> {
+        debug!("Found code section entry");
+        let v = validator.code_section_entry(&function)?;
+        Ok((function, v.into_validator(Default::default())))
+    }
+
+    /// Translate the deferred function bodies on the rayon thread pool
+    ///
+    /// If several bodies are invalid, the error of the first one is returned, like in a sequential translation.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn translate_parallel(&mut self, functions: Vec<DeferredCode<'_>>) -> Result<()> {
+        use rayon::prelude::*;
+
+        let (start, options, memory_sizes) = (self.code_section_start, self.options, &self.memory_sizes);
+        let func_types = &self.func_types;
+        let code = functions
+            .into_par_iter()
+            .map(|(function, validator)| {
+                conversion::convert_module_code(function, Some(validator), start, options, memory_sizes, func_types)
+            })
+            .collect::<Vec<_>>();
+        self.code = code.into_iter().collect::<Result<Vec<_>>>()?;
+        Ok(())
+    }
+
+    /// Validate the deferred function bodies on the rayon thread pool, leaving their translation to
+    /// [`LazyFunctions`](crate::LazyFunctions)
+    ///
+    /// Returns the range of each body in the module and the type of its function.
+    #[cfg(feature = "parallel")]
+    pub(crate) fn validate_parallel(
+        &mut self,
+        functions: Vec<DeferredCode<'_>>,
+    ) -> Result<Vec<(core::ops::Range<usize>, FuncType)>> {
+        use rayon::prelude::*;
+
+        let valid = functions
+            .into_par_iter()
+            .map(|(function, mut validator)| validator.validate(&function).map(|()| function.range()))
+            .collect::<Vec<_>>();
+        let ranges = valid.into_iter().collect::<wasmparser::Result<Vec<_>>>()?;
+
+        let types = self.code_type_addrs.iter().map(|ty| self.func_types.get(*ty as usize).cloned());
+        let bodies = ranges.into_iter().zip(types).map(|(range, ty)| Some((range, ty?))).collect::<Option<Vec<_>>>();
+        let bodies = bodies.ok_or_else(|| ParseError::Other("No func type for func".to_string()))?;
+        self.code = bodies.iter().map(|_| Default::default()).collect();
+        self.lazy_code = true;
+        Ok(bodies)
+    }
 }
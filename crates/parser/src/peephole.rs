@@ -0,0 +1,406 @@
+//! Peephole optimizations of the translated instructions
+//!
+//! After every instruction the translator emits, the rules below are matched against the end of
+//! the instruction stream and replace the instructions they match with fused or constant folded ones. A rule only
+//! matches windows that end with the new instruction, so instructions before a block start,
+//! which branches and the label stack refer to, are never rewritten.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use tinywasm_types::ConstAddr;
+use tinywasm_types::Instruction::{self, *};
+
+/// The constant pool of the function being translated, see [`tinywasm_types::WasmFunction::constants`]
+///
+/// Every value is only stored once, so folded constants get a new index instead of changing
+/// the value of one that other instructions might refer to.
+#[derive(Debug, Default)]
+pub(crate) struct Constants {
+    values: Vec<u64>,
+    indices: BTreeMap<u64, ConstAddr>,
+}
+
+impl Constants {
+    pub(crate) fn add(&mut self, value: u64) -> ConstAddr {
+        *self.indices.entry(value).or_insert_with(|| {
+            self.values.push(value);
+            self.values.len() as ConstAddr - 1
+        })
+    }
+
+    pub(crate) fn get(&self, addr: ConstAddr) -> u64 {
+        self.values[addr as usize]
+    }
+
+    pub(crate) fn into_values(self) -> Vec<u64> {
+        self.values
+    }
+}
+
+/// A rewrite of the last `window` instructions
+pub(crate) struct Rule {
+    pub(crate) name: &'static str,
+    pub(crate) window: usize,
+    // replaces the end of the instructions and returns true if they match the rule
+    rewrite: fn(&mut Vec<Instruction>, &mut Constants, &[u64]) -> bool,
+}
+
+macro_rules! rules {
+    ($pool:ident, $mems:ident; $($name:ident: [$($pat:pat),+] $(if $guard:expr)? => [$($replacement:expr),*],)*) => {
+        /// The rules in the order they are tried
+        #[allow(unused_variables)]
+        pub(crate) const RULES: &[Rule] = &[$(
+            Rule {
+                name: stringify!($name),
+                window: [$(stringify!($pat)),+].len(),
+                rewrite: |instrs, $pool, $mems| {
+                    let start = instrs.len() - [$(stringify!($pat)),+].len();
+                    match instrs[start..] {
+                        [$($pat),+] $(if $guard)? => {
+                            instrs.truncate(start);
+                            $(instrs.push($replacement);)*
+                            true
+                        }
+                        _ => false,
+                    }
+                },
+            },
+        )*];
+    };
+}
+
+// the local, memory and table indices of fused instructions are smaller, see `SmallLocalAddr`
+const SMALL: u32 = u16::MAX as u32;
+
+// fold two 64-bit constants of the pool
+fn fold_i64(pool: &mut Constants, a: ConstAddr, b: ConstAddr, op: fn(i64, i64) -> i64) -> ConstAddr {
+    pool.add(op(pool.get(a) as i64, pool.get(b) as i64) as u64)
+}
+
+// whether a `len` byte access at `addr + offset` is in bounds of the memory's minimum size
+fn in_bounds(memories: &[u64], mem_addr: u16, addr: i32, offset: u64, len: u64) -> bool {
+    let end = (addr as u32 as u64).checked_add(offset).and_then(|addr| addr.checked_add(len));
+    matches!((memories.get(mem_addr as usize), end), (Some(size), Some(end)) if end <= *size)
+}
+
+rules! {
+    pool, memories;
+    // constant folding, before the rules that fuse constant operands
+    i32_add_fold: [I32Const(a), I32Const(b), I32Add] => [I32Const(a.wrapping_add(b))],
+    i32_sub_fold: [I32Const(a), I32Const(b), I32Sub] => [I32Const(a.wrapping_sub(b))],
+    i32_mul_fold: [I32Const(a), I32Const(b), I32Mul] => [I32Const(a.wrapping_mul(b))],
+    i32_and_fold: [I32Const(a), I32Const(b), I32And] => [I32Const(a & b)],
+    i32_or_fold: [I32Const(a), I32Const(b), I32Or] => [I32Const(a | b)],
+    i32_xor_fold: [I32Const(a), I32Const(b), I32Xor] => [I32Const(a ^ b)],
+    i32_shl_fold: [I32Const(a), I32Const(b), I32Shl] => [I32Const(a.wrapping_shl(b as u32))],
+    i32_shr_s_fold: [I32Const(a), I32Const(b), I32ShrS] => [I32Const(a.wrapping_shr(b as u32))],
+    i32_shr_u_fold: [I32Const(a), I32Const(b), I32ShrU] => [I32Const((a as u32).wrapping_shr(b as u32) as i32)],
+    i64_add_fold: [I64Const(a), I64Const(b), I64Add] => [I64Const(fold_i64(pool, a, b, i64::wrapping_add))],
+    i64_sub_fold: [I64Const(a), I64Const(b), I64Sub] => [I64Const(fold_i64(pool, a, b, i64::wrapping_sub))],
+    i64_mul_fold: [I64Const(a), I64Const(b), I64Mul] => [I64Const(fold_i64(pool, a, b, i64::wrapping_mul))],
+    i64_and_fold: [I64Const(a), I64Const(b), I64And] => [I64Const(fold_i64(pool, a, b, |a, b| a & b))],
+    i64_or_fold: [I64Const(a), I64Const(b), I64Or] => [I64Const(fold_i64(pool, a, b, |a, b| a | b))],
+    i64_xor_fold: [I64Const(a), I64Const(b), I64Xor] => [I64Const(fold_i64(pool, a, b, |a, b| a ^ b))],
+    i64_shl_fold: [I64Const(a), I64Const(b), I64Shl] => [I64Const(fold_i64(pool, a, b, |a, b| a.wrapping_shl(b as u32)))],
+    i64_shr_s_fold: [I64Const(a), I64Const(b), I64ShrS] => [I64Const(fold_i64(pool, a, b, |a, b| a.wrapping_shr(b as u32)))],
+    i64_shr_u_fold: [I64Const(a), I64Const(b), I64ShrU]
+        => [I64Const(fold_i64(pool, a, b, |a, b| (a as u64).wrapping_shr(b as u32) as i64))],
+    i32_eqz_fold: [I32Const(a), I32Eqz] => [I32Const((a == 0) as i32)],
+    i32_eq_fold: [I32Const(a), I32Const(b), I32Eq] => [I32Const((a == b) as i32)],
+    i32_ne_fold: [I32Const(a), I32Const(b), I32Ne] => [I32Const((a != b) as i32)],
+    i32_lt_s_fold: [I32Const(a), I32Const(b), I32LtS] => [I32Const((a < b) as i32)],
+    i32_lt_u_fold: [I32Const(a), I32Const(b), I32LtU] => [I32Const(((a as u32) < b as u32) as i32)],
+    i32_gt_s_fold: [I32Const(a), I32Const(b), I32GtS] => [I32Const((a > b) as i32)],
+    i32_gt_u_fold: [I32Const(a), I32Const(b), I32GtU] => [I32Const((a as u32 > b as u32) as i32)],
+    i32_le_s_fold: [I32Const(a), I32Const(b), I32LeS] => [I32Const((a <= b) as i32)],
+    i32_le_u_fold: [I32Const(a), I32Const(b), I32LeU] => [I32Const((a as u32 <= b as u32) as i32)],
+    i32_ge_s_fold: [I32Const(a), I32Const(b), I32GeS] => [I32Const((a >= b) as i32)],
+    i32_ge_u_fold: [I32Const(a), I32Const(b), I32GeU] => [I32Const((a as u32 >= b as u32) as i32)],
+    // branches with a constant condition are either always or never taken
+    br_if_never: [I32Const(0), BrIf(_)] => [],
+    br_if_always: [I32Const(_), BrIf(depth)] => [Br(depth)],
+
+    local_get2: [LocalGet(a), LocalGet(b)] if a <= SMALL && b <= SMALL => [LocalGet2(a as u16, b as u16)],
+    local_get3: [LocalGet2(a, b), LocalGet(c)] if c <= SMALL => [LocalGet3(a, b, c as u16)],
+    local_tee_get: [LocalTee(a), LocalGet(b)] if a <= SMALL && b <= SMALL => [LocalTeeGet(a as u16, b as u16)],
+    // opt-in, seems to make performance worse for most workloads, see `FusionRules::OPT_IN`
+    local_get_set: [LocalGet(a), LocalSet(b)] if a <= SMALL && b <= SMALL => [LocalGetSet(a as u16, b as u16)],
+
+    i64_xor_const_rotl: [I64Xor, I64Const(a), I64Rotl] => [I64XorConstRotl(a)],
+    i32_local_get_const_add: [LocalGet(a), I32Const(b), I32Add] if a <= SMALL => [I32LocalGetConstAdd(a as u16, b)],
+    // the value stays in the I32Const after the fused instruction
+    i32_store_local: [LocalGet(local), I32Const(value), I32Store { offset, mem_addr }]
+        if local <= SMALL && pool.get(offset) <= u32::MAX as u64 && mem_addr <= u8::MAX as u16
+        => [I32StoreLocal { local: local as u16, offset: pool.get(offset) as u32, mem_addr: mem_addr as u8 }, I32Const(value)],
+
+    // accesses with a constant address in bounds of the memory's minimum size, which can't trap
+    i32_load_static: [I32Const(addr), I32Load { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 4)
+        => [I32LoadStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    i64_load_static: [I32Const(addr), I64Load { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 8)
+        => [I64LoadStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    f32_load_static: [I32Const(addr), F32Load { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 4)
+        => [F32LoadStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    f64_load_static: [I32Const(addr), F64Load { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 8)
+        => [F64LoadStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    // the address has to be pushed right before the value, so only values from a single instruction are moved
+    i32_store_static_local: [I32Const(addr), LocalGet(local), I32Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 4)
+        => [LocalGet(local), I32StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    i32_store_static_const: [I32Const(addr), I32Const(value), I32Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 4)
+        => [I32Const(value), I32StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    i64_store_static_local: [I32Const(addr), LocalGet(local), I64Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 8)
+        => [LocalGet(local), I64StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    i64_store_static_const: [I32Const(addr), I64Const(value), I64Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 8)
+        => [I64Const(value), I64StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    f32_store_static_local: [I32Const(addr), LocalGet(local), F32Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 4)
+        => [LocalGet(local), F32StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    f32_store_static_const: [I32Const(addr), F32Const(value), F32Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 4)
+        => [F32Const(value), F32StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    f64_store_static_local: [I32Const(addr), LocalGet(local), F64Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 8)
+        => [LocalGet(local), F64StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+    f64_store_static_const: [I32Const(addr), F64Const(value), F64Store { offset, mem_addr }]
+        if in_bounds(memories, mem_addr, addr, pool.get(offset), 8)
+        => [F64Const(value), F64StoreStatic { addr: addr as u32 + pool.get(offset) as u32, mem_addr }],
+
+    i32_add_const: [I32Const(a), I32Add] => [I32AddConst(a)],
+    i32_sub_const: [I32Const(a), I32Sub] => [I32SubConst(a)],
+    i64_add_const: [I64Const(a), I64Add] => [I64AddConst(a)],
+    i64_sub_const: [I64Const(a), I64Sub] => [I64SubConst(a)],
+    i32_eq_const: [I32Const(a), I32Eq] => [I32EqConst(a)],
+    i32_ne_const: [I32Const(a), I32Ne] => [I32NeConst(a)],
+    i32_lt_s_const: [I32Const(a), I32LtS] => [I32LtSConst(a)],
+    i32_lt_u_const: [I32Const(a), I32LtU] => [I32LtUConst(a)],
+    i32_gt_s_const: [I32Const(a), I32GtS] => [I32GtSConst(a)],
+    i32_gt_u_const: [I32Const(a), I32GtU] => [I32GtUConst(a)],
+
+    i32_eqz_br_if: [I32Eqz, BrIf(a)] => [I32EqzBrIf(a)],
+
+    // operands from locals, see `local_get2` for how the pairs are formed
+    i32_add_locals: [LocalGet2(a, b), I32Add] => [I32AddLocals(a, b)],
+    i32_sub_locals: [LocalGet2(a, b), I32Sub] => [I32SubLocals(a, b)],
+    i32_lt_s_locals: [LocalGet2(a, b), I32LtS] => [I32LtSLocals(a, b)],
+    i32_lt_u_locals: [LocalGet2(a, b), I32LtU] => [I32LtULocals(a, b)],
+    i32_lt_s_local_const: [LocalGet(a), I32LtSConst(c)] if a <= SMALL => [I32LtSLocalConst(a as u16, c)],
+    i32_lt_u_local_const: [LocalGet(a), I32LtUConst(c)] if a <= SMALL => [I32LtULocalConst(a as u16, c)],
+}
+
+/// The peephole rules used to fuse instructions, see [`crate::Parser::fusion_rules`]
+///
+/// The rules are named after the instructions they match, see [`FusionRules::names`]. By default, all rules
+/// are enabled except for the ones in [`FusionRules::OPT_IN`], which only pay off for some workloads.
+/// This only selects among the rules built into the parser: rules can't be added, since fused instructions
+/// need their own opcodes and interpreter support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FusionRules(u128);
+
+impl Default for FusionRules {
+    fn default() -> Self {
+        let mut rules = Self::all();
+        for name in Self::OPT_IN {
+            rules.set(name, false);
+        }
+        rules
+    }
+}
+
+impl FusionRules {
+    /// The rules that are disabled by default
+    pub const OPT_IN: &'static [&'static str] = &["local_get_set"];
+
+    /// All rules, including the opt-in ones
+    pub fn all() -> Self {
+        Self(u128::MAX >> (128 - RULES.len()))
+    }
+
+    /// No rules, so only unreachable code is removed and small functions are inlined
+    pub fn none() -> Self {
+        Self(0)
+    }
+
+    /// The names of all rules, in the order they are tried
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        RULES.iter().map(|rule| rule.name)
+    }
+
+    /// Enable or disable the rule with the given name, returns `false` if there is no such rule
+    pub fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let Some(i) = RULES.iter().position(|rule| rule.name == name) else { return false };
+        match enabled {
+            true => self.0 |= 1 << i,
+            false => self.0 &= !(1 << i),
+        }
+        true
+    }
+
+    /// Whether the rule with the given name is enabled
+    pub fn is_enabled(&self, name: &str) -> bool {
+        RULES.iter().position(|rule| rule.name == name).is_some_and(|i| self.0 & (1 << i) != 0)
+    }
+}
+
+/// Apply the enabled rules to the end of the instructions until none of them matches
+///
+/// `memories` are the minimum sizes of the module's memories in bytes, including imported ones.
+pub(crate) fn optimize(instrs: &mut Vec<Instruction>, pool: &mut Constants, memories: &[u64], rules: FusionRules) {
+    // every rule shrinks the instructions, so this terminates
+    'rewrite: loop {
+        for (i, rule) in RULES.iter().enumerate() {
+            if rule.window > instrs.len() || rules.0 & (1 << i) == 0 {
+                continue;
+            }
+
+            // instructions that are the immediates of the one before them can't be fused
+            let start = instrs.len() - rule.window;
+            if start > 0 && matches!(instrs[start - 1], I32StoreLocal { .. }) {
+                continue;
+            }
+
+            if (rule.rewrite)(instrs, pool, memories) {
+                crate::log::debug!("applied peephole rule {}", rule.name);
+                continue 'rewrite;
+            }
+        }
+        return;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // emit the instructions one by one, like the translator, for a module with a one page memory
+    fn translate_with(instrs: &[Instruction], pool: &mut Constants) -> Vec<Instruction> {
+        translate_rules(instrs, pool, FusionRules::default())
+    }
+
+    fn translate_rules(instrs: &[Instruction], pool: &mut Constants, rules: FusionRules) -> Vec<Instruction> {
+        let mut out = Vec::new();
+        for instr in instrs {
+            out.push(instr.clone());
+            optimize(&mut out, pool, &[65536], rules);
+        }
+        out
+    }
+
+    fn translate(instrs: &[Instruction]) -> Vec<Instruction> {
+        translate_with(instrs, &mut Constants::default())
+    }
+
+    #[test]
+    fn test_rules() {
+        assert_eq!(translate(&[LocalGet(0), LocalGet(1), LocalGet(2), LocalGet(3)]), [LocalGet3(0, 1, 2), LocalGet(3)]);
+        assert_eq!(translate(&[LocalGet(0), I32Const(4), I32Add]), [I32LocalGetConstAdd(0, 4)]);
+        assert_eq!(translate(&[LocalGet(0), LocalGet(1), I32Const(4), I32Add]), [LocalGet2(0, 1), I32AddConst(4)]);
+        assert_eq!(translate(&[I32Eqz, BrIf(2), Nop]), [I32EqzBrIf(2), Nop]);
+        assert_eq!(translate(&[LocalGet(0), LocalGet(70000)]), [LocalGet(0), LocalGet(70000)]);
+        assert_eq!(translate(&[LocalGet(0), LocalGet(1), I32LtU, BrIf(0)]), [I32LtULocals(0, 1), BrIf(0)]);
+        assert_eq!(translate(&[LocalGet(2), I32Const(10), I32LtS]), [I32LtSLocalConst(2, 10)]);
+        assert_eq!(translate(&[LocalGet(0), LocalGet(1), LocalGet(2), I32Sub]), [LocalGet3(0, 1, 2), I32Sub]);
+
+        let mut pool = Constants::default();
+        let (small, large) = (pool.add(8), pool.add(u32::MAX as u64 + 1));
+        let store = I32Store { offset: small, mem_addr: 0 };
+        let fused = I32StoreLocal { local: 1, offset: 8, mem_addr: 0 };
+        assert_eq!(translate_with(&[LocalGet(1), I32Const(3), store.clone()], &mut pool), [fused.clone(), I32Const(3)]);
+        let large = I32Store { offset: large, mem_addr: 0 };
+        assert_eq!(
+            translate_with(&[LocalGet(1), I32Const(3), large.clone()], &mut pool),
+            [LocalGet(1), I32Const(3), large]
+        );
+
+        // constants are folded before they are fused
+        assert_eq!(translate(&[I32Const(6), I32Const(7), I32Mul, I32Const(2), I32Add]), [I32Const(44)]);
+        assert_eq!(
+            translate(&[LocalGet(0), I32Const(1), I32Const(33), I32Shl, I32And]),
+            [LocalGet(0), I32Const(2), I32And]
+        );
+        let (a, b) = (pool.add(-8i64 as u64), pool.add(1));
+        let [I64Const(folded)] = translate_with(&[I64Const(a), I64Const(b), I64ShrU], &mut pool)[..] else {
+            panic!("i64.shr_u should be folded")
+        };
+        assert_eq!(pool.get(folded), -8i64 as u64 >> 1);
+        assert_eq!(pool.get(a), -8i64 as u64);
+        assert_eq!(translate(&[I32Const(-1), I32Const(0), I32LtU]), [I32Const(0)]);
+        assert_eq!(translate(&[I32Const(3), I32Const(3), I32Eq, BrIf(1)]), [Br(1)]);
+        assert_eq!(translate(&[Nop, I32Const(0), I32Eqz, I32Eqz, BrIf(1)]), [Nop]);
+
+        // the value of the fused store isn't an operand of the add
+        let instrs = [LocalGet(1), I32Const(3), store, I32Add];
+        assert_eq!(translate_with(&instrs, &mut pool), [fused, I32Const(3), I32Add]);
+    }
+
+    #[test]
+    fn test_static_memory() {
+        let mut pool = Constants::default();
+        let (zero, four) = (pool.add(0), pool.add(4));
+        let load = I32Load { offset: four, mem_addr: 0 };
+        assert_eq!(translate_with(&[I32Const(8), load.clone()], &mut pool), [I32LoadStatic { addr: 12, mem_addr: 0 }]);
+        let store = F64Store { offset: zero, mem_addr: 0 };
+        assert_eq!(
+            translate_with(&[I32Const(65528), LocalGet(1), store.clone()], &mut pool),
+            [LocalGet(1), F64StoreStatic { addr: 65528, mem_addr: 0 }]
+        );
+
+        // accesses that could be out of bounds keep their bounds check
+        assert_eq!(translate_with(&[I32Const(65532), load.clone()], &mut pool), [I32Const(65532), load.clone()]);
+        assert_eq!(translate_with(&[I32Const(-4), load.clone()], &mut pool), [I32Const(-4), load.clone()]);
+        let other = I32Load { offset: zero, mem_addr: 1 };
+        assert_eq!(translate_with(&[I32Const(0), other.clone()], &mut pool), [I32Const(0), other]);
+        let instrs = [I32Const(65529), LocalGet(1), store];
+        assert_eq!(translate_with(&instrs, &mut pool), instrs);
+
+        // the value of `i32.store_local` isn't an address
+        let fused = I32StoreLocal { local: 0, offset: 0, mem_addr: 0 };
+        let instrs = [LocalGet(0), I32Const(4), I32Store { offset: zero, mem_addr: 0 }, load.clone()];
+        assert_eq!(translate_with(&instrs, &mut pool), [fused, I32Const(4), load]);
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut pool = Constants::default();
+        assert_eq!((pool.add(0), pool.add(5), pool.add(0)), (0, 1, 0));
+        assert_eq!(pool.into_values(), [0, 5]);
+    }
+
+    #[test]
+    fn test_rule_table() {
+        for (i, rule) in RULES.iter().enumerate() {
+            assert!(rule.window >= 2, "{} doesn't fuse anything", rule.name);
+            assert!(RULES[..i].iter().all(|r| r.name != rule.name), "{} is defined twice", rule.name);
+        }
+        // every rule has a bit in `FusionRules`
+        assert!(RULES.len() <= 128);
+
+        // rules only look at the end of the instructions
+        let mut instrs = vec![LocalGet(0), LocalGet(1), Nop];
+        optimize(&mut instrs, &mut Constants::default(), &[], FusionRules::all());
+        assert_eq!(instrs, [LocalGet(0), LocalGet(1), Nop]);
+    }
+
+    #[test]
+    fn test_fusion_rules() {
+        let instrs = [LocalGet(0), LocalSet(1), LocalGet(0), LocalGet(1)];
+        assert_eq!(translate(&instrs), [LocalGet(0), LocalSet(1), LocalGet2(0, 1)]);
+
+        let mut rules = FusionRules::default();
+        assert!(!rules.is_enabled("local_get_set") && rules.is_enabled("local_get2"));
+        assert!(rules.set("local_get_set", true) && rules.set("local_get2", false));
+        assert!(!rules.set("unknown", true));
+        let translated = translate_rules(&instrs, &mut Constants::default(), rules);
+        assert_eq!(translated, [LocalGetSet(0, 1), LocalGet(0), LocalGet(1)]);
+
+        assert_eq!(translate_rules(&instrs[2..], &mut Constants::default(), FusionRules::none()), instrs[2..]);
+        assert!(FusionRules::names().all(|name| FusionRules::all().is_enabled(name)));
+        assert_eq!(FusionRules::names().count(), RULES.len());
+    }
+}
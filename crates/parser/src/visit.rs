@@ -1,9 +1,9 @@
-use crate::{conversion::convert_blocktype, Result};
+use crate::{conversion::convert_blocktype, peephole, peephole::Constants, Result, TranslateOptions};
 
 use crate::conversion::{convert_heaptype, convert_memarg, convert_valtype};
 use alloc::string::ToString;
 use alloc::{boxed::Box, format, vec::Vec};
-use tinywasm_types::{BlockArgsPacked, Instruction};
+use tinywasm_types::{BlockArgsPacked, ConstAddr, FuncType, Instruction};
 use wasmparser::{FuncValidator, FunctionBody, VisitOperator, WasmModuleResources};
 
 struct ValidateThenVisit<'a, T, U>(T, &'a mut U);
@@ -27,35 +27,55 @@ where
     wasmparser::for_each_operator!(validate_then_visit);
 }
 
+/// The instructions, offsets, `br_table` targets and constants of a function
+pub(crate) type Body = (Box<[Instruction]>, Box<[u32]>, Box<[u32]>, Box<[u64]>);
+
 pub(crate) fn process_operators<R: WasmModuleResources>(
     validator: Option<&mut FuncValidator<R>>,
     body: &FunctionBody<'_>,
-) -> Result<Box<[Instruction]>> {
+    code_section_start: usize,
+    options: TranslateOptions,
+    memory_sizes: &[u64],
+    func_types: &[FuncType],
+) -> Result<Body> {
     let mut reader = body.get_operators_reader()?;
     let remaining = reader.get_binary_reader().bytes_remaining();
-    let mut builder = FunctionBuilder::new(remaining);
+    let mut builder = FunctionBuilder::new(remaining, options, memory_sizes, func_types);
+    let mut offsets = Vec::with_capacity(remaining);
+
+    // instructions pushed while visiting an operator are mapped to that operator's offset,
+    // fused instructions replace the ones they were fused from and keep the first offset
+    let mut record_offset = |builder: &FunctionBuilder<'_>, pos: usize| {
+        offsets.truncate(builder.instructions.len());
+        offsets.resize(builder.instructions.len(), (pos - code_section_start) as u32);
+    };
 
     if let Some(validator) = validator {
         while !reader.eof() {
-            let validate = validator.visitor(reader.original_position());
+            let pos = reader.original_position();
+            let validate = validator.visitor(pos);
             reader.visit_operator(&mut ValidateThenVisit(validate, &mut builder))???;
+            record_offset(&builder, pos);
         }
         validator.finish(reader.original_position())?;
     } else {
         while !reader.eof() {
+            let pos = reader.original_position();
             reader.visit_operator(&mut builder)??;
+            record_offset(&builder, pos);
         }
     }
 
-    Ok(builder.instructions.into_boxed_slice())
+    let br_table_targets = builder.br_table_targets.into_boxed_slice();
+    let constants = builder.constants.into_values().into_boxed_slice();
+    Ok((builder.instructions.into_boxed_slice(), offsets.into_boxed_slice(), br_table_targets, constants))
 }
 
 macro_rules! define_operands {
     ($($name:ident, $instr:expr),*) => {
         $(
             fn $name(&mut self) -> Self::Output {
-                self.instructions.push($instr);
-                Ok(())
+                self.visit($instr)
             }
         )*
     };
@@ -65,16 +85,7 @@ macro_rules! define_primitive_operands {
     ($($name:ident, $instr:expr, $ty:ty),*) => {
         $(
             fn $name(&mut self, arg: $ty) -> Self::Output {
-                self.instructions.push($instr(arg));
-                Ok(())
-            }
-        )*
-    };
-    ($($name:ident, $instr:expr, $ty:ty, $ty2:ty),*) => {
-        $(
-            fn $name(&mut self, arg: $ty, arg2: $ty) -> Self::Output {
-                self.instructions.push($instr(arg, arg2));
-                Ok(())
+                self.visit($instr(arg))
             }
         )*
     };
@@ -85,24 +96,46 @@ macro_rules! define_mem_operands {
         $(
             fn $name(&mut self, mem_arg: wasmparser::MemArg) -> Self::Output {
                 let arg = convert_memarg(mem_arg);
-                self.instructions.push(Instruction::$instr {
-                    offset: arg.offset,
-                    mem_addr: arg.mem_addr,
-                });
-                Ok(())
+                let mem_addr = self.small_index(arg.mem_addr)?;
+                self.visit_const(arg.offset, |offset| Instruction::$instr { offset, mem_addr })
             }
         )*
     };
 }
 
-pub(crate) struct FunctionBuilder {
+pub(crate) struct FunctionBuilder<'m> {
     instructions: Vec<Instruction>,
     label_ptrs: Vec<usize>,
+    br_table_targets: Vec<u32>,
+    constants: Constants,
+    options: TranslateOptions,
+    // the minimum sizes of the module's memories, see `peephole::optimize`
+    memory_sizes: &'m [u64],
+    // the module's types, to resolve the arities of blocks
+    func_types: &'m [FuncType],
+    // set while skipping unreachable code, to the number of blocks entered since
+    unreachable: Option<u32>,
 }
 
-impl FunctionBuilder {
-    pub(crate) fn new(instr_capacity: usize) -> Self {
-        Self { instructions: Vec::with_capacity(instr_capacity), label_ptrs: Vec::with_capacity(256) }
+impl<'m> FunctionBuilder<'m> {
+    pub(crate) fn new(
+        instr_capacity: usize,
+        options: TranslateOptions,
+        memory_sizes: &'m [u64],
+        func_types: &'m [FuncType],
+    ) -> Self {
+        let mut instructions = Vec::with_capacity(instr_capacity);
+        if options.yield_points {
+            instructions.push(Instruction::Yield);
+        }
+        if options.coverage {
+            instructions.push(Instruction::Probe);
+        }
+        let label_ptrs = Vec::with_capacity(256);
+        let constants = Constants::default();
+        let br_table_targets = Vec::new();
+        let unreachable = None;
+        Self { instructions, label_ptrs, br_table_targets, constants, options, memory_sizes, func_types, unreachable }
     }
 
     #[cold]
@@ -110,14 +143,81 @@ impl FunctionBuilder {
         Err(crate::ParseError::UnsupportedOperator(format!("Unsupported instruction: {:?}", name)))
     }
 
+    // memory and table indices of instructions are 16 bits, see `SmallMemAddr`
+    fn small_index(&self, idx: u32) -> Result<u16> {
+        idx.try_into().map_err(|_| crate::ParseError::UnsupportedOperator(format!("Index {} is too large", idx)))
+    }
+
+    fn block_args(&mut self, blockty: wasmparser::BlockType) -> Result<BlockArgsPacked> {
+        convert_blocktype(blockty, self.func_types, &mut self.constants)
+    }
+
+    // instructions with 64-bit immediates refer to them in the constant pool
+    fn visit_const(&mut self, value: u64, instr: impl FnOnce(ConstAddr) -> Instruction) -> Result<()> {
+        if self.unreachable.is_some() {
+            return Ok(());
+        }
+        let addr = self.constants.add(value);
+        self.visit(instr(addr))
+    }
+
     #[inline]
     fn visit(&mut self, op: Instruction) -> Result<()> {
+        if self.unreachable.is_some() {
+            return Ok(());
+        }
+
         self.instructions.push(op);
+        if self.options.fuse {
+            peephole::optimize(&mut self.instructions, &mut self.constants, self.memory_sizes, self.options.rules);
+
+            // everything up to the end of the block is unreachable, the validator has already checked it
+            if let Some(Instruction::Br(_) | Instruction::Return | Instruction::Unreachable) = self.instructions.last()
+            {
+                self.unreachable = Some(0);
+            }
+        }
+        Ok(())
+    }
+
+    // skip blocks inside unreachable code, returns true if the block was skipped
+    fn skip_block_start(&mut self) -> bool {
+        match &mut self.unreachable {
+            Some(depth) => {
+                *depth += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the end of the block or the `else` branch that contains the unreachable code is reachable again,
+    // returns true if the instruction was skipped
+    fn skip_block_end(&mut self) -> bool {
+        match self.unreachable {
+            Some(0) => {
+                self.unreachable = None;
+                false
+            }
+            Some(depth) => {
+                self.unreachable = Some(depth - 1);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // a new basic block starts after block instructions and conditional branches
+    fn visit_block_start(&mut self, op: Instruction) -> Result<()> {
+        self.visit(op)?;
+        if self.options.coverage {
+            self.visit(Instruction::Probe)?;
+        }
         Ok(())
     }
 }
 
-impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
+impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder<'_> {
     type Output = Result<()>;
 
     fn visit_default(&mut self, op: &str) -> Self::Output {
@@ -126,11 +226,13 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
 
     define_primitive_operands! {
         visit_br, Instruction::Br, u32,
-        visit_br_if, Instruction::BrIf, u32,
         visit_global_get, Instruction::GlobalGet, u32,
         visit_global_set, Instruction::GlobalSet, u32,
-        visit_i32_const, Instruction::I32Const, i32,
-        visit_i64_const, Instruction::I64Const, i64
+        visit_i32_const, Instruction::I32Const, i32
+    }
+
+    fn visit_i64_const(&mut self, value: i64) -> Self::Output {
+        self.visit_const(value as u64, Instruction::I64Const)
     }
 
     define_mem_operands! {
@@ -202,7 +304,7 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
         visit_i32_clz, Instruction::I32Clz,
         visit_i32_ctz, Instruction::I32Ctz,
         visit_i32_popcnt, Instruction::I32Popcnt,
-        // visit_i32_add, Instruction::I32Add, custom implementation
+        visit_i32_add, Instruction::I32Add,
         visit_i32_sub, Instruction::I32Sub,
         visit_i32_mul, Instruction::I32Mul,
         visit_i32_div_s, Instruction::I32DivS,
@@ -233,7 +335,7 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
         visit_i64_shl, Instruction::I64Shl,
         visit_i64_shr_s, Instruction::I64ShrS,
         visit_i64_shr_u, Instruction::I64ShrU,
-        // visit_i64_rotl, Instruction::I64Rotl, custom implementation
+        visit_i64_rotl, Instruction::I64Rotl,
         visit_i64_rotr, Instruction::I64Rotr,
         visit_f32_abs, Instruction::F32Abs,
         visit_f32_neg, Instruction::F32Neg,
@@ -308,89 +410,68 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
     }
 
     fn visit_local_get(&mut self, idx: u32) -> Self::Output {
-        if let Some(instruction) = self.instructions.last_mut() {
-            match instruction {
-                Instruction::LocalGet(a) => *instruction = Instruction::LocalGet2(*a, idx),
-                Instruction::LocalGet2(a, b) => *instruction = Instruction::LocalGet3(*a, *b, idx),
-                Instruction::LocalTee(a) => *instruction = Instruction::LocalTeeGet(*a, idx),
-                _ => return self.visit(Instruction::LocalGet(idx)),
-            };
-            Ok(())
-        } else {
-            self.visit(Instruction::LocalGet(idx))
-        }
+        self.visit(Instruction::LocalGet(idx))
     }
 
     fn visit_local_set(&mut self, idx: u32) -> Self::Output {
         self.visit(Instruction::LocalSet(idx))
-        // if let Some(instruction) = self.instructions.last_mut() {
-        //     match instruction {
-        //         // Needs more testing, seems to make performance worse
-        //         // Instruction::LocalGet(a) => *instruction = Instruction::LocalGetSet(*a, idx),
-        //         _ => return self.visit(Instruction::LocalSet(idx)),
-        //     };
-        //     // Ok(())
-        // } else {
-        //     self.visit(Instruction::LocalSet(idx))
-        // }
     }
 
     fn visit_local_tee(&mut self, idx: u32) -> Self::Output {
         self.visit(Instruction::LocalTee(idx))
     }
 
-    fn visit_i64_rotl(&mut self) -> Self::Output {
-        if self.instructions.len() < 2 {
-            return self.visit(Instruction::I64Rotl);
-        }
-
-        match self.instructions[self.instructions.len() - 2..] {
-            [Instruction::I64Xor, Instruction::I64Const(a)] => {
-                self.instructions.pop();
-                self.instructions.pop();
-                self.visit(Instruction::I64XorConstRotl(a))
-            }
-            _ => self.visit(Instruction::I64Rotl),
+    fn visit_block(&mut self, blockty: wasmparser::BlockType) -> Self::Output {
+        if self.skip_block_start() {
+            return Ok(());
         }
+        self.label_ptrs.push(self.instructions.len());
+        self.visit_block_start(Instruction::Block(self.block_args(blockty)?, 0))
     }
 
-    fn visit_i32_add(&mut self) -> Self::Output {
-        self.visit(Instruction::I32Add)
-        // if self.instructions.len() < 2 {
-        //     return self.visit(Instruction::I32Add);
-        // }
-
-        // match self.instructions[self.instructions.len() - 2..] {
-        //     // [Instruction::LocalGet(a), Instruction::I32Const(b)] => {
-        //     //     self.instructions.pop();
-        //     //     self.instructions.pop();
-        //     //     self.visit(Instruction::I32LocalGetConstAdd(a, b))
-        //     // }
-        //     _ => self.visit(Instruction::I32Add),
-        // }
-    }
-
-    fn visit_block(&mut self, blockty: wasmparser::BlockType) -> Self::Output {
-        self.label_ptrs.push(self.instructions.len());
-        self.visit(Instruction::Block(convert_blocktype(blockty), 0))
+    fn visit_br_if(&mut self, relative_depth: u32) -> Self::Output {
+        self.visit_block_start(Instruction::BrIf(relative_depth))
     }
 
     fn visit_loop(&mut self, ty: wasmparser::BlockType) -> Self::Output {
+        if self.skip_block_start() {
+            return Ok(());
+        }
         self.label_ptrs.push(self.instructions.len());
-        self.visit(Instruction::Loop(convert_blocktype(ty), 0))
+        self.visit(Instruction::Loop(self.block_args(ty)?, 0))?;
+
+        // branches to a loop continue after the loop instruction, so this runs on every iteration
+        if self.options.yield_points {
+            self.visit(Instruction::Yield)?;
+        }
+        if self.options.coverage {
+            self.visit(Instruction::Probe)?;
+        }
+        Ok(())
     }
 
     fn visit_if(&mut self, ty: wasmparser::BlockType) -> Self::Output {
+        if self.skip_block_start() {
+            return Ok(());
+        }
         self.label_ptrs.push(self.instructions.len());
-        self.visit(Instruction::If(BlockArgsPacked::new(convert_blocktype(ty)), 0, 0))
+        self.visit_block_start(Instruction::If(self.block_args(ty)?, 0))
     }
 
     fn visit_else(&mut self) -> Self::Output {
+        // the `else` of an `if` inside the unreachable code is skipped with it
+        if self.unreachable.is_some_and(|depth| depth > 0) {
+            return Ok(());
+        }
+        self.unreachable = None;
         self.label_ptrs.push(self.instructions.len());
-        self.visit(Instruction::Else(0))
+        self.visit_block_start(Instruction::Else(0))
     }
 
     fn visit_end(&mut self) -> Self::Output {
+        if self.skip_block_end() {
+            return Ok(());
+        }
         let Some(label_pointer) = self.label_ptrs.pop() else {
             return self.visit(Instruction::EndFunc);
         };
@@ -414,21 +495,18 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
                 let if_label_pointer = self.label_ptrs.pop().ok_or_else(error)?;
 
                 let if_instruction = &mut self.instructions[if_label_pointer];
-                let Instruction::If(_, ref mut else_offset, ref mut end_offset) = if_instruction else {
+                let Instruction::If(_, ref mut else_offset) = if_instruction else {
                     return Err(error());
                 };
 
+                // the end of the if block is found through the else instruction
                 *else_offset = (label_pointer - if_label_pointer)
                     .try_into()
                     .expect("else_instr_end_offset is too large, tinywasm does not support blocks that large");
-
-                *end_offset = (current_instr_ptr - if_label_pointer)
-                    .try_into()
-                    .expect("else_instr_end_offset is too large, tinywasm does not support blocks that large");
             }
             Instruction::Block(_, ref mut end_offset)
             | Instruction::Loop(_, ref mut end_offset)
-            | Instruction::If(_, _, ref mut end_offset) => {
+            | Instruction::If(_, ref mut end_offset) => {
                 *end_offset = (current_instr_ptr - label_pointer)
                     .try_into()
                     .expect("else_instr_end_offset is too large, tinywasm does not support  blocks that large");
@@ -440,20 +518,26 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
             }
         };
 
-        self.visit(Instruction::EndBlockFrame)
+        self.visit_block_start(Instruction::EndBlockFrame)
     }
 
     fn visit_br_table(&mut self, targets: wasmparser::BrTable<'_>) -> Self::Output {
-        let def = targets.default();
-        let instrs = targets
-            .targets()
-            .map(|t| t.map(Instruction::BrLabel))
-            .collect::<Result<Vec<Instruction>, wasmparser::BinaryReaderError>>()
-            .expect("BrTable targets are invalid, this should have been caught by the validator");
+        if self.unreachable.is_some() {
+            return Ok(());
+        }
 
-        self.instructions
-            .extend(IntoIterator::into_iter([Instruction::BrTable(def, instrs.len() as u32)]).chain(instrs));
+        let start = self.br_table_targets.len() as u32;
+        self.br_table_targets.push(targets.len());
+        for target in targets.targets() {
+            let target = target.expect("BrTable targets are invalid, this should have been caught by the validator");
+            self.br_table_targets.push(target);
+        }
+        self.br_table_targets.push(targets.default());
 
+        self.visit(Instruction::BrTable(start))?;
+        if self.options.fuse {
+            self.unreachable = Some(0);
+        }
         Ok(())
     }
 
@@ -462,6 +546,7 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
     }
 
     fn visit_call_indirect(&mut self, ty: u32, table: u32, _table_byte: u8) -> Self::Output {
+        let table = self.small_index(table)?;
         self.visit(Instruction::CallIndirect(ty, table))
     }
 
@@ -478,16 +563,26 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
     }
 
     fn visit_f64_const(&mut self, val: wasmparser::Ieee64) -> Self::Output {
-        self.visit(Instruction::F64Const(f64::from_bits(val.bits())))
+        self.visit_const(val.bits(), Instruction::F64Const)
     }
 
     // Bulk Memory Operations
 
-    define_primitive_operands! {
-        visit_memory_init, Instruction::MemoryInit, u32, u32,
-        visit_memory_copy, Instruction::MemoryCopy, u32, u32,
-        visit_table_init, Instruction::TableInit, u32, u32
+    fn visit_memory_init(&mut self, data_index: u32, mem: u32) -> Self::Output {
+        let mem = self.small_index(mem)?;
+        self.visit(Instruction::MemoryInit(data_index, mem))
+    }
+
+    fn visit_memory_copy(&mut self, dst_mem: u32, src_mem: u32) -> Self::Output {
+        let (dst_mem, src_mem) = (self.small_index(dst_mem)?, self.small_index(src_mem)?);
+        self.visit(Instruction::MemoryCopy(dst_mem, src_mem))
+    }
+
+    fn visit_table_init(&mut self, elem_index: u32, table: u32) -> Self::Output {
+        let table = self.small_index(table)?;
+        self.visit(Instruction::TableInit(elem_index, table))
     }
+
     define_primitive_operands! {
         visit_memory_fill, Instruction::MemoryFill, u32,
         visit_data_drop, Instruction::DataDrop, u32
@@ -498,7 +593,8 @@ impl<'a> wasmparser::VisitOperator<'a> for FunctionBuilder {
     }
 
     fn visit_table_copy(&mut self, dst_table: u32, src_table: u32) -> Self::Output {
-        self.visit(Instruction::TableCopy { from: src_table, to: dst_table })
+        let (from, to) = (self.small_index(src_table)?, self.small_index(dst_table)?);
+        self.visit(Instruction::TableCopy { from, to })
     }
 
     // Reference Types
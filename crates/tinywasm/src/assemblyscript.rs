@@ -0,0 +1,146 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use tinywasm_types::{FuncType, ValType, WasmValue};
+
+use crate::{log, Error, Extern, FuncContext, Imports, Result, Trap};
+
+impl Imports {
+    /// Define the `env` imports AssemblyScript modules use by default
+    ///
+    /// * `abort` traps with [`Trap::Abort`], containing the decoded message and location
+    /// * `trace` logs the message and its arguments at the `info` level
+    /// * `seed` returns the seed for `Math.random`, based on the current time with `std`.
+    ///   Without `std`, a fixed seed is used, so a custom `seed` should be defined instead.
+    ///
+    /// Strings are read from the memory exported as `memory`, with invalid UTF-16 (such as unpaired
+    /// surrogates) replaced by `U+FFFD`. Any of these functions can be replaced by defining it again afterwards.
+    #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+    pub fn define_assemblyscript(&mut self) -> Result<&mut Self> {
+        let abort = Extern::typed_func(|mut ctx, (message, file, line, column): (i32, i32, i32, i32)| {
+            let message = load_string(&mut ctx, message)?;
+            let file = load_string(&mut ctx, file)?;
+            Err::<(), _>(Trap::Abort { message, file, line: line as u32, column: column as u32 }.into())
+        });
+
+        // trace(message, n, a0, a1, a2, a3, a4) has more parameters than typed functions support
+        let params = [[ValType::I32; 2].as_slice(), &[ValType::F64; 5]].concat();
+        let trace_ty = FuncType { params: params.into(), results: [].into() };
+        let trace = Extern::func(&trace_ty, |mut ctx, args| {
+            let (WasmValue::I32(message), WasmValue::I32(n)) = (args[0], args[1]) else {
+                unreachable!("arguments are checked against the function type")
+            };
+            let message = load_string(&mut ctx, message)?;
+            let args: Vec<_> =
+                args[2..].iter().take(n.clamp(0, 5) as usize).filter_map(|arg| f64::try_from(*arg).ok()).collect();
+            log::info!("trace: {} {:?}", message, args);
+            Ok(Vec::new())
+        });
+
+        let seed = Extern::typed_func(|_, ()| Ok(seed()));
+
+        self.define("env", "abort", abort)?;
+        self.define("env", "trace", trace)?;
+        self.define("env", "seed", seed)
+    }
+}
+
+#[cfg(feature = "std")]
+fn seed() -> f64 {
+    use crate::std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(1.0, |time| time.as_nanos() as f64)
+}
+
+#[cfg(not(feature = "std"))]
+fn seed() -> f64 {
+    1.0
+}
+
+// AssemblyScript passes null for missing strings, which it prints as "null"
+//
+// JavaScript strings don't have to be valid UTF-16, and a message that can't be decoded
+// shouldn't hide the abort itself, so this decodes lossily unlike `MemoryStringExt::load_as_string`
+fn load_string(ctx: &mut FuncContext<'_>, ptr: i32) -> Result<String> {
+    if ptr == 0 {
+        return Ok(String::from("null"));
+    }
+
+    let memory = ctx.exported_memory("memory")?;
+    let ptr = ptr as u32 as usize;
+    let header = ptr.checked_sub(4).ok_or_else(|| Error::Other("Invalid AssemblyScript string".into()))?;
+    let size = memory.load(header, 4)?;
+    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+    let units = memory.load(ptr, size as usize)?.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+    Ok(char::decode_utf16(units).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Store};
+    use alloc::{string::ToString, vec};
+    use tinywasm_types::*;
+
+    // an AssemblyScript string: its size in bytes followed by its UTF-16 code units
+    fn as_string(s: &str) -> Vec<u8> {
+        let units: Vec<u8> = s.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        [(units.len() as u32).to_le_bytes().as_slice(), &units].concat()
+    }
+
+    #[test]
+    fn test_abort() -> Result<()> {
+        let abort_ty = FuncType { params: [ValType::I32; 4].into(), results: [].into() };
+        let run = WasmFunction {
+            instructions: vec![
+                Instruction::I32Const(4),
+                Instruction::I32Const(32),
+                Instruction::I32Const(3),
+                Instruction::I32Const(5),
+                Instruction::Call(0),
+                Instruction::EndFunc,
+            ]
+            .into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: FuncType::default(),
+        };
+
+        // the message "héllo 🦀" is stored at 4, the file name at 32
+        let data = [as_string("héllo 🦀"), vec![0; 8], as_string("main.ts")].concat();
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![run].into(),
+            func_types: vec![abort_ty, FuncType::default()].into(),
+            imports: vec![Import { module: "env".into(), name: "abort".into(), kind: ImportKind::Function(0) }].into(),
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            data: vec![Data {
+                data: data.into(),
+                range: 0..0,
+                kind: DataKind::Active { mem: 0, offset: ConstInstruction::I32Const(0) },
+            }]
+            .into(),
+            exports: vec![
+                Export { name: "memory".into(), kind: ExternalKind::Memory, index: 0 },
+                Export { name: "run".into(), kind: ExternalKind::Func, index: 1 },
+            ]
+            .into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.define_assemblyscript()?;
+        let instance = module.instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<(), ()>(&store, "run")?;
+
+        let Err(Error::Trap(trap)) = run.call(&mut store, ()) else { panic!("abort should trap") };
+        assert_eq!(trap.to_string(), "abort: héllo 🦀 in main.ts(3:5)");
+
+        // an unpaired surrogate in the message
+        instance.exported_memory_mut(&mut store, "memory")?.store(4, 2, &0xd800u16.to_le_bytes())?;
+        let Err(Error::Trap(trap)) = run.call(&mut store, ()) else { panic!("abort should trap") };
+        assert_eq!(trap.to_string(), "abort: \u{fffd}éllo 🦀 in main.ts(3:5)");
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use tinywasm_types::WasmValue;
+
+use crate::sync::{Rc, RefCell};
+use crate::{Error, FuncContext, FuncHandle, ModuleInstance, Result, Store};
+
+// the values returned by `asyncify_get_state`
+const STATE_UNWINDING: i32 = 1;
+const STATE_REWINDING: i32 = 2;
+
+// the function and arguments to call again when resuming
+type SuspendedCall = (FuncHandle, Vec<WasmValue>);
+
+/// Support for modules transformed with Binaryen's `--asyncify` pass
+///
+/// Asyncify lets a host function suspend the WebAssembly code calling it: the host function calls
+/// [`Asyncify::suspend`], the guest unwinds its stack into linear memory, and [`Asyncify::call_asyncified`]
+/// returns [`AsyncifyResult::Suspended`]. Later, [`Asyncify::resume`] rewinds the stack and calls the
+/// host function again, which can then return its result.
+///
+/// The unwound stack is stored in the guest memory between `data` and `end`, which must not be used
+/// by the guest otherwise. The first 8 bytes of this region hold asyncify's bookkeeping.
+/// Clones of an `Asyncify` share the suspended call, so a clone can be moved into the host function.
+///
+/// ```rust
+/// # use tinywasm::{Asyncify, Extern};
+/// let asyncify = Asyncify::new(1024, 4096);
+/// let suspending = asyncify.clone();
+/// let sleep = Extern::typed_func(move |mut ctx, ()| {
+///     let resumed = suspending.suspend(&mut ctx)?;
+///     // the return value is ignored while unwinding
+///     Ok(resumed as i32)
+/// });
+/// ```
+#[derive(Debug, Clone)]
+pub struct Asyncify {
+    data: u32,
+    end: u32,
+    suspended: Rc<RefCell<Option<SuspendedCall>>>,
+}
+
+/// The result of [`Asyncify::call_asyncified`] and [`Asyncify::resume`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsyncifyResult {
+    /// The function returned
+    Finished(Vec<WasmValue>),
+    /// A host function suspended the call, which can be continued with [`Asyncify::resume`]
+    Suspended,
+}
+
+impl Asyncify {
+    /// Create a new asyncify helper storing unwound stacks in the guest memory between `data` and `end`
+    pub fn new(data: u32, end: u32) -> Self {
+        Self { data, end, suspended: Rc::new(RefCell::new(None)) }
+    }
+
+    /// Check if a call is suspended
+    pub fn is_suspended(&self) -> bool {
+        self.suspended.borrow().is_some()
+    }
+
+    /// Call an exported function of an asyncified module
+    pub fn call_asyncified(
+        &self,
+        store: &mut Store,
+        func: &FuncHandle,
+        params: &[WasmValue],
+    ) -> Result<AsyncifyResult> {
+        if self.is_suspended() {
+            return Err(Error::Other("Asyncify: a call is already suspended".to_string()));
+        }
+
+        let result = func.call(store, params)?;
+        self.finish(store, func, params, result)
+    }
+
+    /// Continue the suspended call
+    pub fn resume(&self, store: &mut Store) -> Result<AsyncifyResult> {
+        let Some((func, params)) = self.suspended.borrow_mut().take() else {
+            return Err(Error::Other("Asyncify: no call is suspended".to_string()));
+        };
+
+        let instance = Self::instance(store, &func)?;
+        instance.exported_func::<i32, ()>(store, "asyncify_start_rewind")?.call(store, self.data as i32)?;
+        let result = func.call(store, &params)?;
+        self.finish(store, &func, &params, result)
+    }
+
+    /// Suspend the call of the calling host function, or finish resuming it
+    ///
+    /// Returns `false` if the call is being suspended, in which case the host function should return
+    /// immediately. Its return value is ignored. When the call is resumed, the host function is called
+    /// again with the same arguments and this returns `true`.
+    pub fn suspend(&self, ctx: &mut FuncContext<'_>) -> Result<bool> {
+        let instance = ctx.module()?;
+        let store = ctx.store_mut();
+
+        if instance.exported_func::<(), i32>(store, "asyncify_get_state")?.call(store, ())? == STATE_REWINDING {
+            instance.exported_func::<(), ()>(store, "asyncify_stop_rewind")?.call(store, ())?;
+            return Ok(true);
+        }
+
+        // the current position and end of the unwound stack
+        let header = [(self.data + 8).to_le_bytes(), self.end.to_le_bytes()].concat();
+        instance.exported_memory_mut(store, "memory")?.store(self.data as usize, header.len(), &header)?;
+        instance.exported_func::<i32, ()>(store, "asyncify_start_unwind")?.call(store, self.data as i32)?;
+        Ok(false)
+    }
+
+    // check if the call returned because it is unwinding
+    fn finish(
+        &self,
+        store: &mut Store,
+        func: &FuncHandle,
+        params: &[WasmValue],
+        result: Vec<WasmValue>,
+    ) -> Result<AsyncifyResult> {
+        let instance = Self::instance(store, func)?;
+        if instance.exported_func::<(), i32>(store, "asyncify_get_state")?.call(store, ())? != STATE_UNWINDING {
+            return Ok(AsyncifyResult::Finished(result));
+        }
+
+        instance.exported_func::<(), ()>(store, "asyncify_stop_unwind")?.call(store, ())?;
+        *self.suspended.borrow_mut() = Some((func.clone(), params.to_vec()));
+        Ok(AsyncifyResult::Suspended)
+    }
+
+    fn instance(store: &Store, func: &FuncHandle) -> Result<ModuleInstance> {
+        store.get_module_instance(func.module_addr).cloned().ok_or_else(|| Store::removed_error(func.module_addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Extern, Imports, Module};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    // add a function without locals, ending `instructions` with `EndFunc`
+    fn add_func(
+        builder: &mut ModuleBuilder,
+        params: &[ValType],
+        results: &[ValType],
+        instructions: &[Instruction],
+    ) -> FuncAddr {
+        let ty = builder.add_type(FuncType { params: params.into(), results: results.into() });
+        builder.add_function(ty, [], [instructions, &[Instruction::EndFunc]].concat())
+    }
+
+    // a module that behaves like an asyncified one, without actually saving its stack:
+    // `main` returns `sleep() + 1`, or 0 if `sleep` started unwinding
+    fn asyncified_module() -> Module {
+        let mut builder = ModuleBuilder::new();
+        let ret = builder.add_type(FuncType { params: [].into(), results: [ValType::I32].into() });
+        let sleep = builder.add_import("env", "sleep", ImportKind::Function(ret));
+        let state = builder.add_global(GlobalType { mutable: true, ty: ValType::I32 }, ConstInstruction::I32Const(0));
+        let mem = builder.add_memory(MemoryType::new_32(1, None));
+
+        let i32 = ValType::I32;
+        let set_state = |state| [Instruction::I32Const(state), Instruction::GlobalSet(0)];
+        let funcs = [
+            ("asyncify_start_unwind", add_func(&mut builder, &[i32], &[], &set_state(STATE_UNWINDING))),
+            ("asyncify_stop_unwind", add_func(&mut builder, &[], &[], &set_state(0))),
+            ("asyncify_start_rewind", add_func(&mut builder, &[i32], &[], &set_state(STATE_REWINDING))),
+            ("asyncify_stop_rewind", add_func(&mut builder, &[], &[], &set_state(0))),
+            ("asyncify_get_state", add_func(&mut builder, &[], &[i32], &[Instruction::GlobalGet(state)])),
+        ];
+        let main = [
+            Instruction::Call(sleep),
+            Instruction::I32Const(1),
+            Instruction::I32Add,
+            Instruction::I32Const(0),
+            Instruction::GlobalGet(state),
+            Instruction::I32Const(STATE_UNWINDING),
+            Instruction::I32Ne,
+            Instruction::Select(None),
+        ];
+        let main = add_func(&mut builder, &[], &[i32], &main);
+
+        for (name, func) in funcs.into_iter().chain([("main", main)]) {
+            builder.add_export(name, ExternalKind::Func, func);
+        }
+        builder.add_export("memory", ExternalKind::Memory, mem);
+        Module::from(builder.finish().expect("valid module"))
+    }
+
+    #[test]
+    fn test_asyncify() -> Result<()> {
+        let asyncify = Asyncify::new(16, 1024);
+        let suspending = asyncify.clone();
+
+        let mut imports = Imports::new();
+        let sleep = Extern::typed_func(move |mut ctx, ()| Ok(if suspending.suspend(&mut ctx)? { 41 } else { 0 }));
+        imports.define("env", "sleep", sleep)?;
+
+        let mut store = Store::default();
+        let instance = asyncified_module().instantiate(&mut store, Some(imports))?;
+        let main = instance.exported_func_untyped(&store, "main")?;
+
+        assert_eq!(asyncify.call_asyncified(&mut store, &main, &[])?, AsyncifyResult::Suspended);
+        assert!(asyncify.is_suspended());
+        assert_eq!(instance.exported_memory(&mut store, "memory")?.load(16, 8)?, [24, 0, 0, 0, 0, 4, 0, 0]);
+        assert!(asyncify.call_asyncified(&mut store, &main, &[]).is_err());
+
+        assert_eq!(asyncify.resume(&mut store)?, AsyncifyResult::Finished(vec![WasmValue::I32(42)]));
+        assert!(!asyncify.is_suspended());
+        assert!(asyncify.resume(&mut store).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use tinywasm_types::FuncAddr;
+
+use crate::error::FuncDisplay;
+use crate::runtime::CallFrame;
+use crate::Store;
+
+/// The WebAssembly call stack at the point a function call trapped
+///
+/// See [`Store::last_backtrace`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Backtrace {
+    /// The frames of the call stack, starting with the innermost one (where the trap occurred)
+    pub frames: Vec<BacktraceFrame>,
+}
+
+/// A single frame of a [`Backtrace`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BacktraceFrame {
+    /// The address of the function in the store
+    pub func_addr: FuncAddr,
+    /// The index of the function in its module, if the module instance still exists
+    pub func_index: Option<FuncAddr>,
+    /// The name of the function, see [`Store::func_name`]
+    pub name: Option<String>,
+    /// The index of the executed instruction in the function
+    pub instr_ptr: usize,
+    /// The byte offset of the executed instruction in the original code section, see [`Store::code_offset`]
+    pub code_offset: Option<u32>,
+}
+
+impl Backtrace {
+    // frames are stored with the innermost frame last, and their instruction pointers
+    // already point past the instruction that trapped or made the call
+    pub(crate) fn capture(store: &Store, frames: &[CallFrame]) -> Self {
+        let frames = frames.iter().rev().map(|cf| {
+            let (func_addr, instr_ptr) = (cf.func_addr, cf.instr_ptr.saturating_sub(1));
+            BacktraceFrame {
+                func_addr,
+                func_index: store.func_index(func_addr),
+                name: store.func_name(func_addr).map(ToString::to_string),
+                instr_ptr,
+                code_offset: store.code_offset(func_addr, instr_ptr),
+            }
+        });
+
+        Self { frames: frames.collect() }
+    }
+}
+
+impl Display for BacktraceFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self.func_index {
+            Some(index) => write!(f, "{}", FuncDisplay(index, self.name.as_deref()))?,
+            None => {
+                write!(f, "func@{}", self.func_addr)?;
+                if let Some(name) = &self.name {
+                    write!(f, " '{}'", name)?;
+                }
+            }
+        }
+
+        match self.code_offset {
+            Some(offset) => write!(f, " at code offset {:#x}", offset),
+            None => write!(f, " at instruction {}", self.instr_ptr),
+        }
+    }
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for (i, frame) in self.frames.iter().enumerate() {
+            writeln!(f, "{:>4}: {}", i, frame)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use alloc::vec::Vec;
+use tinywasm_types::WasmValue;
+
+use crate::sync::{Rc, RefCell};
+use crate::{Extern, FuncContext, Function, HostFunction, Trap};
+
+#[cfg(feature = "std")]
+use crate::std::time::{Duration, Instant};
+
+/// A budget for calls into host functions
+///
+/// A budget limits how often, and with `std` for how long in total, the guest may call the
+/// host functions it is attached to (see [`Extern::with_budget`]). Once the budget is exhausted,
+/// further calls trap with [`Trap::HostBudgetExceeded`] until the budget is [`reset`](HostBudget::reset).
+///
+/// Time is measured after each call returns, so the call that exhausts a time budget still completes.
+/// Clones of a budget share the same usage, so a budget can be attached to several functions
+/// to limit their combined use.
+///
+/// ```rust
+/// # use tinywasm::{Extern, HostBudget};
+/// # use std::time::Duration;
+/// let budget = HostBudget::new().max_calls(1000).max_time(Duration::from_millis(1));
+/// let render = Extern::typed_func(|_ctx, ()| Ok(())).with_budget(&budget);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HostBudget {
+    max_calls: Option<u64>,
+    #[cfg(feature = "std")]
+    max_time: Option<Duration>,
+    used: Rc<RefCell<Usage>>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    calls: u64,
+    #[cfg(feature = "std")]
+    time: Duration,
+}
+
+impl HostBudget {
+    /// Create a new, unlimited budget
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the number of calls
+    pub fn max_calls(mut self, calls: u64) -> Self {
+        self.max_calls = Some(calls);
+        self
+    }
+
+    /// Limit the total time spent in the host functions
+    #[cfg(feature = "std")]
+    pub fn max_time(mut self, time: Duration) -> Self {
+        self.max_time = Some(time);
+        self
+    }
+
+    /// The number of calls made since the budget was created or reset
+    pub fn calls(&self) -> u64 {
+        self.used.borrow().calls
+    }
+
+    /// The time spent in the host functions since the budget was created or reset
+    #[cfg(feature = "std")]
+    pub fn time(&self) -> Duration {
+        self.used.borrow().time
+    }
+
+    /// Check if the budget is exhausted
+    pub fn is_exhausted(&self) -> bool {
+        let used = self.used.borrow();
+
+        #[cfg(feature = "std")]
+        if self.max_time.is_some_and(|max| used.time >= max) {
+            return true;
+        }
+
+        self.max_calls.is_some_and(|max| used.calls >= max)
+    }
+
+    /// Reset the usage of the budget (and all of its clones)
+    pub fn reset(&self) {
+        *self.used.borrow_mut() = Usage::default();
+    }
+
+    fn charge(&self, ctx: FuncContext<'_>, func: &HostFunction, args: &[WasmValue]) -> crate::Result<Vec<WasmValue>> {
+        if self.is_exhausted() {
+            crate::log::debug!("host budget exceeded");
+            return Err(Trap::HostBudgetExceeded.into());
+        }
+        self.used.borrow_mut().calls += 1;
+
+        #[cfg(feature = "std")]
+        let start = Instant::now();
+        let res = func.call(ctx, args);
+
+        #[cfg(feature = "std")]
+        {
+            self.used.borrow_mut().time += start.elapsed();
+        }
+
+        res
+    }
+}
+
+impl Extern {
+    /// Attach a budget to a host function
+    ///
+    /// Other externs are returned unchanged. See [`HostBudget`]
+    pub fn with_budget(self, budget: &HostBudget) -> Self {
+        let Self::Function(Function::Host(func)) = self else {
+            return self;
+        };
+
+        let budget = budget.clone();
+        let ty = func.ty.clone();
+        Extern::func(&ty, move |ctx, args| budget.charge(ctx, &func, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Store;
+
+    fn call(func: &Extern, store: &mut Store) -> crate::Result<Vec<WasmValue>> {
+        let Extern::Function(Function::Host(func)) = func else { unreachable!() };
+        func.call(FuncContext { store, module_addr: 0, frame: None }, &[])
+    }
+
+    #[test]
+    fn test_call_budget() {
+        let mut store = Store::default();
+        let budget = HostBudget::new().max_calls(2);
+        let a = Extern::typed_func(|_, ()| Ok(())).with_budget(&budget);
+        let b = Extern::typed_func(|_, ()| Ok(())).with_budget(&budget);
+
+        assert!(call(&a, &mut store).is_ok());
+        assert!(call(&b, &mut store).is_ok());
+        assert!(matches!(call(&a, &mut store), Err(crate::Error::Trap(Trap::HostBudgetExceeded))));
+        assert_eq!(budget.calls(), 2);
+
+        budget.reset();
+        assert!(call(&b, &mut store).is_ok());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_time_budget() {
+        let mut store = Store::default();
+        let budget = HostBudget::new().max_time(Duration::from_millis(1));
+        let slow = Extern::typed_func(|_, ()| {
+            crate::std::thread::sleep(Duration::from_millis(2));
+            Ok(())
+        });
+        let slow = slow.with_budget(&budget);
+
+        assert!(call(&slow, &mut store).is_ok());
+        assert!(budget.time() >= Duration::from_millis(2));
+        assert!(budget.is_exhausted());
+        assert!(call(&slow, &mut store).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{Imports, Result};
+
+/// A reusable set of imports, e.g. host functions packaged in their own crate
+///
+/// Bundles are added to an import set with [`Imports::add_bundle`]. Functions taking and returning
+/// `&mut Imports` are bundles as well, which includes the built-in ones like [`Imports::define_metering`].
+///
+/// ```rust
+/// use tinywasm::{Extern, HostBundle, Imports, Result};
+///
+/// struct Logging {
+///     target: &'static str,
+/// }
+///
+/// impl HostBundle for Logging {
+///     fn define(self, imports: &mut Imports) -> Result<()> {
+///         let target = self.target;
+///         imports.define("log", "info", Extern::typed_func(move |_, code: i32| {
+///             println!("[{}] {}", target, code);
+///             Ok(())
+///         }))?;
+///         Ok(())
+///     }
+/// }
+///
+/// let mut imports = Imports::new();
+/// imports.add_bundle(Logging { target: "plugin" })?.add_bundle(Imports::define_metering)?;
+/// # Ok::<(), tinywasm::Error>(())
+/// ```
+pub trait HostBundle {
+    /// Define the bundle's imports
+    fn define(self, imports: &mut Imports) -> Result<()>;
+}
+
+impl<F> HostBundle for F
+where
+    F: for<'a> FnOnce(&'a mut Imports) -> Result<&'a mut Imports>,
+{
+    fn define(self, imports: &mut Imports) -> Result<()> {
+        self(imports).map(|_| ())
+    }
+}
+
+impl Imports {
+    /// Add the imports of a [`HostBundle`]
+    ///
+    /// Like [`Imports::define`], this replaces previously defined imports with the same name.
+    pub fn add_bundle(&mut self, bundle: impl HostBundle) -> Result<&mut Self> {
+        bundle.define(self)?;
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{Rc, RefCell};
+    use crate::{Extern, Module, Store};
+    use alloc::{vec, vec::Vec};
+    use tinywasm_types::*;
+
+    struct Recorder(Rc<RefCell<Vec<i32>>>);
+
+    impl HostBundle for Recorder {
+        fn define(self, imports: &mut Imports) -> Result<()> {
+            let values = self.0;
+            imports.define(
+                "recorder",
+                "record",
+                Extern::typed_func(move |_, value: i32| {
+                    values.borrow_mut().push(value);
+                    Ok(())
+                }),
+            )?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bundles() -> Result<()> {
+        let record_ty = FuncType { params: [ValType::I32].into(), results: [].into() };
+        let run = WasmFunction {
+            instructions: vec![Instruction::I32Const(7), Instruction::Call(0), Instruction::EndFunc].into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: FuncType::default(),
+        };
+        let import = Import { module: "recorder".into(), name: "record".into(), kind: ImportKind::Function(0) };
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![run].into(),
+            func_types: vec![record_ty, FuncType::default()].into(),
+            imports: vec![import].into(),
+            exports: vec![Export { name: "run".into(), kind: ExternalKind::Func, index: 1 }].into(),
+            ..Default::default()
+        });
+
+        let values = Rc::new(RefCell::new(Vec::new()));
+        let mut imports = Imports::new();
+        imports.add_bundle(Recorder(values.clone()))?.add_bundle(Imports::define_metering)?;
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, Some(imports))?;
+        instance.exported_func::<(), ()>(&store, "run")?.call(&mut store, ())?;
+        assert_eq!(*values.borrow(), [7]);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use alloc::vec::Vec;
+use tinywasm_types::TinyWasmModule;
+
+use crate::{log, Module, Result};
+
+/// The SHA-256 hash of a WebAssembly binary, see [`ModuleCache`]
+pub type ModuleHash = [u8; 32];
+
+/// A cache of parsed modules, stored as `.twasm` archives and looked up by the hash of the binary
+///
+/// See [`Module::parse_bytes_cached`]. Caches can't fail: if an archive can't be stored or loaded,
+/// the binary is just parsed again. Archives created by a different version of tinywasm are ignored
+/// and replaced.
+pub trait ModuleCache {
+    /// Get the archive stored for a hash
+    fn get(&self, hash: &ModuleHash) -> Option<Vec<u8>>;
+
+    /// Store the archive of a module
+    fn put(&self, hash: &ModuleHash, archive: &[u8]);
+}
+
+impl Module {
+    /// Parse a module from bytes, or load it from a cache if it has been parsed before. Requires the `cache` feature.
+    ///
+    /// Cached archives are checked using [`TinyWasmModule::from_twasm_unaligned_verified`], which also checks
+    /// the types of all operands, so caches shared with other processes can't break the runtime.
+    pub fn parse_bytes_cached(wasm: &[u8], cache: &impl ModuleCache) -> Result<Self> {
+        let hash = sha256(wasm);
+
+        if let Some(archive) = cache.get(&hash) {
+            match TinyWasmModule::from_twasm_unaligned_verified(&archive) {
+                Ok(data) => return Ok(data.into()),
+                Err(_e) => log::debug!("ignoring cached module: {}", _e),
+            }
+        }
+
+        let module = Self::parse_bytes(wasm)?;
+        cache.put(&hash, &module.data.serialize_twasm());
+        Ok(module)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use fs::FsModuleCache;
+
+#[cfg(feature = "std")]
+mod fs {
+    use super::{ModuleCache, ModuleHash};
+    use crate::log;
+    use crate::std::{fs, path::PathBuf, vec::Vec};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    // makes the names of temporary files unique between threads of the same process
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    /// A [`ModuleCache`] storing archives as files in a directory
+    ///
+    /// The directory is created when the first module is stored. Archives are written to a temporary
+    /// file first and then renamed, so several processes can share the same directory.
+    #[derive(Debug, Clone)]
+    pub struct FsModuleCache {
+        dir: PathBuf,
+    }
+
+    impl FsModuleCache {
+        /// Create a cache storing archives in `dir`
+        pub fn new(dir: impl Into<PathBuf>) -> Self {
+            Self { dir: dir.into() }
+        }
+
+        fn path(&self, hash: &ModuleHash, extension: &str) -> PathBuf {
+            let name: alloc::string::String = hash.iter().map(|b| alloc::format!("{:02x}", b)).collect();
+            self.dir.join(name).with_extension(extension)
+        }
+    }
+
+    impl ModuleCache for FsModuleCache {
+        fn get(&self, hash: &ModuleHash) -> Option<Vec<u8>> {
+            fs::read(self.path(hash, "twasm")).ok()
+        }
+
+        fn put(&self, hash: &ModuleHash, archive: &[u8]) {
+            let id = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+            let tmp = self.path(hash, &alloc::format!("{}.{}.tmp", crate::std::process::id(), id));
+            let res = fs::create_dir_all(&self.dir)
+                .and_then(|()| fs::write(&tmp, archive))
+                .and_then(|()| fs::rename(&tmp, self.path(hash, "twasm")));
+
+            if let Err(_e) = res {
+                log::error!("failed to cache module in {}: {}", self.dir.display(), _e);
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+    }
+}
+
+// SHA-256, so that caches can be shared without having to worry about collisions
+fn sha256(data: &[u8]) -> ModuleHash {
+    use sha2::Digest;
+    sha2::Sha256::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::RefCell;
+    use alloc::collections::BTreeMap;
+
+    #[test]
+    fn test_sha256() {
+        let hex =
+            |hash: ModuleHash| hash.iter().map(|b| alloc::format!("{:02x}", b)).collect::<alloc::string::String>();
+        assert_eq!(hex(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(sha256(&[b'a'; 1000])), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[derive(Default)]
+    struct MemoryCache(RefCell<BTreeMap<ModuleHash, Vec<u8>>>);
+
+    impl ModuleCache for MemoryCache {
+        fn get(&self, hash: &ModuleHash) -> Option<Vec<u8>> {
+            self.0.borrow().get(hash).cloned()
+        }
+
+        fn put(&self, hash: &ModuleHash, archive: &[u8]) {
+            self.0.borrow_mut().insert(*hash, archive.to_vec());
+        }
+    }
+
+    #[test]
+    fn test_parse_bytes_cached() -> Result<()> {
+        let wasm = include_bytes!("../../../examples/wasm/add.wasm");
+        let cache = MemoryCache::default();
+
+        let module = Module::parse_bytes_cached(wasm, &cache)?;
+        assert_eq!(cache.0.borrow().len(), 1);
+        assert_eq!(Module::parse_bytes_cached(wasm, &cache)?.data, module.data);
+
+        // broken archives are replaced
+        cache.0.borrow_mut().values_mut().for_each(|archive| archive.truncate(8));
+        assert_eq!(Module::parse_bytes_cached(wasm, &cache)?.data, module.data);
+        assert!(cache.0.borrow().values().all(|archive| archive.len() > 8));
+        Ok(())
+    }
+}
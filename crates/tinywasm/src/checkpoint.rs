@@ -0,0 +1,77 @@
+use crate::sync::MaybeSendSync;
+use crate::{Extern, FuncContext, Imports, InstanceSnapshot, Result};
+
+/// The name of the host module defined by [`Imports::define_checkpoint`]
+pub const CHECKPOINT_MODULE: &str = "tinywasm";
+
+impl Imports {
+    /// Let the guest request checkpoints of its own state
+    ///
+    /// This defines `checkpoint()` in the [`CHECKPOINT_MODULE`] module. When the guest calls it,
+    /// the calling instance is captured using [`crate::ModuleInstance::snapshot`] and passed to `callback`,
+    /// e.g. to persist it for a durable-execution engine. Errors returned by the callback trap the guest.
+    ///
+    /// ```wat
+    /// (import "tinywasm" "checkpoint" (func $checkpoint))
+    /// ```
+    pub fn define_checkpoint(
+        &mut self,
+        callback: impl Fn(InstanceSnapshot) -> Result<()> + MaybeSendSync + 'static,
+    ) -> Result<&mut Self> {
+        let checkpoint =
+            Extern::typed_func(move |ctx: FuncContext<'_>, ()| callback(ctx.module()?.snapshot(ctx.store())?));
+        self.define(CHECKPOINT_MODULE, "checkpoint", checkpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{Rc, RefCell};
+    use crate::{Module, Store};
+    use alloc::vec::Vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_checkpoint() -> Result<()> {
+        // stores its param in memory, then checkpoints
+        let mut builder = ModuleBuilder::new();
+        let checkpoint_ty = builder.add_type(FuncType::default());
+        let checkpoint = builder.add_import(CHECKPOINT_MODULE, "checkpoint", ImportKind::Function(checkpoint_ty));
+        let mem = builder.add_memory(MemoryType::new_32(1, None));
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [].into() });
+        let run = builder.add_function_with_constants(
+            ty,
+            [],
+            [
+                Instruction::I32Const(0),
+                Instruction::LocalGet(0),
+                Instruction::I32Store { offset: 0, mem_addr: mem as u16 },
+                Instruction::Call(checkpoint),
+                Instruction::EndFunc,
+            ],
+            [0],
+        );
+        builder.add_export("run", ExternalKind::Func, run);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let checkpoints = Rc::new(RefCell::new(Vec::new()));
+        let mut imports = Imports::new();
+        let saved = checkpoints.clone();
+        imports.define_checkpoint(move |snapshot| {
+            saved.borrow_mut().push(snapshot);
+            Ok(())
+        })?;
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<i32, ()>(&store, "run")?;
+        run.call(&mut store, 1)?;
+        run.call(&mut store, 2)?;
+
+        let checkpoints = checkpoints.borrow();
+        let first = checkpoints.first().and_then(|c| c.memory(0)).map(|m| m[0]);
+        assert_eq!((checkpoints.len(), first), (2, Some(1)));
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use tinywasm_types::{FuncAddr, Instruction, ModuleInstanceAddr};
+
+use crate::{Function, Store};
+
+/// The basic blocks executed since coverage was enabled, see [`Store::coverage`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    /// The coverage of every function containing coverage probes
+    pub functions: Vec<FunctionCoverage>,
+}
+
+/// The coverage of a single function
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionCoverage {
+    /// The address of the function in the store
+    pub func_addr: FuncAddr,
+    /// The module instance that owns the function
+    pub owner: ModuleInstanceAddr,
+    /// The index of the function in its module, if the module instance still exists
+    pub func_index: Option<FuncAddr>,
+    /// The name of the function, see [`Store::func_name`]
+    pub name: Option<String>,
+    /// The basic blocks of the function, in the order they appear in the code
+    pub blocks: Vec<BlockCoverage>,
+}
+
+/// The coverage of a single basic block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCoverage {
+    /// The index of the block's coverage probe in the function
+    pub instr_ptr: usize,
+    /// The byte offset of the block in the original code section, see [`Store::code_offset`]
+    pub code_offset: Option<u32>,
+    /// How often the block was executed
+    pub hits: u64,
+}
+
+/// A line in the guest's source code, see [`Coverage::to_lcov`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The path of the source file
+    pub file: String,
+    /// The line in the source file, starting at 1
+    pub line: u32,
+}
+
+impl FunctionCoverage {
+    /// The number of blocks that were executed at least once
+    pub fn covered(&self) -> usize {
+        self.blocks.iter().filter(|block| block.hits > 0).count()
+    }
+
+    // the name used in reports, falling back to the function's index or address
+    fn display_name(&self) -> String {
+        match (&self.name, self.func_index) {
+            (Some(name), _) => name.clone(),
+            (None, Some(index)) => format!("func[{}]", index),
+            (None, None) => format!("func@{}", self.func_addr),
+        }
+    }
+}
+
+#[derive(Default)]
+struct LcovFile {
+    // (line, name, hits) of every function starting in the file
+    functions: Vec<(u32, String, u64)>,
+    lines: BTreeMap<u32, u64>,
+}
+
+impl Coverage {
+    /// Format the coverage as an LCOV tracefile
+    ///
+    /// `locate` maps the blocks of a function to lines in the guest's source code, usually
+    /// by looking up the block's [`BlockCoverage::code_offset`] in the module's DWARF line table.
+    /// Blocks it returns `None` for are left out. Lines containing several blocks are reported
+    /// with the hits of the most executed block, and functions with the hits of their first block.
+    ///
+    /// Without debug information, code offsets can be used as line numbers:
+    /// ```rust
+    /// # use tinywasm::{Coverage, SourceLocation};
+    /// # let coverage = Coverage::default();
+    /// let lcov = coverage.to_lcov(|_func, block| {
+    ///     Some(SourceLocation { file: "module.wasm".into(), line: block.code_offset? })
+    /// });
+    /// ```
+    pub fn to_lcov(&self, locate: impl Fn(&FunctionCoverage, &BlockCoverage) -> Option<SourceLocation>) -> String {
+        let mut files: BTreeMap<String, LcovFile> = BTreeMap::new();
+
+        for func in &self.functions {
+            let mut entry = true;
+            for block in &func.blocks {
+                let Some(location) = locate(func, block) else {
+                    entry = false;
+                    continue;
+                };
+
+                let file = files.entry(location.file).or_default();
+                if entry {
+                    file.functions.push((location.line, func.display_name(), block.hits));
+                    entry = false;
+                }
+
+                let hits = file.lines.entry(location.line).or_default();
+                *hits = (*hits).max(block.hits);
+            }
+        }
+
+        let mut lcov = String::from("TN:\n");
+        for (path, file) in files {
+            lcov += &format!("SF:{}\n", path);
+            for (line, name, _) in &file.functions {
+                lcov += &format!("FN:{},{}\n", line, name);
+            }
+            for (_, name, hits) in &file.functions {
+                lcov += &format!("FNDA:{},{}\n", hits, name);
+            }
+            let functions_hit = file.functions.iter().filter(|(_, _, hits)| *hits > 0).count();
+            lcov += &format!("FNF:{}\nFNH:{}\n", file.functions.len(), functions_hit);
+
+            for (line, hits) in &file.lines {
+                lcov += &format!("DA:{},{}\n", line, hits);
+            }
+            let lines_hit = file.lines.values().filter(|hits| **hits > 0).count();
+            lcov += &format!("LF:{}\nLH:{}\nend_of_record\n", file.lines.len(), lines_hit);
+        }
+        lcov
+    }
+}
+
+impl Store {
+    /// Enable or disable recording coverage
+    ///
+    /// Coverage is recorded by the probes the parser inserts when enabled with
+    /// [`crate::parser::Parser::coverage`]. Functions parsed without probes are not covered.
+    /// Enabling coverage again or disabling it discards the recorded coverage.
+    pub fn enable_coverage(&mut self, enabled: bool) {
+        self.coverage = enabled.then(BTreeMap::new);
+    }
+
+    /// Get the coverage recorded since coverage was enabled, or `None` if it is disabled
+    pub fn coverage(&self) -> Option<Coverage> {
+        let hits = self.coverage.as_ref()?;
+        let mut functions = Vec::new();
+
+        for (addr, func) in self.data.funcs.iter().enumerate() {
+            let Some(func) = func else { continue };
+            let Function::Wasm(wasm) = &func.func else { continue };
+            let func_addr = addr as FuncAddr;
+            let func_hits = hits.get(&func_addr);
+
+            let blocks: Vec<_> = (wasm.instructions.iter().enumerate())
+                .filter(|(_, instr)| matches!(instr, Instruction::Probe))
+                .map(|(instr_ptr, _)| BlockCoverage {
+                    instr_ptr,
+                    code_offset: wasm.offsets.get(instr_ptr).copied(),
+                    hits: func_hits.and_then(|hits| hits.get(&instr_ptr)).copied().unwrap_or(0),
+                })
+                .collect();
+
+            if blocks.is_empty() {
+                continue;
+            }
+
+            functions.push(FunctionCoverage {
+                func_addr,
+                owner: func.owner,
+                func_index: self.func_index(func_addr),
+                name: self.func_name(func_addr).map(ToString::to_string),
+                blocks,
+            });
+        }
+
+        Some(Coverage { functions })
+    }
+
+    #[inline]
+    pub(crate) fn record_probe(&mut self, func_addr: FuncAddr, instr_ptr: usize) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            *coverage.entry(func_addr).or_default().entry(instr_ptr).or_default() += 1;
+        }
+    }
+}
+
+impl Display for Coverage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        for func in &self.functions {
+            match func.func_index {
+                Some(index) => write!(f, "func[{}]", index)?,
+                None => write!(f, "func@{}", func.func_addr)?,
+            }
+            if let Some(name) = &func.name {
+                write!(f, " '{}'", name)?;
+            }
+            writeln!(f, ": {}/{} blocks", func.covered(), func.blocks.len())?;
+
+            for block in &func.blocks {
+                match block.code_offset {
+                    Some(offset) => write!(f, "  {:#x}", offset)?,
+                    None => write!(f, "  instruction {}", block.instr_ptr)?,
+                }
+                writeln!(f, ": {}", block.hits)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Result};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_coverage() -> Result<()> {
+        // `if (local.get 0) {} else {}` with probes, as inserted by the parser
+        let ty = FuncType { params: [ValType::I32].into(), results: [].into() };
+        let func = WasmFunction {
+            instructions: vec![
+                Instruction::Probe,
+                Instruction::LocalGet(0),
+                Instruction::If(BlockArgsPacked::EMPTY, 2),
+                Instruction::Probe,
+                Instruction::Else(2),
+                Instruction::Probe,
+                Instruction::EndBlockFrame,
+                Instruction::Probe,
+                Instruction::EndFunc,
+            ]
+            .into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![func].into(),
+            func_types: vec![ty].into(),
+            exports: vec![Export { name: "branch".into(), kind: ExternalKind::Func, index: 0 }].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        let branch = instance.exported_func::<i32, ()>(&store, "branch")?;
+
+        branch.call(&mut store, 1)?;
+        assert_eq!(store.coverage(), None);
+
+        store.enable_coverage(true);
+        branch.call(&mut store, 1)?;
+        branch.call(&mut store, 1)?;
+
+        let coverage = store.coverage().expect("coverage is enabled");
+        let func = &coverage.functions[0];
+        assert_eq!((func.func_index, func.name.as_deref()), (Some(0), Some("branch")));
+        assert_eq!(
+            func.blocks.iter().map(|b| (b.instr_ptr, b.hits)).collect::<Vec<_>>(),
+            [(0, 2), (3, 2), (5, 0), (7, 2)]
+        );
+        assert_eq!(func.covered(), 3);
+        assert!(coverage.to_string().starts_with("func[0] 'branch': 3/4 blocks\n  instruction 0: 2\n"));
+
+        branch.call(&mut store, 0)?;
+        assert_eq!(store.coverage().map(|c| c.functions[0].covered()), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lcov() {
+        let block = |instr_ptr, hits| BlockCoverage { instr_ptr, code_offset: None, hits };
+        let func = |func_addr, name: Option<&str>, blocks| FunctionCoverage {
+            func_addr,
+            owner: 0,
+            func_index: Some(func_addr),
+            name: name.map(ToString::to_string),
+            blocks,
+        };
+        let coverage = Coverage {
+            functions: vec![
+                func(0, Some("main"), vec![block(0, 1), block(3, 0), block(5, 4)]),
+                func(1, None, vec![block(0, 0)]),
+            ],
+        };
+
+        // blocks 3 and 5 of main are on the same line, func[1] has no debug info
+        let lcov = coverage.to_lcov(|func, block| match func.func_addr {
+            0 => Some(SourceLocation { file: "main.rs".into(), line: 10 + block.instr_ptr.min(3) as u32 }),
+            _ => None,
+        });
+
+        assert_eq!(
+            lcov,
+            "TN:\nSF:main.rs\nFN:10,main\nFNDA:1,main\nFNF:1\nFNH:1\nDA:10,1\nDA:13,4\nLF:2\nLH:2\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use alloc::string::ToString;
+use alloc::vec;
+use tinywasm_types::{ValType, WasmValue};
+
+use crate::sync::{Rc, RefCell};
+use crate::{Error, Extern, FuncContext, ImportType, Imports, Result};
+
+// the errno returned by unimplemented syscalls
+const ENOSYS: i32 = 52;
+
+impl Imports {
+    /// Define the common `env` imports of emscripten builds that weren't built with `-sSTANDALONE_WASM`
+    ///
+    /// * `emscripten_memcpy_js` (and `_emscripten_memcpy_js`, `emscripten_memcpy_big`) copies memory
+    /// * `emscripten_resize_heap` grows the memory exported as `memory`, and `emscripten_notify_memory_growth` does nothing
+    /// * `setTempRet0` and `getTempRet0` store the high bits of 64-bit return values
+    /// * `abort` returns an error
+    /// * With `std`, `emscripten_get_now` returns the milliseconds since the unix epoch
+    /// * Any other `env.__syscall_*` function returning an `i32` fails with `-ENOSYS`
+    ///
+    /// The syscalls are resolved using a fallback resolver, which takes precedence over (and falls back to)
+    /// a previously set [`Imports::fallback`]. Setting a fallback afterwards replaces it.
+    pub fn define_emscripten(&mut self) -> Result<&mut Self> {
+        let memcpy = || {
+            Extern::typed_func(|mut ctx, (dest, src, len): (i32, i32, i32)| {
+                let mut memory = ctx.exported_memory_mut("memory")?;
+                let bytes = memory.load_vec(src as u32 as usize, len as u32 as usize)?;
+                memory.store(dest as u32 as usize, bytes.len(), &bytes)
+            })
+        };
+        self.define("env", "emscripten_memcpy_js", memcpy())?;
+        self.define("env", "_emscripten_memcpy_js", memcpy())?;
+        self.define("env", "emscripten_memcpy_big", memcpy())?;
+
+        self.define("env", "emscripten_resize_heap", Extern::typed_func(resize_heap))?;
+        self.define("env", "emscripten_notify_memory_growth", Extern::typed_func(|_, _index: i32| Ok(())))?;
+
+        let temp_ret = Rc::new(RefCell::new(0));
+        let (set, get) = (temp_ret.clone(), temp_ret);
+        self.define(
+            "env",
+            "setTempRet0",
+            Extern::typed_func(move |_, value: i32| {
+                *set.borrow_mut() = value;
+                Ok(())
+            }),
+        )?;
+        self.define("env", "getTempRet0", Extern::typed_func(move |_, ()| Ok(*get.borrow())))?;
+
+        let abort = Extern::typed_func(|_, ()| Err::<(), _>(Error::Other("emscripten abort() called".to_string())));
+        self.define("env", "abort", abort)?;
+
+        #[cfg(feature = "std")]
+        self.define("env", "emscripten_get_now", Extern::typed_func(|_, ()| Ok(now())))?;
+
+        Ok(self.prepend_fallback(|module, name, ty| match ty {
+            ImportType::Function(ty) if module == "env" && name.starts_with("__syscall_") => {
+                if *ty.results != [ValType::I32] {
+                    return None;
+                }
+                Some(Extern::func(ty, |_, _| Ok(vec![WasmValue::I32(-ENOSYS)])))
+            }
+            _ => None,
+        }))
+    }
+}
+
+// grow the memory to at least `requested` bytes, returning 1 on success
+fn resize_heap(mut ctx: FuncContext<'_>, requested: i32) -> Result<i32> {
+    let mut memory = ctx.exported_memory_mut("memory")?;
+    let pages = (requested as u32 as u64).div_ceil(crate::store::PAGE_SIZE);
+    let delta = pages.saturating_sub(memory.page_count() as u64);
+    Ok(memory.grow(u32::try_from(delta).unwrap_or(u32::MAX)).is_some() as i32)
+}
+
+#[cfg(feature = "std")]
+fn now() -> f64 {
+    use crate::std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |time| time.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Store};
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_emscripten() -> Result<()> {
+        let syscall_ty = FuncType { params: [ValType::I32; 3].into(), results: [ValType::I32].into() };
+        let memcpy_ty = FuncType { params: [ValType::I32; 3].into(), results: [].into() };
+        let resize_ty = FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() };
+        let run_ty = FuncType { params: [].into(), results: [ValType::I32].into() };
+
+        // run() copies 4 bytes from 0 to 8, grows the memory to 2 pages and returns the result of a syscall
+        let run = WasmFunction {
+            instructions: vec![
+                Instruction::I32Const(8),
+                Instruction::I32Const(0),
+                Instruction::I32Const(4),
+                Instruction::Call(1),
+                Instruction::I32Const(65537),
+                Instruction::Call(2),
+                Instruction::Drop,
+                Instruction::I32Const(0),
+                Instruction::I32Const(0),
+                Instruction::I32Const(0),
+                Instruction::Call(0),
+                Instruction::EndFunc,
+            ]
+            .into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: run_ty.clone(),
+        };
+
+        let import =
+            |name: &str, ty| Import { module: "env".into(), name: name.into(), kind: ImportKind::Function(ty) };
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![run].into(),
+            func_types: vec![syscall_ty, memcpy_ty, resize_ty, run_ty].into(),
+            imports: vec![
+                import("__syscall_openat", 0),
+                import("emscripten_memcpy_js", 1),
+                import("emscripten_resize_heap", 2),
+            ]
+            .into(),
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            exports: vec![
+                Export { name: "memory".into(), kind: ExternalKind::Memory, index: 0 },
+                Export { name: "run".into(), kind: ExternalKind::Func, index: 3 },
+            ]
+            .into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.define_emscripten()?;
+        let instance = module.instantiate(&mut store, Some(imports))?;
+        instance.exported_memory_mut(&mut store, "memory")?.store(0, 4, &[1, 2, 3, 4])?;
+
+        let run = instance.exported_func::<(), i32>(&store, "run")?;
+        assert_eq!(run.call(&mut store, ())?, -ENOSYS);
+
+        let mut memory = instance.exported_memory_mut(&mut store, "memory")?;
+        assert_eq!(memory.load(8, 4)?, [1, 2, 3, 4]);
+        assert_eq!(memory.page_count(), 2);
+        Ok(())
+    }
+}
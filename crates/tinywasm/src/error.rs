@@ -1,6 +1,11 @@
+use alloc::boxed::Box;
+use alloc::format;
 use alloc::string::{String, ToString};
-use core::fmt::Display;
-use tinywasm_types::FuncType;
+use core::any::Any;
+use core::fmt::{Debug, Display};
+use tinywasm_types::{FuncAddr, FuncType};
+
+use crate::sync::MaybeSendSync;
 
 #[cfg(feature = "parser")]
 pub use tinywasm_parser::ParseError;
@@ -20,6 +25,9 @@ pub enum Error {
     /// An unknown error occurred
     Other(String),
 
+    /// A host function returned a custom error, see [`Error::host`]
+    Host(HostError),
+
     /// A function did not return a value
     FuncDidNotReturn,
 
@@ -47,6 +55,64 @@ pub enum Error {
     ParseError(ParseError),
 }
 
+/// A custom error returned by a host function, see [`Error::host`]
+pub struct HostError(Box<dyn HostErrorObject>);
+
+trait HostErrorObject: Debug + Display + MaybeSendSync {
+    fn as_any(&self) -> &dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: Debug + Display + MaybeSendSync + 'static> HostErrorObject for T {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+impl Debug for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl Display for HostError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl Error {
+    /// Create an error that carries a custom value, e.g. to return structured data from a host function
+    ///
+    /// The error is passed through the WebAssembly code calling the host function unchanged,
+    /// so the host can recover it using [`Error::downcast_ref`] or [`Error::downcast`].
+    pub fn host(error: impl Debug + Display + MaybeSendSync + 'static) -> Self {
+        Self::Host(HostError(Box::new(error)))
+    }
+
+    /// Get the custom error created by [`Error::host`], if it has the type `T`
+    pub fn downcast_ref<T: 'static>(&self) -> Option<&T> {
+        match self {
+            Self::Host(error) => (*error.0).as_any().downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Take the custom error created by [`Error::host`], returning the error unchanged if it doesn't have the type `T`
+    pub fn downcast<T: 'static>(self) -> core::result::Result<T, Self> {
+        match self {
+            Self::Host(error) if (*error.0).as_any().is::<T>() => {
+                Ok(*error.0.into_any().downcast().expect("type was checked"))
+            }
+            other => Err(other),
+        }
+    }
+}
+
 #[derive(Debug)]
 /// Errors that can occur when linking a WebAssembly module
 pub enum LinkingError {
@@ -56,6 +122,8 @@ pub enum LinkingError {
         module: String,
         /// The import name
         name: String,
+        /// For function imports, the index and name of the function in the importing module
+        func: Option<(FuncAddr, Option<String>)>,
     },
 
     /// A mismatched import type was encountered
@@ -64,16 +132,38 @@ pub enum LinkingError {
         module: String,
         /// The import name
         name: String,
+        /// For function imports, the index and name of the function in the importing module
+        func: Option<(FuncAddr, Option<String>)>,
     },
 }
 
 impl LinkingError {
     pub(crate) fn incompatible_import_type(import: &tinywasm_types::Import) -> Self {
-        Self::IncompatibleImportType { module: import.module.to_string(), name: import.name.to_string() }
+        Self::IncompatibleImportType { module: import.module.to_string(), name: import.name.to_string(), func: None }
     }
 
     pub(crate) fn unknown_import(import: &tinywasm_types::Import) -> Self {
-        Self::UnknownImport { module: import.module.to_string(), name: import.name.to_string() }
+        Self::UnknownImport { module: import.module.to_string(), name: import.name.to_string(), func: None }
+    }
+
+    // the error for the imported function `index`
+    pub(crate) fn for_func(mut self, index: FuncAddr, name: Option<&str>) -> Self {
+        let (Self::UnknownImport { func, .. } | Self::IncompatibleImportType { func, .. }) = &mut self;
+        *func = Some((index, name.map(ToString::to_string)));
+        self
+    }
+}
+
+// a function by its index in a module, shown as `func[12] 'name'` like in backtraces
+pub(crate) struct FuncDisplay<'a>(pub(crate) FuncAddr, pub(crate) Option<&'a str>);
+
+impl Display for FuncDisplay<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "func[{}]", self.0)?;
+        match self.1 {
+            Some(name) => write!(f, " '{}'", name),
+            None => Ok(()),
+        }
     }
 }
 
@@ -87,11 +177,11 @@ pub enum Trap {
 
     /// An out-of-bounds memory access occurred
     MemoryOutOfBounds {
-        /// The offset of the access
+        /// The effective address of the access (saturated to `usize::MAX` if it overflows)
         offset: usize,
-        /// The size of the access
+        /// The size of the access in bytes
         len: usize,
-        /// The maximum size of the memory
+        /// The size of the memory in bytes at the time of the access
         max: usize,
     },
 
@@ -135,11 +225,36 @@ pub enum Trap {
         expected: FuncType,
         /// The actual type
         actual: FuncType,
+        /// The index and name of the called function in its module, if the module instance still exists
+        func: Option<(FuncAddr, Option<String>)>,
+    },
+
+    /// A host function was called after its [`crate::HostBudget`] was exhausted
+    HostBudgetExceeded,
+
+    /// The store ran out of fuel, see [`crate::Store::set_fuel`]
+    OutOfFuel,
+
+    /// Execution was interrupted using an [`crate::InterruptHandle`]
+    Interrupted,
+
+    /// The guest called AssemblyScript's `abort`, see [`crate::Imports::define_assemblyscript`]
+    Abort {
+        /// The message passed to `abort`
+        message: String,
+        /// The source file `abort` was called from
+        file: String,
+        /// The line `abort` was called from
+        line: u32,
+        /// The column `abort` was called from
+        column: u32,
     },
 }
 
 impl Trap {
     /// Get the message of the trap
+    ///
+    /// These messages are stable and match the ones used by the WebAssembly spec test suite
     pub fn message(&self) -> &'static str {
         match self {
             Self::Unreachable => "unreachable",
@@ -152,6 +267,23 @@ impl Trap {
             Self::UndefinedElement { .. } => "undefined element",
             Self::UninitializedElement { .. } => "uninitialized element",
             Self::IndirectCallTypeMismatch { .. } => "indirect call type mismatch",
+            Self::HostBudgetExceeded => "host budget exceeded",
+            Self::OutOfFuel => "out of fuel",
+            Self::Interrupted => "interrupted",
+            Self::Abort { .. } => "abort",
+        }
+    }
+
+    /// Check if the trap matches a message expected by a spec test (e.g. in `assert_trap`)
+    ///
+    /// Like the spec's reference interpreter, only a prefix of the message has to match.
+    /// For element traps, the message includes the element index (e.g. `uninitialized element 2`).
+    pub fn matches_spec(&self, expected: &str) -> bool {
+        match self {
+            Self::UndefinedElement { index } | Self::UninitializedElement { index } => {
+                format!("{} {}", self.message(), index).starts_with(expected)
+            }
+            _ => self.message().starts_with(expected),
         }
     }
 }
@@ -164,6 +296,13 @@ impl LinkingError {
             Self::IncompatibleImportType { .. } => "incompatible import type",
         }
     }
+
+    /// Check if the error matches a message expected by a spec test (e.g. in `assert_unlinkable`)
+    ///
+    /// Like the spec's reference interpreter, only a prefix of the message has to match.
+    pub fn matches_spec(&self, expected: &str) -> bool {
+        self.message().starts_with(expected)
+    }
 }
 
 impl From<LinkingError> for Error {
@@ -172,6 +311,13 @@ impl From<LinkingError> for Error {
     }
 }
 
+#[cfg(feature = "std")]
+impl From<crate::std::io::Error> for Error {
+    fn from(value: crate::std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
 impl From<Trap> for Error {
     fn from(value: Trap) -> Self {
         Self::Trap(value)
@@ -192,6 +338,7 @@ impl Display for Error {
             Self::CallStackUnderflow => write!(f, "call stack empty"),
             Self::InvalidLabelType => write!(f, "invalid label type"),
             Self::Other(message) => write!(f, "unknown error: {}", message),
+            Self::Host(err) => write!(f, "host error: {}", err),
             Self::UnsupportedFeature(feature) => write!(f, "unsupported feature: {}", feature),
             Self::FuncDidNotReturn => write!(f, "function did not return"),
             Self::BlockStackUnderflow => write!(f, "label stack underflow"),
@@ -204,11 +351,17 @@ impl Display for Error {
 impl Display for LinkingError {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
-            Self::UnknownImport { module, name } => write!(f, "unknown import: {}.{}", module, name),
-            Self::IncompatibleImportType { module, name } => {
-                write!(f, "incompatible import type: {}.{}", module, name)
+            Self::UnknownImport { module, name, .. } => write!(f, "unknown import: {}.{}", module, name)?,
+            Self::IncompatibleImportType { module, name, .. } => {
+                write!(f, "incompatible import type: {}.{}", module, name)?
             }
         }
+
+        let (Self::UnknownImport { func, .. } | Self::IncompatibleImportType { func, .. }) = self;
+        match func {
+            Some((index, name)) => write!(f, " for {}", FuncDisplay(*index, name.as_deref())),
+            None => Ok(()),
+        }
     }
 }
 
@@ -217,7 +370,11 @@ impl Display for Trap {
         match self {
             Self::Unreachable => write!(f, "unreachable"),
             Self::MemoryOutOfBounds { offset, len, max } => {
-                write!(f, "out of bounds memory access: offset={}, len={}, max={}", offset, len, max)
+                write!(
+                    f,
+                    "out of bounds memory access: {} bytes at address {} (memory size is {} bytes)",
+                    len, offset, max
+                )
             }
             Self::TableOutOfBounds { offset, len, max } => {
                 write!(f, "out of bounds table access: offset={}, len={}, max={}", offset, len, max)
@@ -230,8 +387,18 @@ impl Display for Trap {
             Self::UninitializedElement { index } => {
                 write!(f, "uninitialized element: index={}", index)
             }
-            Self::IndirectCallTypeMismatch { expected, actual } => {
-                write!(f, "indirect call type mismatch: expected={:?}, actual={:?}", expected, actual)
+            Self::IndirectCallTypeMismatch { expected, actual, func } => {
+                write!(f, "indirect call type mismatch")?;
+                if let Some((index, name)) = func {
+                    write!(f, " calling {}", FuncDisplay(*index, name.as_deref()))?;
+                }
+                write!(f, ": expected={:?}, actual={:?}", expected, actual)
+            }
+            Self::HostBudgetExceeded => write!(f, "host budget exceeded"),
+            Self::OutOfFuel => write!(f, "out of fuel"),
+            Self::Interrupted => write!(f, "interrupted"),
+            Self::Abort { message, file, line, column } => {
+                write!(f, "abort: {} in {}({}:{})", message, file, line, column)
             }
         }
     }
@@ -247,5 +414,36 @@ impl From<tinywasm_parser::ParseError> for Error {
     }
 }
 
+#[cfg(feature = "archive")]
+impl From<tinywasm_types::archive::TwasmError> for Error {
+    fn from(value: tinywasm_types::archive::TwasmError) -> Self {
+        Self::Other(value.to_string())
+    }
+}
+
 /// A wrapper around [`core::result::Result`] for tinywasm operations
 pub type Result<T, E = Error> = crate::std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_messages() {
+        let oob = Trap::MemoryOutOfBounds { offset: 8, len: 4, max: 10 };
+        assert!(oob.matches_spec("out of bounds memory access"));
+        assert!(!oob.matches_spec("out of bounds table access"));
+
+        let uninitialized = Trap::UninitializedElement { index: 2 };
+        assert!(uninitialized.matches_spec("uninitialized element"));
+        assert!(uninitialized.matches_spec("uninitialized element 2"));
+        assert!(!uninitialized.matches_spec("uninitialized element 3"));
+
+        assert!(Trap::Unreachable.matches_spec("unreachable"));
+        assert!(!Trap::Unreachable.matches_spec("unreachable executed!"));
+
+        let unknown = LinkingError::UnknownImport { module: "env".into(), name: "f".into(), func: None };
+        assert!(unknown.matches_spec("unknown import"));
+        assert!(!unknown.matches_spec("incompatible import type"));
+    }
+}
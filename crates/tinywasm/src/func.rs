@@ -1,11 +1,10 @@
 use crate::{log, runtime::RawWasmValue, unlikely, Function};
-use alloc::{boxed::Box, format, string::String, string::ToString, vec, vec::Vec};
+use alloc::{boxed::Box, format, string::String, string::ToString, vec::Vec};
 use tinywasm_types::{FuncType, ModuleInstanceAddr, ValType, WasmValue};
 
-use crate::runtime::{CallFrame, Stack};
-use crate::{Error, FuncContext, Result, Store};
+use crate::{Error, FuncContext, Result, Store, StoreEvent};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// A function handle
 pub struct FuncHandle {
     pub(crate) module_addr: ModuleInstanceAddr,
@@ -22,9 +21,80 @@ impl FuncHandle {
     /// See <https://webassembly.github.io/spec/core/exec/modules.html#invocation>
     #[inline]
     pub fn call(&self, store: &mut Store, params: &[WasmValue]) -> Result<Vec<WasmValue>> {
+        self.call_as(store, params)
+    }
+
+    /// Call a function, converting its results as they are read off the stack
+    #[inline]
+    pub(crate) fn call_as<R: FromWasmValueTuple>(&self, store: &mut Store, params: &[WasmValue]) -> Result<R> {
+        match &self.name {
+            Some(name) if unlikely(store.call_metrics_enabled()) => {
+                #[cfg(feature = "std")]
+                let start = crate::std::time::Instant::now();
+                let res = self.invoke(store, params);
+                #[cfg(feature = "std")]
+                store.record_call(self.module_addr, name, res.is_err(), start.elapsed());
+                #[cfg(not(feature = "std"))]
+                store.record_call(self.module_addr, name, res.is_err());
+                res
+            }
+            _ => self.invoke(store, params),
+        }
+    }
+
+    #[inline]
+    fn invoke<R: FromWasmValueTuple>(&self, store: &mut Store, params: &[WasmValue]) -> Result<R> {
         // Comments are ordered by the steps in the spec
         // In this implementation, some steps are combined and ordered differently for performance reasons
+        // 3-5. Check the arguments against the function type
+        self.check_params(params)?;
+        let func_ty = &self.ty;
 
+        store.translate_func(self.addr)?;
+        let func_inst = store.get_func(self.addr as usize)?;
+        let wasm_func = match &func_inst.func {
+            Function::Host(host_func) => {
+                let host_func = host_func.clone();
+                let ctx = FuncContext { store, module_addr: self.module_addr, frame: None };
+                return R::from_wasm_values(host_func.call(ctx, params)?.into_iter());
+            }
+            Function::Wasm(wasm_func) => wasm_func,
+        };
+
+        // 6. Let f be the dummy frame
+        let call_frame_params = params.iter().map(|v| RawWasmValue::from(*v));
+
+        // 7. Push the frame f to the call stack
+        // & 8. Push the values to the stack (Not needed since they are copied to the frame's locals)
+        let mut stack = store.take_stack(wasm_func.clone(), self.addr, func_inst.owner, call_frame_params);
+
+        // 9. Invoke the function instance
+        let runtime = store.runtime();
+        let res = runtime.exec(store, &mut stack).and_then(|()| {
+            // Once the function returns:
+            let result_m = func_ty.results.len();
+
+            // 1. Assert: m values are on the top of the stack (Ensured by validation)
+            assert!(stack.values.len() >= result_m);
+
+            // 2. Pop m values from the stack
+            let res = stack.values.last_n(result_m)?;
+
+            // The values are returned as the results of the invocation.
+            R::from_wasm_values(res.iter().zip(func_ty.results.iter()).map(|(v, ty)| v.attach_type(*ty)))
+        });
+
+        if let Err(Error::Trap(trap)) = &res {
+            store.record_backtrace(&stack);
+            store.emit(StoreEvent::Trap(trap));
+        }
+
+        store.give_stack(stack);
+        res
+    }
+
+    #[inline]
+    pub(crate) fn check_params(&self, params: &[WasmValue]) -> Result<()> {
         // 3. Let func_ty be the function type
         let func_ty = &self.ty;
 
@@ -48,40 +118,7 @@ impl FuncHandle {
         })) {
             return Err(Error::Other("Type mismatch".into()));
         }
-
-        let func_inst = store.get_func(self.addr as usize)?;
-        let wasm_func = match &func_inst.func {
-            Function::Host(host_func) => {
-                let func = &host_func.clone().func;
-                let ctx = FuncContext { store, module_addr: self.module_addr };
-                return (func)(ctx, params);
-            }
-            Function::Wasm(wasm_func) => wasm_func,
-        };
-
-        // 6. Let f be the dummy frame
-        let call_frame_params = params.iter().map(|v| RawWasmValue::from(*v));
-        let call_frame = CallFrame::new(wasm_func.clone(), func_inst.owner, call_frame_params, 0);
-
-        // 7. Push the frame f to the call stack
-        // & 8. Push the values to the stack (Not needed since the call frame owns the values)
-        let mut stack = Stack::new(call_frame);
-
-        // 9. Invoke the function instance
-        let runtime = store.runtime();
-        runtime.exec(store, &mut stack)?;
-
-        // Once the function returns:
-        let result_m = func_ty.results.len();
-
-        // 1. Assert: m values are on the top of the stack (Ensured by validation)
-        assert!(stack.values.len() >= result_m);
-
-        // 2. Pop m values from the stack
-        let res = stack.values.last_n(result_m)?;
-
-        // The values are returned as the results of the invocation.
-        Ok(res.iter().zip(func_ty.results.iter()).map(|(v, ty)| v.attach_type(*ty)).collect())
+        Ok(())
     }
 }
 
@@ -94,40 +131,61 @@ pub struct FuncHandleTyped<P, R> {
 }
 
 pub trait IntoWasmValueTuple {
-    fn into_wasm_value_tuple(self) -> Vec<WasmValue>;
+    /// The values, usually a fixed-size array
+    type Values: AsRef<[WasmValue]>;
+
+    fn into_wasm_values(self) -> Self::Values;
 }
 
 pub trait FromWasmValueTuple {
-    fn from_wasm_value_tuple(values: &[WasmValue]) -> Result<Self>
+    fn from_wasm_values(values: impl Iterator<Item = WasmValue>) -> Result<Self>
     where
         Self: Sized;
+
+    #[inline]
+    fn from_wasm_value_tuple(values: &[WasmValue]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::from_wasm_values(values.iter().copied())
+    }
+}
+
+impl FromWasmValueTuple for Vec<WasmValue> {
+    #[inline]
+    fn from_wasm_values(values: impl Iterator<Item = WasmValue>) -> Result<Self> {
+        Ok(values.collect())
+    }
 }
 
 impl<P: IntoWasmValueTuple, R: FromWasmValueTuple> FuncHandleTyped<P, R> {
     /// Call a typed function
+    ///
+    /// The parameters are copied from an array on the native stack into the new frame's locals,
+    /// and the results are converted straight from the value stack, so no `Vec` is allocated.
     pub fn call(&self, store: &mut Store, params: P) -> Result<R> {
-        // Convert params into Vec<WasmValue>
-        let wasm_values = params.into_wasm_value_tuple();
-
-        // Call the underlying WASM function
-        let result = self.func.call(store, &wasm_values)?;
-
-        // Convert the Vec<WasmValue> back to R
-        R::from_wasm_value_tuple(&result)
+        self.func.call_as(store, params.into_wasm_values().as_ref())
     }
 }
 
+macro_rules! count {
+    () => { 0 };
+    ($head:ident $(, $tail:ident)*) => { 1 + count!($($tail),*) };
+}
+
 macro_rules! impl_into_wasm_value_tuple {
     ($($T:ident),*) => {
         impl<$($T),*> IntoWasmValueTuple for ($($T,)*)
         where
             $($T: Into<WasmValue>),*
         {
+            type Values = [WasmValue; count!($($T),*)];
+
             #[allow(non_snake_case)]
             #[inline]
-            fn into_wasm_value_tuple(self) -> Vec<WasmValue> {
+            fn into_wasm_values(self) -> Self::Values {
                 let ($($T,)*) = self;
-                vec![$($T.into(),)*]
+                [$($T.into(),)*]
             }
         }
     }
@@ -136,9 +194,11 @@ macro_rules! impl_into_wasm_value_tuple {
 macro_rules! impl_into_wasm_value_tuple_single {
     ($T:ident) => {
         impl IntoWasmValueTuple for $T {
+            type Values = [WasmValue; 1];
+
             #[inline]
-            fn into_wasm_value_tuple(self) -> Vec<WasmValue> {
-                vec![self.into()]
+            fn into_wasm_values(self) -> Self::Values {
+                [self.into()]
             }
         }
     };
@@ -151,14 +211,14 @@ macro_rules! impl_from_wasm_value_tuple {
             $($T: TryFrom<WasmValue, Error = ()>),*
         {
             #[inline]
-            fn from_wasm_value_tuple(values: &[WasmValue]) -> Result<Self> {
+            fn from_wasm_values(values: impl Iterator<Item = WasmValue>) -> Result<Self> {
                 #[allow(unused_variables, unused_mut)]
-                let mut iter = values.iter();
+                let mut iter = values;
 
                 Ok((
                     $(
                         $T::try_from(
-                            *iter.next()
+                            iter.next()
                             .ok_or(Error::Other("Not enough values in WasmValue vector".to_string()))?
                         )
                         .map_err(|e| Error::Other(format!("FromWasmValueTuple: Could not convert WasmValue to expected type: {:?}", e,
@@ -174,10 +234,8 @@ macro_rules! impl_from_wasm_value_tuple_single {
     ($T:ident) => {
         impl FromWasmValueTuple for $T {
             #[inline]
-            fn from_wasm_value_tuple(values: &[WasmValue]) -> Result<Self> {
-                #[allow(unused_variables, unused_mut)]
-                let mut iter = values.iter();
-                $T::try_from(*iter.next().ok_or(Error::Other("Not enough values in WasmValue vector".to_string()))?)
+            fn from_wasm_values(mut values: impl Iterator<Item = WasmValue>) -> Result<Self> {
+                $T::try_from(values.next().ok_or(Error::Other("Not enough values in WasmValue vector".to_string()))?)
                     .map_err(|e| {
                         Error::Other(format!(
                             "FromWasmValueTupleSingle: Could not convert WasmValue to expected type: {:?}",
@@ -281,3 +339,37 @@ impl_into_wasm_value_tuple!(T1, T2, T3);
 impl_into_wasm_value_tuple!(T1, T2, T3, T4);
 impl_into_wasm_value_tuple!(T1, T2, T3, T4, T5);
 impl_into_wasm_value_tuple!(T1, T2, T3, T4, T5, T6);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use tinywasm_types::{ExternalKind, Instruction, ModuleBuilder};
+
+    #[test]
+    fn test_typed_call() -> Result<()> {
+        // `swap` returns its two params in reverse order
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType {
+            params: Box::new([ValType::I32, ValType::I64]),
+            results: Box::new([ValType::I64, ValType::I32]),
+        });
+        let instructions = [Instruction::LocalGet(1), Instruction::LocalGet(0), Instruction::EndFunc];
+        let swap = builder.add_function(ty, [], instructions);
+        builder.add_export("swap", ExternalKind::Func, swap);
+        let mut store = Store::default();
+        let instance = Module::from(builder.finish().expect("valid module")).instantiate(&mut store, None)?;
+
+        let typed = instance.exported_func::<(i32, i64), (i64, i32)>(&store, "swap")?;
+        assert_eq!(typed.call(&mut store, (1, 2))?, (2, 1));
+        let untyped = instance.exported_func_untyped(&store, "swap")?;
+        assert_eq!(untyped.call(&mut store, &[1.into(), 2i64.into()])?, [2i64.into(), 1.into()]);
+
+        // the params are still checked against the function type
+        let wrong = instance.exported_func::<(i32, i32), (i64, i32)>(&store, "swap")?;
+        assert!(wrong.call(&mut store, (1, 2)).is_err());
+        let wrong = instance.exported_func::<(i32, i64), (i32, i32)>(&store, "swap")?;
+        assert!(wrong.call(&mut store, (1, 2)).is_err());
+        Ok(())
+    }
+}
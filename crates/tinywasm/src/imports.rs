@@ -1,12 +1,14 @@
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
-use alloc::rc::Rc;
+use alloc::format;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::fmt::Debug;
 
 use crate::func::{FromWasmValueTuple, IntoWasmValueTuple, ValTypesFromTuple};
-use crate::{log, LinkingError, Result};
+use crate::runtime::{CallFrame, LocalStack, ValueStack};
+use crate::sync::{MaybeSendSync, Rc};
+use crate::{log, unlikely, LinkingError, Result};
 use tinywasm_types::*;
 
 /// The internal representation of a function
@@ -41,18 +43,36 @@ impl HostFunction {
     }
 
     /// Call the function
+    ///
+    /// Fails if the returned values don't match the function's result types.
     pub fn call(&self, ctx: FuncContext<'_>, args: &[WasmValue]) -> Result<Vec<WasmValue>> {
-        (self.func)(ctx, args)
+        let results = (self.func)(ctx, args)?;
+
+        // results are pushed onto the operand stack as-is, so they have to match exactly
+        let types = results.iter().map(WasmValue::val_type);
+        if unlikely(results.len() != self.ty.results.len() || !types.eq(self.ty.results.iter().copied())) {
+            log::error!("host function returned {:?}, expected {:?}", results, self.ty.results);
+            return Err(crate::Error::Other(format!(
+                "host function result mismatch: expected {:?}, got {:?}",
+                self.ty.results, results
+            )));
+        }
+
+        Ok(results)
     }
 }
 
+#[cfg(not(feature = "sync"))]
 pub(crate) type HostFuncInner = Box<dyn Fn(FuncContext<'_>, &[WasmValue]) -> Result<Vec<WasmValue>>>;
+#[cfg(feature = "sync")]
+pub(crate) type HostFuncInner = Box<dyn Fn(FuncContext<'_>, &[WasmValue]) -> Result<Vec<WasmValue>> + Send + Sync>;
 
 /// The context of a host-function call
 #[derive(Debug)]
 pub struct FuncContext<'a> {
     pub(crate) store: &'a mut crate::Store,
     pub(crate) module_addr: ModuleInstanceAddr,
+    pub(crate) frame: Option<Frame<'a>>,
 }
 
 impl FuncContext<'_> {
@@ -67,18 +87,83 @@ impl FuncContext<'_> {
     }
 
     /// Get a reference to the module instance
-    pub fn module(&self) -> crate::ModuleInstance {
+    ///
+    /// Fails if the calling module instance has been removed from the store, e.g. by this host function
+    pub fn module(&self) -> Result<crate::ModuleInstance> {
         self.store.get_module_instance_raw(self.module_addr)
     }
 
+    /// Get a read-only view of the WebAssembly frame that called this function
+    ///
+    /// Returns `None` if the function was called directly from the host, e.g. using [`crate::FuncHandle::call`]
+    pub fn frame(&self) -> Option<Frame<'_>> {
+        self.frame
+    }
+
     /// Get a reference to an exported memory
     pub fn exported_memory(&mut self, name: &str) -> Result<crate::MemoryRef<'_>> {
-        self.module().exported_memory(self.store, name)
+        self.module()?.exported_memory(self.store, name)
     }
 
     /// Get a reference to an exported memory
     pub fn exported_memory_mut(&mut self, name: &str) -> Result<crate::MemoryRefMut<'_>> {
-        self.module().exported_memory_mut(self.store, name)
+        self.module()?.exported_memory_mut(self.store, name)
+    }
+}
+
+/// A read-only view of a WebAssembly call frame
+///
+/// See [`FuncContext::frame`]
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    pub(crate) cf: &'a CallFrame,
+    pub(crate) values: &'a ValueStack,
+    pub(crate) locals: &'a LocalStack,
+}
+
+impl Frame<'_> {
+    /// The address of the function this frame belongs to
+    pub fn func_addr(&self) -> FuncAddr {
+        self.cf.func_addr
+    }
+
+    /// The address of the module instance that owns the function
+    pub fn module_addr(&self) -> ModuleInstanceAddr {
+        self.cf.func_instance.1
+    }
+
+    /// The index of the current instruction in the function
+    pub fn instr_ptr(&self) -> usize {
+        self.cf.instr_ptr
+    }
+
+    /// The byte offset of the current instruction in the original code section
+    ///
+    /// See [`crate::Store::code_offset`]
+    pub fn code_offset(&self) -> Option<u32> {
+        self.cf.func_instance.0.offsets.get(self.cf.instr_ptr).copied()
+    }
+
+    /// The values of the function's parameters and locals
+    pub fn locals(&self) -> Vec<WasmValue> {
+        let func = &self.cf.func_instance.0;
+        let types = func.ty.params.iter().chain(func.locals.iter());
+        types.zip(self.cf.locals(self.locals)).map(|(ty, val)| val.attach_type(*ty)).collect()
+    }
+
+    /// The number of values on the operand stack
+    ///
+    /// This includes the values of all frames below this one in the call stack
+    pub fn stack_depth(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The raw bits of the top `n` values on the operand stack, with the top value last
+    ///
+    /// The operand stack is untyped, so the values are returned as their raw 64-bit representation.
+    /// Returns `None` if there are less than `n` values on the stack.
+    pub fn stack_top(&self, n: usize) -> Option<Vec<u64>> {
+        Some(self.values.last_n(n).ok()?.iter().map(|v| u64::from(*v)).collect())
     }
 }
 
@@ -135,17 +220,22 @@ impl Extern {
     }
 
     /// Create a new function import
+    ///
+    /// The function can return multiple values, which have to match the result types of `ty`.
+    /// Calls fail if they don't.
     pub fn func(
         ty: &tinywasm_types::FuncType,
-        func: impl Fn(FuncContext<'_>, &[WasmValue]) -> Result<Vec<WasmValue>> + 'static,
+        func: impl Fn(FuncContext<'_>, &[WasmValue]) -> Result<Vec<WasmValue>> + MaybeSendSync + 'static,
     ) -> Self {
         Self::Function(Function::Host(Rc::new(HostFunction { func: Box::new(func), ty: ty.clone() })))
     }
 
     /// Create a new typed function import
+    ///
+    /// Params and results can be either a single value or a tuple of up to six values.
     // TODO: currently, this is slower than `Extern::func` because of the type conversions.
     //       we should be able to optimize this and make it even faster than `Extern::func`.
-    pub fn typed_func<P, R>(func: impl Fn(FuncContext<'_>, P) -> Result<R> + 'static) -> Self
+    pub fn typed_func<P, R>(func: impl Fn(FuncContext<'_>, P) -> Result<R> + MaybeSendSync + 'static) -> Self
     where
         P: FromWasmValueTuple + ValTypesFromTuple,
         R: IntoWasmValueTuple + ValTypesFromTuple + Debug,
@@ -153,13 +243,24 @@ impl Extern {
         let inner_func = move |ctx: FuncContext<'_>, args: &[WasmValue]| -> Result<Vec<WasmValue>> {
             let args = P::from_wasm_value_tuple(args)?;
             let result = func(ctx, args)?;
-            Ok(result.into_wasm_value_tuple().to_vec())
+            Ok(result.into_wasm_values().as_ref().to_vec())
         };
 
         let ty = tinywasm_types::FuncType { params: P::val_types(), results: R::val_types() };
         Self::Function(Function::Host(Rc::new(HostFunction { func: Box::new(inner_func), ty })))
     }
 
+    /// Create a stub function that fails with [`LinkingError::UnknownImport`] when called
+    ///
+    /// Useful with [`Imports::fallback`] to load modules that import functions the host doesn't provide
+    pub fn trap_unresolved(module: &str, name: &str, ty: &FuncType) -> Self {
+        let (module, name) = (module.to_string(), name.to_string());
+        Self::func(ty, move |_ctx, _args| {
+            log::error!("called unresolved import {}.{}", module, name);
+            Err(LinkingError::UnknownImport { module: module.clone(), name: name.clone(), func: None }.into())
+        })
+    }
+
     /// Get the kind of the external value
     pub fn kind(&self) -> ExternalKind {
         match self {
@@ -184,6 +285,43 @@ impl From<&Import> for ExternName {
     }
 }
 
+/// The type of an import, see [`Imports::fallback`]
+#[derive(Debug, Clone, Copy)]
+pub enum ImportType<'a> {
+    /// A function import
+    Function(&'a FuncType),
+    /// A table import
+    Table(&'a TableType),
+    /// A memory import
+    Memory(&'a MemoryType),
+    /// A global import
+    Global(&'a GlobalType),
+}
+
+impl<'a> ImportType<'a> {
+    fn new(import: &'a Import, func_types: &'a [FuncType]) -> Option<Self> {
+        Some(match &import.kind {
+            ImportKind::Function(ty) => Self::Function(func_types.get(*ty as usize)?),
+            ImportKind::Table(ty) => Self::Table(ty),
+            ImportKind::Memory(ty) => Self::Memory(ty),
+            ImportKind::Global(ty) => Self::Global(ty),
+        })
+    }
+}
+
+#[cfg(not(feature = "sync"))]
+type ImportResolverInner = Box<dyn Fn(&str, &str, ImportType<'_>) -> Option<Extern>>;
+#[cfg(feature = "sync")]
+type ImportResolverInner = Box<dyn Fn(&str, &str, ImportType<'_>) -> Option<Extern> + Send + Sync>;
+
+struct ImportResolver(ImportResolverInner);
+
+impl Debug for ImportResolver {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("ImportResolver")
+    }
+}
+
 #[derive(Debug, Default)]
 /// Imports for a module instance
 ///
@@ -218,11 +356,16 @@ impl From<&Import> for ExternName {
 ///
 /// Note that module instance addresses for [`Imports::link_module`] can be obtained from [`crate::ModuleInstance::id`].
 /// Now, the imports object can be passed to [`crate::ModuleInstance::instantiate`].
+///
+/// Imports that are not defined here are looked up in the instances registered
+/// with [`crate::Store::register_instance`], and finally passed to the [`Imports::fallback`] resolver.
 pub struct Imports {
     values: BTreeMap<ExternName, Extern>,
     modules: BTreeMap<String, ModuleInstanceAddr>,
+    fallback: Option<ImportResolver>,
 }
 
+#[derive(Debug, Clone)]
 pub(crate) enum ResolvedExtern<S, V> {
     // already in the store
     Store(S),
@@ -247,16 +390,52 @@ impl ResolvedImports {
 impl Imports {
     /// Create a new empty import set
     pub fn new() -> Self {
-        Imports { values: BTreeMap::new(), modules: BTreeMap::new() }
+        Imports { values: BTreeMap::new(), modules: BTreeMap::new(), fallback: None }
     }
 
     /// Merge two import sets
+    ///
+    /// Imports and the fallback resolver of `other` take precedence
     pub fn merge(mut self, other: Self) -> Self {
         self.values.extend(other.values);
         self.modules.extend(other.modules);
+        self.fallback = other.fallback.or(self.fallback);
         self
     }
 
+    /// Set a resolver for imports that can't be resolved otherwise
+    ///
+    /// The resolver is called with the module name, name and type of every import that is
+    /// neither defined in this import set nor exported by a linked or registered instance.
+    /// It can synthesize a value for the import, e.g. a stub function:
+    ///
+    /// ```rust
+    /// use tinywasm::{Extern, Imports, ImportType};
+    /// let mut imports = Imports::new();
+    /// imports.fallback(|module, name, ty| match ty {
+    ///     ImportType::Function(ty) => Some(Extern::trap_unresolved(module, name, ty)),
+    ///     _ => None,
+    /// });
+    /// ```
+    pub fn fallback(
+        &mut self,
+        resolver: impl Fn(&str, &str, ImportType<'_>) -> Option<Extern> + MaybeSendSync + 'static,
+    ) -> &mut Self {
+        self.fallback = Some(ImportResolver(Box::new(resolver)));
+        self
+    }
+
+    // add a resolver that is tried before the current fallback resolver
+    pub(crate) fn prepend_fallback(
+        &mut self,
+        resolver: impl Fn(&str, &str, ImportType<'_>) -> Option<Extern> + MaybeSendSync + 'static,
+    ) -> &mut Self {
+        let previous = self.fallback.take();
+        self.fallback(move |module, name, ty| {
+            resolver(module, name, ty).or_else(|| previous.as_ref().and_then(|previous| (previous.0)(module, name, ty)))
+        })
+    }
+
     /// Link a module
     ///
     /// This will automatically link all imported values on instantiation
@@ -273,8 +452,9 @@ impl Imports {
 
     pub(crate) fn take(
         &mut self,
-        store: &mut crate::Store,
+        store: &crate::Store,
         import: &Import,
+        func_types: &[FuncType],
     ) -> Option<ResolvedExtern<ExternVal, Extern>> {
         let name = ExternName::from(import);
         if let Some(v) = self.values.get(&name) {
@@ -284,6 +464,15 @@ impl Imports {
             let instance = store.get_module_instance(*addr)?;
             return Some(ResolvedExtern::Store(instance.export_addr(&import.name)?));
         }
+        if let Some(instance) = store.registered_instance(&name.module) {
+            if let Some(val) = instance.export_addr(&import.name) {
+                return Some(ResolvedExtern::Store(val));
+            }
+        }
+        if let Some(fallback) = &self.fallback {
+            let ty = ImportType::new(import, func_types)?;
+            return (fallback.0)(&import.module, &import.name, ty).map(ResolvedExtern::Extern);
+        }
 
         None
     }
@@ -296,10 +485,17 @@ impl Imports {
         Ok(())
     }
 
-    fn compare_table_types(import: &Import, expected: &TableType, actual: &TableType) -> Result<()> {
+    fn compare_table_types(
+        import: &Import,
+        expected: &TableType,
+        actual: &TableType,
+        real_size: Option<usize>,
+    ) -> Result<()> {
         Self::compare_types(import, &actual.element_type, &expected.element_type)?;
 
-        if actual.size_initial > expected.size_initial {
+        if actual.size_initial > expected.size_initial
+            && real_size.map_or(true, |size| actual.size_initial as usize > size)
+        {
             return Err(LinkingError::incompatible_import_type(import).into());
         }
 
@@ -341,89 +537,320 @@ impl Imports {
         Ok(())
     }
 
-    pub(crate) fn link(
+    /// Resolve and type-check all imports of a module
+    ///
+    /// Values that need to be added to the store are returned as [`ResolvedExtern::Extern`]
+    pub(crate) fn resolve(
         mut self,
-        store: &mut crate::Store,
+        store: &crate::Store,
         module: &crate::Module,
-        idx: ModuleInstanceAddr,
-    ) -> Result<ResolvedImports> {
-        let mut imports = ResolvedImports::new();
+    ) -> Result<Vec<ResolvedExtern<ExternVal, Extern>>> {
+        let mut resolved = Vec::with_capacity(module.data.imports.len());
+        let mut func_idx: FuncAddr = 0;
 
         for import in module.data.imports.iter() {
-            let val = self.take(store, import).ok_or_else(|| LinkingError::unknown_import(import))?;
+            let val = match self.resolve_import(store, module, import) {
+                Ok(val) => val,
+                // name the function import like the backtraces of its calls would
+                Err(crate::Error::Linker(err)) if matches!(import.kind, ImportKind::Function(_)) => {
+                    let names = &module.data.func_names;
+                    let name = names.binary_search_by_key(&func_idx, |(i, _)| *i).ok().map(|i| &*names[i].1);
+                    return Err(err.for_func(func_idx, name).into());
+                }
+                Err(err) => return Err(err),
+            };
 
-            match val {
-                // A link to something that needs to be added to the store
-                ResolvedExtern::Extern(ex) => match (ex, &import.kind) {
-                    (Extern::Global { ty, val }, ImportKind::Global(import_ty)) => {
-                        Self::compare_types(import, &ty, import_ty)?;
-                        imports.globals.push(store.add_global(ty, val.into(), idx)?);
+            if matches!(import.kind, ImportKind::Function(_)) {
+                func_idx += 1;
+            }
+            resolved.push(val);
+        }
+
+        Ok(resolved)
+    }
+
+    fn resolve_import(
+        &mut self,
+        store: &crate::Store,
+        module: &crate::Module,
+        import: &Import,
+    ) -> Result<ResolvedExtern<ExternVal, Extern>> {
+        let val =
+            self.take(store, import, &module.data.func_types).ok_or_else(|| LinkingError::unknown_import(import))?;
+
+        match &val {
+            // A link to something that needs to be added to the store
+            ResolvedExtern::Extern(ex) => match (ex, &import.kind) {
+                (Extern::Global { ty, .. }, ImportKind::Global(import_ty)) => {
+                    Self::compare_types(import, ty, import_ty)?;
+                }
+                (Extern::Table { ty, .. }, ImportKind::Table(import_ty)) => {
+                    Self::compare_table_types(import, ty, import_ty, None)?;
+                }
+                (Extern::Memory { ty }, ImportKind::Memory(import_ty)) => {
+                    Self::compare_memory_types(import, ty, import_ty, None)?;
+                }
+                (Extern::Function(extern_func), ImportKind::Function(ty)) => {
+                    let import_func_type = module
+                        .data
+                        .func_types
+                        .get(*ty as usize)
+                        .ok_or_else(|| LinkingError::incompatible_import_type(import))?;
+
+                    Self::compare_types(import, extern_func.ty(), import_func_type)?;
+                }
+                _ => return Err(LinkingError::incompatible_import_type(import).into()),
+            },
+
+            // A link to something already in the store
+            ResolvedExtern::Store(val) => {
+                // check if the kind matches
+                if val.kind() != (&import.kind).into() {
+                    return Err(LinkingError::incompatible_import_type(import).into());
+                }
+
+                match (val, &import.kind) {
+                    (ExternVal::Global(global_addr), ImportKind::Global(ty)) => {
+                        let global = store.get_global(*global_addr as usize)?;
+                        Self::compare_types(import, &global.borrow().ty, ty)?;
                     }
-                    (Extern::Table { ty, .. }, ImportKind::Table(import_ty)) => {
-                        Self::compare_table_types(import, &ty, import_ty)?;
-                        imports.tables.push(store.add_table(ty, idx)?);
+                    (ExternVal::Table(table_addr), ImportKind::Table(ty)) => {
+                        let table = store.get_table(*table_addr as usize)?;
+                        let (size, kind) = {
+                            let table = table.borrow();
+                            (table.size() as usize, table.kind.clone())
+                        };
+                        Self::compare_table_types(import, &kind, ty, Some(size))?;
                     }
-                    (Extern::Memory { ty }, ImportKind::Memory(import_ty)) => {
-                        Self::compare_memory_types(import, &ty, import_ty, None)?;
-                        imports.memories.push(store.add_mem(ty, idx)?);
+                    (ExternVal::Memory(memory_addr), ImportKind::Memory(ty)) => {
+                        let mem = store.get_mem(*memory_addr as usize)?;
+                        let (size, kind) = {
+                            let mem = mem.borrow();
+                            (mem.page_count(), mem.kind)
+                        };
+                        Self::compare_memory_types(import, &kind, ty, Some(size))?;
                     }
-                    (Extern::Function(extern_func), ImportKind::Function(ty)) => {
+                    (ExternVal::Func(func_addr), ImportKind::Function(ty)) => {
+                        let func = store.get_func(*func_addr as usize)?;
                         let import_func_type = module
                             .data
                             .func_types
                             .get(*ty as usize)
                             .ok_or_else(|| LinkingError::incompatible_import_type(import))?;
 
-                        Self::compare_types(import, extern_func.ty(), import_func_type)?;
-                        imports.funcs.push(store.add_func(extern_func, idx)?);
+                        // types are equal exactly if their IDs are, `compare_types` only reports the mismatch
+                        if store.types.get(import_func_type) != Some(func.type_id) {
+                            Self::compare_types(import, func.func.ty(), import_func_type)?;
+                        }
                     }
                     _ => return Err(LinkingError::incompatible_import_type(import).into()),
-                },
+                }
+            }
+        }
 
-                // A link to something already in the store
-                ResolvedExtern::Store(val) => {
-                    // check if the kind matches
-                    if val.kind() != (&import.kind).into() {
-                        return Err(LinkingError::incompatible_import_type(import).into());
-                    }
+        Ok(val)
+    }
 
-                    match (val, &import.kind) {
-                        (ExternVal::Global(global_addr), ImportKind::Global(ty)) => {
-                            let global = store.get_global(global_addr as usize)?;
-                            Self::compare_types(import, &global.borrow().ty, ty)?;
-                            imports.globals.push(global_addr);
-                        }
-                        (ExternVal::Table(table_addr), ImportKind::Table(ty)) => {
-                            let table = store.get_table(table_addr as usize)?;
-                            Self::compare_table_types(import, &table.borrow().kind, ty)?;
-                            imports.tables.push(table_addr);
-                        }
-                        (ExternVal::Memory(memory_addr), ImportKind::Memory(ty)) => {
-                            let mem = store.get_mem(memory_addr as usize)?;
-                            let (size, kind) = {
-                                let mem = mem.borrow();
-                                (mem.page_count(), mem.kind)
-                            };
-                            Self::compare_memory_types(import, &kind, ty, Some(size))?;
-                            imports.memories.push(memory_addr);
-                        }
-                        (ExternVal::Func(func_addr), ImportKind::Function(ty)) => {
-                            let func = store.get_func(func_addr as usize)?;
-                            let import_func_type = module
-                                .data
-                                .func_types
-                                .get(*ty as usize)
-                                .ok_or_else(|| LinkingError::incompatible_import_type(import))?;
+    /// Add resolved imports to the store, returning their addresses
+    ///
+    /// The imports have to be type-checked already, see [`Imports::resolve`]
+    pub(crate) fn apply(
+        store: &mut crate::Store,
+        resolved: impl IntoIterator<Item = ResolvedExtern<ExternVal, Extern>>,
+        idx: ModuleInstanceAddr,
+    ) -> Result<ResolvedImports> {
+        let mut imports = ResolvedImports::new();
 
-                            Self::compare_types(import, func.func.ty(), import_func_type)?;
-                            imports.funcs.push(func_addr);
-                        }
-                        _ => return Err(LinkingError::incompatible_import_type(import).into()),
-                    }
+        for val in resolved {
+            match val {
+                ResolvedExtern::Extern(Extern::Global { ty, val }) => {
+                    imports.globals.push(store.add_global(ty, val.into(), idx)?)
                 }
+                ResolvedExtern::Extern(Extern::Table { ty, .. }) => imports.tables.push(store.add_table(ty, idx)?),
+                ResolvedExtern::Extern(Extern::Memory { ty }) => imports.memories.push(store.add_mem(ty, idx)?),
+                ResolvedExtern::Extern(Extern::Function(func)) => imports.funcs.push(store.add_func(func, idx)?),
+                ResolvedExtern::Store(ExternVal::Global(addr)) => imports.globals.push(addr),
+                ResolvedExtern::Store(ExternVal::Table(addr)) => imports.tables.push(addr),
+                ResolvedExtern::Store(ExternVal::Memory(addr)) => imports.memories.push(addr),
+                ResolvedExtern::Store(ExternVal::Func(addr)) => imports.funcs.push(addr),
             }
         }
 
         Ok(imports)
     }
+
+    pub(crate) fn link(
+        self,
+        store: &mut crate::Store,
+        module: &crate::Module,
+        idx: ModuleInstanceAddr,
+    ) -> Result<ResolvedImports> {
+        let resolved = self.resolve(store, module)?;
+        Self::apply(store, resolved, idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, FuncContext, Module, Store};
+    use alloc::vec;
+
+    // a module exporting `run`, which passes its param to the imported `env.split` and returns its results
+    fn module(results: Box<[ValType]>) -> Module {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I64]), results });
+        let split = builder.add_import("env", "split", ImportKind::Function(ty));
+        let run =
+            builder.add_function(ty, [], [Instruction::LocalGet(0), Instruction::Call(split), Instruction::EndFunc]);
+        builder.add_export("run", ExternalKind::Func, run);
+        Module::from(builder.finish().expect("valid module"))
+    }
+
+    #[test]
+    fn test_multi_value_host_func() -> Result<()> {
+        let split = |_: FuncContext<'_>, v: i64| Ok(((v >> 32) as i32, v as i32));
+        let mut imports = Imports::new();
+        imports.define("env", "split", Extern::typed_func(split))?;
+
+        let mut store = Store::default();
+        let instance = module(Box::new([ValType::I32, ValType::I32])).instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<i64, (i32, i32)>(&store, "run")?;
+        assert_eq!(run.call(&mut store, 0x1_0000_0002)?, (1, 2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_func_result_mismatch() -> Result<()> {
+        let ty = FuncType { params: Box::new([ValType::I64]), results: Box::new([ValType::I32, ValType::I64]) };
+        let mut imports = Imports::new();
+        imports.define("env", "split", Extern::func(&ty, |_, _| Ok(vec![WasmValue::I32(1)])))?;
+
+        let mut store = Store::default();
+        let instance = module(ty.results.clone()).instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func_untyped(&store, "run")?;
+        assert!(matches!(run.call(&mut store, &[WasmValue::I64(0)]), Err(Error::Other(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_error_names() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I64]), results: Box::new([]) });
+        builder.add_import("env", "memory", ImportKind::Memory(MemoryType::new_32(1, None)));
+        let log = builder.add_import("env", "log", ImportKind::Function(ty));
+        builder.func_names([(log, "host::log".into())]);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        // only function imports are named
+        let err = module.clone().instantiate(&mut Store::default(), None).expect_err("no env.memory");
+        assert_eq!(err.to_string(), "linking error: unknown import: env.memory");
+
+        let mut imports = Imports::new();
+        imports.define("env", "memory", Extern::memory(MemoryType::new_32(1, None)))?;
+        let err = module.clone().instantiate(&mut Store::default(), Some(imports)).expect_err("no env.log");
+        assert_eq!(err.to_string(), "linking error: unknown import: env.log for func[0] 'host::log'");
+
+        let mut imports = Imports::new();
+        imports.define("env", "memory", Extern::memory(MemoryType::new_32(1, None)))?;
+        imports.define("env", "log", Extern::typed_func(|_: FuncContext<'_>, _: i32| Ok(())))?;
+        let err = module.instantiate(&mut Store::default(), Some(imports)).expect_err("env.log takes an i64");
+        assert_eq!(err.to_string(), "linking error: incompatible import type: env.log for func[0] 'host::log'");
+        Ok(())
+    }
+
+    // exports `run`, which calls `env.hook` and then loads the last 4 bytes of the minimum size of its memory,
+    // both with a static load and a bounds-checked one
+    fn static_access_module(min: u64, import_memory: bool) -> Module {
+        let mut builder = ModuleBuilder::new();
+        let hook_ty = builder.add_type(FuncType { params: Box::new([]), results: Box::new([]) });
+        let hook = builder.add_import("env", "hook", ImportKind::Function(hook_ty));
+        match import_memory {
+            true => builder.add_import("a", "memory", ImportKind::Memory(MemoryType::new_32(min, None))),
+            false => builder.add_memory(MemoryType::new_32(min, None)),
+        };
+
+        let ty = builder.add_type(FuncType { params: Box::new([]), results: Box::new([ValType::I32, ValType::I32]) });
+        let addr = (min * MemoryType::PAGE_SIZE - 4) as u32;
+        let instructions = [
+            Instruction::Call(hook),
+            Instruction::I32LoadStatic { addr, mem_addr: 0 },
+            Instruction::I32Const(addr as i32),
+            Instruction::I32Load { offset: 0, mem_addr: 0 },
+            Instruction::EndFunc,
+        ];
+        let run = builder.add_function_with_constants(ty, [], instructions, [0]);
+        builder.add_export("run", ExternalKind::Func, run);
+        Module::from(builder.finish().expect("valid module"))
+    }
+
+    #[test]
+    fn test_static_memory_access() -> Result<()> {
+        let mut a = ModuleBuilder::new();
+        a.add_memory(MemoryType::new_32(1, None));
+        a.add_export("memory", ExternalKind::Memory, 0);
+        let mut store = Store::default();
+        let a = Module::from(a.finish().expect("valid module")).instantiate(&mut store, None)?;
+
+        {
+            let mut memory = a.exported_memory_mut(&mut store, "memory")?;
+            assert_eq!(memory.grow(1), Some(1));
+            memory.store(2 * MemoryType::PAGE_SIZE as usize - 4, 4, &42i32.to_le_bytes())?;
+        }
+
+        let imports = || -> Result<Imports> {
+            let mut imports = Imports::new();
+            imports.define("env", "hook", Extern::typed_func(|_, ()| Ok(())))?.link_module("a", a.id())?;
+            Ok(imports)
+        };
+        assert!(static_access_module(3, true).instantiate(&mut store, Some(imports()?)).is_err());
+
+        // both loads agree
+        let b = static_access_module(2, true).instantiate(&mut store, Some(imports()?))?;
+        assert_eq!(b.exported_func::<(), (i32, i32)>(&store, "run")?.call(&mut store, ())?, (42, 42));
+
+        // removing the instance releases its memory, so it stops running once the host function returns
+        let remove = |mut ctx: FuncContext<'_>, ()| {
+            let addr = ctx.module()?.id();
+            ctx.store_mut().remove_instance(addr)?;
+            assert!(ctx.module().is_err());
+            Ok(())
+        };
+        let mut imports = Imports::new();
+        imports.define("env", "hook", Extern::typed_func(remove))?;
+        let c = static_access_module(1, false).instantiate(&mut store, Some(imports))?;
+        assert!(c.exported_func::<(), (i32, i32)>(&store, "run")?.call(&mut store, ()).is_err());
+        Ok(())
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct PluginError {
+        code: i64,
+    }
+
+    impl core::fmt::Display for PluginError {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            write!(f, "plugin error {}", self.code)
+        }
+    }
+
+    #[test]
+    fn test_host_error() -> Result<()> {
+        let split = |_: FuncContext<'_>, code: i64| -> Result<(i32, i32)> { Err(Error::host(PluginError { code })) };
+        let mut imports = Imports::new();
+        imports.define("env", "split", Extern::typed_func(split))?;
+
+        let mut store = Store::default();
+        let instance = module(Box::new([ValType::I32, ValType::I32])).instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<i64, (i32, i32)>(&store, "run")?;
+
+        let err = run.call(&mut store, 7).expect_err("host function fails");
+        assert_eq!(
+            (err.downcast_ref(), err.to_string()),
+            (Some(&PluginError { code: 7 }), "host error: plugin error 7".into())
+        );
+        assert!(err.downcast_ref::<i64>().is_none());
+        assert_eq!(err.downcast::<PluginError>().ok(), Some(PluginError { code: 7 }));
+        Ok(())
+    }
 }
@@ -0,0 +1,186 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+use core::ops::Range;
+
+use crate::{MemoryRef, MemoryRefMut, Result};
+
+const ROW_LEN: usize = 16;
+
+/// A copy of a region of linear memory, see [`MemoryRef::inspect`]
+///
+/// Regions are detached from the memory, so they can be kept around and compared
+/// to a later copy of the same region using [`MemoryRegion::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryRegion {
+    offset: usize,
+    data: Vec<u8>,
+}
+
+impl MemoryRegion {
+    /// The address of the first byte of the region
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The addresses covered by the region
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.data.len()
+    }
+
+    /// The contents of the region
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Get the byte at the address `addr`, if it is part of the region
+    pub fn get(&self, addr: usize) -> Option<u8> {
+        self.data.get(addr.checked_sub(self.offset)?).copied()
+    }
+
+    /// Format the region as a hexdump of 16 bytes per line, with a column for the address and the ASCII characters
+    ///
+    /// ```text
+    /// 00000010  74 69 6e 79 20 f0 9f a6  80 00 00 00 00 00 00 00  |tiny ...........|
+    /// ```
+    pub fn hexdump(&self) -> Hexdump<'_> {
+        Hexdump(self)
+    }
+
+    /// Compare the region to a later copy of it, returning the changed bytes as contiguous runs
+    ///
+    /// Only the addresses covered by both regions are compared.
+    pub fn diff(&self, newer: &MemoryRegion) -> Vec<MemoryChange> {
+        let start = self.offset.max(newer.offset);
+        let end = self.range().end.min(newer.range().end);
+
+        let mut changes: Vec<MemoryChange> = Vec::new();
+        for addr in start..end {
+            let (old, new) = (self.data[addr - self.offset], newer.data[addr - newer.offset]);
+            if old == new {
+                continue;
+            }
+
+            match changes.last_mut() {
+                Some(change) if change.range().end == addr => {
+                    change.old.push(old);
+                    change.new.push(new);
+                }
+                _ => changes.push(MemoryChange { offset: addr, old: alloc::vec![old], new: alloc::vec![new] }),
+            }
+        }
+        changes
+    }
+}
+
+/// A run of changed bytes, see [`MemoryRegion::diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryChange {
+    /// The address of the first changed byte
+    pub offset: usize,
+    /// The bytes in the older region
+    pub old: Vec<u8>,
+    /// The bytes in the newer region
+    pub new: Vec<u8>,
+}
+
+impl MemoryChange {
+    /// The addresses that changed
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.old.len()
+    }
+}
+
+impl Display for MemoryChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}: ", self.offset)?;
+        self.old.iter().try_for_each(|b| write!(f, "{:02x}", b))?;
+        f.write_str(" -> ")?;
+        self.new.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+/// A hexdump of a [`MemoryRegion`], see [`MemoryRegion::hexdump`]
+#[derive(Debug, Clone, Copy)]
+pub struct Hexdump<'a>(&'a MemoryRegion);
+
+impl Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, row) in self.0.data.chunks(ROW_LEN).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{:08x}  ", self.0.offset + i * ROW_LEN)?;
+            for col in 0..ROW_LEN {
+                // an extra space after 8 bytes, like `hexdump -C`
+                let sep = if col == ROW_LEN / 2 - 1 { "  " } else { " " };
+                match row.get(col) {
+                    Some(byte) => write!(f, "{:02x}{}", byte, sep)?,
+                    None => write!(f, "  {}", sep)?,
+                }
+            }
+
+            f.write_str(" |")?;
+            for byte in row {
+                let c = if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' };
+                write!(f, "{}", c)?;
+            }
+            f.write_str("|")?;
+        }
+        Ok(())
+    }
+}
+
+impl MemoryRef<'_> {
+    /// Copy a region of memory for inspecting it, see [`MemoryRegion`]
+    pub fn inspect(&self, range: Range<usize>) -> Result<MemoryRegion> {
+        Ok(MemoryRegion { offset: range.start, data: self.load_vec(range.start, range.len())? })
+    }
+}
+
+impl MemoryRefMut<'_> {
+    /// Copy a region of memory for inspecting it, see [`MemoryRegion`]
+    pub fn inspect(&self, range: Range<usize>) -> Result<MemoryRegion> {
+        Ok(MemoryRegion { offset: range.start, data: self.load_vec(range.start, range.len())? })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Store};
+    use alloc::string::ToString;
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        let module = Module::from(TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            exports: vec![Export { name: "memory".into(), kind: ExternalKind::Memory, index: 0 }].into(),
+            ..Default::default()
+        });
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        let mut memory = instance.exported_memory_mut(&mut store, "memory")?;
+
+        memory.store(16, 5, b"tiny\n")?;
+        let before = memory.inspect(12..32)?;
+        assert_eq!((before.range(), before.get(16), before.get(11)), (12..32, Some(b't'), None));
+        assert_eq!(
+            before.hexdump().to_string(),
+            "0000000c  00 00 00 00 74 69 6e 79  0a 00 00 00 00 00 00 00  |....tiny........|\n\
+             0000001c  00 00 00 00                                       |....|"
+        );
+
+        memory.store(17, 2, b"ou")?;
+        memory.store(24, 1, &[0xff])?;
+        let changes = memory.inspect(0..20)?.diff(&before);
+        assert_eq!(changes, vec![MemoryChange { offset: 17, old: b"ou".to_vec(), new: b"in".to_vec() }]);
+        assert_eq!(changes[0].to_string(), "00000011: 6f75 -> 696e");
+        assert_eq!(before.diff(&memory.inspect(12..32)?).len(), 2);
+
+        assert!(memory.inspect(65530..65540).is_err());
+        Ok(())
+    }
+}
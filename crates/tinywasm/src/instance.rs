@@ -1,8 +1,64 @@
-use alloc::{boxed::Box, format, rc::Rc, string::ToString};
+use alloc::{boxed::Box, collections::BTreeMap, format, string::String, string::ToString, vec::Vec};
 use tinywasm_types::*;
 
 use crate::func::{FromWasmValueTuple, IntoWasmValueTuple};
-use crate::{log, Error, FuncHandle, FuncHandleTyped, Imports, MemoryRef, MemoryRefMut, Module, Result, Store};
+use crate::imports::{ResolvedExtern, ResolvedImports};
+use crate::module::ExportIndex;
+use crate::store::{GlobalInstance, TypeId};
+use crate::sync::{Rc, RefCell};
+use crate::{
+    log, CallMetrics, Error, Extern, FuncHandle, FuncHandleTyped, Imports, MemoryRef, MemoryRefMut, Module, Result,
+    Store, TableRef,
+};
+
+/// A module with its imports already resolved and type-checked against a [`Store`]
+///
+/// Instantiating an `InstancePre` skips looking up and type-checking the imports,
+/// so it is much cheaper than [`Module::instantiate`] when the same module is instantiated
+/// over and over again, e.g. once per request.
+/// Functions defined in the module are shared between all instances created from it.
+///
+/// Created with [`Module::instantiate_pre`]
+#[derive(Debug, Clone)]
+pub struct InstancePre {
+    store_id: usize,
+    // the module without its functions, which are in `funcs`
+    module: Module,
+    funcs: Box<[Rc<WasmFunction>]>,
+    imports: Box<[ResolvedExtern<ExternVal, Extern>]>,
+}
+
+impl InstancePre {
+    pub(crate) fn new(store: &Store, mut module: Module, imports: Option<Imports>) -> Result<Self> {
+        module.check_supported()?;
+        let imports = imports.unwrap_or_default().resolve(store, &module)?;
+        let funcs = core::mem::take(&mut module.data.funcs).into_vec().into_iter().map(Rc::new).collect();
+        Ok(Self { store_id: store.id(), module, funcs, imports: imports.into_boxed_slice() })
+    }
+
+    /// Instantiate the module in the given store
+    ///
+    /// The store has to be the one the imports were resolved against.
+    /// Runs the start function if it exists.
+    pub fn instantiate(&self, store: &mut Store) -> Result<ModuleInstance> {
+        let instance = self.instantiate_no_start(store)?;
+        let _ = instance.start(store)?;
+        Ok(instance)
+    }
+
+    /// Instantiate the module in the given store without running the start function
+    pub fn instantiate_no_start(&self, store: &mut Store) -> Result<ModuleInstance> {
+        if self.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        let idx = store.next_module_instance_idx();
+        log::info!("Instantiating pre-linked module at index {}", idx);
+
+        let addrs = Imports::apply(store, self.imports.iter().cloned(), idx)?;
+        ModuleInstance::instantiate_linked(store, idx, self.module.clone(), self.funcs.iter().cloned(), addrs, false)
+    }
+}
 
 /// An instanciated WebAssembly module
 ///
@@ -21,6 +77,7 @@ pub(crate) struct ModuleInstanceInner {
     pub(crate) idx: ModuleInstanceAddr,
 
     pub(crate) types: Box<[FuncType]>,
+    pub(crate) type_ids: Box<[TypeId]>,
 
     pub(crate) func_addrs: Box<[FuncAddr]>,
     pub(crate) table_addrs: Box<[TableAddr]>,
@@ -32,6 +89,8 @@ pub(crate) struct ModuleInstanceInner {
     pub(crate) func_start: Option<FuncAddr>,
     pub(crate) imports: Box<[Import]>,
     pub(crate) exports: Box<[Export]>,
+    pub(crate) export_index: Rc<ExportIndex>,
+    pub(crate) func_names: Box<[(FuncAddr, Box<str>)]>,
 }
 
 impl ModuleInstance {
@@ -42,8 +101,9 @@ impl ModuleInstance {
     }
 
     #[inline]
-    pub(crate) fn swap_with(&mut self, other_addr: ModuleInstanceAddr, store: &mut Store) {
-        self.swap(store.get_module_instance_raw(other_addr))
+    pub(crate) fn swap_with(&mut self, other_addr: ModuleInstanceAddr, store: &mut Store) -> Result<()> {
+        self.swap(store.get_module_instance_raw(other_addr)?);
+        Ok(())
     }
 
     /// Get the module instance's address
@@ -52,10 +112,15 @@ impl ModuleInstance {
         self.0.idx
     }
 
+    #[inline]
+    pub(crate) fn store_id(&self) -> usize {
+        self.0.store_id
+    }
+
     /// Instantiate the module in the given store
     ///
     /// See <https://webassembly.github.io/spec/core/exec/modules.html#exec-instantiation>
-    pub fn instantiate(store: &mut Store, module: Module, imports: Option<Imports>) -> Result<Self> {
+    pub fn instantiate(store: &mut Store, mut module: Module, imports: Option<Imports>) -> Result<Self> {
         // This doesn't completely follow the steps in the spec, but the end result is the same
         // Constant expressions are evaluated directly where they are used, so we
         // don't need to create a auxiliary frame etc.
@@ -63,24 +128,71 @@ impl ModuleInstance {
         let idx = store.next_module_instance_idx();
         log::info!("Instantiating module at index {}", idx);
         let imports = imports.unwrap_or_default();
+        module.check_supported()?;
+
+        let addrs = imports.link(store, &module, idx)?;
+        let funcs = core::mem::take(&mut module.data.funcs).into_vec().into_iter().map(Rc::new);
+        Self::instantiate_linked(store, idx, module, funcs, addrs, false)
+    }
+
+    /// Instantiate the module in the given store without initializing its active element and data segments
+    ///
+    /// The segments are applied by [`ModuleInstance::run_start`], so the instance's tables
+    /// and memories can be inspected or modified before that.
+    pub fn instantiate_deferred(store: &mut Store, mut module: Module, imports: Option<Imports>) -> Result<Self> {
+        let idx = store.next_module_instance_idx();
+        log::info!("Instantiating module at index {} (deferred)", idx);
+        let imports = imports.unwrap_or_default();
+        module.check_supported()?;
+
+        let addrs = imports.link(store, &module, idx)?;
+        let funcs = core::mem::take(&mut module.data.funcs).into_vec().into_iter().map(Rc::new);
+        Self::instantiate_linked(store, idx, module, funcs, addrs, true)
+    }
 
-        let mut addrs = imports.link(store, &module, idx)?;
-        let data = module.data;
+    // Instantiate a module whose imports have already been added to the store
+    fn instantiate_linked(
+        store: &mut Store,
+        idx: ModuleInstanceAddr,
+        module: Module,
+        funcs: impl IntoIterator<Item = Rc<WasmFunction>>,
+        mut addrs: ResolvedImports,
+        defer_segments: bool,
+    ) -> Result<Self> {
+        let (data, export_index) = (module.data, module.exports);
+
+        // imported memories must not shrink below the minimum size this module was compiled for
+        let imported_mems = data.imports.iter().filter_map(|import| match &import.kind {
+            ImportKind::Memory(ty) => Some(ty),
+            _ => None,
+        });
+        for (addr, ty) in addrs.memories.iter().zip(imported_mems) {
+            store.get_mem(*addr as usize)?.borrow_mut().require_pages(ty.page_count_initial)?;
+        }
 
         // TODO: check if the compiler correctly optimizes this to prevent wasted allocations
-        addrs.funcs.extend(store.init_funcs(data.funcs.into(), idx)?);
-        addrs.tables.extend(store.init_tables(data.table_types.into(), idx)?);
-        addrs.memories.extend(store.init_memories(data.memory_types.into(), idx)?);
+        let func_addrs = store.init_funcs(funcs, idx)?;
+        #[cfg(feature = "parallel")]
+        if let Some(lazy) = module.lazy {
+            store.defer_translation(&func_addrs, lazy);
+        }
+        addrs.funcs.extend(func_addrs);
+        addrs.tables.extend(store.init_tables(&data.table_types, idx)?);
+        addrs.memories.extend(store.init_memories(&data.memory_types, idx)?);
 
-        let global_addrs = store.init_globals(addrs.globals, data.globals.into(), &addrs.funcs, idx)?;
+        let global_addrs = store.init_globals(addrs.globals, &data.globals, &addrs.funcs, idx)?;
+        let mut pending = defer_segments.then(Vec::new);
         let (elem_addrs, elem_trapped) =
-            store.init_elements(&addrs.tables, &addrs.funcs, &global_addrs, &data.elements, idx)?;
-        let (data_addrs, data_trapped) = store.init_datas(&addrs.memories, data.data.into(), idx)?;
+            store.init_elements(&addrs.tables, &addrs.funcs, &global_addrs, &data.elements, idx, pending.as_mut())?;
+        let (data_addrs, data_trapped) =
+            store.init_datas(&addrs.memories, &global_addrs, &data.data, idx, pending.as_mut())?;
+        store.defer_segments(idx, pending.unwrap_or_default());
 
         let instance = ModuleInstanceInner {
             failed_to_instantiate: elem_trapped.is_some() || data_trapped.is_some(),
             store_id: store.id(),
             idx,
+            type_ids: store.types.intern_all(&data.func_types),
             types: data.func_types,
             func_addrs: addrs.funcs.into_boxed_slice(),
             table_addrs: addrs.tables.into_boxed_slice(),
@@ -91,6 +203,8 @@ impl ModuleInstance {
             func_start: data.start_func,
             imports: data.imports,
             exports: data.exports,
+            export_index,
+            func_names: data.func_names,
         };
 
         let instance = ModuleInstance::new(instance);
@@ -107,9 +221,153 @@ impl ModuleInstance {
         Ok(instance)
     }
 
+    /// Fork the module instance, creating a new instance in the same store
+    ///
+    /// The new instance shares the same code and imports as this instance.
+    /// Tables, globals and element/data segments owned by this instance are copied,
+    /// while its memories are shared copy-on-write, so forking is cheap even for large memories.
+    /// The start function is not run again.
+    pub fn fork(&self, store: &mut Store) -> Result<Self> {
+        if self.0.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        let owner = self.id();
+        let idx = store.next_module_instance_idx();
+        log::info!("Forking module instance {} to index {}", owner, idx);
+
+        let (func_addrs, funcs) = store.fork_funcs(&self.0.func_addrs, owner, idx);
+        let instance = ModuleInstanceInner {
+            failed_to_instantiate: self.0.failed_to_instantiate,
+            store_id: self.0.store_id,
+            idx,
+            types: self.0.types.clone(),
+            type_ids: self.0.type_ids.clone(),
+            table_addrs: store.fork_tables(&self.0.table_addrs, owner, idx, &funcs),
+            mem_addrs: store.fork_memories(&self.0.mem_addrs, owner, idx),
+            global_addrs: store.fork_globals(&self.0.global_addrs, owner, idx, &funcs),
+            elem_addrs: store.fork_elements(&self.0.elem_addrs, idx, &funcs),
+            data_addrs: store.fork_datas(&self.0.data_addrs, idx),
+            func_addrs,
+            func_start: self.0.func_start,
+            imports: self.0.imports.clone(),
+            exports: self.0.exports.clone(),
+            export_index: self.0.export_index.clone(),
+            func_names: self.0.func_names.clone(),
+        };
+
+        let instance = ModuleInstance::new(instance);
+        store.add_instance(instance.clone())?;
+        Ok(instance)
+    }
+
+    // see `Store::hot_swap`
+    pub(crate) fn hot_swap(&self, store: &mut Store, module: &Module) -> Result<Self> {
+        if self.0.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+        module.check_supported()?;
+
+        let data = &module.data;
+        let incompatible = |what: &str| Err(Error::Other(format!("incompatible module: {}", what)));
+        if data.imports != self.0.imports {
+            return incompatible("imports differ");
+        }
+
+        let imported =
+            |kind: ExternalKind| self.0.imports.iter().filter(|i| ExternalKind::from(&i.kind) == kind).count();
+        let (func_imports, table_imports) = (imported(ExternalKind::Func), imported(ExternalKind::Table));
+        let (mem_imports, global_imports) = (imported(ExternalKind::Memory), imported(ExternalKind::Global));
+
+        let mems = &self.0.mem_addrs[mem_imports..];
+        if mems.len() != data.memory_types.len()
+            || mems
+                .iter()
+                .zip(data.memory_types.iter())
+                .any(|(addr, ty)| store.data.memories[*addr as usize].borrow().kind != *ty)
+        {
+            return incompatible("memories differ");
+        }
+
+        let tables = &self.0.table_addrs[table_imports..];
+        if tables.len() != data.table_types.len()
+            || tables
+                .iter()
+                .zip(data.table_types.iter())
+                .any(|(addr, ty)| store.data.tables[*addr as usize].borrow().kind != *ty)
+        {
+            return incompatible("tables differ");
+        }
+
+        let globals = &self.0.global_addrs[global_imports..];
+        if globals.len() != data.globals.len()
+            || globals
+                .iter()
+                .zip(data.globals.iter())
+                .any(|(addr, g)| store.get_global(*addr as usize).map_or(true, |global| global.borrow().ty != g.ty))
+        {
+            return incompatible("globals differ");
+        }
+
+        // the type of a function in the new module, imported functions are unchanged
+        let new_func_ty = |store: &Store, idx: usize| match idx.checked_sub(func_imports) {
+            Some(local) => data.funcs.get(local).map(|f| f.ty.clone()),
+            None => {
+                self.0.func_addrs.get(idx).and_then(|addr| Some(store.get_func(*addr as usize).ok()?.func.ty().clone()))
+            }
+        };
+
+        for export in self.0.exports.iter().filter(|e| e.kind == ExternalKind::Func) {
+            let Some(new) = module.exports.get(&export.name).map(|i| &data.exports[*i]) else { continue };
+            let old_ty = store.get_func(self.0.func_addrs[export.index as usize] as usize)?.func.ty();
+            if new.kind != ExternalKind::Func || new_func_ty(store, new.index as usize).as_ref() != Some(old_ty) {
+                return incompatible(&format!("export {} has a different type", export.name));
+            }
+        }
+
+        log::info!("Hot swapping the code of module instance {}", self.id());
+        let new_funcs = store.init_funcs(data.funcs.iter().cloned().map(Rc::new), self.id())?;
+        #[cfg(feature = "parallel")]
+        if let Some(lazy) = &module.lazy {
+            store.defer_translation(&new_funcs, lazy.clone());
+        }
+
+        let mut remap = BTreeMap::new();
+        for (old, new) in self.0.func_addrs[func_imports..].iter().zip(new_funcs.iter()) {
+            if store.get_func(*old as usize)?.type_id == store.get_func(*new as usize)?.type_id {
+                remap.insert(*old, *new);
+            }
+        }
+        store.remap_funcs(self.id(), (&self.0.table_addrs, &self.0.global_addrs, &self.0.elem_addrs), &remap);
+
+        let func_addrs = self.0.func_addrs[..func_imports].iter().copied().chain(new_funcs).collect();
+        let instance = ModuleInstance::new(ModuleInstanceInner {
+            failed_to_instantiate: self.0.failed_to_instantiate,
+            store_id: self.0.store_id,
+            idx: self.0.idx,
+            types: data.func_types.clone(),
+            type_ids: store.types.intern_all(&data.func_types),
+            func_addrs,
+            table_addrs: self.0.table_addrs.clone(),
+            mem_addrs: self.0.mem_addrs.clone(),
+            global_addrs: self.0.global_addrs.clone(),
+            elem_addrs: self.0.elem_addrs.clone(),
+            data_addrs: self.0.data_addrs.clone(),
+            func_start: data.start_func,
+            imports: self.0.imports.clone(),
+            exports: data.exports.clone(),
+            export_index: module.exports.clone(),
+            func_names: data.func_names.clone(),
+        });
+
+        store.replace_instance(instance.clone())?;
+        store.free_unused_funcs(self.0.func_addrs[func_imports..].iter().copied().collect());
+        Ok(instance)
+    }
+
     /// Get a export by name
     pub fn export_addr(&self, name: &str) -> Option<ExternVal> {
-        let exports = self.0.exports.iter().find(|e| e.name == name.into())?;
+        let exports = &self.0.exports[*self.0.export_index.get(name)?];
         let kind = exports.kind.clone();
         let addr = match kind {
             ExternalKind::Func => self.0.func_addrs.get(exports.index as usize)?,
@@ -121,6 +379,27 @@ impl ModuleInstance {
         Some(ExternVal::new(kind, *addr))
     }
 
+    /// Get the call metrics of the instance's exported functions, by export name
+    ///
+    /// Returns `None` if call metrics are disabled (see [`Store::enable_call_metrics`])
+    /// or none of the exports have been called yet
+    pub fn metrics<'a>(&self, store: &'a Store) -> Option<&'a BTreeMap<String, CallMetrics>> {
+        store.instance_metrics(self.id())
+    }
+
+    /// Get the name of a function by its index in the module
+    ///
+    /// Uses the `name` section if the module has one, and otherwise the name the function is exported as
+    pub fn func_name(&self, idx: FuncAddr) -> Option<&str> {
+        match self.0.func_names.binary_search_by_key(&idx, |(i, _)| *i) {
+            Ok(i) => Some(&self.0.func_names[i].1),
+            Err(_) => {
+                let export = self.0.exports.iter().find(|e| e.kind == ExternalKind::Func && e.index == idx)?;
+                Some(&export.name)
+            }
+        }
+    }
+
     #[inline]
     pub(crate) fn new(inner: ModuleInstanceInner) -> Self {
         Self(Rc::new(inner))
@@ -131,11 +410,46 @@ impl ModuleInstance {
         self.0.types.get(addr as usize).expect("No func type for func, this is a bug")
     }
 
+    #[inline]
+    pub(crate) fn type_id(&self, addr: TypeAddr) -> TypeId {
+        self.0.type_ids[addr as usize]
+    }
+
     #[inline]
     pub(crate) fn func_addrs(&self) -> &[FuncAddr] {
         &self.0.func_addrs
     }
 
+    #[inline]
+    pub(crate) fn table_addrs(&self) -> &[TableAddr] {
+        &self.0.table_addrs
+    }
+
+    #[inline]
+    pub(crate) fn mem_addrs(&self) -> &[MemAddr] {
+        &self.0.mem_addrs
+    }
+
+    #[inline]
+    pub(crate) fn global_addrs(&self) -> &[GlobalAddr] {
+        &self.0.global_addrs
+    }
+
+    #[inline]
+    pub(crate) fn elem_addrs(&self) -> &[ElemAddr] {
+        &self.0.elem_addrs
+    }
+
+    #[inline]
+    pub(crate) fn data_addrs(&self) -> &[DataAddr] {
+        &self.0.data_addrs
+    }
+
+    #[inline]
+    pub(crate) fn imports(&self) -> &[Import] {
+        &self.0.imports
+    }
+
     // resolve a function address to the global store address
     #[inline]
     pub(crate) fn resolve_func_addr(&self, addr: FuncAddr) -> FuncAddr {
@@ -199,14 +513,30 @@ impl ModuleInstance {
         Ok(FuncHandleTyped { func, marker: core::marker::PhantomData })
     }
 
+    /// Get an exported table by name
+    pub fn exported_table(&self, store: &Store, name: &str) -> Result<TableRef> {
+        if self.0.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        let export = self.export_addr(name).ok_or_else(|| Error::Other(format!("Export not found: {}", name)))?;
+        let ExternVal::Table(table_addr) = export else {
+            return Err(Error::Other(format!("Export is not a table: {}", name)));
+        };
+
+        let table = store.get_table(table_addr as usize)?;
+        Ok(TableRef { instance: table.clone() })
+    }
+
     /// Get an exported memory by name
     pub fn exported_memory<'a>(&self, store: &'a mut Store, name: &str) -> Result<MemoryRef<'a>> {
         let export = self.export_addr(name).ok_or_else(|| Error::Other(format!("Export not found: {}", name)))?;
         let ExternVal::Memory(mem_addr) = export else {
             return Err(Error::Other(format!("Export is not a memory: {}", name)));
         };
-        let mem = self.memory(store, mem_addr)?;
-        Ok(mem)
+        // the export already resolved to the store address
+        let mem = store.get_mem(mem_addr as usize)?;
+        Ok(MemoryRef { instance: mem.borrow() })
     }
 
     /// Get an exported memory by name
@@ -215,8 +545,9 @@ impl ModuleInstance {
         let ExternVal::Memory(mem_addr) = export else {
             return Err(Error::Other(format!("Export is not a memory: {}", name)));
         };
-        let mem = self.memory_mut(store, mem_addr)?;
-        Ok(mem)
+        // the export already resolved to the store address
+        let mem = store.get_mem(mem_addr as usize)?;
+        Ok(MemoryRefMut { instance: mem.borrow_mut() })
     }
 
     /// Get a memory by address
@@ -276,4 +607,481 @@ impl ModuleInstance {
         let _ = func.call(store, &[])?;
         Ok(Some(()))
     }
+
+    /// Finish a deferred instantiation and run the start function
+    ///
+    /// Applies the active element and data segments of an instance created with
+    /// [`ModuleInstance::instantiate_deferred`] (this does nothing for other instances)
+    /// and then runs the start function, if there is one.
+    pub fn run_start(&self, store: &mut Store) -> Result<Option<()>> {
+        if self.0.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        store.init_pending_segments(self.id())?;
+        self.start(store)
+    }
+
+    /// Take a snapshot of the values of the mutable globals defined by this instance
+    ///
+    /// Imported globals are not included. The snapshot can be restored with [`ModuleInstance::restore_globals`],
+    /// also into a new instance of the same module, e.g. to keep small guest state across restarts.
+    pub fn snapshot_globals(&self, store: &Store) -> Result<GlobalsSnapshot> {
+        if self.0.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        let mut values = Vec::new();
+        for (idx, addr) in self.0.global_addrs.iter().enumerate() {
+            let global = store.get_global(*addr as usize)?.borrow();
+            if global.owner == self.id() && global.ty.mutable {
+                values.push((idx as u32, global.get()));
+            }
+        }
+
+        Ok(GlobalsSnapshot { values })
+    }
+
+    /// Restore the values of globals from a snapshot taken with [`ModuleInstance::snapshot_globals`]
+    ///
+    /// Fails without changing any global if the snapshot doesn't match this instance's globals
+    pub fn restore_globals(&self, store: &mut Store, snapshot: &GlobalsSnapshot) -> Result<()> {
+        for (global, value) in self.check_globals(store, snapshot)? {
+            global.borrow_mut().set(value)?;
+        }
+        Ok(())
+    }
+
+    // the globals to restore from `snapshot`, without changing any of them
+    pub(crate) fn check_globals(
+        &self,
+        store: &Store,
+        snapshot: &GlobalsSnapshot,
+    ) -> Result<Vec<(Rc<RefCell<GlobalInstance>>, WasmValue)>> {
+        if self.0.store_id != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        let mut globals = Vec::with_capacity(snapshot.values.len());
+        for (idx, value) in &snapshot.values {
+            let global = self.0.global_addrs.get(*idx as usize).map(|addr| store.get_global(*addr as usize));
+            let Some(Ok(global)) = global else {
+                return Err(Error::Other(format!("global {} not found", idx)));
+            };
+
+            let instance = global.borrow();
+            if instance.owner != self.id() || !instance.ty.mutable || instance.ty.ty != value.val_type() {
+                return Err(Error::Other(format!("global {} does not match the snapshot", idx)));
+            }
+            globals.push((global.clone(), *value));
+        }
+        Ok(globals)
+    }
+}
+
+/// The values of an instance's mutable globals, see [`ModuleInstance::snapshot_globals`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GlobalsSnapshot {
+    /// The values of the globals, by their index in the module
+    pub values: Vec<(u32, WasmValue)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ImportType, LinkingError, Trap};
+    use alloc::vec;
+
+    // a module exporting `run(i32) -> i32`, which calls the imported `env.double`
+    fn double_module() -> Module {
+        let ty = FuncType { params: vec![ValType::I32].into(), results: vec![ValType::I32].into() };
+        let func = WasmFunction {
+            instructions: vec![Instruction::LocalGet(0), Instruction::Call(0), Instruction::EndFunc].into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+
+        Module::from(TinyWasmModule {
+            funcs: vec![func].into(),
+            func_types: vec![ty].into(),
+            imports: vec![Import { module: "env".into(), name: "double".into(), kind: ImportKind::Function(0) }].into(),
+            exports: vec![Export { name: "run".into(), kind: ExternalKind::Func, index: 1 }].into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_instance_pre() -> Result<()> {
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.define("env", "double", Extern::typed_func(|_, x: i32| Ok(x * 2)))?;
+
+        let pre = double_module().instantiate_pre(&store, Some(imports))?;
+        let a = pre.instantiate(&mut store)?;
+        let b = pre.instantiate(&mut store)?;
+        assert_ne!(a.id(), b.id());
+
+        for (i, instance) in [a, b].iter().enumerate() {
+            let run = instance.exported_func::<i32, i32>(&store, "run")?;
+            assert_eq!(run.call(&mut store, i as i32 + 1)?, (i as i32 + 1) * 2);
+        }
+
+        let mut other = Store::default();
+        assert!(matches!(pre.instantiate(&mut other), Err(Error::InvalidStore)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_lookup() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([]), results: Box::new([ValType::I32]) });
+        let one = builder.add_function_with_constants(ty, [], [Instruction::I32Const(1), Instruction::EndFunc], []);
+        let two = builder.add_function_with_constants(ty, [], [Instruction::I32Const(2), Instruction::EndFunc], []);
+        let memory = builder.add_memory(MemoryType::new_32(1, None));
+        builder.add_export("two", ExternalKind::Func, two).add_export("one", ExternalKind::Func, one);
+        builder.add_export("memory", ExternalKind::Memory, memory);
+
+        let mut store = Store::default();
+        let module = Module::from(builder.finish().expect("valid module"));
+        let instance = module.clone().instantiate(&mut store, None)?;
+        let fork = instance.fork(&mut store)?;
+        for instance in [&instance, &fork] {
+            assert_eq!(instance.exported_func::<(), i32>(&store, "one")?.call(&mut store, ())?, 1);
+            assert_eq!(instance.exported_func::<(), i32>(&store, "two")?.call(&mut store, ())?, 2);
+            assert_eq!(instance.exported_memory(&mut store, "memory")?.load(0, 1)?, [0]);
+            assert!(instance.export_addr("three").is_none());
+            assert!(instance.exported_func_untyped(&store, "memory").is_err());
+        }
+
+        let pre = module.instantiate_pre(&store, None)?.instantiate(&mut store)?;
+        assert!(matches!(pre.export_addr("memory"), Some(ExternVal::Memory(addr)) if addr == pre.resolve_mem_addr(0)));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(not(feature = "no-float"))]
+    fn test_globals_snapshot() -> Result<()> {
+        let global = |mutable, ty, init| Global { ty: GlobalType { mutable, ty }, init };
+        let module = TinyWasmModule {
+            globals: vec![
+                global(true, ValType::I32, ConstInstruction::I32Const(1)),
+                global(false, ValType::I64, ConstInstruction::I64Const(2)),
+                global(true, ValType::F32, ConstInstruction::F32Const(0.5)),
+            ]
+            .into(),
+            ..Default::default()
+        };
+
+        let mut store = Store::default();
+        let a = Module::from(&module).instantiate(&mut store, None)?;
+        let b = Module::from(&module).instantiate(&mut store, None)?;
+        let global_b =
+            |store: &Store, idx: usize| store.get_global(b.0.global_addrs[idx] as usize).unwrap().borrow().get();
+
+        store.get_global(a.0.global_addrs[0] as usize)?.borrow_mut().set(WasmValue::I32(42))?;
+        let snapshot = a.snapshot_globals(&store)?;
+        assert_eq!(snapshot.values, vec![(0, WasmValue::I32(42)), (2, WasmValue::F32(0.5))]);
+
+        b.restore_globals(&mut store, &snapshot)?;
+        assert_eq!(global_b(&store, 0), WasmValue::I32(42));
+
+        let invalid = GlobalsSnapshot { values: vec![(0, WasmValue::I32(7)), (1, WasmValue::I64(3))] };
+        assert!(b.restore_globals(&mut store, &invalid).is_err());
+        assert_eq!((global_b(&store, 0), global_b(&store, 1)), (WasmValue::I32(42), WasmValue::I64(2)));
+        Ok(())
+    }
+
+    // a module with a counter global, exporting `version() -> i32` and `inc() -> i32`
+    fn versioned_module(version: i32, step: i32) -> Module {
+        let ty = FuncType { params: Default::default(), results: vec![ValType::I32].into() };
+        let func = |instructions: &[Instruction]| WasmFunction {
+            instructions: instructions.into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+
+        use Instruction::*;
+        let inc = [GlobalGet(0), I32Const(step), I32Add, GlobalSet(0), GlobalGet(0), EndFunc];
+        Module::from(TinyWasmModule {
+            funcs: vec![func(&[I32Const(version), EndFunc]), func(&inc)].into(),
+            func_types: vec![ty.clone()].into(),
+            globals: vec![Global {
+                ty: GlobalType { mutable: true, ty: ValType::I32 },
+                init: ConstInstruction::I32Const(0),
+            }]
+            .into(),
+            exports: vec![
+                Export { name: "version".into(), kind: ExternalKind::Func, index: 0 },
+                Export { name: "inc".into(), kind: ExternalKind::Func, index: 1 },
+            ]
+            .into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_hot_swap() -> Result<()> {
+        let mut store = Store::default();
+        let instance = versioned_module(1, 1).instantiate(&mut store, None)?;
+        assert_eq!(instance.exported_func::<(), i32>(&store, "inc")?.call(&mut store, ())?, 1);
+
+        let old_inc = instance.exported_func::<(), i32>(&store, "inc")?;
+        let swapped = store.hot_swap(&instance, &versioned_module(2, 10))?;
+        assert_eq!(swapped.id(), instance.id());
+        assert_eq!(swapped.exported_func::<(), i32>(&store, "version")?.call(&mut store, ())?, 2);
+        assert_eq!(swapped.exported_func::<(), i32>(&store, "inc")?.call(&mut store, ())?, 11);
+        assert_eq!(store.get_module_instance(instance.id()).unwrap().func_addrs(), swapped.func_addrs());
+
+        // the replaced functions are freed
+        assert!(old_inc.call(&mut store, ()).is_err());
+        assert!(instance.func_addrs().iter().all(|addr| store.data.funcs[*addr as usize].is_none()));
+
+        // unless another instance imports them
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Default::default(), results: vec![ValType::I32].into() });
+        let inc = builder.add_import("v", "inc", ImportKind::Function(ty));
+        builder.add_export("inc", ExternalKind::Func, inc);
+        let mut imports = Imports::new();
+        imports.link_module("v", swapped.id())?;
+        let importer = Module::from(builder.finish().expect("valid module")).instantiate(&mut store, Some(imports))?;
+        let swapped = store.hot_swap(&swapped, &versioned_module(3, 100))?;
+        assert_eq!(importer.exported_func::<(), i32>(&store, "inc")?.call(&mut store, ())?, 21);
+        assert_eq!(swapped.exported_func::<(), i32>(&store, "inc")?.call(&mut store, ())?, 121);
+
+        // `version` returns an i64 instead of an i32
+        let mut incompatible = versioned_module(3, 1);
+        let mut funcs = incompatible.data.funcs.into_vec();
+        funcs[0] = WasmFunction {
+            instructions: vec![Instruction::I64Const(0), Instruction::EndFunc].into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: vec![3].into(),
+            ty: FuncType { params: Default::default(), results: vec![ValType::I64].into() },
+        };
+        incompatible.data.funcs = funcs.into();
+        assert!(store.hot_swap(&swapped, &incompatible).is_err());
+
+        let mut incompatible = versioned_module(3, 1);
+        incompatible.data.globals = Default::default();
+        assert!(store.hot_swap(&swapped, &incompatible).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backtrace() -> Result<()> {
+        let ty = FuncType { params: Default::default(), results: Default::default() };
+        let func = |instructions: &[Instruction]| WasmFunction {
+            instructions: instructions.into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![
+                func(&[Instruction::Unreachable, Instruction::EndFunc]),
+                func(&[Instruction::Call(0), Instruction::EndFunc]),
+            ]
+            .into(),
+            func_types: vec![ty.clone()].into(),
+            exports: vec![Export { name: "run".into(), kind: ExternalKind::Func, index: 1 }].into(),
+            func_names: vec![(0, "my_module::inner".into())].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        assert_eq!(
+            (instance.func_name(0), instance.func_name(1), instance.func_name(2)),
+            (Some("my_module::inner"), Some("run"), None)
+        );
+
+        let run = instance.exported_func::<(), ()>(&store, "run")?;
+        assert!(matches!(run.call(&mut store, ()), Err(Error::Trap(Trap::Unreachable))));
+
+        let backtrace = store.last_backtrace().expect("trap should record a backtrace");
+        let frames: Vec<_> = backtrace.frames.iter().map(|f| (f.func_index, f.name.as_deref(), f.instr_ptr)).collect();
+        assert_eq!(frames, vec![(Some(0), Some("my_module::inner"), 0), (Some(1), Some("run"), 0)]);
+        assert_eq!(
+            backtrace.to_string(),
+            "   0: func[0] 'my_module::inner' at instruction 0\n   1: func[1] 'run' at instruction 0\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_call_metrics() -> Result<()> {
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.define("env", "double", Extern::typed_func(|_, x: i32| Ok(x * 2)))?;
+        let instance = double_module().instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<i32, i32>(&store, "run")?;
+
+        run.call(&mut store, 1)?;
+        assert!(instance.metrics(&store).is_none());
+
+        store.enable_call_metrics(true);
+        for i in 0..3 {
+            run.call(&mut store, i)?;
+        }
+        assert!(run.func.call(&mut store, &[]).is_err());
+
+        let metrics = instance.metrics(&store).expect("run has been called");
+        assert_eq!((metrics["run"].calls, metrics["run"].errors), (4, 1));
+
+        store.enable_call_metrics(false);
+        assert!(instance.metrics(&store).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_frame() -> Result<()> {
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.define(
+            "env",
+            "double",
+            Extern::typed_func(|ctx, x: i32| {
+                let frame = ctx.frame().expect("called from wasm");
+                assert_eq!(frame.instr_ptr(), 1);
+                assert_eq!(frame.locals(), vec![WasmValue::I32(x)]);
+                assert_eq!((frame.stack_depth(), frame.stack_top(0)), (0, Some(vec![])));
+                assert_eq!(frame.stack_top(1), None);
+                Ok(frame.func_addr() as i32)
+            }),
+        )?;
+
+        let instance = double_module().instantiate(&mut store, Some(imports))?;
+        let Some(ExternVal::Func(run_addr)) = instance.export_addr("run") else { panic!("missing export") };
+        let run = instance.exported_func::<i32, i32>(&store, "run")?;
+        assert_eq!(run.call(&mut store, 5)?, run_addr as i32);
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_pre_checks_imports() {
+        let store = Store::default();
+        let mut imports = Imports::new();
+        imports.define("env", "double", Extern::typed_func(|_, x: i64| Ok(x * 2))).unwrap();
+
+        let res = double_module().instantiate_pre(&store, Some(imports));
+        assert!(matches!(res, Err(Error::Linker(_))));
+    }
+
+    #[test]
+    fn test_fallback_imports() -> Result<()> {
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.fallback(|module, name, ty| match ty {
+            ImportType::Function(ty) => Some(Extern::trap_unresolved(module, name, ty)),
+            _ => None,
+        });
+
+        // unresolved functions are replaced by stubs that fail when called
+        let instance = double_module().instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<i32, i32>(&store, "run")?;
+        let res = run.call(&mut store, 1);
+        assert!(matches!(res, Err(Error::Linker(LinkingError::UnknownImport { .. }))));
+
+        // explicitly defined imports take precedence
+        let mut imports = Imports::new();
+        imports.fallback(|module, name, ty| match ty {
+            ImportType::Function(ty) => Some(Extern::trap_unresolved(module, name, ty)),
+            _ => None,
+        });
+        imports.define("env", "double", Extern::typed_func(|_, x: i32| Ok(x * 2)))?;
+        let instance = double_module().instantiate(&mut store, Some(imports))?;
+        assert_eq!(instance.exported_func::<i32, i32>(&store, "run")?.call(&mut store, 2)?, 4);
+
+        // values returned by the fallback are type-checked
+        let mut imports = Imports::new();
+        imports.fallback(|_, _, _| Some(Extern::global(WasmValue::I32(0), false)));
+        let res = double_module().instantiate(&mut store, Some(imports));
+        assert!(matches!(res, Err(Error::Linker(LinkingError::IncompatibleImportType { .. }))));
+        Ok(())
+    }
+
+    // a module with `inc` and `dec` callbacks in an exported table, and functions returning them as funcrefs
+    fn callback_module() -> Module {
+        let func = |params: &[ValType], results: &[ValType], instructions: &[Instruction]| WasmFunction {
+            instructions: instructions.into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: FuncType { params: params.into(), results: results.into() },
+        };
+
+        let (i32, func_ref) = (ValType::I32, ValType::RefFunc);
+        let funcs = vec![
+            func(
+                &[i32],
+                &[i32],
+                &[Instruction::LocalGet(0), Instruction::I32Const(1), Instruction::I32Add, Instruction::EndFunc],
+            ),
+            func(
+                &[i32],
+                &[i32],
+                &[Instruction::LocalGet(0), Instruction::I32Const(1), Instruction::I32Sub, Instruction::EndFunc],
+            ),
+            func(&[i32], &[func_ref], &[Instruction::LocalGet(0), Instruction::TableGet(0), Instruction::EndFunc]),
+            func(&[], &[func_ref], &[Instruction::RefFunc(1), Instruction::EndFunc]),
+        ];
+
+        Module::from(TinyWasmModule {
+            func_types: funcs.iter().map(|f| f.ty.clone()).collect(),
+            funcs: funcs.into_boxed_slice(),
+            table_types: vec![TableType::new(func_ref, 2, None)].into(),
+            elements: vec![Element {
+                kind: ElementKind::Active { table: 0, offset: ConstInstruction::I32Const(0) },
+                items: vec![ElementItem::Func(0), ElementItem::Func(1)].into(),
+                range: 0..0,
+                ty: func_ref,
+            }]
+            .into(),
+            exports: vec![
+                Export { name: "callbacks".into(), kind: ExternalKind::Table, index: 0 },
+                Export { name: "callback".into(), kind: ExternalKind::Func, index: 2 },
+                Export { name: "dec".into(), kind: ExternalKind::Func, index: 3 },
+            ]
+            .into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_call_func_ref() -> Result<()> {
+        let mut store = Store::default();
+        let instance = callback_module().instantiate(&mut store, None)?;
+
+        // returned from a function
+        let callback = instance.exported_func_untyped(&store, "callback")?;
+        let inc = callback.call(&mut store, &[WasmValue::I32(0)])?;
+        let inc = store.get_func_ref(&inc[0])?.expect("inc is not null");
+        assert_eq!(inc.call(&mut store, &[WasmValue::I32(41)])?, vec![WasmValue::I32(42)]);
+
+        let dec = instance.exported_func_untyped(&store, "dec")?.call(&mut store, &[])?;
+        let dec = store.get_func_ref(&dec[0])?.expect("dec is not null");
+        assert_eq!(dec.call(&mut store, &[WasmValue::I32(43)])?, vec![WasmValue::I32(42)]);
+
+        // read from a table
+        let table = instance.exported_table(&store, "callbacks")?;
+        assert_eq!((table.size()?, table.element_type()?), (2, ValType::RefFunc));
+        let dec = store.get_func_ref(&table.get(1)?)?.expect("dec is not null");
+        assert_eq!(dec.call(&mut store, &[WasmValue::I32(1)])?, vec![WasmValue::I32(0)]);
+        assert!(table.get(2).is_err());
+
+        assert!(store.get_func_ref(&WasmValue::RefNull(ValType::RefFunc))?.is_none());
+        assert!(store.get_func_ref(&WasmValue::I32(0)).is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,117 @@
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use serde_json::{Number, Value};
+use tinywasm_types::{ValType, WasmValue};
+
+use crate::{Error, ModuleInstance, Result, Store};
+
+impl ModuleInstance {
+    /// Call an exported function with JSON arguments, converting them using the function's signature
+    ///
+    /// Integers can be passed as numbers or strings, e.g. `"18446744073709551615"` for 64-bit values
+    /// that don't fit in a JSON number. Both signed and unsigned values are accepted and reinterpreted
+    /// as the wasm type. Floats can also be passed as strings like `"NaN"` or `"-inf"`, and references
+    /// only as `null`. Results are returned the same way, with integers always signed and non-finite
+    /// floats as strings. References are returned as their address, or `null`.
+    pub fn invoke_dynamic(&self, store: &mut Store, name: &str, args: &[Value]) -> Result<Vec<Value>> {
+        let func = self.exported_func_untyped(store, name)?;
+        let params = &func.ty.params;
+        if params.len() != args.len() {
+            return Err(Error::Other(format!("param count mismatch: expected {}, got {}", params.len(), args.len())));
+        }
+
+        let args = params.iter().zip(args).enumerate().map(|(i, (ty, arg))| {
+            from_json(arg, *ty).ok_or_else(|| {
+                Error::Other(format!("invalid argument {} of {}: expected {:?}, got {}", i, name, ty, arg))
+            })
+        });
+        let args = args.collect::<Result<Vec<_>>>()?;
+
+        Ok(func.call(store, &args)?.iter().map(to_json).collect())
+    }
+}
+
+fn from_json(value: &Value, ty: ValType) -> Option<WasmValue> {
+    Some(match (ty, value) {
+        (ValType::I32, Value::Number(n)) => {
+            let i = n.as_i64()?;
+            WasmValue::I32(i32::try_from(i).ok().or_else(|| u32::try_from(i).ok().map(|u| u as i32))?)
+        }
+        (ValType::I32, Value::String(s)) => {
+            WasmValue::I32(s.parse().ok().or_else(|| s.parse::<u32>().ok().map(|u| u as i32))?)
+        }
+        (ValType::I64, Value::Number(n)) => WasmValue::I64(n.as_i64().or_else(|| n.as_u64().map(|u| u as i64))?),
+        (ValType::I64, Value::String(s)) => {
+            WasmValue::I64(s.parse().ok().or_else(|| s.parse::<u64>().ok().map(|u| u as i64))?)
+        }
+        (ValType::F32, Value::Number(n)) => WasmValue::F32(n.as_f64()? as f32),
+        (ValType::F32, Value::String(s)) => WasmValue::F32(s.parse().ok()?),
+        (ValType::F64, Value::Number(n)) => WasmValue::F64(n.as_f64()?),
+        (ValType::F64, Value::String(s)) => WasmValue::F64(s.parse().ok()?),
+        (ValType::RefFunc | ValType::RefExtern, Value::Null) => WasmValue::RefNull(ty),
+        _ => return None,
+    })
+}
+
+fn to_json(value: &WasmValue) -> Value {
+    match value {
+        WasmValue::I32(i) => Value::from(*i),
+        WasmValue::I64(i) => Value::from(*i),
+        WasmValue::F32(f) => float_to_json(*f as f64),
+        WasmValue::F64(f) => float_to_json(*f),
+        WasmValue::RefExtern(addr) | WasmValue::RefFunc(addr) => Value::from(*addr),
+        WasmValue::RefNull(_) => Value::Null,
+    }
+}
+
+fn float_to_json(f: f64) -> Value {
+    match Number::from_f64(f) {
+        Some(n) => Value::Number(n),
+        None => Value::String(f.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_invoke_dynamic() -> Result<()> {
+        // returns its params
+        let types: Box<[ValType]> = [ValType::I32, ValType::I64, ValType::F64].into();
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: types.clone(), results: types });
+        let echo = builder.add_function(
+            ty,
+            [],
+            [Instruction::LocalGet(0), Instruction::LocalGet(1), Instruction::LocalGet(2), Instruction::EndFunc],
+        );
+        builder.add_export("echo", ExternalKind::Func, echo);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+
+        let args = [Value::from(u32::MAX), Value::from("18446744073709551615"), Value::from("-inf")];
+        let results = instance.invoke_dynamic(&mut store, "echo", &args)?;
+        assert_eq!(results, vec![Value::from(-1), Value::from(-1i64), Value::from(f64::NEG_INFINITY.to_string())]);
+
+        let args = [Value::from(-2), Value::from(3), Value::from(0.5)];
+        let results = instance.invoke_dynamic(&mut store, "echo", &args)?;
+        assert_eq!(results, args.to_vec());
+
+        assert!(instance.invoke_dynamic(&mut store, "echo", &args[..2]).is_err());
+        assert!(instance
+            .invoke_dynamic(&mut store, "echo", &[Value::from(0.5), args[1].clone(), args[2].clone()])
+            .is_err());
+        assert!(instance
+            .invoke_dynamic(&mut store, "echo", &[Value::from(i64::MAX), args[1].clone(), args[2].clone()])
+            .is_err());
+        Ok(())
+    }
+}
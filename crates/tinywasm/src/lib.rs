@@ -22,8 +22,41 @@
 //!  Enables the `tinywasm-parser` crate. This is enabled by default.
 //!- **`archive`**\
 //!  Enables pre-parsing of archives. This is enabled by default.
+//!- **`cache`**\
+//!  Enables [`Module::parse_bytes_cached`] to skip parsing modules that are in a [`ModuleCache`].
+//!  Enables `parser` and `archive`.
+//!- **`compression`**\
+//!  Enables loading and creating archives compressed with lz4 or DEFLATE. Requires `archive`.
+//!- **`mmap`**\
+//!  Adds `Module::from_twasm_file_mapped` to memory-map archive files instead of reading them. Enables `std`, `archive` and `unsafe`.
+//!  The file must not be modified while it is being loaded.
 //!- **`unsafe`**\
 //!  Uses `unsafe` code to improve performance, particularly in Memory access
+//!- **`sync`**\
+//!  Makes [`Store`] and all handles into it `Send` and `Sync` by using `Arc` and locks instead of `Rc` and `RefCell`.
+//!  Host functions then also need to be `Send + Sync`. Requires `std` or `critical-section`.
+//!- **`critical-section`**\
+//!  Implements the cells used by `sync` using the [`critical-section`](https://docs.rs/critical-section) crate
+//!  instead of `std` locks, e.g. to share a [`Store`] with interrupt handlers on `no_std` targets.
+//!  `Arc` is still used, so the target needs atomic pointer operations.
+//!- **`profiler`**\
+//!  Enables the sampling [`Profiler`] for guest code. Requires `std`.
+//!- **`opcode-counts`**\
+//!  Enables [`Store::enable_opcode_counts`] to count the executed instructions by opcode and function,
+//!  and the sequences of opcodes executed in a row, e.g. to find out which instructions dominate a
+//!  workload and which of the parser's fusion rules to enable for it.
+//!- **`no-float`**\
+//!  Compiles out the floating-point instructions, e.g. for integer-only embedded targets.
+//!  Instantiating modules that use `f32` or `f64` values fails with [`Error::UnsupportedFeature`].
+//!- **`opt-size`**\
+//!  Optimizes the interpreter for code size instead of speed by sharing the handlers of similar instructions,
+//!  e.g. for microcontrollers with little flash. Combine with `no-float` and `opt-level = "z"` for the smallest builds.
+//!- **`parallel`**\
+//!  Translates the function bodies in [`Module::parse_bytes`] on the [`rayon`](https://docs.rs/rayon) thread pool,
+//!  which shortens loading large modules on multi-core hosts. [`Module::parse_bytes_async`] only validates the module
+//!  and translates its functions in the background, or when they are first called. Requires `std`.
+//!- **`json`**\
+//!  Enables [`ModuleInstance::invoke_dynamic`] to call exports with [`serde_json`](https://docs.rs/serde_json) values.
 //!
 //! With all these features disabled, TinyWasm only depends on `core`, `alloc` and `libm`.
 //! By disabling `std`, you can use TinyWasm in `no_std` environments. This requires
@@ -94,21 +127,65 @@ pub(crate) mod log {
 
 mod error;
 pub use {
+    asyncify::{Asyncify, AsyncifyResult},
+    backtrace::{Backtrace, BacktraceFrame},
+    budget::HostBudget,
+    bundle::HostBundle,
+    checkpoint::CHECKPOINT_MODULE,
+    coverage::{BlockCoverage, Coverage, FunctionCoverage, SourceLocation},
     error::*,
     func::{FuncHandle, FuncHandleTyped},
     imports::*,
-    instance::ModuleInstance,
+    inspect::{Hexdump, MemoryChange, MemoryRegion},
+    instance::{GlobalsSnapshot, InstancePre, ModuleInstance},
+    lockstep::{MeteredCall, Slice},
+    metering::METERING_MODULE,
     module::Module,
     reference::*,
+    snapshot::{InstanceSnapshot, SnapshotDelta},
     store::*,
+    suspend::SuspendedCall,
+    sync::{ExternObject, MaybeSendSync},
 };
 
+mod assemblyscript;
+mod asyncify;
+mod backtrace;
+mod budget;
+mod bundle;
+mod checkpoint;
+mod coverage;
+mod emscripten;
 mod func;
 mod imports;
+mod inspect;
 mod instance;
+mod lockstep;
+mod metering;
 mod module;
 mod reference;
+mod snapshot;
 mod store;
+mod suspend;
+mod sync;
+
+#[cfg(feature = "json")]
+mod json;
+
+#[cfg(feature = "cache")]
+mod cache;
+#[cfg(feature = "cache")]
+pub use cache::*;
+
+#[cfg(feature = "profiler")]
+mod profiler;
+#[cfg(feature = "profiler")]
+pub use profiler::*;
+
+#[cfg(feature = "opcode-counts")]
+mod opcode_counts;
+#[cfg(feature = "opcode-counts")]
+pub use opcode_counts::{FunctionOpcodeCounts, FusionCandidate, OpcodeCounts};
 
 /// Runtime for executing WebAssembly modules.
 pub mod runtime;
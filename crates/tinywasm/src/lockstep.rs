@@ -0,0 +1,323 @@
+use alloc::vec::Vec;
+use tinywasm_types::WasmValue;
+
+use crate::runtime::{RawWasmValue, Stack};
+use crate::sync::{Rc, RefCell};
+use crate::{Error, FuncContext, FuncHandle, Function, Result, Store, StoreEvent, Trap};
+
+/// A call that runs in slices of a fixed number of instructions, see [`FuncHandle::call_metered`]
+///
+/// Every executed instruction costs one unit of fuel, like with [`Store::set_fuel`], and host
+/// functions can consume more using [`Store::consume_fuel`]. Since the count only depends on the
+/// executed code, running the same guests with the same budgets always stops them at the same
+/// instructions, which makes it possible to advance many instances in lockstep.
+#[derive(Debug)]
+pub struct MeteredCall {
+    pub(crate) func: FuncHandle,
+    pub(crate) state: State,
+    pub(crate) consumed: u64,
+    // where the stack goes if the call is dropped before it finished, see `Store::take_unused_stack`
+    pub(crate) dropped_stacks: Rc<RefCell<Vec<Stack>>>,
+}
+
+impl Drop for MeteredCall {
+    fn drop(&mut self) {
+        if let State::Wasm(stack) = core::mem::replace(&mut self.state, State::Done) {
+            self.dropped_stacks.borrow_mut().push(stack);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum State {
+    Wasm(Stack),
+    Host(Vec<WasmValue>),
+    Done,
+}
+
+/// The result of running a slice of a [`MeteredCall`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Slice {
+    /// The budget was used up before the function returned
+    Paused {
+        /// The fuel consumed by this slice, the whole budget unless a host function changed the fuel
+        consumed: u64,
+    },
+    /// The function returned
+    Finished {
+        /// The fuel consumed by this slice
+        consumed: u64,
+        /// The values returned by the function
+        results: Vec<WasmValue>,
+    },
+}
+
+impl Slice {
+    /// The fuel consumed by this slice
+    pub fn consumed(&self) -> u64 {
+        match self {
+            Self::Paused { consumed } | Self::Finished { consumed, .. } => *consumed,
+        }
+    }
+}
+
+impl FuncHandle {
+    /// Prepare a call that can be run in slices of a fixed number of instructions, see [`MeteredCall`]
+    ///
+    /// Nothing is executed until [`MeteredCall::run`] is called.
+    pub fn call_metered(&self, store: &mut Store, params: &[WasmValue]) -> Result<MeteredCall> {
+        self.check_params(params)?;
+
+        store.translate_func(self.addr)?;
+        let func_inst = store.get_func(self.addr as usize)?;
+        let state = match &func_inst.func {
+            Function::Host(_) => State::Host(params.to_vec()),
+            Function::Wasm(wasm_func) => {
+                let params = params.iter().map(|v| RawWasmValue::from(*v));
+                State::Wasm(store.take_stack(wasm_func.clone(), self.addr, func_inst.owner, params))
+            }
+        };
+
+        Ok(MeteredCall { func: self.clone(), state, consumed: 0, dropped_stacks: store.dropped_stacks.clone() })
+    }
+}
+
+impl MeteredCall {
+    /// Run the call until it returns or `budget` units of fuel have been consumed
+    ///
+    /// If the store has its own fuel limit, the slice is also limited by the remaining fuel,
+    /// and the fuel it consumes is subtracted from it. Traps end the call, and running it again fails.
+    ///
+    /// Host functions calling [`Store::set_fuel`] during the slice replace the fuel left for the rest of it.
+    /// The slice still counts the fuel it actually consumed, which can exceed `budget` if the fuel was raised,
+    /// and doesn't count anything run after metering was disabled with `None`. Afterwards, the store's fuel is
+    /// what it was before the slice minus the consumed fuel, or zero if that is more than it had.
+    pub fn run(&mut self, store: &mut Store, budget: u64) -> Result<Slice> {
+        let fuel = store.fuel();
+        let budget = fuel.map_or(budget, |fuel| fuel.min(budget));
+
+        store.set_fuel(Some(budget));
+        let start = store.fuel_consumed();
+        let res = self.run_inner(store);
+        let consumed = store.fuel_consumed() - start;
+        store.set_fuel(fuel.map(|fuel| fuel.saturating_sub(consumed)));
+
+        self.consumed += consumed;
+        match res? {
+            Some(results) => Ok(Slice::Finished { consumed, results }),
+            None => Ok(Slice::Paused { consumed }),
+        }
+    }
+
+    /// The fuel consumed by all slices so far
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Check if the call has finished, either by returning or by trapping
+    pub fn is_finished(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    // returns `None` if the call was paused
+    fn run_inner(&mut self, store: &mut Store) -> Result<Option<Vec<WasmValue>>> {
+        let func = &self.func;
+        match core::mem::replace(&mut self.state, State::Done) {
+            State::Done => Err(Error::Other("metered call has already finished".into())),
+            State::Host(params) => {
+                let Function::Host(host_func) = &store.get_func(func.addr as usize)?.func else {
+                    return Err(Error::Other("metered call has an invalid function".into()));
+                };
+
+                let host_func = host_func.clone();
+                host_func.call(FuncContext { store, module_addr: func.module_addr, frame: None }, &params).map(Some)
+            }
+            State::Wasm(mut stack) => {
+                let runtime = store.runtime();
+                match runtime.exec(store, &mut stack) {
+                    Ok(()) => {
+                        let results = stack.values.last_n(func.ty.results.len()).map(|results| {
+                            let results = results.iter().zip(func.ty.results.iter());
+                            results.map(|(v, ty)| v.attach_type(*ty)).collect()
+                        });
+                        store.give_stack(stack);
+                        results.map(Some)
+                    }
+
+                    // the instruction that ran out of fuel hasn't been executed, so it runs first when resuming
+                    Err(Error::Trap(Trap::OutOfFuel)) if stack.out_of_fuel => {
+                        stack.out_of_fuel = false;
+                        if let Some(cf) = stack.call_stack.top_mut() {
+                            cf.instr_ptr -= 1;
+                        }
+                        self.state = State::Wasm(stack);
+                        Ok(None)
+                    }
+
+                    Err(error) => {
+                        if let Error::Trap(trap) = &error {
+                            store.record_backtrace(&stack);
+                            store.emit(StoreEvent::Trap(trap));
+                        }
+                        store.give_stack(stack);
+                        Err(error)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    // counts down from its param in a loop and returns the number of iterations
+    fn countdown() -> Module {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        let func = builder.add_function(
+            ty,
+            [ValType::I32],
+            [
+                Instruction::Loop(BlockArgsPacked::EMPTY, 10),
+                Instruction::LocalGet(1),
+                Instruction::I32Const(1),
+                Instruction::I32Add,
+                Instruction::LocalSet(1),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::LocalTee(0),
+                Instruction::BrIf(0),
+                Instruction::EndBlockFrame,
+                Instruction::LocalGet(1),
+                Instruction::EndFunc,
+            ],
+        );
+        builder.add_export("countdown", ExternalKind::Func, func);
+        Module::from(builder.finish().expect("valid module"))
+    }
+
+    #[test]
+    fn test_metered_call() -> Result<()> {
+        let mut store = Store::default();
+        let instance = countdown().instantiate(&mut store, None)?;
+        let func = instance.exported_func_untyped(&store, "countdown")?;
+
+        // the same call, once in slices of 7 instructions and once at once
+        let mut call = func.call_metered(&mut store, &[WasmValue::I32(10)])?;
+        let mut slices = Vec::new();
+        let results = loop {
+            match call.run(&mut store, 7)? {
+                Slice::Paused { consumed } => slices.push(consumed),
+                Slice::Finished { consumed, results } => {
+                    slices.push(consumed);
+                    break results;
+                }
+            }
+        };
+
+        let mut once = func.call_metered(&mut store, &[WasmValue::I32(10)])?;
+        let Slice::Finished { consumed, results: expected } = once.run(&mut store, u64::MAX)? else {
+            panic!("call should finish");
+        };
+
+        assert_eq!((results, call.consumed()), (expected, consumed));
+        assert!(slices[..slices.len() - 1].iter().all(|c| *c == 7));
+        assert!(call.is_finished() && call.run(&mut store, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_metered_call_fuel() -> Result<()> {
+        let mut store = Store::default();
+        let instance = countdown().instantiate(&mut store, None)?;
+        let func = instance.exported_func_untyped(&store, "countdown")?;
+
+        // the store's fuel limits the slice and is charged for it
+        store.set_fuel(Some(5));
+        let mut call = func.call_metered(&mut store, &[WasmValue::I32(10)])?;
+        assert_eq!(call.run(&mut store, 10)?, Slice::Paused { consumed: 5 });
+        assert_eq!(store.fuel(), Some(0));
+
+        store.set_fuel(None);
+        assert_eq!(call.run(&mut store, 0)?.consumed(), 0);
+        assert!(
+            matches!(call.run(&mut store, u64::MAX)?, Slice::Finished { results, .. } if results == vec![WasmValue::I32(10)])
+        );
+        assert_eq!(store.fuel(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_metered_call_set_fuel() -> Result<()> {
+        // calls `set_fuel(fuel)` (with -1 for `None`), then counts down from `n` like `countdown`
+        let mut builder = ModuleBuilder::new();
+        let set_fuel_ty = builder.add_type(FuncType { params: [ValType::I64].into(), results: [].into() });
+        builder.add_import("env", "set_fuel", ImportKind::Function(set_fuel_ty));
+        let ty =
+            builder.add_type(FuncType { params: [ValType::I32, ValType::I64].into(), results: [ValType::I32].into() });
+        let func = builder.add_function(
+            ty,
+            [ValType::I32],
+            [
+                Instruction::LocalGet(1),
+                Instruction::Call(0),
+                Instruction::Loop(BlockArgsPacked::EMPTY, 10),
+                Instruction::LocalGet(2),
+                Instruction::I32Const(1),
+                Instruction::I32Add,
+                Instruction::LocalSet(2),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::LocalTee(0),
+                Instruction::BrIf(0),
+                Instruction::EndBlockFrame,
+                Instruction::LocalGet(2),
+                Instruction::EndFunc,
+            ],
+        );
+        builder.add_export("run", ExternalKind::Func, func);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let mut imports = crate::Imports::new();
+        let set_fuel = crate::Extern::typed_func(|mut ctx, fuel: i64| {
+            ctx.store_mut().set_fuel(u64::try_from(fuel).ok());
+            Ok(())
+        });
+        imports.define("env", "set_fuel", set_fuel)?;
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, Some(imports))?;
+        let func = instance.exported_func_untyped(&store, "run")?;
+        let params = [WasmValue::I32(10), WasmValue::I64(1000)];
+
+        let mut call = func.call_metered(&mut store, &params)?;
+        let Slice::Finished { consumed: expected, .. } = call.run(&mut store, u64::MAX)? else {
+            panic!("call should finish");
+        };
+
+        // raising the fuel in a slice lets it consume more than its budget
+        let mut call = func.call_metered(&mut store, &params)?;
+        assert!(matches!(call.run(&mut store, 5)?, Slice::Finished { consumed, .. } if consumed == expected));
+        assert!(expected > 5 && store.fuel().is_none());
+
+        // the store is charged for all of it, without going below zero
+        store.set_fuel(Some(50));
+        let mut call = func.call_metered(&mut store, &params)?;
+        assert_eq!(call.run(&mut store, 5)?.consumed(), expected);
+        assert_eq!(store.fuel(), Some(0));
+
+        // nothing is counted once metering is disabled
+        store.set_fuel(Some(50));
+        let mut call = func.call_metered(&mut store, &[WasmValue::I32(10), WasmValue::I64(-1)])?;
+        let consumed = call.run(&mut store, 5)?.consumed();
+        assert!(consumed <= 5 && call.is_finished());
+        assert_eq!(store.fuel(), Some(50 - consumed));
+        Ok(())
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{Extern, Imports, Result};
+
+/// The name of the host module defined by [`Imports::define_metering`]
+pub const METERING_MODULE: &str = "tinywasm_metering";
+
+impl Imports {
+    /// Let the guest inspect and consume the store's fuel (see [`crate::Store::set_fuel`])
+    ///
+    /// This defines two functions in the [`METERING_MODULE`] module:
+    /// * `gas_remaining() -> i64`: the remaining fuel, or `-1` if metering is disabled
+    /// * `gas_consume(i64)`: consume fuel, trapping with [`crate::Trap::OutOfFuel`] if not enough is left.
+    ///   The amount is interpreted as unsigned, so negative amounts always run out of fuel.
+    ///
+    /// ```wat
+    /// (import "tinywasm_metering" "gas_remaining" (func $gas_remaining (result i64)))
+    /// (import "tinywasm_metering" "gas_consume" (func $gas_consume (param i64)))
+    /// ```
+    pub fn define_metering(&mut self) -> Result<&mut Self> {
+        let remaining =
+            Extern::typed_func(|ctx, ()| Ok(ctx.store().fuel().map_or(-1, |fuel| fuel.min(i64::MAX as u64) as i64)));
+        let consume = Extern::typed_func(|mut ctx, amount: i64| ctx.store_mut().consume_fuel(amount as u64));
+
+        self.define(METERING_MODULE, "gas_remaining", remaining)?;
+        self.define(METERING_MODULE, "gas_consume", consume)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Module, Store, Trap};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    // spends `n` fuel through gas_consume and returns the remaining fuel
+    fn metered_module() -> Module {
+        let remaining_ty = FuncType { params: [].into(), results: [ValType::I64].into() };
+        let consume_ty = FuncType { params: [ValType::I64].into(), results: [].into() };
+        let run = WasmFunction {
+            instructions: vec![
+                Instruction::LocalGet(0),
+                Instruction::Call(1),
+                Instruction::Call(0),
+                Instruction::EndFunc,
+            ]
+            .into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: FuncType { params: [ValType::I64].into(), results: [ValType::I64].into() },
+        };
+
+        let import = |name: &str, ty| Import {
+            module: METERING_MODULE.into(),
+            name: name.into(),
+            kind: ImportKind::Function(ty),
+        };
+        Module::from(TinyWasmModule {
+            func_types: vec![remaining_ty, consume_ty, run.ty.clone()].into(),
+            funcs: vec![run].into(),
+            imports: vec![import("gas_remaining", 0), import("gas_consume", 1)].into(),
+            exports: vec![Export { name: "run".into(), kind: ExternalKind::Func, index: 2 }].into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_metering() -> Result<()> {
+        let mut store = Store::default();
+        let mut imports = Imports::new();
+        imports.define_metering()?;
+        let instance = metered_module().instantiate(&mut store, Some(imports))?;
+        let run = instance.exported_func::<i64, i64>(&store, "run")?;
+
+        assert_eq!(run.call(&mut store, 10)?, -1);
+
+        // LocalGet, Call and Call are charged before gas_remaining reads the fuel
+        store.set_fuel(Some(100));
+        assert_eq!(run.call(&mut store, 10)?, 100 - 10 - 3);
+        assert_eq!(store.fuel(), Some(100 - 10 - 4));
+
+        let res = run.call(&mut store, 1000);
+        assert!(matches!(res, Err(Error::Trap(Trap::OutOfFuel))));
+        assert_eq!(store.fuel(), Some(0));
+
+        store.set_fuel(Some(2));
+        assert!(matches!(run.call(&mut store, 0), Err(Error::Trap(Trap::OutOfFuel))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuel_set_by_host() -> Result<()> {
+        // `run` calls `start_metering`, which sets the fuel, and then `inner`
+        let mut builder = ModuleBuilder::new();
+        let empty = builder.add_type(FuncType::default());
+        let start = builder.add_import("env", "start_metering", ImportKind::Function(empty));
+        let inner = builder.add_function(empty, [], [Instruction::EndFunc]);
+        let run =
+            builder.add_function(empty, [], [Instruction::Call(start), Instruction::Call(inner), Instruction::EndFunc]);
+        builder.add_export("run", ExternalKind::Func, run);
+
+        let mut imports = Imports::new();
+        imports.define(
+            "env",
+            "start_metering",
+            Extern::typed_func(|mut ctx, ()| {
+                ctx.store_mut().set_fuel(Some(10));
+                Ok(())
+            }),
+        )?;
+
+        // the call started without fuel, so it is metered from the call to `inner` on
+        let mut store = Store::default();
+        let module = Module::from(builder.finish().expect("valid module"));
+        let instance = module.instantiate(&mut store, Some(imports))?;
+        instance.exported_func::<(), ()>(&store, "run")?.call(&mut store, ())?;
+        assert_eq!(store.fuel(), Some(10 - 2));
+        Ok(())
+    }
+}
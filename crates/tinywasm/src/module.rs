@@ -1,23 +1,56 @@
-use crate::{Imports, ModuleInstance, Result, Store};
-use tinywasm_types::TinyWasmModule;
+use crate::sync::Rc;
+use crate::{Imports, InstancePre, ModuleInstance, Result, Store};
+use alloc::{boxed::Box, collections::BTreeMap};
+use tinywasm_types::{Export, ModuleFrontend, TinyWasmModule};
 
-#[derive(Debug)]
+/// The position of each export in [`TinyWasmModule::exports`], by name
+///
+/// Built once per module and shared with its instances, so looking up an export doesn't scan all of them.
+pub(crate) type ExportIndex = BTreeMap<Box<str>, usize>;
+
+pub(crate) fn index_exports(exports: &[Export]) -> Rc<ExportIndex> {
+    Rc::new(exports.iter().enumerate().map(|(i, export)| (export.name.clone(), i)).collect())
+}
+
+#[derive(Debug, Clone)]
 /// A WebAssembly Module
 ///
+/// Modules are independent of any [`Store`], so they can be cloned (or shared with the `sync` feature)
+/// and instantiated in multiple stores, e.g. one store per thread.
+///
 /// See <https://webassembly.github.io/spec/core/syntax/modules.html#syntax-module>
 pub struct Module {
     pub(crate) data: TinyWasmModule,
+    pub(crate) exports: Rc<ExportIndex>,
+    // the function bodies of a module parsed with `Module::parse_bytes_async`, `data.funcs` has no instructions yet
+    #[cfg(feature = "parallel")]
+    pub(crate) lazy: Option<alloc::sync::Arc<tinywasm_parser::LazyFunctions>>,
 }
 
 impl From<&TinyWasmModule> for Module {
     fn from(data: &TinyWasmModule) -> Self {
-        Self { data: data.clone() }
+        data.clone().into()
     }
 }
 
 impl From<TinyWasmModule> for Module {
     fn from(data: TinyWasmModule) -> Self {
-        Self { data }
+        Self {
+            exports: index_exports(&data.exports),
+            data,
+            #[cfg(feature = "parallel")]
+            lazy: None,
+        }
+    }
+}
+
+#[cfg(feature = "wasm-encoder")]
+impl TryFrom<&tinywasm_parser::wasm_encoder::Module> for Module {
+    type Error = crate::Error;
+
+    fn try_from(module: &tinywasm_parser::wasm_encoder::Module) -> Result<Self> {
+        let parser = tinywasm_parser::Parser::new();
+        Ok(parser.parse_wasm_encoder_module(module)?.into())
     }
 }
 
@@ -30,6 +63,18 @@ impl Module {
         Ok(data.into())
     }
 
+    #[cfg(feature = "parallel")]
+    /// Parse a module from bytes, returning once it has been validated. Requires the `parallel` feature.
+    ///
+    /// The function bodies are translated on the `rayon` thread pool in the background. A function called
+    /// before its body has been translated is translated by the caller, so the first call may take longer.
+    /// Unlike [`Module::parse_bytes`], calls of small functions are not inlined.
+    pub fn parse_bytes_async(wasm: impl Into<alloc::sync::Arc<[u8]>>) -> Result<Self> {
+        let parser = tinywasm_parser::Parser::new();
+        let (data, lazy) = parser.parse_module_bytes_lazy(wasm)?;
+        Ok(Self { lazy: Some(lazy), ..data.into() })
+    }
+
     #[cfg(all(feature = "parser", feature = "std"))]
     /// Parse a module from a file. Requires `parser` and `std` features.
     pub fn parse_file(path: impl AsRef<crate::std::path::Path> + Clone) -> Result<Self> {
@@ -46,10 +91,62 @@ impl Module {
         Ok(data.into())
     }
 
+    #[cfg(all(feature = "archive", feature = "std"))]
+    /// Load a module from a `.twasm` archive file, see [`TinyWasmModule::serialize_twasm`]. Requires `archive` and `std` features.
+    ///
+    /// The archive is read into memory and checked using [`TinyWasmModule::from_twasm_unaligned_verified`].
+    /// With the `mmap` feature, `Module::from_twasm_file_mapped` checks it in place instead.
+    pub fn from_twasm_file(path: impl AsRef<crate::std::path::Path>) -> Result<Self> {
+        let archive = crate::std::fs::read(path)?;
+        Ok(TinyWasmModule::from_twasm_unaligned_verified(&archive)?.into())
+    }
+
+    #[cfg(feature = "mmap")]
+    /// Load a module from a memory-mapped `.twasm` archive file. Requires the `mmap` feature.
+    ///
+    /// Like [`Module::from_twasm_file`], but the archive is checked in place instead of being read
+    /// into memory first. The module doesn't reference the mapping, so it is unmapped before this returns.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this or any other process, until this returns.
+    /// Otherwise the archive can change while it is checked and read, which is undefined behavior.
+    #[allow(unsafe_code)]
+    pub unsafe fn from_twasm_file_mapped(path: impl AsRef<crate::std::path::Path>) -> Result<Self> {
+        let file = crate::std::fs::File::open(path)?;
+
+        // SAFETY: the caller guarantees that the file isn't modified while it is mapped
+        let archive = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(TinyWasmModule::from_twasm_verified(&archive)?.into())
+    }
+
+    /// Parse a module using a custom [`ModuleFrontend`]
+    ///
+    /// This also works without the `parser` feature, e.g. for frontends decoding a different format.
+    pub fn parse_with<F: ModuleFrontend>(frontend: &F, bytes: &[u8]) -> Result<Self>
+    where
+        F::Error: Into<crate::Error>,
+    {
+        let data = frontend.parse_module_bytes(bytes).map_err(Into::into)?;
+        Ok(data.into())
+    }
+
+    /// The features the module was compiled with, from its `target_features` custom section
+    ///
+    /// If the module uses a feature tinywasm doesn't support, parsing it fails with
+    /// [`crate::ParseError::UnsupportedFeature`] naming the feature.
+    pub fn required_features(&self) -> impl Iterator<Item = &str> {
+        self.data.target_features.iter().map(|feature| &**feature)
+    }
+
     /// Instantiate the module in the given store
     ///
     /// Runs the start function if it exists
-    /// If you want to run the start function yourself, use `ModuleInstance::instantiate`
+    /// If you want to run the start function yourself, use `ModuleInstance::instantiate`,
+    /// or `ModuleInstance::instantiate_deferred` to also defer initializing the active data and element segments
+    ///
+    /// The imports are resolved and type-checked on every call. To instantiate the same module
+    /// with the same imports many times, resolve them once with [`Module::instantiate_pre`].
     ///
     /// See <https://webassembly.github.io/spec/core/exec/modules.html#exec-instantiation>
     pub fn instantiate(self, store: &mut Store, imports: Option<Imports>) -> Result<ModuleInstance> {
@@ -57,4 +154,262 @@ impl Module {
         let _ = instance.start(store)?;
         Ok(instance)
     }
+
+    /// Resolve and type-check the module's imports once, for instantiating it many times
+    ///
+    /// The resulting [`InstancePre`] can only be instantiated in the given store.
+    /// Imports that are linked to values already in the store are shared between all
+    /// instances, while [`crate::Extern`] values are added to the store again for every instance.
+    pub fn instantiate_pre(self, store: &Store, imports: Option<Imports>) -> Result<InstancePre> {
+        InstancePre::new(store, self, imports)
+    }
+}
+
+impl Module {
+    /// Check that the module only uses features supported by this build
+    ///
+    /// With the `no-float` feature, modules using floating-point types or instructions are rejected.
+    pub(crate) fn check_supported(&self) -> Result<()> {
+        #[cfg(feature = "no-float")]
+        if let Some(location) = uses_floats(&self.translated()?.data) {
+            return Err(crate::Error::UnsupportedFeature(alloc::format!(
+                "floating-point {} (tinywasm was built with the `no-float` feature)",
+                location
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The module with all function bodies translated, waiting for the ones still being translated
+    #[cfg(feature = "no-float")]
+    pub(crate) fn translated(&self) -> Result<alloc::borrow::Cow<'_, Self>> {
+        #[cfg(feature = "parallel")]
+        if let Some(lazy) = &self.lazy {
+            let mut module = Self { lazy: None, ..self.clone() };
+            let funcs = (0..lazy.len()).map(|idx| lazy.get(idx).cloned()).collect::<Result<_, _>>()?;
+            module.data.funcs = funcs;
+            return Ok(alloc::borrow::Cow::Owned(module));
+        }
+
+        Ok(alloc::borrow::Cow::Borrowed(self))
+    }
+}
+
+// Find the first use of a floating-point type or instruction in the module
+#[cfg(feature = "no-float")]
+fn uses_floats(module: &TinyWasmModule) -> Option<alloc::string::String> {
+    use alloc::format;
+    use tinywasm_types::{BlockArgs, ImportKind, Instruction::*, ValType};
+
+    let is_float = |ty: &ValType| matches!(ty, ValType::F32 | ValType::F64);
+    let is_float_block = |args: BlockArgs| matches!(args, BlockArgs::Type(ValType::F32 | ValType::F64));
+
+    if let Some(i) = module.func_types.iter().position(|ty| ty.params.iter().chain(ty.results.iter()).any(is_float)) {
+        return Some(format!("values in function type {}", i));
+    }
+
+    if let Some(i) = module.globals.iter().position(|global| is_float(&global.ty.ty)) {
+        return Some(format!("global {}", i));
+    }
+
+    for import in module.imports.iter() {
+        if matches!(&import.kind, ImportKind::Global(ty) if is_float(&ty.ty)) {
+            return Some(format!("global import {}.{}", import.module, import.name));
+        }
+    }
+
+    for (i, func) in module.funcs.iter().enumerate() {
+        if func.locals.iter().any(is_float) {
+            return Some(format!("locals in function {}", i));
+        }
+
+        let float_instr = |instr: &_| match instr {
+            Block(args, _) | Loop(args, _) | If(args, _) => args.try_unpack().is_some_and(is_float_block),
+            Select(Some(ty)) => is_float(ty),
+            F32Abs
+            | F32Add
+            | F32Ceil
+            | F32Const(_)
+            | F32ConvertI32S
+            | F32ConvertI32U
+            | F32ConvertI64S
+            | F32ConvertI64U
+            | F32Copysign
+            | F32DemoteF64
+            | F32Div
+            | F32Eq
+            | F32Floor
+            | F32Ge
+            | F32Gt
+            | F32Le
+            | F32Load { .. }
+            | F32LoadStatic { .. }
+            | F32Lt
+            | F32Max
+            | F32Min
+            | F32Mul
+            | F32Ne
+            | F32Nearest
+            | F32Neg
+            | F32ReinterpretI32
+            | F32Sqrt
+            | F32Store { .. }
+            | F32StoreStatic { .. }
+            | F32Sub
+            | F32Trunc
+            | F64Abs
+            | F64Add
+            | F64Ceil
+            | F64Const(_)
+            | F64ConvertI32S
+            | F64ConvertI32U
+            | F64ConvertI64S
+            | F64ConvertI64U
+            | F64Copysign
+            | F64Div
+            | F64Eq
+            | F64Floor
+            | F64Ge
+            | F64Gt
+            | F64Le
+            | F64Load { .. }
+            | F64LoadStatic { .. }
+            | F64Lt
+            | F64Max
+            | F64Min
+            | F64Mul
+            | F64Ne
+            | F64Nearest
+            | F64Neg
+            | F64PromoteF32
+            | F64ReinterpretI64
+            | F64Sqrt
+            | F64Store { .. }
+            | F64StoreStatic { .. }
+            | F64Sub
+            | F64Trunc
+            | I32ReinterpretF32
+            | I32TruncF32S
+            | I32TruncF32U
+            | I32TruncF64S
+            | I32TruncF64U
+            | I32TruncSatF32S
+            | I32TruncSatF32U
+            | I32TruncSatF64S
+            | I32TruncSatF64U
+            | I64ReinterpretF64
+            | I64TruncF32S
+            | I64TruncF32U
+            | I64TruncF64S
+            | I64TruncF64U
+            | I64TruncSatF32S
+            | I64TruncSatF32U
+            | I64TruncSatF64S
+            | I64TruncSatF64U => true,
+            _ => false,
+        };
+
+        if let Some(instr) = func.instructions.iter().position(float_instr) {
+            return Some(format!("instruction {:?} in function {}", func.instructions[instr], i));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+    use alloc::{boxed::Box, string::ToString};
+    use tinywasm_types::*;
+
+    // a frontend for a "format" consisting of a single i32 constant
+    struct ConstFrontend;
+
+    impl ModuleFrontend for ConstFrontend {
+        type Error = Error;
+
+        fn parse_module_bytes(&self, bytes: &[u8]) -> Result<TinyWasmModule> {
+            let value = bytes.try_into().map_err(|_| Error::Other("expected 4 bytes".into()))?;
+            let mut builder = ModuleBuilder::new();
+            let ty = builder.add_type(FuncType { params: Box::new([]), results: Box::new([ValType::I32]) });
+            let instructions = [Instruction::I32Const(i32::from_le_bytes(value)), Instruction::EndFunc];
+            let func = builder.add_function(ty, [], instructions);
+            builder.add_export("value", ExternalKind::Func, func);
+            builder.finish().map_err(|e| Error::Other(e.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_parse_with() -> Result<()> {
+        let module = Module::parse_with(&ConstFrontend, &42i32.to_le_bytes())?;
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        assert_eq!(instance.exported_func::<(), i32>(&store, "value")?.call(&mut store, ())?, 42);
+
+        assert!(Module::parse_with(&ConstFrontend, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "no-float")]
+    fn test_no_float() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I32]), results: Box::new([ValType::I32]) });
+        let instructions = [Instruction::LocalGet(0), Instruction::F32ConvertI32S, Instruction::I32TruncF32S];
+        builder.add_function(ty, [], [instructions.as_slice(), &[Instruction::EndFunc]].concat());
+        let module = Module::from(builder.finish().map_err(|e| Error::Other(e.to_string()))?);
+
+        let mut store = Store::default();
+        assert!(matches!(module.clone().instantiate(&mut store, None), Err(Error::UnsupportedFeature(_))));
+
+        let instance = Module::parse_with(&ConstFrontend, &1i32.to_le_bytes())?.instantiate(&mut store, None)?;
+        assert!(matches!(store.hot_swap(&instance, &module), Err(Error::UnsupportedFeature(_))));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_parse_bytes_async() -> Result<()> {
+        let wasm = include_bytes!("../../../examples/wasm/add.wasm");
+        let module = Module::parse_bytes_async(&wasm[..])?;
+        let mut store = Store::default();
+        let instance = module.clone().instantiate(&mut store, None)?;
+        assert_eq!(instance.exported_func::<(i32, i32), i32>(&store, "add")?.call(&mut store, (1, 2))?, 3);
+        assert_eq!(instance.exported_func::<(i64, i64), i64>(&store, "add_64")?.call(&mut store, (1, 2))?, 3);
+
+        // every instance translates its functions on their own
+        let instance = module.instantiate_pre(&store, None)?.instantiate(&mut store)?;
+        assert_eq!(instance.exported_func::<(i32, i32), i32>(&store, "add")?.call(&mut store, (2, 3))?, 5);
+        assert!(Module::parse_bytes_async(&wasm[..40]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "archive", feature = "std"))]
+    fn test_from_twasm_file() -> Result<()> {
+        let archive = Module::parse_with(&ConstFrontend, &7i32.to_le_bytes())?.data.serialize_twasm();
+        let path =
+            crate::std::env::temp_dir().join(alloc::format!("tinywasm-test-{}.twasm", crate::std::process::id()));
+        crate::std::fs::write(&path, &archive)?;
+
+        let module = Module::from_twasm_file(&path);
+        // SAFETY: the file is only removed once the module has been loaded
+        #[cfg(feature = "mmap")]
+        #[allow(unsafe_code)]
+        let mapped = unsafe { Module::from_twasm_file_mapped(&path) };
+        crate::std::fs::remove_file(&path)?;
+
+        let module = module?;
+        #[cfg(feature = "mmap")]
+        assert_eq!(mapped?.data, module.data);
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        assert_eq!(instance.exported_func::<(), i32>(&store, "value")?.call(&mut store, ())?, 7);
+        assert!(matches!(Module::from_twasm_file(&path), Err(Error::Io(_))));
+        Ok(())
+    }
 }
@@ -0,0 +1,354 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::fmt::{Display, Formatter};
+use tinywasm_types::{opcode, FuncAddr, ModuleInstanceAddr};
+
+use crate::{unlikely, Store};
+
+// the number of fusion candidates listed in reports
+const REPORTED_CANDIDATES: usize = 20;
+
+// the initial number of slots of the trigram table, which grows when half of them are used
+const TRIGRAM_SLOTS: usize = 1 << 12;
+
+#[derive(Debug)]
+pub(crate) struct OpcodeCounter {
+    // the instructions executed by each function by opcode, indexed by the function's address
+    funcs: Vec<Option<Box<[u64; 256]>>>,
+    // indexed by the first opcode shifted left by 8 and the second one
+    digrams: Box<[u64]>,
+    // an open-addressed hash table of the trigram counts, keyed by the three opcodes packed into
+    // the low bytes of a `u32` plus one, so that an unused slot has the key 0
+    trigrams: Box<[(u32, u64)]>,
+    trigram_count: usize,
+    // the last executed instruction and the opcodes of the two executed up to it in a row
+    last: Option<(FuncAddr, usize)>,
+    history: [u8; 2],
+    run: u8,
+}
+
+impl Default for OpcodeCounter {
+    fn default() -> Self {
+        let (digrams, trigrams) = (vec![0; 1 << 16].into_boxed_slice(), vec![(0, 0); TRIGRAM_SLOTS].into_boxed_slice());
+        Self { funcs: Vec::new(), digrams, trigrams, trigram_count: 0, last: None, history: [0; 2], run: 0 }
+    }
+}
+
+impl OpcodeCounter {
+    #[inline(always)]
+    pub(crate) fn record(&mut self, func_addr: FuncAddr, instr_ptr: usize, opcode: u8) {
+        let func = func_addr as usize;
+        if unlikely(func >= self.funcs.len()) {
+            self.funcs.resize(func + 1, None);
+        }
+        self.funcs[func].get_or_insert_with(|| Box::new([0; 256]))[opcode as usize] += 1;
+
+        // only instructions that directly follow each other in the same function form a sequence
+        let follows = self.last == Some((func_addr, instr_ptr.wrapping_sub(1)));
+        let run = if follows { self.run } else { 0 };
+        let [before, prev] = self.history;
+        if run >= 1 {
+            self.digrams[(prev as usize) << 8 | opcode as usize] += 1;
+        }
+        if run >= 2 {
+            self.count_trigram(u32::from_be_bytes([0, before, prev, opcode]) + 1);
+        }
+
+        self.last = Some((func_addr, instr_ptr));
+        self.history = [prev, opcode];
+        self.run = (run + 1).min(2);
+    }
+
+    #[inline(always)]
+    fn count_trigram(&mut self, key: u32) {
+        let slot = trigram_slot(&self.trigrams, key);
+        let (slot_key, count) = &mut self.trigrams[slot];
+        *count += 1;
+        if *slot_key == 0 {
+            *slot_key = key;
+            self.trigram_count += 1;
+            if unlikely(self.trigram_count * 2 > self.trigrams.len()) {
+                self.grow_trigrams();
+            }
+        }
+    }
+
+    #[cold]
+    fn grow_trigrams(&mut self) {
+        let mut trigrams = vec![(0, 0); self.trigrams.len() * 2].into_boxed_slice();
+        for &(key, count) in self.trigrams.iter().filter(|(key, _)| *key != 0) {
+            trigrams[trigram_slot(&trigrams, key)] = (key, count);
+        }
+        self.trigrams = trigrams;
+    }
+
+    // the counted trigrams, in no particular order
+    fn trigrams(&self) -> impl Iterator<Item = ([u8; 3], u64)> + '_ {
+        self.trigrams.iter().filter(|(key, _)| *key != 0).map(|(key, count)| {
+            let [_, a, b, c] = (key - 1).to_be_bytes();
+            ([a, b, c], *count)
+        })
+    }
+}
+
+// the slot of `key` in a trigram table, or the free slot it would be inserted into
+#[inline(always)]
+fn trigram_slot(trigrams: &[(u32, u64)], key: u32) -> usize {
+    let mask = trigrams.len() - 1;
+    let mut slot = ((key as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize & mask;
+    while trigrams[slot].0 != key && trigrams[slot].0 != 0 {
+        slot = (slot + 1) & mask;
+    }
+    slot
+}
+
+/// The instructions executed since opcode counting was enabled, see [`Store::opcode_counts`]
+///
+/// Formatting the counts with [`Display`] gives a report of the most executed opcodes, the functions
+/// executing the most instructions and the sequences that are worth fusing, see [`OpcodeCounts::fusion_candidates`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OpcodeCounts {
+    /// The counts of every function that executed at least one instruction, by address
+    pub functions: Vec<FunctionOpcodeCounts>,
+    /// How often each pair of opcodes was executed directly after each other in the same function
+    pub digrams: BTreeMap<[u8; 2], u64>,
+    /// How often each sequence of three opcodes was executed directly after each other in the same function
+    pub trigrams: BTreeMap<[u8; 3], u64>,
+}
+
+/// A sequence of instructions that could be fused into one, see [`OpcodeCounts::fusion_candidates`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FusionCandidate {
+    /// The opcodes of the instructions, in the order they are executed
+    pub opcodes: Vec<u8>,
+    /// How often the sequence was executed
+    pub count: u64,
+}
+
+impl FusionCandidate {
+    /// The number of instruction dispatches that fusing the sequence would have saved
+    pub fn saved(&self) -> u64 {
+        self.count * (self.opcodes.len() as u64).saturating_sub(1)
+    }
+}
+
+// whether the instruction can be part of a fused one, `last` if it ends the sequence
+fn fusable(op: u8, last: bool) -> bool {
+    use opcode::{Block, Else, EndBlockFrame, EndFunc, If, Loop, Probe, Unreachable, Yield};
+    use opcode::{Br, BrIf, BrTable, Call, CallIndirect, I32EqzBrIf, Return};
+    // block boundaries are referred to by branches, and instrumentation has to stay separate
+    let boundaries = [Block, Loop, If, Else, EndBlockFrame, EndFunc, Unreachable, Yield, Probe];
+    // execution continues elsewhere after these, so they can only end a fused instruction
+    let exits = [Br, BrIf, BrTable, I32EqzBrIf, Return, Call, CallIndirect];
+    !boundaries.contains(&op) && (last || !exits.contains(&op))
+}
+
+/// The instructions executed by a single function
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionOpcodeCounts {
+    /// The address of the function in the store
+    pub func_addr: FuncAddr,
+    /// The module instance that owns the function
+    pub owner: ModuleInstanceAddr,
+    /// The index of the function in its module, if the module instance still exists
+    pub func_index: Option<FuncAddr>,
+    /// The name of the function, see [`Store::func_name`]
+    pub name: Option<String>,
+    /// How often each opcode was executed, see [`tinywasm_types::opcode`]
+    pub opcodes: BTreeMap<u8, u64>,
+}
+
+impl FunctionOpcodeCounts {
+    /// The number of instructions the function executed
+    pub fn total(&self) -> u64 {
+        self.opcodes.values().sum()
+    }
+}
+
+impl OpcodeCounts {
+    /// The number of instructions executed by all functions
+    pub fn total(&self) -> u64 {
+        self.functions.iter().map(FunctionOpcodeCounts::total).sum()
+    }
+
+    /// How often each opcode was executed by all functions, the most executed first
+    pub fn by_opcode(&self) -> Vec<(u8, u64)> {
+        let mut opcodes = BTreeMap::<u8, u64>::new();
+        for (op, count) in self.functions.iter().flat_map(|func| func.opcodes.iter()) {
+            *opcodes.entry(*op).or_default() += count;
+        }
+
+        let mut opcodes: Vec<_> = opcodes.into_iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(&a.1));
+        opcodes
+    }
+
+    /// The sequences of opcodes that would save the most dispatches if they were fused, the best first
+    ///
+    /// Sequences containing block boundaries, or branches and calls anywhere but at their end, are left out.
+    /// Use them to decide which of the parser's opt-in fusion rules to enable for a workload, see
+    /// `tinywasm_parser::FusionRules`, or which instructions to add.
+    pub fn fusion_candidates(&self) -> Vec<FusionCandidate> {
+        let digrams = self.digrams.iter().map(|(ops, count)| (&ops[..], *count));
+        let trigrams = self.trigrams.iter().map(|(ops, count)| (&ops[..], *count));
+        let mut candidates: Vec<_> = (digrams.chain(trigrams))
+            .filter(|(ops, _)| ops.iter().enumerate().all(|(i, op)| fusable(*op, i == ops.len() - 1)))
+            .map(|(ops, count)| FusionCandidate { opcodes: ops.to_vec(), count })
+            .collect();
+        candidates.sort_by_key(|candidate| Reverse(candidate.saved()));
+        candidates
+    }
+}
+
+impl Store {
+    /// Enable or disable counting the executed instructions
+    ///
+    /// While enabled, every instruction the interpreter executes in this store is counted by
+    /// its opcode and function, which slows down execution. Enabling counting again or
+    /// disabling it discards the recorded counts. Requires the `opcode-counts` feature.
+    pub fn enable_opcode_counts(&mut self, enabled: bool) {
+        self.opcode_counter = enabled.then(OpcodeCounter::default);
+    }
+
+    /// Get the instructions executed since counting was enabled, or `None` if it is disabled
+    pub fn opcode_counts(&self) -> Option<OpcodeCounts> {
+        let counter = self.opcode_counter.as_ref()?;
+        let functions = (counter.funcs.iter().enumerate())
+            .filter_map(|(addr, counts)| Some((addr as FuncAddr, counts.as_ref()?)))
+            .map(|(func_addr, counts)| FunctionOpcodeCounts {
+                func_addr,
+                owner: self.get_func(func_addr as usize).map(|func| func.owner).unwrap_or_default(),
+                func_index: self.func_index(func_addr),
+                name: self.func_name(func_addr).map(ToString::to_string),
+                opcodes: (0..=u8::MAX).zip(counts.iter().copied()).filter(|(_, count)| *count > 0).collect(),
+            })
+            .collect();
+
+        let digrams = (0..=u16::MAX)
+            .zip(counter.digrams.iter().copied())
+            .filter(|(_, count)| *count > 0)
+            .map(|(ops, count)| (ops.to_be_bytes(), count))
+            .collect();
+
+        Some(OpcodeCounts { functions, digrams, trigrams: counter.trigrams().collect() })
+    }
+}
+
+// `count` as a percentage of `total` with one decimal, without using floats
+struct Percent(u64, u64);
+
+impl Display for Percent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let permille = (self.0 as u128 * 1000 / (self.1 as u128).max(1)) as u64;
+        write!(f, "{:>3}.{}%", permille / 10, permille % 10)
+    }
+}
+
+impl Display for OpcodeCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        let total = self.total();
+        writeln!(f, "{} instructions executed", total)?;
+
+        writeln!(f, "\nopcodes:")?;
+        for (op, count) in self.by_opcode() {
+            let name = opcode::name(op).unwrap_or("unknown");
+            writeln!(f, "  {:<24} {:>14} {}", name, count, Percent(count, total))?;
+        }
+
+        writeln!(f, "\nfunctions:")?;
+        let mut functions: Vec<_> = self.functions.iter().map(|func| (func, func.total())).collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1));
+        for (func, count) in functions {
+            match func.func_index {
+                Some(index) => write!(f, "  func[{}]", index)?,
+                None => write!(f, "  func@{}", func.func_addr)?,
+            }
+            if let Some(name) = &func.name {
+                write!(f, " '{}'", name)?;
+            }
+            writeln!(f, ": {} {}", count, Percent(count, total))?;
+        }
+
+        writeln!(f, "\nfusion candidates (executions, saved dispatches):")?;
+        for candidate in self.fusion_candidates().into_iter().take(REPORTED_CANDIDATES) {
+            let names: Vec<_> = candidate.opcodes.iter().map(|op| opcode::name(*op).unwrap_or("unknown")).collect();
+            let saved = Percent(candidate.saved(), total);
+            writeln!(f, "  {:<56} {:>14} {}", names.join(", "), candidate.count, saved)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Result};
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_opcode_counts() -> Result<()> {
+        // `run` calls `double` twice
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I32]), results: Box::new([ValType::I32]) });
+        let run = [Instruction::LocalGet(0), Instruction::Call(1), Instruction::Call(1), Instruction::EndFunc];
+        let run = builder.add_function(ty, [], run);
+        let double = [Instruction::LocalGet2(0, 0), Instruction::I32Add, Instruction::EndFunc];
+        builder.add_function(ty, [], double);
+        builder.add_export("run", ExternalKind::Func, run);
+
+        let mut store = Store::default();
+        let instance = Module::from(builder.finish().expect("valid module")).instantiate(&mut store, None)?;
+        let run = instance.exported_func::<i32, i32>(&store, "run")?;
+        assert_eq!(store.opcode_counts(), None);
+
+        store.enable_opcode_counts(true);
+        assert_eq!(run.call(&mut store, 3)?, 12);
+        let counts = store.opcode_counts().expect("counting is enabled");
+        assert_eq!(counts.total(), 10);
+        assert_eq!(counts.by_opcode()[0], (opcode::EndFunc, 3));
+
+        let double = &counts.functions[1];
+        assert_eq!((double.func_index, double.total()), (Some(1), 6));
+        assert_eq!(double.opcodes[&opcode::LocalGet2], 2);
+
+        // calls and returns end a sequence
+        assert_eq!(counts.digrams[&[opcode::LocalGet2, opcode::I32Add]], 2);
+        assert_eq!(counts.digrams[&[opcode::LocalGet, opcode::Call]], 1);
+        assert_eq!(counts.digrams.len(), 3);
+        assert_eq!(counts.trigrams.len(), 1);
+        assert_eq!(counts.trigrams[&[opcode::LocalGet2, opcode::I32Add, opcode::EndFunc]], 2);
+        let candidates = counts.fusion_candidates();
+        assert_eq!(candidates[0], FusionCandidate { opcodes: vec![opcode::LocalGet2, opcode::I32Add], count: 2 });
+        assert_eq!(candidates.len(), 2, "sequences with `EndFunc` can't be fused");
+
+        let report = counts.to_string();
+        assert!(report.starts_with("10 instructions executed\n"));
+        assert!(report.contains("  I32Add                                2  20.0%\n"));
+        assert!(report.contains("  func[0] 'run': 4  40.0%\n"));
+
+        store.enable_opcode_counts(false);
+        assert_eq!(store.opcode_counts(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trigram_growth() {
+        // every sequence of three opcodes below 20 once, in a row, which fills the table past its initial size
+        let mut counter = OpcodeCounter::default();
+        let ops: Vec<u8> =
+            (0..20 * 20 * 20).flat_map(|i: u32| [i / 400, i / 20 % 20, i % 20]).map(|op| op as u8).collect();
+        for (instr_ptr, op) in ops.iter().enumerate() {
+            counter.record(0, instr_ptr, *op);
+        }
+
+        assert!(counter.trigrams.len() > TRIGRAM_SLOTS);
+        let trigrams: BTreeMap<_, _> = counter.trigrams().collect();
+        assert_eq!((trigrams.len(), counter.trigram_count), (8000, 8000));
+        assert_eq!(trigrams.values().sum::<u64>(), ops.len() as u64 - 2);
+        assert_eq!(trigrams[&[1, 2, 3]], ops.windows(3).filter(|ops| *ops == [1, 2, 3]).count() as u64);
+    }
+}
@@ -0,0 +1,164 @@
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tinywasm_types::FuncAddr;
+
+use crate::std::{sync::Arc, thread, time::Duration};
+
+// published while no guest code is running
+const IDLE: u64 = u64::MAX;
+
+/// A sampling profiler for guest code
+///
+/// While a profiler is attached to a store (see [`crate::Store::set_profiler`]), the interpreter
+/// publishes the function and instruction it is currently executing. [`Profiler::start`] spawns
+/// a background thread that periodically samples this location and aggregates the samples into a [`Profile`].
+///
+/// ```rust
+/// # use tinywasm::{Profiler, Store};
+/// # use std::time::Duration;
+/// let profiler = Profiler::new();
+/// let mut store = Store::default();
+/// store.set_profiler(Some(profiler.clone()));
+///
+/// let sampling = profiler.start(Duration::from_micros(100));
+/// // ... call some functions
+/// let profile = sampling.stop();
+/// for (func_addr, func) in &profile.functions {
+///     println!("function {}: {} samples", func_addr, func.samples);
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    current: Arc<AtomicU64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    /// Create a new profiler
+    pub fn new() -> Self {
+        Self { current: Arc::new(AtomicU64::new(IDLE)) }
+    }
+
+    /// Start sampling on a background thread, taking a sample every `interval`
+    pub fn start(&self, interval: Duration) -> Sampling {
+        let running = Arc::new(AtomicBool::new(true));
+        let (current, keep_running) = (self.current.clone(), running.clone());
+
+        let thread = thread::spawn(move || {
+            let mut profile = Profile::default();
+            while keep_running.load(Ordering::Relaxed) {
+                thread::sleep(interval);
+                profile.record(current.load(Ordering::Relaxed));
+            }
+            profile
+        });
+
+        Sampling { running, thread }
+    }
+
+    #[inline(always)]
+    pub(crate) fn publish(&self, func_addr: FuncAddr, instr_ptr: usize) {
+        self.current.store((func_addr as u64) << 32 | instr_ptr as u32 as u64, Ordering::Relaxed);
+    }
+
+    // publish the idle state once the returned guard is dropped
+    pub(crate) fn idle_on_drop(&self) -> IdleGuard<'_> {
+        IdleGuard(self)
+    }
+}
+
+pub(crate) struct IdleGuard<'a>(&'a Profiler);
+
+impl Drop for IdleGuard<'_> {
+    fn drop(&mut self) {
+        self.0.current.store(IDLE, Ordering::Relaxed);
+    }
+}
+
+/// A running sampling thread, see [`Profiler::start`]
+#[derive(Debug)]
+pub struct Sampling {
+    running: Arc<AtomicBool>,
+    thread: thread::JoinHandle<Profile>,
+}
+
+impl Sampling {
+    /// Stop sampling and return the collected profile
+    pub fn stop(self) -> Profile {
+        self.running.store(false, Ordering::Relaxed);
+        self.thread.join().unwrap_or_default()
+    }
+}
+
+/// The samples collected by a [`Profiler`]
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// The number of samples taken while no guest code was running
+    pub idle: u64,
+    /// The samples taken in each function, by the function's address in the store
+    ///
+    /// Addresses of exported functions can be looked up using [`crate::ModuleInstance::export_addr`]
+    pub functions: BTreeMap<FuncAddr, FunctionProfile>,
+}
+
+/// The samples collected for a single function
+#[derive(Debug, Clone, Default)]
+pub struct FunctionProfile {
+    /// The number of samples taken in this function
+    pub samples: u64,
+    /// The number of samples taken at each instruction, by the instruction's index in the function
+    pub instructions: BTreeMap<usize, u64>,
+}
+
+impl Profile {
+    /// The total number of samples taken
+    pub fn total(&self) -> u64 {
+        self.idle + self.functions.values().map(|f| f.samples).sum::<u64>()
+    }
+
+    fn record(&mut self, current: u64) {
+        if current == IDLE {
+            self.idle += 1;
+            return;
+        }
+
+        let func = self.functions.entry((current >> 32) as FuncAddr).or_default();
+        func.samples += 1;
+        *func.instructions.entry(current as u32 as usize).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let profiler = Profiler::new();
+        let mut profile = Profile::default();
+
+        profiler.publish(3, 7);
+        profile.record(profiler.current.load(Ordering::Relaxed));
+        profile.record(profiler.current.load(Ordering::Relaxed));
+        profiler.publish(3, 8);
+        profile.record(profiler.current.load(Ordering::Relaxed));
+        drop(profiler.idle_on_drop());
+        profile.record(profiler.current.load(Ordering::Relaxed));
+
+        assert_eq!((profile.total(), profile.idle), (4, 1));
+        let func = &profile.functions[&3];
+        assert_eq!((func.samples, func.instructions[&7], func.instructions[&8]), (3, 2, 1));
+    }
+
+    #[test]
+    fn test_default_is_idle() {
+        let mut profile = Profile::default();
+        profile.record(Profiler::default().current.load(Ordering::Relaxed));
+        assert_eq!((profile.idle, profile.functions.len()), (1, 0));
+    }
+}
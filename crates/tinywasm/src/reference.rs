@@ -1,13 +1,13 @@
-use core::cell::{Ref, RefCell, RefMut};
 use core::ffi::CStr;
 
+use crate::sync::{Rc, Ref, RefCell, RefMut};
+use alloc::boxed::Box;
 use alloc::ffi::CString;
-use alloc::rc::Rc;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 
-use crate::{GlobalInstance, MemoryInstance, Result};
-use tinywasm_types::WasmValue;
+use crate::{GlobalInstance, MemoryInstance, MemoryObserver, Result, TableInstance};
+use tinywasm_types::{ValType, WasmValue};
 
 // This module essentially contains the public APIs to interact with the data stored in the store
 
@@ -61,7 +61,9 @@ impl MemoryRefMut<'_> {
     }
 
     /// Grow the memory by the given number of pages
-    pub fn grow(&mut self, delta_pages: i32) -> Option<i32> {
+    ///
+    /// Returns the previous number of pages, or `None` if the memory can't grow that much.
+    pub fn grow(&mut self, delta_pages: u32) -> Option<i32> {
         self.instance.grow(delta_pages)
     }
 
@@ -84,6 +86,28 @@ impl MemoryRefMut<'_> {
     pub fn store(&mut self, offset: usize, len: usize, data: &[u8]) -> Result<()> {
         self.instance.store(offset, len, data)
     }
+
+    /// Store a string as little-endian UTF-16, returning the number of bytes written
+    ///
+    /// No length prefix or terminator is written, see [`MemoryStringExt::load_utf16_string`].
+    pub fn store_utf16_string(&mut self, offset: usize, string: &str) -> Result<usize> {
+        let bytes: Vec<u8> = string.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        self.store(offset, bytes.len(), &bytes)?;
+        Ok(bytes.len())
+    }
+
+    /// Attach an observer that is called on every access to this memory, replacing any previous one
+    ///
+    /// To inspect what the observer collected later, pass it wrapped in an `Rc`
+    /// (or an `Arc` with the `sync` feature) and keep a clone around.
+    pub fn set_observer(&mut self, observer: impl MemoryObserver + 'static) {
+        self.instance.set_observer(Some(Box::new(observer)));
+    }
+
+    /// Remove the memory's observer
+    pub fn clear_observer(&mut self) {
+        self.instance.set_observer(None);
+    }
 }
 
 #[doc(hidden)]
@@ -136,6 +160,28 @@ pub trait MemoryStringExt: MemoryRefLoad {
         }
         Ok(string)
     }
+
+    /// Load a little-endian UTF-16 string of `len` bytes from memory
+    ///
+    /// Unlike [`MemoryStringExt::load_js_string`], this decodes surrogate pairs.
+    fn load_utf16_string(&self, offset: usize, len: usize) -> Result<String> {
+        let units = self.load(offset, len)?.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+        char::decode_utf16(units)
+            .collect::<core::result::Result<String, _>>()
+            .map_err(|_| crate::Error::Other("Invalid UTF-16 string".to_string()))
+    }
+
+    /// Load an AssemblyScript string from memory
+    ///
+    /// `ptr` points to the string's UTF-16 data, which is preceded by the `rtSize` field of its
+    /// object header containing the length of the string in bytes.
+    fn load_as_string(&self, ptr: usize) -> Result<String> {
+        let header =
+            ptr.checked_sub(4).ok_or_else(|| crate::Error::Other("Invalid AssemblyScript string".to_string()))?;
+        let size = self.load(header, 4)?;
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]);
+        self.load_utf16_string(ptr, size as usize)
+    }
 }
 
 impl MemoryStringExt for MemoryRef<'_> {}
@@ -154,7 +200,106 @@ impl GlobalRef {
     }
 
     /// Set the value of the global
+    ///
+    /// Fails if the global is being read or written at the same time, e.g. from another thread.
     pub fn set(&self, val: WasmValue) -> Result<()> {
-        self.instance.borrow_mut().set(val)
+        let mut global =
+            self.instance.try_borrow_mut().map_err(|_| crate::Error::Other("global is already borrowed".into()))?;
+        global.set(val)
+    }
+}
+
+/// A reference to a table instance
+#[derive(Debug, Clone)]
+pub struct TableRef {
+    pub(crate) instance: Rc<RefCell<TableInstance>>,
+}
+
+impl TableRef {
+    /// Get the element at the given index
+    ///
+    /// Function references can be called using [`crate::Store::get_func_ref`].
+    /// Fails if the table is being written at the same time, e.g. from another thread.
+    pub fn get(&self, idx: u32) -> Result<WasmValue> {
+        self.borrow()?.get_wasm_val(idx as usize)
+    }
+
+    /// Get the current size of the table
+    ///
+    /// Fails if the table is being written at the same time, e.g. from another thread.
+    pub fn size(&self) -> Result<u32> {
+        Ok(self.borrow()?.size() as u32)
+    }
+
+    /// Get the type of the table's elements
+    ///
+    /// Fails if the table is being written at the same time, e.g. from another thread.
+    pub fn element_type(&self) -> Result<ValType> {
+        Ok(self.borrow()?.kind.element_type)
+    }
+
+    fn borrow(&self) -> Result<Ref<'_, TableInstance>> {
+        self.instance.try_borrow().map_err(|_| crate::Error::Other("table is already borrowed".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Store};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_utf16_strings() -> Result<()> {
+        let module = Module::from(TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            exports: vec![Export { name: "memory".into(), kind: ExternalKind::Memory, index: 0 }].into(),
+            ..Default::default()
+        });
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        let mut memory = instance.exported_memory_mut(&mut store, "memory")?;
+
+        // an AssemblyScript string is preceded by its size in bytes
+        let len = memory.store_utf16_string(8, "tiny 🦀")?;
+        assert_eq!(len, 14);
+        memory.store(4, 4, &(len as u32).to_le_bytes())?;
+
+        assert_eq!(memory.load_utf16_string(8, len)?, "tiny 🦀");
+        assert_eq!(memory.load_as_string(8)?, "tiny 🦀");
+        assert!(memory.load_js_string(8, len).is_err());
+
+        // a lone surrogate
+        memory.store(0, 2, &0xd800u16.to_le_bytes())?;
+        assert!(memory.load_utf16_string(0, 2).is_err());
+        assert!(memory.load_as_string(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflicting_borrows() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let table = builder.add_table(TableType::new(ValType::RefFunc, 1, None));
+        let global = builder.add_global(GlobalType { mutable: true, ty: ValType::I32 }, ConstInstruction::I32Const(0));
+        builder.add_export("table", ExternalKind::Table, table);
+        let module = Module::from(builder.finish().expect("valid module"));
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+
+        let table = instance.exported_table(&store, "table")?;
+        let global =
+            GlobalRef { instance: store.get_global(instance.global_addrs()[global as usize] as usize)?.clone() };
+        {
+            let _write = table.instance.borrow_mut();
+            let _read = global.instance.borrow();
+            assert!(table.get(0).is_err());
+            assert!(global.set(WasmValue::I32(1)).is_err());
+        }
+
+        assert!(table.get(0).is_ok());
+        global.set(WasmValue::I32(1))?;
+        assert_eq!(global.get(), WasmValue::I32(1));
+        Ok(())
     }
 }
@@ -0,0 +1,147 @@
+//! Shared instruction handlers for the `opt-size` feature
+//!
+//! By default, the macros in [`super::macros`] generate specialized code for every instruction.
+//! With `opt-size`, they call these handlers instead, which each implement a whole family of
+//! instructions and get the actual operation as a function pointer. This is slower, but only
+//! generates one copy of each handler per value type and keeps `exec_one` small.
+
+use tinywasm_types::MemAddr;
+
+use crate::runtime::{RawWasmValue, Stack};
+use crate::{Error, ModuleInstance, Result, Store, Trap};
+
+/// Values that can be loaded from memory
+pub(super) trait MemValue {
+    /// Whether the value is sign-extended when loaded into a larger type
+    const SIGNED: bool;
+}
+
+macro_rules! impl_mem_value {
+    ($($t:ty => $signed:literal),*) => {
+        $(impl MemValue for $t { const SIGNED: bool = $signed; })*
+    };
+}
+
+impl_mem_value! {
+    i8 => true, i16 => true, i32 => true, i64 => true,
+    u8 => false, u16 => false, u32 => false, u64 => false,
+    f32 => false, f64 => false
+}
+
+// Extend a little-endian value of up to 8 bytes to 64 bits
+#[inline]
+fn extend(bytes: &[u8], signed: bool) -> u64 {
+    let mut buf = [0u8; 8];
+    buf[..bytes.len()].copy_from_slice(bytes);
+    let value = u64::from_le_bytes(buf);
+
+    let shift = 64 - bytes.len() as u32 * 8;
+    match signed && shift < 64 {
+        true => ((value << shift) as i64 >> shift) as u64,
+        false => value,
+    }
+}
+
+/// Load `len` bytes from memory and push them onto the stack
+///
+/// Smaller integers are stored extended to 64 bits, so this works for all target types.
+#[inline(never)]
+pub(super) fn mem_load(
+    stack: &mut Stack,
+    store: &Store,
+    module: &ModuleInstance,
+    (mem_addr, offset): (MemAddr, u64),
+    len: usize,
+    signed: bool,
+) -> Result<()> {
+    let addr = stack.values.pop_t::<u32>()?;
+    let mem = super::memory(&mut stack.memory, store, module, mem_addr)?.borrow();
+    let addr = mem.effective_addr(addr, offset, len)?;
+    let value = extend(mem.load(addr, len)?, signed);
+    stack.values.push(value.into());
+    Ok(())
+}
+
+/// Pop a value and store its lowest `len` bytes in memory
+#[inline(never)]
+pub(super) fn mem_store(
+    stack: &mut Stack,
+    store: &Store,
+    module: &ModuleInstance,
+    (mem_addr, offset): (MemAddr, u64),
+    len: usize,
+) -> Result<()> {
+    let value = stack.values.pop()?.raw_value();
+    let addr = stack.values.pop_t::<u32>()?;
+
+    let mut mem = super::memory(&mut stack.memory, store, module, mem_addr)?.borrow_mut();
+    let addr = mem.effective_addr(addr, offset, len)?;
+    mem.store(addr, len, &value[..len])
+}
+
+/// Compare two values on the stack
+#[inline(never)]
+pub(super) fn comp<T: From<RawWasmValue>>(stack: &mut Stack, op: fn(T, T) -> bool) -> Result<()> {
+    let b: T = stack.values.pop()?.into();
+    let a: T = stack.values.pop()?.into();
+    stack.values.push((op(a, b) as i32).into());
+    Ok(())
+}
+
+/// Apply an operation to two values on the stack
+#[inline(never)]
+pub(super) fn binop<T: From<RawWasmValue>>(stack: &mut Stack, op: fn(T, T) -> T) -> Result<()>
+where
+    RawWasmValue: From<T>,
+{
+    let b: T = stack.values.pop()?.into();
+    let a: T = stack.values.pop()?.into();
+    stack.values.push(op(a, b).into());
+    Ok(())
+}
+
+/// Apply an operation to a single value on the stack
+#[inline(never)]
+pub(super) fn unop<T: From<RawWasmValue>>(stack: &mut Stack, op: fn(T) -> T) -> Result<()>
+where
+    RawWasmValue: From<T>,
+{
+    let a: T = stack.values.pop()?.into();
+    stack.values.push(op(a).into());
+    Ok(())
+}
+
+/// Apply an operation to two integers on the stack, trapping on division by zero or overflow
+#[inline(never)]
+pub(super) fn checked_binop<T: From<RawWasmValue> + Default + PartialEq>(
+    stack: &mut Stack,
+    op: fn(T, T) -> Option<T>,
+) -> Result<()>
+where
+    RawWasmValue: From<T>,
+{
+    let b: T = stack.values.pop()?.into();
+    let a: T = stack.values.pop()?.into();
+
+    if b == T::default() {
+        return Err(Error::Trap(Trap::DivisionByZero));
+    }
+
+    let result = op(a, b).ok_or(Error::Trap(Trap::IntegerOverflow))?;
+    stack.values.push(result.into());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extend() {
+        assert_eq!(extend(&[0xff], true), u64::MAX);
+        assert_eq!(extend(&[0xff], false), 0xff);
+        assert_eq!(extend(&[0x00, 0x80], true) as i64, i16::MIN as i64);
+        assert_eq!(extend(&[0x01, 0x02, 0x03, 0x04], <u32 as MemValue>::SIGNED), 0x0403_0201);
+        assert_eq!(extend(&u64::MAX.to_le_bytes(), true), u64::MAX);
+    }
+}
@@ -0,0 +1,119 @@
+//! Floating-point instructions
+//!
+//! These are kept separate from `exec_one` so they can be compiled out using the `no-float` feature.
+
+use core::ops::Neg;
+use tinywasm_types::Instruction;
+
+use super::{macros::*, traits::*, unsupported};
+use crate::runtime::{CallFrame, Stack};
+use crate::{unlikely, Error, ModuleInstance, Result, Store};
+
+#[cfg(not(feature = "std"))]
+#[allow(unused_imports)]
+use super::no_std_floats::NoStdFloatExt;
+
+// inlined so the compiler can merge this into the jump table of exec_one
+#[cfg_attr(not(feature = "opt-size"), inline(always))]
+pub(super) fn exec_float(
+    instr: &Instruction,
+    cf: &CallFrame,
+    stack: &mut Stack,
+    store: &Store,
+    module: &ModuleInstance,
+) -> Result<()> {
+    use tinywasm_types::Instruction::*;
+    match instr {
+        F32Const(val) => stack.values.push((*val).into()),
+        F64Const(val) => stack.values.push(f64::from_bits(cf.constant(*val)).into()),
+
+        F32Store { mem_addr, offset } => mem_store!(f32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        F64Store { mem_addr, offset } => mem_store!(f64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        F32Load { mem_addr, offset } => mem_load!(f32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        F64Load { mem_addr, offset } => mem_load!(f64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        F32StoreStatic { addr, mem_addr } => mem_store_static!(f32, (mem_addr, addr), stack, store, module),
+        F64StoreStatic { addr, mem_addr } => mem_store_static!(f64, (mem_addr, addr), stack, store, module),
+        F32LoadStatic { addr, mem_addr } => mem_load_static!(f32, (mem_addr, addr), stack, store, module),
+        F64LoadStatic { addr, mem_addr } => mem_load_static!(f64, (mem_addr, addr), stack, store, module),
+
+        F32Eq => comp!(==, f32, stack),
+        F64Eq => comp!(==, f64, stack),
+        F32Ne => comp!(!=, f32, stack),
+        F64Ne => comp!(!=, f64, stack),
+        F32Lt => comp!(<, f32, stack),
+        F64Lt => comp!(<, f64, stack),
+        F32Le => comp!(<=, f32, stack),
+        F64Le => comp!(<=, f64, stack),
+        F32Ge => comp!(>=, f32, stack),
+        F64Ge => comp!(>=, f64, stack),
+        F32Gt => comp!(>, f32, stack),
+        F64Gt => comp!(>, f64, stack),
+
+        F32Add => arithmetic!(+, f32, stack),
+        F64Add => arithmetic!(+, f64, stack),
+        F32Sub => arithmetic!(-, f32, stack),
+        F64Sub => arithmetic!(-, f64, stack),
+        F32Div => arithmetic!(/, f32, stack),
+        F64Div => arithmetic!(/, f64, stack),
+        F32Mul => arithmetic!(*, f32, stack),
+        F64Mul => arithmetic!(*, f64, stack),
+
+        F32ConvertI32S => conv!(i32, f32, stack),
+        F32ConvertI64S => conv!(i64, f32, stack),
+        F64ConvertI32S => conv!(i32, f64, stack),
+        F64ConvertI64S => conv!(i64, f64, stack),
+        F32ConvertI32U => conv!(u32, f32, stack),
+        F32ConvertI64U => conv!(u64, f32, stack),
+        F64ConvertI32U => conv!(u32, f64, stack),
+        F64ConvertI64U => conv!(u64, f64, stack),
+        F32DemoteF64 => conv!(f64, f32, stack),
+        F64PromoteF32 => conv!(f32, f64, stack),
+
+        F32Abs => arithmetic_single!(abs, f32, stack),
+        F64Abs => arithmetic_single!(abs, f64, stack),
+        F32Neg => arithmetic_single!(neg, f32, stack),
+        F64Neg => arithmetic_single!(neg, f64, stack),
+        F32Ceil => arithmetic_single!(ceil, f32, stack),
+        F64Ceil => arithmetic_single!(ceil, f64, stack),
+        F32Floor => arithmetic_single!(floor, f32, stack),
+        F64Floor => arithmetic_single!(floor, f64, stack),
+        F32Trunc => arithmetic_single!(trunc, f32, stack),
+        F64Trunc => arithmetic_single!(trunc, f64, stack),
+        F32Nearest => arithmetic_single!(tw_nearest, f32, stack),
+        F64Nearest => arithmetic_single!(tw_nearest, f64, stack),
+        F32Sqrt => arithmetic_single!(sqrt, f32, stack),
+        F64Sqrt => arithmetic_single!(sqrt, f64, stack),
+        F32Min => arithmetic!(tw_minimum, f32, stack),
+        F64Min => arithmetic!(tw_minimum, f64, stack),
+        F32Max => arithmetic!(tw_maximum, f32, stack),
+        F64Max => arithmetic!(tw_maximum, f64, stack),
+        F32Copysign => arithmetic!(copysign, f32, stack),
+        F64Copysign => arithmetic!(copysign, f64, stack),
+
+        // no-op instructions since types are erased at runtime
+        I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => {}
+
+        // unsigned versions of these are a bit broken atm
+        I32TruncF32S => checked_conv_float!(f32, i32, stack),
+        I32TruncF64S => checked_conv_float!(f64, i32, stack),
+        I32TruncF32U => checked_conv_float!(f32, u32, i32, stack),
+        I32TruncF64U => checked_conv_float!(f64, u32, i32, stack),
+        I64TruncF32S => checked_conv_float!(f32, i64, stack),
+        I64TruncF64S => checked_conv_float!(f64, i64, stack),
+        I64TruncF32U => checked_conv_float!(f32, u64, i64, stack),
+        I64TruncF64U => checked_conv_float!(f64, u64, i64, stack),
+
+        I32TruncSatF32S => arithmetic_single!(trunc, f32, i32, stack),
+        I32TruncSatF32U => arithmetic_single!(trunc, f32, u32, stack),
+        I32TruncSatF64S => arithmetic_single!(trunc, f64, i32, stack),
+        I32TruncSatF64U => arithmetic_single!(trunc, f64, u32, stack),
+        I64TruncSatF32S => arithmetic_single!(trunc, f32, i64, stack),
+        I64TruncSatF32U => arithmetic_single!(trunc, f32, u64, stack),
+        I64TruncSatF64S => arithmetic_single!(trunc, f64, i64, stack),
+        I64TruncSatF64U => arithmetic_single!(trunc, f64, u64, stack),
+
+        i => return Err(unsupported(i)),
+    };
+
+    Ok(())
+}
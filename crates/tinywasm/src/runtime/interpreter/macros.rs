@@ -20,7 +20,77 @@ macro_rules! break_to {
     }};
 }
 
+// Define `exec_one`, which runs the instruction at the instruction pointer
+//
+// By default, the arms are a single `match` over the instructions. With the `dispatch-table`
+// feature, every arm becomes a handler function instead, which are looked up in a table
+// indexed by the opcode of the instruction (see `Instruction::opcode`).
+// Instructions without an arm are passed to `exec_other`.
+macro_rules! exec_one {
+    ($(#[$attr:meta])* |$cf:ident, $stack:ident, $store:ident, $module:ident| {
+        $($name:ident $(($($arg:tt)*))? $({ $($field:tt)* })? => $body:expr,)*
+    }) => {
+        $(#[$attr])*
+        #[cfg(not(feature = "dispatch-table"))]
+        #[cfg_attr(not(feature = "opt-size"), inline(always))]
+        fn exec_one(
+            $cf: &mut CallFrame,
+            $stack: &mut Stack,
+            $store: &mut Store,
+            $module: &ModuleInstance,
+        ) -> Result<ExecResult> {
+            check_instr_ptr($cf)?;
+
+            use tinywasm_types::Instruction::*;
+            match $cf.current_instruction() {
+                $($name $(($($arg)*))? $({ $($field)* })? => $body,)*
+                instr => exec_other(instr, $cf, $stack, $store, $module)?,
+            };
+
+            Ok(ExecResult::Ok)
+        }
+
+        // every handler takes all arguments, and arms that always return are followed by `Ok(ExecResult::Ok)`
+        // a `static`, so every dispatch indexes the same table instead of a copy inlined into `exec_one`
+        #[cfg(feature = "dispatch-table")]
+        #[allow(unreachable_code, unused_variables)]
+        static HANDLERS: [Handler; 256] = {
+            use tinywasm_types::{opcode, Instruction::*};
+
+            let other: Handler = |cf, stack, store, module| {
+                exec_other(cf.current_instruction(), cf, stack, store, module)?;
+                Ok(ExecResult::Ok)
+            };
+
+            let mut handlers = [other; 256];
+            $(handlers[opcode::$name as usize] = |$cf, $stack, $store, $module| {
+                let $name $(($($arg)*))? $({ $($field)* })? = $cf.current_instruction() else {
+                    cold();
+                    unreachable!("the instruction doesn't match its opcode")
+                };
+                $body;
+                Ok(ExecResult::Ok)
+            };)*
+            handlers
+        };
+
+        $(#[$attr])*
+        #[cfg(feature = "dispatch-table")]
+        #[inline(always)]
+        fn exec_one(
+            $cf: &mut CallFrame,
+            $stack: &mut Stack,
+            $store: &mut Store,
+            $module: &ModuleInstance,
+        ) -> Result<ExecResult> {
+            check_instr_ptr($cf)?;
+            HANDLERS[$cf.current_instruction().opcode() as usize]($cf, $stack, $store, $module)
+        }
+    };
+}
+
 /// Load a value from memory
+#[cfg(not(feature = "opt-size"))]
 macro_rules! mem_load {
     ($type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
         mem_load!($type, $type, $arg, $stack, $store, $module)
@@ -28,37 +98,20 @@ macro_rules! mem_load {
 
     ($load_type:ty, $target_type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
         let (mem_addr, offset) = $arg;
+        let addr = $stack.values.pop_t::<u32>()?;
 
-        let mem_idx = $module.resolve_mem_addr(*mem_addr);
-        let mem = $store.get_mem(mem_idx as usize)?;
-        let mem_ref = mem.borrow_mut();
-
-        let addr: u64 = $stack.values.pop()?.into();
-        let addr = offset.checked_add(addr).ok_or_else(|| {
-            cold();
-            Error::Trap(crate::Trap::MemoryOutOfBounds {
-                offset: *offset as usize,
-                len: core::mem::size_of::<$load_type>(),
-                max: mem_ref.max_pages(),
-            })
-        })?;
-
-        let addr: usize = addr.try_into().ok().ok_or_else(|| {
-            cold();
-            Error::Trap(crate::Trap::MemoryOutOfBounds {
-                offset: *offset as usize,
-                len: core::mem::size_of::<$load_type>(),
-                max: mem_ref.max_pages(),
-            })
-        })?;
+        let mem = $crate::runtime::interpreter::memory(&mut $stack.memory, $store, $module, (*mem_addr).into())?;
+        let mem_ref = mem.borrow();
 
         const LEN: usize = core::mem::size_of::<$load_type>();
+        let addr = mem_ref.effective_addr(addr, offset, LEN)?;
         let val = mem_ref.load_as::<LEN, $load_type>(addr)?;
         $stack.values.push((val as $target_type).into());
     }};
 }
 
 /// Store a value to memory
+#[cfg(not(feature = "opt-size"))]
 macro_rules! mem_store {
     ($type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
         mem_store!($type, $type, $arg, $stack, $store, $module)
@@ -66,11 +119,36 @@ macro_rules! mem_store {
 
     ($store_type:ty, $target_type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
         let (mem_addr, offset) = $arg;
-        let mem = $store.get_mem($module.resolve_mem_addr(*mem_addr) as usize)?;
         let val: $store_type = $stack.values.pop()?.into();
         let val = val.to_le_bytes();
-        let addr: u64 = $stack.values.pop()?.into();
-        mem.borrow_mut().store((*offset + addr) as usize, val.len(), &val)?;
+        let addr = $stack.values.pop_t::<u32>()?;
+
+        let mem = $crate::runtime::interpreter::memory(&mut $stack.memory, $store, $module, (*mem_addr).into())?;
+        let mut mem_ref = mem.borrow_mut();
+        let addr = mem_ref.effective_addr(addr, offset, val.len())?;
+        mem_ref.store(addr, val.len(), &val)?;
+    }};
+}
+
+/// Load a value from a constant address in bounds of the memory's minimum size, see `MemoryInstance::load_static`
+macro_rules! mem_load_static {
+    ($type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
+        let (mem_addr, addr) = $arg;
+        let mem = $crate::runtime::interpreter::memory(&mut $stack.memory, $store, $module, (*mem_addr).into())?;
+
+        const LEN: usize = core::mem::size_of::<$type>();
+        let val = mem.borrow().load_static::<LEN, $type>(*addr as usize);
+        $stack.values.push(val.into());
+    }};
+}
+
+/// Store a value to a constant address in bounds of the memory's minimum size, see `MemoryInstance::store_static`
+macro_rules! mem_store_static {
+    ($type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
+        let (mem_addr, addr) = $arg;
+        let val: $type = $stack.values.pop()?.into();
+        let mem = $crate::runtime::interpreter::memory(&mut $stack.memory, $store, $module, (*mem_addr).into())?;
+        mem.borrow_mut().store_static(*addr as usize, &val.to_le_bytes());
     }};
 }
 
@@ -80,6 +158,7 @@ macro_rules! mem_store {
 /// Rust sadly doesn't have wrapping casts for floats yet, maybe never.
 /// Alternatively, https://crates.io/crates/az could be used for this but
 /// it's not worth the dependency.
+#[cfg(not(feature = "no-float"))]
 #[rustfmt::skip] 
 macro_rules! float_min_max {
     (f32, i32) => {(-2147483904.0_f32, 2147483648.0_f32)};
@@ -105,6 +184,7 @@ macro_rules! conv {
 }
 
 /// Convert a value on the stack with error checking
+#[cfg(not(feature = "no-float"))]
 macro_rules! checked_conv_float {
     // Direct conversion with error checking (two types)
     ($from:tt, $to:tt, $stack:ident) => {{
@@ -128,6 +208,7 @@ macro_rules! checked_conv_float {
 }
 
 /// Compare two values on the stack
+#[cfg(not(feature = "opt-size"))]
 macro_rules! comp {
     ($op:tt, $to:ty, $stack:ident) => {{
         let b: $to = $stack.values.pop()?.into();
@@ -144,7 +225,18 @@ macro_rules! comp_zero {
     }};
 }
 
+/// Apply an operation to two locals and push its result
+macro_rules! local_binop {
+    ($ty:ty, $op:expr, ($a:expr, $b:expr), $cf:ident, $stack:ident) => {{
+        let a: $ty = $cf.get_local(&$stack.locals, *$a as usize).into();
+        let b: $ty = $cf.get_local(&$stack.locals, *$b as usize).into();
+        let op: fn($ty, $ty) -> _ = $op;
+        $stack.values.push(op(a, b).into());
+    }};
+}
+
 /// Apply an arithmetic method to two values on the stack
+#[cfg(not(feature = "opt-size"))]
 macro_rules! arithmetic {
     ($op:ident, $to:ty, $stack:ident) => {{
         let b: $to = $stack.values.pop()?.into();
@@ -161,6 +253,7 @@ macro_rules! arithmetic {
 }
 
 /// Apply an arithmetic method to a single value on the stack
+#[cfg(not(feature = "opt-size"))]
 macro_rules! arithmetic_single {
     ($op:ident, $ty:ty, $stack:ident) => {{
         let a: $ty = $stack.values.pop()?.into();
@@ -174,6 +267,7 @@ macro_rules! arithmetic_single {
 }
 
 /// Apply an arithmetic operation to two values on the stack with error checking
+#[cfg(not(feature = "opt-size"))]
 macro_rules! checked_int_arithmetic {
     ($op:ident, $to:ty, $stack:ident) => {{
         let b: $to = $stack.values.pop()?.into();
@@ -188,14 +282,94 @@ macro_rules! checked_int_arithmetic {
     }};
 }
 
+// With `opt-size`, the macros below call the shared handlers in `compact` instead,
+// see the module docs there for details.
+
+#[cfg(feature = "opt-size")]
+macro_rules! mem_load {
+    ($type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
+        mem_load!($type, $type, $arg, $stack, $store, $module)
+    }};
+
+    ($load_type:ty, $target_type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
+        let (mem_addr, offset) = $arg;
+        let signed = <$load_type as $crate::runtime::interpreter::compact::MemValue>::SIGNED;
+        let len = core::mem::size_of::<$load_type>();
+        $crate::runtime::interpreter::compact::mem_load(
+            $stack,
+            $store,
+            $module,
+            ((*mem_addr).into(), offset),
+            len,
+            signed,
+        )?;
+    }};
+}
+
+#[cfg(feature = "opt-size")]
+macro_rules! mem_store {
+    ($type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
+        mem_store!($type, $type, $arg, $stack, $store, $module)
+    }};
+
+    ($store_type:ty, $target_type:ty, $arg:expr, $stack:ident, $store:ident, $module:ident) => {{
+        let (mem_addr, offset) = $arg;
+        let len = core::mem::size_of::<$store_type>();
+        $crate::runtime::interpreter::compact::mem_store($stack, $store, $module, ((*mem_addr).into(), offset), len)?;
+    }};
+}
+
+#[cfg(feature = "opt-size")]
+macro_rules! comp {
+    ($op:tt, $to:ty, $stack:ident) => {{
+        $crate::runtime::interpreter::compact::comp::<$to>($stack, |a, b| a $op b)?;
+    }};
+}
+
+#[cfg(feature = "opt-size")]
+macro_rules! arithmetic {
+    ($op:ident, $to:ty, $stack:ident) => {{
+        $crate::runtime::interpreter::compact::binop::<$to>($stack, |a, b| a.$op(b) as $to)?;
+    }};
+
+    ($op:tt, $ty:ty, $stack:ident) => {{
+        $crate::runtime::interpreter::compact::binop::<$ty>($stack, |a, b| a $op b)?;
+    }};
+}
+
+#[cfg(feature = "opt-size")]
+macro_rules! arithmetic_single {
+    ($op:ident, $ty:ty, $stack:ident) => {{
+        $crate::runtime::interpreter::compact::unop::<$ty>($stack, |a| a.$op() as $ty)?;
+    }};
+
+    ($op:ident, $from:ty, $to:ty, $stack:ident) => {{
+        let a: $from = $stack.values.pop()?.into();
+        $stack.values.push((a.$op() as $to).into());
+    }};
+}
+
+#[cfg(feature = "opt-size")]
+macro_rules! checked_int_arithmetic {
+    ($op:ident, $to:ty, $stack:ident) => {{
+        $crate::runtime::interpreter::compact::checked_binop::<$to>($stack, <$to>::$op)?;
+    }};
+}
+
 pub(super) use arithmetic;
 pub(super) use arithmetic_single;
 pub(super) use break_to;
+#[cfg(not(feature = "no-float"))]
 pub(super) use checked_conv_float;
 pub(super) use checked_int_arithmetic;
 pub(super) use comp;
 pub(super) use comp_zero;
 pub(super) use conv;
+pub(super) use exec_one;
+#[cfg(not(feature = "no-float"))]
 pub(super) use float_min_max;
+pub(super) use local_binop;
 pub(super) use mem_load;
+pub(super) use mem_load_static;
 pub(super) use mem_store;
+pub(super) use mem_store_static;
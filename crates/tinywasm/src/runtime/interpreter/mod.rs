@@ -1,34 +1,126 @@
 use alloc::format;
-use alloc::{string::ToString, vec::Vec};
-use core::ops::{BitAnd, BitOr, BitXor, Neg};
-use tinywasm_types::{ElementKind, ValType};
+use alloc::string::ToString;
+use core::ops::{BitAnd, BitOr, BitXor};
+use tinywasm_types::{Addr, ElementKind, MemAddr, ModuleInstanceAddr, ValType, WasmFunction};
 
 use super::{InterpreterRuntime, Stack};
-use crate::runtime::{BlockFrame, BlockType, CallFrame};
+use crate::runtime::{BlockFrame, BlockType, CallFrame, RawWasmValue};
+use crate::store::{pages_to_bytes, MemoryInstance, ResourceKind, ResourceRequest, TableElement};
+use crate::sync::{Rc, RefCell};
 use crate::{cold, log, unlikely};
-use crate::{Error, FuncContext, ModuleInstance, Result, Store, Trap};
+use crate::{Error, Frame, FuncContext, ModuleInstance, Result, Store, StoreEvent, Trap};
 
 mod macros;
 mod traits;
 use {macros::*, traits::*};
 
-#[cfg(not(feature = "std"))]
+#[cfg(feature = "opt-size")]
+mod compact;
+
+#[cfg(not(feature = "no-float"))]
+mod float;
+
+#[cfg(all(not(feature = "std"), not(feature = "no-float")))]
 mod no_std_floats;
 
-#[cfg(not(feature = "std"))]
-#[allow(unused_imports)]
-use no_std_floats::NoStdFloatExt;
+// Convert an operand to an index or length, saturating if `usize` is smaller than 32 bits
+// so that values that don't fit still fail bounds checks instead of wrapping around
+#[inline(always)]
+fn to_index(value: u32) -> usize {
+    usize::try_from(value).unwrap_or(usize::MAX)
+}
+
+// Host functions can remove the calling instance, which releases its memories,
+// so its code must not continue running (static memory accesses rely on the memories' minimum sizes)
+#[inline(always)]
+fn check_alive(store: &Store, module: &ModuleInstance) -> Result<()> {
+    match store.get_module_instance(module.id()) {
+        Some(_) => Ok(()),
+        None => Err(Store::removed_error(module.id())),
+    }
+}
+
+// With quotas or a limiter, calls that need a larger stack than the callee's instance may use
+// trap like a stack overflow
+#[inline(always)]
+fn check_stack(store: &mut Store, stack: &Stack, owner: ModuleInstanceAddr, func: &WasmFunction) -> Result<()> {
+    if unlikely(store.has_limits()) {
+        // the params move from the value stack to the locals, and both frames are pushed to the call stack
+        let frames = 2 * core::mem::size_of::<CallFrame>();
+        let bytes = stack.size_in_bytes() + frames + func.locals.len() * core::mem::size_of::<RawWasmValue>();
+        if !store.allow_stack(owner, bytes) {
+            return Err(Trap::CallStackOverflow.into());
+        }
+    }
+    Ok(())
+}
+
+// The memory `mem_addr` of the running function's module
+//
+// Nearly all accesses go to the first memory, so it is cached in the stack instead of being looked up
+// through the module's addresses and the store every time. The cache holds the memory itself rather than
+// its data, so it stays valid when the memory grows. It is cleared whenever code of another module starts
+// running and after host calls, which can change the store.
+#[inline(always)]
+fn memory<'a>(
+    cache: &'a mut Option<Rc<RefCell<MemoryInstance>>>,
+    store: &'a Store,
+    module: &ModuleInstance,
+    mem_addr: MemAddr,
+) -> Result<&'a Rc<RefCell<MemoryInstance>>> {
+    match (mem_addr, cache) {
+        (0, Some(mem)) => Ok(mem),
+        (0, cache) => Ok(cache.insert(store.get_mem(module.resolve_mem_addr(0) as usize)?.clone())),
+        (mem_addr, _) => store.get_mem(module.resolve_mem_addr(mem_addr) as usize),
+    }
+}
 
 impl InterpreterRuntime {
-    // #[inline(always)] // a small 2-3% performance improvement in some cases
     pub(crate) fn exec(&self, store: &mut Store, stack: &mut Stack) -> Result<()> {
+        // without fuel, the loop doesn't check it before every instruction
+        match store.fuel {
+            Some(_) => Self::exec_loop::<true>(store, stack),
+            None => Self::exec_loop::<false>(store, stack),
+        }
+    }
+
+    // #[inline(always)] // a small 2-3% performance improvement in some cases
+    fn exec_loop<const METERED: bool>(store: &mut Store, stack: &mut Stack) -> Result<()> {
         // The current call frame, gets updated inside of exec_one
         let mut cf = stack.call_stack.pop()?;
 
         // The function to execute, gets updated from ExecResult::Call
-        let mut current_module = store.get_module_instance_raw(cf.func_instance.1);
+        let mut current_module = store.get_module_instance_raw(cf.func_instance.1)?;
+        stack.memory = None;
+
+        #[cfg(feature = "profiler")]
+        let profiler = store.profiler.clone();
+        #[cfg(feature = "profiler")]
+        let _idle = profiler.as_ref().map(|p| p.idle_on_drop());
 
         loop {
+            #[cfg(feature = "profiler")]
+            if let Some(profiler) = &profiler {
+                profiler.publish(cf.func_addr, cf.instr_ptr);
+            }
+
+            if let (true, Some(fuel)) = (METERED, store.fuel.as_mut()) {
+                if unlikely(*fuel == 0) {
+                    // like other traps, the instruction pointer points past the instruction that trapped
+                    cf.instr_ptr += 1;
+                    stack.call_stack.push(cf)?;
+                    stack.out_of_fuel = true;
+                    store.emit(StoreEvent::FuelExhausted);
+                    return Err(Trap::OutOfFuel.into());
+                }
+                *fuel -= 1;
+            }
+
+            #[cfg(feature = "opcode-counts")]
+            if let (Some(counter), Some(instr)) = (store.opcode_counter.as_mut(), cf.instructions().get(cf.instr_ptr)) {
+                counter.record(cf.func_addr, cf.instr_ptr, instr.opcode());
+            }
+
             match exec_one(&mut cf, stack, store, &current_module) {
                 // Continue execution at the new top of the call stack
                 Ok(ExecResult::Call) => {
@@ -39,10 +131,20 @@ impl InterpreterRuntime {
                         stack.blocks.truncate(old);
                     }
 
+                    // after a return, drop the locals of the frames above the caller
+                    stack.locals.truncate(cf.locals_ptr + cf.local_count());
+
                     // keeping the pointer seperate from the call frame is about 2% faster
                     // than storing it in the call frame
                     if cf.func_instance.1 != current_module.id() {
-                        current_module.swap_with(cf.func_instance.1, store);
+                        current_module.swap_with(cf.func_instance.1, store)?;
+                        stack.memory = None;
+                    }
+
+                    // fuel set by a host function is used from the next call or return on
+                    if !METERED && unlikely(store.fuel.is_some()) {
+                        stack.call_stack.push(cf)?;
+                        return Self::exec_loop::<true>(store, stack);
                     }
                 }
 
@@ -71,30 +173,29 @@ enum ExecResult {
     Call,
 }
 
-/// Run a single step of the interpreter
-/// A seperate function is used so later, we can more easily implement
-/// a step-by-step debugger (using generators once they're stable?)
 // we want this be always part of the loop, rust just doesn't inline it as its too big
 // this can be a 30%+ performance difference in some cases
-#[inline(always)]
-fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &ModuleInstance) -> Result<ExecResult> {
-    let instrs = &cf.func_instance.0.instructions;
-
-    if unlikely(cf.instr_ptr >= instrs.len() || instrs.is_empty()) {
-        log::error!("instr_ptr out of bounds: {} >= {}", cf.instr_ptr, instrs.len());
-        return Err(Error::Other(format!("instr_ptr out of bounds: {} >= {}", cf.instr_ptr, instrs.len())));
-    }
-
-    // A match statement is probably the fastest way to do this without
-    // unreasonable complexity. This *should* be optimized to a jump table.
-    // See https://pliniker.github.io/post/dispatchers/
-    use tinywasm_types::Instruction::*;
-    match cf.current_instruction() {
-        Nop => { /* do nothing */ }
+//
+// A match statement is probably the fastest way to do this without
+// unreasonable complexity. This *should* be optimized to a jump table.
+// See https://pliniker.github.io/post/dispatchers/
+// With the `dispatch-table` feature, the arms become handlers in a table instead, see `exec_one!`
+exec_one! {
+    /// Run a single step of the interpreter
+    /// A seperate function is used so later, we can more easily implement
+    /// a step-by-step debugger (using generators once they're stable?)
+    |cf, stack, store, module| {
+        Nop => { /* do nothing */ },
+        Yield => {
+            if unlikely(store.take_interrupt()) {
+                return Err(Trap::Interrupted.into());
+            }
+        },
+        Probe => store.record_probe(cf.func_addr, cf.instr_ptr),
         Unreachable => {
             cold();
             return Err(crate::Trap::Unreachable.into());
-        }
+        },
         Drop => stack.values.pop().map(|_| ())?,
 
         Select(
@@ -109,26 +210,31 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
                 let _ = stack.values.pop()?;
                 stack.values.push(val2);
             }
-        }
+        },
 
         Call(v) => {
             // prepare the call frame
             let func_idx = module.resolve_func_addr(*v);
+            store.translate_func(func_idx)?;
             let func_inst = store.get_func(func_idx as usize)?.clone();
 
             let wasm_func = match &func_inst.func {
                 crate::Function::Wasm(wasm_func) => wasm_func.clone(),
                 crate::Function::Host(host_func) => {
-                    let func = &host_func.func;
                     let params = stack.values.pop_params(&host_func.ty.params)?;
-                    let res = (func)(FuncContext { store, module_addr: module.id() }, &params)?;
+                    let frame = Some(Frame { cf, values: &stack.values, locals: &stack.locals });
+                    let res = host_func.call(FuncContext { store, module_addr: module.id(), frame }, &params)?;
+                    check_alive(store, module)?;
+                    stack.memory = None;
                     stack.values.extend_from_typed(&res);
                     return Ok(ExecResult::Ok);
                 }
             };
 
+            check_stack(store, stack, func_inst.owner, &wasm_func)?;
             let params = stack.values.pop_n_rev(wasm_func.ty.params.len())?;
-            let call_frame = CallFrame::new(wasm_func, func_inst.owner, params, stack.blocks.len());
+            let call_frame =
+                CallFrame::new(wasm_func, func_idx, func_inst.owner, params, stack.blocks.len(), &mut stack.locals);
 
             // push the call frame
             cf.instr_ptr += 1; // skip the call instruction
@@ -137,51 +243,57 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
 
             // call the function
             return Ok(ExecResult::Call);
-        }
+        },
 
         CallIndirect(type_addr, table_addr) => {
-            let table = store.get_table(module.resolve_table_addr(*table_addr) as usize)?;
+            let table = store.get_table(module.resolve_table_addr((*table_addr).into()) as usize)?;
             let table_idx = stack.values.pop_t::<u32>()?;
 
             // verify that the table is of the right type, this should be validated by the parser already
             let func_ref = {
                 let table = table.borrow();
                 assert!(table.kind.element_type == ValType::RefFunc, "table is not of type funcref");
-                table.get(table_idx as usize)?.addr().ok_or(Trap::UninitializedElement { index: table_idx as usize })?
+                let table_idx = to_index(table_idx);
+                table.get(table_idx)?.addr().ok_or(Trap::UninitializedElement { index: table_idx })?
             };
 
-            let func_inst = store.get_func(func_ref as usize)?.clone();
-            let call_ty = module.func_ty(*type_addr);
+            // a call site that called the same function before doesn't need to look it up or check its type again
+            let site = (cf.func_addr, cf.instr_ptr);
+            let (wasm_func, owner) = match store.indirect_calls.get(site, func_ref) {
+                Some(cached) => cached,
+                None => {
+                    store.translate_func(func_ref)?;
+                    let func_inst = store.get_func(func_ref as usize)?.clone();
+                    if unlikely(func_inst.type_id != module.type_id(*type_addr)) {
+                        let call_ty = module.func_ty(*type_addr);
+                        let actual = func_inst.func.ty().clone();
+                        let name = store.func_name(func_ref).map(Into::into);
+                        let func = store.func_index(func_ref).map(|idx| (idx, name));
+                        return Err(Trap::IndirectCallTypeMismatch { actual, expected: call_ty.clone(), func }.into());
+                    }
 
-            let wasm_func = match func_inst.func {
-                crate::Function::Wasm(ref f) => f.clone(),
-                crate::Function::Host(host_func) => {
-                    if unlikely(host_func.ty != *call_ty) {
-                        log::error!("indirect call type mismatch: {:?} != {:?}", host_func.ty, call_ty);
-                        return Err(Trap::IndirectCallTypeMismatch {
-                            actual: host_func.ty.clone(),
-                            expected: call_ty.clone(),
+                    match func_inst.func {
+                        crate::Function::Wasm(f) => {
+                            store.indirect_calls.insert(site, func_ref, f.clone(), func_inst.owner);
+                            (f, func_inst.owner)
+                        }
+                        crate::Function::Host(host_func) => {
+                            let params = stack.values.pop_params(&host_func.ty.params)?;
+                            let frame = Some(Frame { cf, values: &stack.values, locals: &stack.locals });
+                            let ctx = FuncContext { store, module_addr: module.id(), frame };
+                            let res = host_func.call(ctx, &params)?;
+                            check_alive(store, module)?;
+                            stack.memory = None;
+                            stack.values.extend_from_typed(&res);
+                            return Ok(ExecResult::Ok);
                         }
-                        .into());
                     }
-
-                    let host_func = host_func.clone();
-                    let params = stack.values.pop_params(&host_func.ty.params)?;
-                    let res = (host_func.func)(FuncContext { store, module_addr: module.id() }, &params)?;
-                    stack.values.extend_from_typed(&res);
-                    return Ok(ExecResult::Ok);
                 }
             };
 
-            if unlikely(wasm_func.ty != *call_ty) {
-                log::error!("indirect call type mismatch: {:?} != {:?}", wasm_func.ty, call_ty);
-                return Err(
-                    Trap::IndirectCallTypeMismatch { actual: wasm_func.ty.clone(), expected: call_ty.clone() }.into()
-                );
-            }
-
+            check_stack(store, stack, owner, &wasm_func)?;
             let params = stack.values.pop_n_rev(wasm_func.ty.params.len())?;
-            let call_frame = CallFrame::new(wasm_func, func_inst.owner, params, stack.blocks.len());
+            let call_frame = CallFrame::new(wasm_func, func_ref, owner, params, stack.blocks.len(), &mut stack.locals);
 
             // push the call frame
             cf.instr_ptr += 1; // skip the call instruction
@@ -190,19 +302,26 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
 
             // call the function
             return Ok(ExecResult::Call);
-        }
+        },
+
+        If(args, offset) => {
+            // the offset points to the else block if there is one, which points to the end
+            let (else_offset, end_offset) = match cf.instructions()[cf.instr_ptr + *offset as usize] {
+                Else(end_offset) => (*offset as usize, *offset as usize + end_offset as usize),
+                _ => (0, *offset as usize),
+            };
 
-        If(args, else_offset, end_offset) => {
             // truthy value is on the top of the stack, so enter the then block
             if stack.values.pop_t::<i32>()? != 0 {
                 cf.enter_block(
                     BlockFrame::new(
                         cf.instr_ptr,
-                        cf.instr_ptr + *end_offset as usize,
+                        cf.instr_ptr + end_offset,
                         stack.values.len(),
                         BlockType::If,
                         &args.unpack(),
                         module,
+                        &cf.func_instance.0.constants,
                     ),
                     &mut stack.values,
                     &mut stack.blocks,
@@ -211,21 +330,22 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
             }
 
             // falsy value is on the top of the stack
-            if *else_offset != 0 {
+            if else_offset != 0 {
                 let label = BlockFrame::new(
-                    cf.instr_ptr + *else_offset as usize,
-                    cf.instr_ptr + *end_offset as usize,
+                    cf.instr_ptr + else_offset,
+                    cf.instr_ptr + end_offset,
                     stack.values.len(),
                     BlockType::Else,
                     &args.unpack(),
                     module,
+                    &cf.func_instance.0.constants,
                 );
-                cf.instr_ptr += *else_offset as usize;
+                cf.instr_ptr += else_offset;
                 cf.enter_block(label, &mut stack.values, &mut stack.blocks);
             } else {
-                cf.instr_ptr += *end_offset as usize;
+                cf.instr_ptr += end_offset;
             }
-        }
+        },
 
         Loop(args, end_offset) => {
             cf.enter_block(
@@ -234,13 +354,14 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
                     cf.instr_ptr + *end_offset as usize,
                     stack.values.len(),
                     BlockType::Loop,
-                    args,
+                    &args.unpack(),
                     module,
+                    &cf.func_instance.0.constants,
                 ),
                 &mut stack.values,
                 &mut stack.blocks,
             );
-        }
+        },
 
         Block(args, end_offset) => {
             cf.enter_block(
@@ -249,45 +370,34 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
                     cf.instr_ptr + *end_offset as usize,
                     stack.values.len(),
                     BlockType::Block,
-                    args,
+                    &args.unpack(),
                     module,
+                    &cf.func_instance.0.constants,
                 ),
                 &mut stack.values,
                 &mut stack.blocks,
             );
-        }
+        },
 
-        BrTable(default, len) => {
-            let instr = cf.instructions()[cf.instr_ptr + 1..cf.instr_ptr + 1 + *len as usize]
-                .iter()
-                .map(|i| match i {
-                    BrLabel(l) => Ok(*l),
-                    _ => {
-                        cold();
-                        panic!("Expected BrLabel, this should have been validated by the parser")
-                    }
-                })
-                .collect::<Result<Vec<_>>>()?;
-
-            if unlikely(instr.len() != *len as usize) {
-                panic!(
-                    "Expected {} BrLabel instructions, got {}, this should have been validated by the parser",
-                    len,
-                    instr.len()
-                );
-            }
+        BrTable(start) => {
+            // the number of labels, followed by the labels and the default label
+            let targets = cf.func_instance.0.br_table_targets.get(*start as usize..);
+            let Some((len, targets)) = targets.and_then(<[_]>::split_first) else {
+                cold();
+                panic!("br_table targets out of range, this should have been validated by the parser")
+            };
 
-            let idx = stack.values.pop_t::<i32>()? as usize;
-            let to = instr.get(idx).unwrap_or(default);
+            let idx = to_index(stack.values.pop_t::<u32>()?).min(*len as usize);
+            let to = &targets[idx];
             break_to!(cf, stack, to);
-        }
+        },
 
         Br(v) => break_to!(cf, stack, v),
         BrIf(v) => {
             if stack.values.pop_t::<i32>()? != 0 {
                 break_to!(cf, stack, v);
             }
-        }
+        },
 
         Return => match stack.call_stack.is_empty() {
             true => return Ok(ExecResult::Return),
@@ -303,7 +413,7 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
                 true => return Ok(ExecResult::Return),
                 false => return Ok(ExecResult::Call),
             }
-        }
+        },
 
         // We're essentially using else as a EndBlockFrame instruction for if blocks
         Else(end_offset) => {
@@ -312,7 +422,7 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
 
             stack.values.truncate_keep(block.stack_ptr, block.results);
             cf.instr_ptr += *end_offset as usize;
-        }
+        },
 
         // remove the label from the label stack
         EndBlockFrame => {
@@ -322,11 +432,12 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
                 .expect("end blockframe: no label to end, this should have been validated by the parser");
 
             stack.values.truncate_keep(block.stack_ptr, block.results);
-        }
+        },
 
-        LocalGet(local_index) => stack.values.push(cf.get_local(*local_index as usize)),
-        LocalSet(local_index) => cf.set_local(*local_index as usize, stack.values.pop()?),
+        LocalGet(local_index) => stack.values.push(cf.get_local(&stack.locals, *local_index as usize)),
+        LocalSet(local_index) => cf.set_local(&mut stack.locals, *local_index as usize, stack.values.pop()?),
         LocalTee(local_index) => cf.set_local(
+            &mut stack.locals,
             *local_index as usize,
             *stack.values.last().expect("localtee: stack is empty. this should have been validated by the parser"),
         ),
@@ -335,17 +446,15 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
             let idx = module.resolve_global_addr(*global_index);
             let global = store.get_global_val(idx as usize)?;
             stack.values.push(global);
-        }
+        },
 
         GlobalSet(global_index) => {
             let idx = module.resolve_global_addr(*global_index);
             store.set_global_val(idx as usize, stack.values.pop()?)?;
-        }
+        },
 
         I32Const(val) => stack.values.push((*val).into()),
-        I64Const(val) => stack.values.push((*val).into()),
-        F32Const(val) => stack.values.push((*val).into()),
-        F64Const(val) => stack.values.push((*val).into()),
+        I64Const(val) => stack.values.push(cf.constant(*val).into()),
 
         MemorySize(addr, byte) => {
             if unlikely(*byte != 0) {
@@ -355,7 +464,7 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
             let mem_idx = module.resolve_mem_addr(*addr);
             let mem = store.get_mem(mem_idx as usize)?;
             stack.values.push((mem.borrow().page_count() as i32).into());
-        }
+        },
 
         MemoryGrow(addr, byte) => {
             if unlikely(*byte != 0) {
@@ -363,160 +472,156 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
             }
 
             let mem_idx = module.resolve_mem_addr(*addr);
-            let mem = store.get_mem(mem_idx as usize)?;
+            let mem = store.get_mem(mem_idx as usize)?.clone();
+
+            let delta = stack.values.pop_t::<u32>()?;
+            let (owner, current) = {
+                let mem = mem.borrow();
+                (mem.owner, mem.data.len())
+            };
+            let allowed = pages_to_bytes(delta as u64).is_some_and(|bytes| {
+                let desired = current.saturating_add(bytes);
+                store.allow_growth(ResourceRequest { instance: owner, kind: ResourceKind::Memory, current, desired })
+            });
 
             let (res, prev_size) = {
                 let mut mem = mem.borrow_mut();
                 let prev_size = mem.page_count() as i32;
-                (mem.grow(stack.values.pop_t::<i32>()?), prev_size)
+                (if allowed { mem.grow(delta) } else { None }, prev_size)
             };
 
             match res {
-                Some(_) => stack.values.push(prev_size.into()),
+                Some(_) => {
+                    let new_pages = mem.borrow().page_count();
+                    store.emit(StoreEvent::MemoryGrown {
+                        addr: mem_idx,
+                        owner,
+                        old_pages: prev_size as usize,
+                        new_pages,
+                    });
+                    stack.values.push(prev_size.into())
+                }
                 None => stack.values.push((-1).into()),
             }
-        }
+        },
 
         // Bulk memory operations
         MemoryCopy(from, to) => {
-            let size: i32 = stack.values.pop()?.into();
-            let src: i32 = stack.values.pop()?.into();
-            let dst: i32 = stack.values.pop()?.into();
+            let size = to_index(stack.values.pop_t::<u32>()?);
+            let src = to_index(stack.values.pop_t::<u32>()?);
+            let dst = to_index(stack.values.pop_t::<u32>()?);
 
-            let mem = store.get_mem(module.resolve_mem_addr(*from) as usize)?;
+            let mem = store.get_mem(module.resolve_mem_addr((*from).into()) as usize)?;
             let mut mem = mem.borrow_mut();
 
             if from == to {
                 // copy within the same memory
-                mem.copy_within(dst as usize, src as usize, size as usize)?;
+                mem.copy_within(dst, src, size)?;
             } else {
                 // copy between two memories
-                let mem2 = store.get_mem(module.resolve_mem_addr(*to) as usize)?;
+                let mem2 = store.get_mem(module.resolve_mem_addr((*to).into()) as usize)?;
                 let mut mem2 = mem2.borrow_mut();
-                mem2.copy_from_slice(dst as usize, mem.load(src as usize, size as usize)?)?;
+                mem2.copy_from_slice(dst, mem.load(src, size)?)?;
             }
-        }
+        },
 
         MemoryFill(addr) => {
-            let size: i32 = stack.values.pop()?.into();
+            let size = to_index(stack.values.pop_t::<u32>()?);
             let val: i32 = stack.values.pop()?.into();
-            let dst: i32 = stack.values.pop()?.into();
+            let dst = to_index(stack.values.pop_t::<u32>()?);
 
             let mem = store.get_mem(module.resolve_mem_addr(*addr) as usize)?;
             let mut mem = mem.borrow_mut();
-            mem.fill(dst as usize, size as usize, val as u8)?;
-        }
+            mem.fill(dst, size, val as u8)?;
+        },
 
         MemoryInit(data_index, mem_index) => {
-            let size = stack.values.pop_t::<i32>()? as usize;
-            let offset = stack.values.pop_t::<i32>()? as usize;
-            let dst = stack.values.pop_t::<i32>()? as usize;
+            let size = to_index(stack.values.pop_t::<u32>()?);
+            let offset = to_index(stack.values.pop_t::<u32>()?);
+            let dst = to_index(stack.values.pop_t::<u32>()?);
 
-            let data = match &store.get_data(module.resolve_data_addr(*data_index) as usize)?.data {
-                Some(data) => data,
-                None => return Err(Trap::MemoryOutOfBounds { offset: 0, len: 0, max: 0 }.into()),
-            };
+            // dropped segments behave like empty ones
+            let data = store.get_data(module.resolve_data_addr(*data_index) as usize)?.data.as_deref().unwrap_or(&[]);
 
-            if unlikely(offset + size > data.len()) {
+            if unlikely(offset.saturating_add(size) > data.len()) {
                 return Err(Trap::MemoryOutOfBounds { offset, len: size, max: data.len() }.into());
             }
 
-            let mem = store.get_mem(module.resolve_mem_addr(*mem_index) as usize)?;
+            let mem = store.get_mem(module.resolve_mem_addr((*mem_index).into()) as usize)?;
             let mut mem = mem.borrow_mut();
 
             // mem.store checks bounds
             mem.store(dst, size, &data[offset..(offset + size)])?;
-        }
+        },
 
         DataDrop(data_index) => {
             let data_idx = module.resolve_data_addr(*data_index);
             let data = store.get_data_mut(data_idx as usize)?;
             data.drop();
-        }
+        },
 
-        I32Store { mem_addr, offset } => mem_store!(i32, (mem_addr, offset), stack, store, module),
-        I64Store { mem_addr, offset } => mem_store!(i64, (mem_addr, offset), stack, store, module),
-        F32Store { mem_addr, offset } => mem_store!(f32, (mem_addr, offset), stack, store, module),
-        F64Store { mem_addr, offset } => mem_store!(f64, (mem_addr, offset), stack, store, module),
-        I32Store8 { mem_addr, offset } => mem_store!(i8, i32, (mem_addr, offset), stack, store, module),
-        I32Store16 { mem_addr, offset } => mem_store!(i16, i32, (mem_addr, offset), stack, store, module),
-        I64Store8 { mem_addr, offset } => mem_store!(i8, i64, (mem_addr, offset), stack, store, module),
-        I64Store16 { mem_addr, offset } => mem_store!(i16, i64, (mem_addr, offset), stack, store, module),
-        I64Store32 { mem_addr, offset } => mem_store!(i32, i64, (mem_addr, offset), stack, store, module),
-
-        I32Load { mem_addr, offset } => mem_load!(i32, (mem_addr, offset), stack, store, module),
-        I64Load { mem_addr, offset } => mem_load!(i64, (mem_addr, offset), stack, store, module),
-        F32Load { mem_addr, offset } => mem_load!(f32, (mem_addr, offset), stack, store, module),
-        F64Load { mem_addr, offset } => mem_load!(f64, (mem_addr, offset), stack, store, module),
-        I32Load8S { mem_addr, offset } => mem_load!(i8, i32, (mem_addr, offset), stack, store, module),
-        I32Load8U { mem_addr, offset } => mem_load!(u8, i32, (mem_addr, offset), stack, store, module),
-        I32Load16S { mem_addr, offset } => mem_load!(i16, i32, (mem_addr, offset), stack, store, module),
-        I32Load16U { mem_addr, offset } => mem_load!(u16, i32, (mem_addr, offset), stack, store, module),
-        I64Load8S { mem_addr, offset } => mem_load!(i8, i64, (mem_addr, offset), stack, store, module),
-        I64Load8U { mem_addr, offset } => mem_load!(u8, i64, (mem_addr, offset), stack, store, module),
-        I64Load16S { mem_addr, offset } => mem_load!(i16, i64, (mem_addr, offset), stack, store, module),
-        I64Load16U { mem_addr, offset } => mem_load!(u16, i64, (mem_addr, offset), stack, store, module),
-        I64Load32S { mem_addr, offset } => mem_load!(i32, i64, (mem_addr, offset), stack, store, module),
-        I64Load32U { mem_addr, offset } => mem_load!(u32, i64, (mem_addr, offset), stack, store, module),
+        I32Store { mem_addr, offset } => mem_store!(i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Store { mem_addr, offset } => mem_store!(i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32Store8 { mem_addr, offset } => mem_store!(i8, i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32Store16 { mem_addr, offset } => mem_store!(i16, i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Store8 { mem_addr, offset } => mem_store!(i8, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Store16 { mem_addr, offset } => mem_store!(i16, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Store32 { mem_addr, offset } => mem_store!(i32, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+
+        I32Load { mem_addr, offset } => mem_load!(i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load { mem_addr, offset } => mem_load!(i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32Load8S { mem_addr, offset } => mem_load!(i8, i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32Load8U { mem_addr, offset } => mem_load!(u8, i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32Load16S { mem_addr, offset } => mem_load!(i16, i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32Load16U { mem_addr, offset } => mem_load!(u16, i32, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load8S { mem_addr, offset } => mem_load!(i8, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load8U { mem_addr, offset } => mem_load!(u8, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load16S { mem_addr, offset } => mem_load!(i16, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load16U { mem_addr, offset } => mem_load!(u16, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load32S { mem_addr, offset } => mem_load!(i32, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I64Load32U { mem_addr, offset } => mem_load!(u32, i64, (mem_addr, cf.constant(*offset)), stack, store, module),
+        I32StoreStatic { addr, mem_addr } => mem_store_static!(i32, (mem_addr, addr), stack, store, module),
+        I64StoreStatic { addr, mem_addr } => mem_store_static!(i64, (mem_addr, addr), stack, store, module),
+        I32LoadStatic { addr, mem_addr } => mem_load_static!(i32, (mem_addr, addr), stack, store, module),
+        I64LoadStatic { addr, mem_addr } => mem_load_static!(i64, (mem_addr, addr), stack, store, module),
 
         I64Eqz => comp_zero!(==, i64, stack),
         I32Eqz => comp_zero!(==, i32, stack),
 
         I32Eq => comp!(==, i32, stack),
         I64Eq => comp!(==, i64, stack),
-        F32Eq => comp!(==, f32, stack),
-        F64Eq => comp!(==, f64, stack),
 
         I32Ne => comp!(!=, i32, stack),
         I64Ne => comp!(!=, i64, stack),
-        F32Ne => comp!(!=, f32, stack),
-        F64Ne => comp!(!=, f64, stack),
 
         I32LtS => comp!(<, i32, stack),
         I64LtS => comp!(<, i64, stack),
         I32LtU => comp!(<, u32, stack),
         I64LtU => comp!(<, u64, stack),
-        F32Lt => comp!(<, f32, stack),
-        F64Lt => comp!(<, f64, stack),
 
         I32LeS => comp!(<=, i32, stack),
         I64LeS => comp!(<=, i64, stack),
         I32LeU => comp!(<=, u32, stack),
         I64LeU => comp!(<=, u64, stack),
-        F32Le => comp!(<=, f32, stack),
-        F64Le => comp!(<=, f64, stack),
 
         I32GeS => comp!(>=, i32, stack),
         I64GeS => comp!(>=, i64, stack),
         I32GeU => comp!(>=, u32, stack),
         I64GeU => comp!(>=, u64, stack),
-        F32Ge => comp!(>=, f32, stack),
-        F64Ge => comp!(>=, f64, stack),
 
         I32GtS => comp!(>, i32, stack),
         I64GtS => comp!(>, i64, stack),
         I32GtU => comp!(>, u32, stack),
         I64GtU => comp!(>, u64, stack),
-        F32Gt => comp!(>, f32, stack),
-        F64Gt => comp!(>, f64, stack),
 
         I64Add => arithmetic!(wrapping_add, i64, stack),
         I32Add => arithmetic!(wrapping_add, i32, stack),
-        F32Add => arithmetic!(+, f32, stack),
-        F64Add => arithmetic!(+, f64, stack),
 
         I32Sub => arithmetic!(wrapping_sub, i32, stack),
         I64Sub => arithmetic!(wrapping_sub, i64, stack),
-        F32Sub => arithmetic!(-, f32, stack),
-        F64Sub => arithmetic!(-, f64, stack),
-
-        F32Div => arithmetic!(/, f32, stack),
-        F64Div => arithmetic!(/, f64, stack),
 
         I32Mul => arithmetic!(wrapping_mul, i32, stack),
         I64Mul => arithmetic!(wrapping_mul, i64, stack),
-        F32Mul => arithmetic!(*, f32, stack),
-        F64Mul => arithmetic!(*, f64, stack),
 
         // these can trap
         I32DivS => checked_int_arithmetic!(checked_div, i32, stack),
@@ -553,14 +658,6 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
         I32Popcnt => arithmetic_single!(count_ones, i32, stack),
         I64Popcnt => arithmetic_single!(count_ones, i64, stack),
 
-        F32ConvertI32S => conv!(i32, f32, stack),
-        F32ConvertI64S => conv!(i64, f32, stack),
-        F64ConvertI32S => conv!(i32, f64, stack),
-        F64ConvertI64S => conv!(i64, f64, stack),
-        F32ConvertI32U => conv!(u32, f32, stack),
-        F32ConvertI64U => conv!(u64, f32, stack),
-        F64ConvertI32U => conv!(u32, f64, stack),
-        F64ConvertI64U => conv!(u64, f64, stack),
         I32Extend8S => conv!(i8, i32, stack),
         I32Extend16S => conv!(i16, i32, stack),
         I64Extend8S => conv!(i8, i64, stack),
@@ -570,67 +667,67 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
         I64ExtendI32S => conv!(i32, i64, stack),
         I32WrapI64 => conv!(i64, i32, stack),
 
-        F32DemoteF64 => conv!(f64, f32, stack),
-        F64PromoteF32 => conv!(f32, f64, stack),
-
-        F32Abs => arithmetic_single!(abs, f32, stack),
-        F64Abs => arithmetic_single!(abs, f64, stack),
-        F32Neg => arithmetic_single!(neg, f32, stack),
-        F64Neg => arithmetic_single!(neg, f64, stack),
-        F32Ceil => arithmetic_single!(ceil, f32, stack),
-        F64Ceil => arithmetic_single!(ceil, f64, stack),
-        F32Floor => arithmetic_single!(floor, f32, stack),
-        F64Floor => arithmetic_single!(floor, f64, stack),
-        F32Trunc => arithmetic_single!(trunc, f32, stack),
-        F64Trunc => arithmetic_single!(trunc, f64, stack),
-        F32Nearest => arithmetic_single!(tw_nearest, f32, stack),
-        F64Nearest => arithmetic_single!(tw_nearest, f64, stack),
-        F32Sqrt => arithmetic_single!(sqrt, f32, stack),
-        F64Sqrt => arithmetic_single!(sqrt, f64, stack),
-        F32Min => arithmetic!(tw_minimum, f32, stack),
-        F64Min => arithmetic!(tw_minimum, f64, stack),
-        F32Max => arithmetic!(tw_maximum, f32, stack),
-        F64Max => arithmetic!(tw_maximum, f64, stack),
-        F32Copysign => arithmetic!(copysign, f32, stack),
-        F64Copysign => arithmetic!(copysign, f64, stack),
-
-        // no-op instructions since types are erased at runtime
-        I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => {}
-
-        // unsigned versions of these are a bit broken atm
-        I32TruncF32S => checked_conv_float!(f32, i32, stack),
-        I32TruncF64S => checked_conv_float!(f64, i32, stack),
-        I32TruncF32U => checked_conv_float!(f32, u32, i32, stack),
-        I32TruncF64U => checked_conv_float!(f64, u32, i32, stack),
-        I64TruncF32S => checked_conv_float!(f32, i64, stack),
-        I64TruncF64S => checked_conv_float!(f64, i64, stack),
-        I64TruncF32U => checked_conv_float!(f32, u64, i64, stack),
-        I64TruncF64U => checked_conv_float!(f64, u64, i64, stack),
-
         TableGet(table_index) => {
             let table_idx = module.resolve_table_addr(*table_index);
             let table = store.get_table(table_idx as usize)?;
-            let idx = stack.values.pop_t::<i32>()? as usize;
-            let v = table.borrow().get_wasm_val(idx)?;
-            stack.values.push(v.into());
-        }
+            let idx = to_index(stack.values.pop_t::<u32>()?);
+            let table = table.borrow();
+
+            // out of bounds accesses trap with a table error instead of an undefined element
+            if unlikely(idx >= table.size() as usize) {
+                return Err(Trap::TableOutOfBounds { offset: idx, len: 1, max: table.size() as usize }.into());
+            }
+
+            stack.values.push(table.get_wasm_val(idx)?.into());
+        },
 
         TableSet(table_index) => {
             let table_idx = module.resolve_table_addr(*table_index);
-            let table = store.get_table(table_idx as usize)?;
-            let val = stack.values.pop_t::<u32>()?;
-            let idx = stack.values.pop_t::<u32>()? as usize;
-            table.borrow_mut().set(idx, val)?;
-        }
+            let val = stack.values.pop_t::<i64>()?;
+            let idx = to_index(stack.values.pop_t::<u32>()?);
+            let val = if val < 0 { None } else { Some(val as Addr) };
+
+            let (owner, old_size) = {
+                let table = store.get_table(table_idx as usize)?.borrow();
+                (table.owner, table.size() as usize)
+            };
+
+            // growing the table to fit the element is limited like `memory.grow`
+            if unlikely(idx >= old_size) && store.has_limits() {
+                let bytes = |elements: usize| elements.saturating_mul(core::mem::size_of::<TableElement>());
+                let (current, desired) = (bytes(old_size), bytes(idx.saturating_add(1)));
+                let request = ResourceRequest { instance: owner, kind: ResourceKind::Table, current, desired };
+                if !store.allow_growth(request) {
+                    return Err(Trap::TableOutOfBounds { offset: idx, len: 1, max: old_size }.into());
+                }
+            }
+
+            let mut table = store.get_table(table_idx as usize)?.borrow_mut();
+            table.set(idx, val)?;
+            let new_size = table.size() as usize;
+            if unlikely(new_size != old_size) {
+                store.emit(StoreEvent::TableGrown { addr: table_idx, owner: table.owner, old_size, new_size });
+            }
+        },
+
+        RefNull(_) => stack.values.push((-1i64).into()),
+        RefIsNull => {
+            let val = stack.values.pop_t::<i64>()?;
+            stack.values.push(((val < 0) as i32).into());
+        },
+        RefFunc(func_index) => {
+            let func_addr = module.resolve_func_addr(*func_index);
+            stack.values.push((func_addr as i64).into());
+        },
 
         TableSize(table_index) => {
             let table_idx = module.resolve_table_addr(*table_index);
             let table = store.get_table(table_idx as usize)?;
             stack.values.push(table.borrow().size().into());
-        }
+        },
 
-        TableInit(table_index, elem_index) => {
-            let table_idx = module.resolve_table_addr(*table_index);
+        TableInit(elem_index, table_index) => {
+            let table_idx = module.resolve_table_addr((*table_index).into());
             let table = store.get_table(table_idx as usize)?;
 
             let elem_idx = module.resolve_elem_addr(*elem_index);
@@ -645,65 +742,210 @@ fn exec_one(cf: &mut CallFrame, stack: &mut Stack, store: &mut Store, module: &M
             };
 
             table.borrow_mut().init(module.func_addrs(), 0, items)?;
-        }
-
-        I32TruncSatF32S => arithmetic_single!(trunc, f32, i32, stack),
-        I32TruncSatF32U => arithmetic_single!(trunc, f32, u32, stack),
-        I32TruncSatF64S => arithmetic_single!(trunc, f64, i32, stack),
-        I32TruncSatF64U => arithmetic_single!(trunc, f64, u32, stack),
-        I64TruncSatF32S => arithmetic_single!(trunc, f32, i64, stack),
-        I64TruncSatF32U => arithmetic_single!(trunc, f32, u64, stack),
-        I64TruncSatF64S => arithmetic_single!(trunc, f64, i64, stack),
-        I64TruncSatF64U => arithmetic_single!(trunc, f64, u64, stack),
+        },
 
         // custom instructions
         LocalGet2(a, b) => {
-            stack.values.extend_from_slice(&[cf.get_local(*a as usize), cf.get_local(*b as usize)]);
-        }
+            let (a, b) = (cf.get_local(&stack.locals, *a as usize), cf.get_local(&stack.locals, *b as usize));
+            stack.values.extend_from_slice(&[a, b]);
+        },
         LocalGet3(a, b, c) => {
             stack.values.extend_from_slice(&[
-                cf.get_local(*a as usize),
-                cf.get_local(*b as usize),
-                cf.get_local(*c as usize),
+                cf.get_local(&stack.locals, *a as usize),
+                cf.get_local(&stack.locals, *b as usize),
+                cf.get_local(&stack.locals, *c as usize),
             ]);
-        }
+        },
         // LocalGet4(a, b, c, d) => {
         //     stack.values.extend_from_slice(&[
-        //         cf.get_local(*a as usize),
-        //         cf.get_local(*b as usize),
-        //         cf.get_local(*c as usize),
-        //         cf.get_local(*d as usize),
+        //         cf.get_local(&stack.locals, *a as usize),
+        //         cf.get_local(&stack.locals, *b as usize),
+        //         cf.get_local(&stack.locals, *c as usize),
+        //         cf.get_local(&stack.locals, *d as usize),
         //     ]);
         // }
         LocalTeeGet(a, b) => {
             #[inline]
-            fn local_tee_get(cf: &mut CallFrame, stack: &mut Stack, a: u32, b: u32) -> Result<()> {
+            fn local_tee_get(cf: &mut CallFrame, stack: &mut Stack, a: u16, b: u16) -> Result<()> {
                 let last = *stack
                     .values
                     .last()
                     .expect("localtee: stack is empty. this should have been validated by the parser");
-                cf.set_local(a as usize, last);
-                stack.values.push(cf.get_local(b as usize));
+                cf.set_local(&mut stack.locals, a as usize, last);
+                stack.values.push(cf.get_local(&stack.locals, b as usize));
                 Ok(())
             }
             local_tee_get(cf, stack, *a, *b)?;
-        }
+        },
         LocalGetSet(a, b) => {
-            let a = cf.get_local(*a as usize);
-            cf.set_local(*b as usize, a);
-        }
+            let a = cf.get_local(&stack.locals, *a as usize);
+            cf.set_local(&mut stack.locals, *b as usize, a);
+        },
+        I32LocalGetConstAdd(local, val) => {
+            let local: i32 = cf.get_local(&stack.locals, *local as usize).into();
+            stack.values.push(local.wrapping_add(*val).into());
+        },
+        I32AddLocals(a, b) => local_binop!(i32, |a, b| a.wrapping_add(b), (a, b), cf, stack),
+        I32SubLocals(a, b) => local_binop!(i32, |a, b| a.wrapping_sub(b), (a, b), cf, stack),
+        I32LtSLocals(a, b) => local_binop!(i32, |a, b| (a < b) as i32, (a, b), cf, stack),
+        I32LtULocals(a, b) => local_binop!(u32, |a, b| (a < b) as i32, (a, b), cf, stack),
+        I32LtSLocalConst(local, c) => {
+            let local: i32 = cf.get_local(&stack.locals, *local as usize).into();
+            stack.values.push(((local < *c) as i32).into());
+        },
+        I32LtULocalConst(local, c) => {
+            let local: u32 = cf.get_local(&stack.locals, *local as usize).into();
+            stack.values.push(((local < *c as u32) as i32).into());
+        },
+        I32StoreLocal { local, offset, mem_addr } => {
+            let I32Const(val) = cf.instructions()[cf.instr_ptr + 1] else {
+                cold();
+                panic!("Expected I32Const after I32StoreLocal, this should have been validated by the parser")
+            };
+
+            let addr: u32 = cf.get_local(&stack.locals, *local as usize).into();
+            let mem = memory(&mut stack.memory, store, module, (*mem_addr).into())?;
+            let mut mem_ref = mem.borrow_mut();
+            let addr = mem_ref.effective_addr(addr, *offset as u64, 4)?;
+            mem_ref.store(addr, 4, &val.to_le_bytes())?;
+
+            // skip the value
+            cf.instr_ptr += 1;
+        },
+        I32AddConst(c) => stack.values.replace_top(|v| i32::from(v).wrapping_add(*c).into()),
+        I32SubConst(c) => stack.values.replace_top(|v| i32::from(v).wrapping_sub(*c).into()),
+        I64AddConst(c) => {
+            let c = cf.constant(*c) as i64;
+            stack.values.replace_top(|v| i64::from(v).wrapping_add(c).into())
+        },
+        I64SubConst(c) => {
+            let c = cf.constant(*c) as i64;
+            stack.values.replace_top(|v| i64::from(v).wrapping_sub(c).into())
+        },
+        I32EqConst(c) => stack.values.replace_top(|v| ((i32::from(v) == *c) as i32).into()),
+        I32NeConst(c) => stack.values.replace_top(|v| ((i32::from(v) != *c) as i32).into()),
+        I32LtSConst(c) => stack.values.replace_top(|v| ((i32::from(v) < *c) as i32).into()),
+        I32LtUConst(c) => stack.values.replace_top(|v| ((u32::from(v) < *c as u32) as i32).into()),
+        I32GtSConst(c) => stack.values.replace_top(|v| ((i32::from(v) > *c) as i32).into()),
+        I32GtUConst(c) => stack.values.replace_top(|v| ((u32::from(v) > *c as u32) as i32).into()),
+        I32EqzBrIf(v) => {
+            if stack.values.pop_t::<i32>()? == 0 {
+                break_to!(cf, stack, v);
+            }
+        },
         I64XorConstRotl(rotate_by) => {
             let val = stack.values.pop_t::<i64>()?;
             let mask = stack.values.pop_t::<i64>()?;
             let res = val ^ mask;
-            stack.values.push(res.rotate_left(*rotate_by as u32).into());
-        }
-        i => {
-            cold();
-            log::error!("unimplemented instruction: {:?}", i);
-            return Err(Error::UnsupportedFeature(alloc::format!("unimplemented instruction: {:?}", i)));
-        }
-    };
+            stack.values.push(res.rotate_left(cf.constant(*rotate_by) as u32).into());
+        },
+    }
+}
+
+#[cfg(feature = "dispatch-table")]
+type Handler = fn(&mut CallFrame, &mut Stack, &mut Store, &ModuleInstance) -> Result<ExecResult>;
+
+#[inline(always)]
+fn check_instr_ptr(cf: &CallFrame) -> Result<()> {
+    let instrs = cf.instructions();
+
+    if unlikely(cf.instr_ptr >= instrs.len() || instrs.is_empty()) {
+        log::error!("instr_ptr out of bounds: {} >= {}", cf.instr_ptr, instrs.len());
+        return Err(Error::Other(format!("instr_ptr out of bounds: {} >= {}", cf.instr_ptr, instrs.len())));
+    }
+    Ok(())
+}
+
+// the instructions without an arm in `exec_one`
+#[cfg(not(feature = "no-float"))]
+use float::exec_float as exec_other;
+
+#[cfg(feature = "no-float")]
+fn exec_other(
+    instr: &tinywasm_types::Instruction,
+    _: &CallFrame,
+    _: &mut Stack,
+    _: &Store,
+    _: &ModuleInstance,
+) -> Result<()> {
+    Err(unsupported(instr))
+}
 
-    Ok(ExecResult::Ok)
+#[cold]
+fn unsupported(instr: &tinywasm_types::Instruction) -> Error {
+    log::error!("unimplemented instruction: {:?}", instr);
+    Error::UnsupportedFeature(alloc::format!("unimplemented instruction: {:?}", instr))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Imports, Module, Result, Store};
+    use alloc::boxed::Box;
+    use tinywasm_types::{ExternalKind, FuncType, ImportKind, Instruction::*, MemoryType, ModuleBuilder, ValType};
+
+    #[test]
+    fn test_fused_locals() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let results = Box::new([ValType::I32; 6]);
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I32, ValType::I32]), results });
+        let instructions = [
+            I32AddLocals(0, 1),
+            I32SubLocals(0, 1),
+            I32LtSLocals(0, 1),
+            I32LtULocals(0, 1),
+            I32LtSLocalConst(0, 0),
+            I32LtULocalConst(0, 0),
+            EndFunc,
+        ];
+        let run = builder.add_function(ty, [], instructions);
+        builder.add_export("run", ExternalKind::Func, run);
+        let mut store = Store::default();
+        let instance = Module::from(builder.finish().expect("valid module")).instantiate(&mut store, None)?;
+
+        // -1 is the largest unsigned value
+        let run = instance.exported_func::<(i32, i32), (i32, i32, i32, i32, i32, i32)>(&store, "run")?;
+        assert_eq!(run.call(&mut store, (-1, 2))?, (1, -3, 1, 0, 1, 0));
+        assert_eq!(run.call(&mut store, (i32::MAX, 1))?, (i32::MIN, i32::MAX - 1, 0, 0, 0, 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_across_modules() -> Result<()> {
+        // `set` stores its param at address 0 of its own memory
+        let mut b = ModuleBuilder::new();
+        let ty = b.add_type(FuncType { params: Box::new([ValType::I32]), results: Box::new([]) });
+        b.add_memory(MemoryType::new_32(1, None));
+        let set = [I32Const(0), LocalGet(0), I32Store { offset: 0, mem_addr: 0 }, EndFunc];
+        let set = b.add_function_with_constants(ty, [], set, [0]);
+        b.add_export("set", ExternalKind::Func, set).add_export("memory", ExternalKind::Memory, 0);
+        let mut store = Store::default();
+        let b = Module::from(b.finish().expect("valid module")).instantiate(&mut store, None)?;
+
+        // `run` stores 1 in its memory, calls `set` with 2 and loads from its memory again
+        let mut a = ModuleBuilder::new();
+        let set_ty = a.add_type(FuncType { params: Box::new([ValType::I32]), results: Box::new([]) });
+        let run_ty = a.add_type(FuncType { params: Box::new([]), results: Box::new([ValType::I32]) });
+        let set = a.add_import("b", "set", ImportKind::Function(set_ty));
+        a.add_memory(MemoryType::new_32(1, None));
+        let instructions = [
+            I32Const(0),
+            I32Const(1),
+            I32Store { offset: 0, mem_addr: 0 },
+            I32Const(2),
+            Call(set),
+            I32Const(0),
+            I32Load { offset: 0, mem_addr: 0 },
+            EndFunc,
+        ];
+        let run = a.add_function_with_constants(run_ty, [], instructions, [0]);
+        a.add_export("run", ExternalKind::Func, run);
+        let mut imports = Imports::new();
+        imports.link_module("b", b.id())?;
+        let a = Module::from(a.finish().expect("valid module")).instantiate(&mut store, Some(imports))?;
+
+        // the call switches to the memory of `b` and back
+        assert_eq!(a.exported_func::<(), i32>(&store, "run")?.call(&mut store, ())?, 1);
+        assert_eq!(b.exported_memory(&mut store, "memory")?.load(0, 4)?, 2i32.to_le_bytes());
+        Ok(())
+    }
 }
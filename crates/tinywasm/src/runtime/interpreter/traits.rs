@@ -5,15 +5,17 @@ where
     fn checked_wrapping_rem(self, rhs: Self) -> Option<Self>;
 }
 
+#[cfg(not(feature = "no-float"))]
 pub(crate) trait TinywasmFloatExt {
     fn tw_minimum(self, other: Self) -> Self;
     fn tw_maximum(self, other: Self) -> Self;
     fn tw_nearest(self) -> Self;
 }
 
-#[cfg(not(feature = "std"))]
+#[cfg(all(not(feature = "std"), not(feature = "no-float")))]
 use super::no_std_floats::NoStdFloatExt;
 
+#[cfg(not(feature = "no-float"))]
 macro_rules! impl_wasm_float_ops {
     ($($t:ty)*) => ($(
         impl TinywasmFloatExt for $t {
@@ -64,6 +66,7 @@ macro_rules! impl_wasm_float_ops {
     )*)
 }
 
+#[cfg(not(feature = "no-float"))]
 impl_wasm_float_ops! { f32 f64 }
 
 pub(crate) trait WasmIntOps {
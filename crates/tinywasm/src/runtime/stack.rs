@@ -1,10 +1,17 @@
 mod block_stack;
 mod call_stack;
+mod local_stack;
 mod value_stack;
 
-use self::{call_stack::CallStack, value_stack::ValueStack};
+use self::call_stack::CallStack;
+use crate::runtime::RawWasmValue;
+use crate::store::MemoryInstance;
+use crate::sync::{Rc, RefCell};
 pub(crate) use block_stack::{BlockFrame, BlockStack, BlockType};
 pub(crate) use call_stack::CallFrame;
+pub(crate) use local_stack::LocalStack;
+use tinywasm_types::{FuncAddr, ModuleInstanceAddr, WasmFunction};
+pub(crate) use value_stack::ValueStack;
 
 /// A WebAssembly Stack
 #[derive(Debug)]
@@ -12,10 +19,55 @@ pub struct Stack {
     pub(crate) values: ValueStack,
     pub(crate) blocks: BlockStack,
     pub(crate) call_stack: CallStack,
+    pub(crate) locals: LocalStack,
+
+    // set when execution stopped because no fuel was left for the next instruction
+    pub(crate) out_of_fuel: bool,
+
+    // the first memory of the running function's module, see `interpreter::memory`
+    pub(crate) memory: Option<Rc<RefCell<MemoryInstance>>>,
 }
 
 impl Stack {
-    pub(crate) fn new(call_frame: CallFrame) -> Self {
-        Self { values: ValueStack::default(), blocks: BlockStack::default(), call_stack: CallStack::new(call_frame) }
+    /// Create a stack without an initial call frame
+    pub(crate) fn empty() -> Self {
+        Self {
+            values: ValueStack::default(),
+            blocks: BlockStack::default(),
+            call_stack: CallStack::default(),
+            locals: LocalStack::default(),
+            out_of_fuel: false,
+            memory: None,
+        }
+    }
+
+    /// Clear the stack (keeping its allocations) and push the frame of a call to `func`
+    pub(crate) fn reset(
+        &mut self,
+        func: Rc<WasmFunction>,
+        func_addr: FuncAddr,
+        owner: ModuleInstanceAddr,
+        params: impl Iterator<Item = RawWasmValue> + ExactSizeIterator,
+    ) {
+        self.clear();
+        self.call_stack.reset(CallFrame::new(func, func_addr, owner, params, 0, &mut self.locals));
+    }
+
+    /// The number of bytes used by the values, labels, frames and locals on the stack
+    pub(crate) fn size_in_bytes(&self) -> usize {
+        use core::mem::{size_of, size_of_val};
+        (self.values.len() + self.locals.len()) * size_of::<RawWasmValue>()
+            + self.blocks.len() * size_of::<BlockFrame>()
+            + size_of_val(self.call_stack.frames())
+    }
+
+    /// Clear the stack, keeping its allocations but dropping its references to functions and memories
+    pub(crate) fn clear(&mut self) {
+        self.values.clear();
+        self.blocks.clear();
+        self.locals.clear();
+        self.call_stack.clear();
+        self.out_of_fuel = false;
+        self.memory = None;
     }
 }
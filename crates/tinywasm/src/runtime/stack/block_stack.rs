@@ -11,6 +11,17 @@ impl BlockStack {
         self.0.len()
     }
 
+    /// The blocks on the stack, with the innermost block last
+    #[inline]
+    pub(crate) fn frames(&self) -> &[BlockFrame] {
+        &self.0
+    }
+
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
     #[inline]
     pub(crate) fn push(&mut self, block: BlockFrame) {
         self.0.push(block);
@@ -64,10 +75,16 @@ impl BlockFrame {
         ty: BlockType,
         args: &BlockArgs,
         module: &ModuleInstance,
+        constants: &[u64],
     ) -> Self {
         let (params, results) = match args {
             BlockArgs::Empty => (0, 0),
             BlockArgs::Type(_) => (0, 1),
+            BlockArgs::Arity { params, results } => (*params as usize, *results as usize),
+            BlockArgs::ArityConst(c) => {
+                let (params, results) = BlockArgs::unpack_arity(constants[*c as usize]);
+                (params as usize, results as usize)
+            }
             BlockArgs::FuncType(t) => {
                 let ty = module.func_ty(*t);
                 (ty.params.len(), ty.results.len())
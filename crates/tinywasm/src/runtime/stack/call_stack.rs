@@ -1,11 +1,12 @@
-use alloc::{boxed::Box, rc::Rc, vec::Vec};
-use tinywasm_types::{Instruction, ModuleInstanceAddr, WasmFunction};
+use alloc::vec::Vec;
+use tinywasm_types::{ConstAddr, FuncAddr, Instruction, ModuleInstanceAddr, WasmFunction};
 
 use crate::runtime::{BlockType, RawWasmValue};
-use crate::unlikely;
+use crate::sync::Rc;
+use crate::{cold, unlikely};
 use crate::{Error, Result, Trap};
 
-use super::BlockFrame;
+use super::{BlockFrame, LocalStack};
 
 const CALL_STACK_SIZE: usize = 128;
 const CALL_STACK_MAX_SIZE: usize = 1024;
@@ -15,12 +16,22 @@ pub(crate) struct CallStack {
     stack: Vec<CallFrame>,
 }
 
+impl Default for CallStack {
+    fn default() -> Self {
+        Self { stack: Vec::with_capacity(CALL_STACK_SIZE) }
+    }
+}
+
 impl CallStack {
     #[inline]
-    pub(crate) fn new(initial_frame: CallFrame) -> Self {
-        let mut stack = Self { stack: Vec::with_capacity(CALL_STACK_SIZE) };
-        stack.push(initial_frame).unwrap();
-        stack
+    pub(crate) fn reset(&mut self, initial_frame: CallFrame) {
+        self.stack.clear();
+        self.stack.push(initial_frame);
+    }
+
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.stack.clear();
     }
 
     #[inline]
@@ -28,6 +39,18 @@ impl CallStack {
         self.stack.is_empty()
     }
 
+    /// The frames on the call stack, with the innermost frame last
+    #[inline]
+    pub(crate) fn frames(&self) -> &[CallFrame] {
+        &self.stack
+    }
+
+    /// The innermost frame
+    #[inline]
+    pub(crate) fn top_mut(&mut self) -> Option<&mut CallFrame> {
+        self.stack.last_mut()
+    }
+
     #[inline]
     pub(crate) fn pop(&mut self) -> Result<CallFrame> {
         match self.stack.pop() {
@@ -51,7 +74,8 @@ pub(crate) struct CallFrame {
     pub(crate) instr_ptr: usize,
     pub(crate) block_ptr: usize,
     pub(crate) func_instance: (Rc<WasmFunction>, ModuleInstanceAddr),
-    pub(crate) locals: Box<[RawWasmValue]>,
+    pub(crate) func_addr: FuncAddr,
+    pub(crate) locals_ptr: usize,
 }
 
 impl CallFrame {
@@ -113,34 +137,40 @@ impl CallFrame {
         Some(())
     }
 
-    // TODO: perf: a lot of time is spent here
     #[inline(always)] // about 10% faster with this
     pub(crate) fn new(
         wasm_func_inst: Rc<WasmFunction>,
+        func_addr: FuncAddr,
         owner: ModuleInstanceAddr,
         params: impl Iterator<Item = RawWasmValue> + ExactSizeIterator,
         block_ptr: usize,
+        locals: &mut LocalStack,
     ) -> Self {
-        let locals = {
-            let local_types = &wasm_func_inst.locals;
-            let total_size = local_types.len() + params.len();
-            let mut locals = Vec::with_capacity(total_size);
-            locals.extend(params);
-            locals.resize_with(total_size, RawWasmValue::default);
-            locals.into_boxed_slice()
-        };
-
-        Self { instr_ptr: 0, func_instance: (wasm_func_inst, owner), locals, block_ptr }
+        let count = wasm_func_inst.ty.params.len() + wasm_func_inst.locals.len();
+        let locals_ptr = locals.push_frame(params, count);
+        Self { instr_ptr: 0, func_instance: (wasm_func_inst, owner), func_addr, locals_ptr, block_ptr }
     }
 
+    /// The number of parameters and locals of the function
     #[inline]
-    pub(crate) fn set_local(&mut self, local_index: usize, value: RawWasmValue) {
-        self.locals[local_index] = value;
+    pub(crate) fn local_count(&self) -> usize {
+        self.func_instance.0.ty.params.len() + self.func_instance.0.locals.len()
     }
 
+    /// The parameters and locals of this frame
     #[inline]
-    pub(crate) fn get_local(&self, local_index: usize) -> RawWasmValue {
-        self.locals[local_index]
+    pub(crate) fn locals<'a>(&self, locals: &'a LocalStack) -> &'a [RawWasmValue] {
+        locals.frame(self.locals_ptr, self.local_count())
+    }
+
+    #[inline(always)]
+    pub(crate) fn set_local(&self, locals: &mut LocalStack, local_index: usize, value: RawWasmValue) {
+        locals.set(self.locals_ptr + local_index, value);
+    }
+
+    #[inline(always)]
+    pub(crate) fn get_local(&self, locals: &LocalStack, local_index: usize) -> RawWasmValue {
+        locals.get(self.locals_ptr + local_index)
     }
 
     #[inline(always)]
@@ -152,4 +182,16 @@ impl CallFrame {
     pub(crate) fn current_instruction(&self) -> &Instruction {
         &self.func_instance.0.instructions[self.instr_ptr]
     }
+
+    /// A 64-bit constant or memory offset of the function, see [`WasmFunction::constants`]
+    #[inline(always)]
+    pub(crate) fn constant(&self, addr: ConstAddr) -> u64 {
+        match self.func_instance.0.constants.get(addr as usize) {
+            Some(constant) => *constant,
+            None => {
+                cold();
+                panic!("constant out of range, this should have been validated by the parser")
+            }
+        }
+    }
 }
@@ -0,0 +1,86 @@
+use crate::runtime::RawWasmValue;
+use alloc::vec::Vec;
+
+pub(crate) const MIN_LOCAL_STACK_SIZE: usize = 1024;
+
+/// The parameters and locals of all frames on the call stack
+///
+/// Each frame owns the range starting at its [`CallFrame::locals_ptr`](super::CallFrame::locals_ptr),
+/// with the innermost frame last, so calling a function only appends its locals
+/// and returning from it truncates them again.
+#[derive(Debug)]
+pub(crate) struct LocalStack {
+    locals: Vec<RawWasmValue>,
+}
+
+impl Default for LocalStack {
+    fn default() -> Self {
+        Self { locals: Vec::with_capacity(MIN_LOCAL_STACK_SIZE) }
+    }
+}
+
+impl LocalStack {
+    /// Push the locals of a new frame, returning their start
+    ///
+    /// The parameters are followed by zeroed locals, `count` values in total.
+    #[inline(always)]
+    pub(crate) fn push_frame(&mut self, params: impl ExactSizeIterator<Item = RawWasmValue>, count: usize) -> usize {
+        let ptr = self.locals.len();
+        self.locals.extend(params);
+        self.locals.resize(ptr + count, RawWasmValue::default());
+        ptr
+    }
+
+    #[inline(always)]
+    pub(crate) fn get(&self, index: usize) -> RawWasmValue {
+        self.locals[index]
+    }
+
+    #[inline(always)]
+    pub(crate) fn set(&mut self, index: usize, value: RawWasmValue) {
+        self.locals[index] = value;
+    }
+
+    /// The locals of a frame, starting at `ptr`
+    #[inline]
+    pub(crate) fn frame(&self, ptr: usize, count: usize) -> &[RawWasmValue] {
+        &self.locals[ptr..ptr + count]
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.locals.len()
+    }
+
+    #[inline]
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.locals.truncate(len);
+    }
+
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.locals.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_stack() {
+        let mut locals = LocalStack::default();
+        let caller = locals.push_frame([1.into(), 2.into()].into_iter(), 3);
+        let callee = locals.push_frame([3.into()].into_iter(), 2);
+        assert_eq!((caller, callee), (0, 3));
+
+        locals.set(callee + 1, 4.into());
+        assert_eq!(locals.frame(callee, 2), &[3.into(), 4.into()]);
+        assert_eq!(locals.frame(caller, 3), &[1.into(), 2.into(), RawWasmValue::default()]);
+
+        // returning drops the callee's locals
+        locals.truncate(callee);
+        assert_eq!(locals.push_frame([].into_iter(), 1), 3);
+        assert_eq!(locals.get(3), RawWasmValue::default());
+    }
+}
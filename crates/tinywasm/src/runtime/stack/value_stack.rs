@@ -48,6 +48,11 @@ impl ValueStack {
         self.stack.len()
     }
 
+    #[inline]
+    pub(crate) fn clear(&mut self) {
+        self.stack.clear();
+    }
+
     pub(crate) fn truncate_keep(&mut self, n: usize, end_keep: usize) {
         let total_to_keep = n + end_keep;
         let len = self.stack.len();
@@ -73,7 +73,7 @@ macro_rules! impl_from_raw_wasm_value {
             #[inline]
             fn from(value: $type) -> Self {
                 #[allow(clippy::redundant_closure_call)]
-                Self(u64::to_ne_bytes($to_raw(value)))
+                Self(u64::to_le_bytes($to_raw(value)))
             }
         }
 
@@ -92,20 +92,39 @@ type RawValue = u64;
 type RawValueRep = [u8; 8];
 
 // This all looks like a lot of extra steps, but the compiler will optimize it all away.
-impl_from_raw_wasm_value!(i32, |x| x as RawValue, |x: RawValueRep| i32::from_ne_bytes(x[0..4].try_into().unwrap()));
-impl_from_raw_wasm_value!(i64, |x| x as RawValue, |x: RawValueRep| i64::from_ne_bytes(x[0..8].try_into().unwrap()));
-impl_from_raw_wasm_value!(f32, |x| f32::to_bits(x) as RawValue, |x: RawValueRep| f32::from_bits(u32::from_ne_bytes(
+// Values are stored in little-endian order, so the low bytes come first on every host.
+impl_from_raw_wasm_value!(i32, |x| x as RawValue, |x: RawValueRep| i32::from_le_bytes(x[0..4].try_into().unwrap()));
+impl_from_raw_wasm_value!(i64, |x| x as RawValue, |x: RawValueRep| i64::from_le_bytes(x[0..8].try_into().unwrap()));
+impl_from_raw_wasm_value!(f32, |x| f32::to_bits(x) as RawValue, |x: RawValueRep| f32::from_bits(u32::from_le_bytes(
     x[0..4].try_into().unwrap()
 )));
-impl_from_raw_wasm_value!(f64, |x| f64::to_bits(x) as RawValue, |x: RawValueRep| f64::from_bits(u64::from_ne_bytes(
+impl_from_raw_wasm_value!(f64, |x| f64::to_bits(x) as RawValue, |x: RawValueRep| f64::from_bits(u64::from_le_bytes(
     x[0..8].try_into().unwrap()
 )));
 
-impl_from_raw_wasm_value!(u8, |x| x as RawValue, |x: RawValueRep| u8::from_ne_bytes(x[0..1].try_into().unwrap()));
-impl_from_raw_wasm_value!(u16, |x| x as RawValue, |x: RawValueRep| u16::from_ne_bytes(x[0..2].try_into().unwrap()));
-impl_from_raw_wasm_value!(u32, |x| x as RawValue, |x: RawValueRep| u32::from_ne_bytes(x[0..4].try_into().unwrap()));
-impl_from_raw_wasm_value!(u64, |x| x as RawValue, |x: RawValueRep| u64::from_ne_bytes(x[0..8].try_into().unwrap()));
-// impl_from_raw_wasm_value!(u128, |x| x, |x: RawValueRep| RawValue::from_ne_bytes(x));
+impl_from_raw_wasm_value!(u8, |x| x as RawValue, |x: RawValueRep| u8::from_le_bytes(x[0..1].try_into().unwrap()));
+impl_from_raw_wasm_value!(u16, |x| x as RawValue, |x: RawValueRep| u16::from_le_bytes(x[0..2].try_into().unwrap()));
+impl_from_raw_wasm_value!(u32, |x| x as RawValue, |x: RawValueRep| u32::from_le_bytes(x[0..4].try_into().unwrap()));
+impl_from_raw_wasm_value!(u64, |x| x as RawValue, |x: RawValueRep| u64::from_le_bytes(x[0..8].try_into().unwrap()));
+// impl_from_raw_wasm_value!(u128, |x| x, |x: RawValueRep| RawValue::from_le_bytes(x));
 
-impl_from_raw_wasm_value!(i8, |x| x as RawValue, |x: RawValueRep| i8::from_ne_bytes(x[0..1].try_into().unwrap()));
-impl_from_raw_wasm_value!(i16, |x| x as RawValue, |x: RawValueRep| i16::from_ne_bytes(x[0..2].try_into().unwrap()));
+impl_from_raw_wasm_value!(i8, |x| x as RawValue, |x: RawValueRep| i8::from_le_bytes(x[0..1].try_into().unwrap()));
+impl_from_raw_wasm_value!(i16, |x| x as RawValue, |x: RawValueRep| i16::from_le_bytes(x[0..2].try_into().unwrap()));
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_value_byte_order() {
+        // the layout doesn't depend on the host's byte order
+        assert_eq!(RawWasmValue::from(0x12345678i32).raw_value(), [0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0]);
+        assert_eq!(RawWasmValue::from(-2i32).raw_value(), (-2i64).to_le_bytes());
+        assert_eq!(RawWasmValue::from(1.5f32).raw_value()[..4], 1.5f32.to_bits().to_le_bytes());
+
+        assert_eq!(i32::from(RawWasmValue::from(-2i32)), -2);
+        assert_eq!(u16::from(RawWasmValue::from(0xabcdu16)), 0xabcd);
+        assert_eq!(f64::from(RawWasmValue::from(-0.25f64)), -0.25);
+        assert_eq!(RawWasmValue::from(WasmValue::F32(2.0)).attach_type(ValType::F32), WasmValue::F32(2.0));
+    }
+}
@@ -0,0 +1,777 @@
+use alloc::format;
+use alloc::vec::Vec;
+use tinywasm_types::{MemoryArch, MemoryType, TableType, ValType, WasmValue};
+
+use crate::store::{max_pages, pages_to_bytes, GlobalInstance, MemoryInstance, TableInstance, DIRTY_PAGE_SIZE};
+use crate::sync::{Rc, RefCell};
+use crate::{Error, GlobalsSnapshot, Imports, Module, ModuleInstance, Result, Store, TableElement};
+
+/// A snapshot of the state of a module instance, see [`ModuleInstance::snapshot`]
+///
+/// Memories are copy-on-write, so taking a snapshot is cheap and their data is only
+/// copied once the instance writes to them again.
+#[derive(Debug, Clone)]
+pub struct InstanceSnapshot {
+    pub(crate) memories: Vec<(u32, MemorySnapshot)>,
+    pub(crate) tables: Vec<(u32, TableSnapshot)>,
+    pub(crate) globals: GlobalsSnapshot,
+    pub(crate) dropped_elems: Vec<u32>,
+    pub(crate) dropped_datas: Vec<u32>,
+    // function references in tables are store addresses instead of indices in the module, see `Store::snapshot`
+    pub(crate) store_addrs: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MemorySnapshot {
+    pub(crate) kind: MemoryType,
+    pub(crate) data: Rc<Vec<u8>>,
+    pub(crate) page_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TableSnapshot {
+    pub(crate) kind: TableType,
+    // function indices in the module for function tables, extern addresses otherwise
+    pub(crate) elements: Vec<Option<u32>>,
+}
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"TWSN";
+const DELTA_MAGIC: &[u8; 4] = b"TWSD";
+pub(crate) const SNAPSHOT_VERSION: u8 = 1;
+
+impl InstanceSnapshot {
+    /// The contents of the memory with the index `idx` in the module, if it is part of the snapshot
+    pub fn memory(&self, idx: u32) -> Option<&[u8]> {
+        self.memories.iter().find(|(i, _)| *i == idx).map(|(_, mem)| mem.data.as_slice())
+    }
+
+    /// The values of the mutable globals
+    pub fn globals(&self) -> &GlobalsSnapshot {
+        &self.globals
+    }
+
+    /// Serialize the snapshot, e.g. to store it on disk or send it to another machine
+    ///
+    /// Load it again with [`InstanceSnapshot::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(SNAPSHOT_MAGIC);
+        out.push(SNAPSHOT_VERSION);
+
+        write_u32(&mut out, self.memories.len() as u32);
+        for (idx, mem) in &self.memories {
+            write_u32(&mut out, *idx);
+            write_memory_type(&mut out, &mem.kind, mem.page_count);
+            write_u64(&mut out, mem.data.len() as u64);
+            out.extend_from_slice(&mem.data);
+        }
+
+        self.write_state(&mut out);
+        out
+    }
+
+    /// Load a snapshot serialized with [`InstanceSnapshot::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        reader.header(SNAPSHOT_MAGIC)?;
+
+        let mut memories = Vec::new();
+        for _ in 0..reader.u32()? {
+            let idx = reader.u32()?;
+            let (kind, page_count) = reader.memory_type()?;
+            let len = reader.u64()?;
+            if pages_to_bytes(page_count as u64).map(|size| size as u64) != Some(len) {
+                return Err(Reader::invalid("memory size"));
+            }
+            let data = Rc::new(reader.bytes(len as usize)?.to_vec());
+            memories.push((idx, MemorySnapshot { kind, data, page_count }));
+        }
+
+        Ok(Self { memories, ..reader.state()? })
+    }
+
+    /// Apply the changes recorded by [`ModuleInstance::snapshot_delta`], bringing this snapshot up to date
+    ///
+    /// Deltas have to be applied in the order they were taken, starting with the first one taken
+    /// after this snapshot. Fails without changing the snapshot if the delta doesn't match its memories.
+    pub fn apply(&mut self, delta: &SnapshotDelta) -> Result<()> {
+        let mut memories = Vec::with_capacity(delta.memories.len());
+        for (idx, mem_delta) in &delta.memories {
+            let pos = self.memories.iter().position(|(i, mem)| i == idx && mem.kind == mem_delta.kind);
+            let len = pages_to_bytes(mem_delta.page_count as u64);
+            match (pos, len) {
+                (Some(pos), Some(len)) if mem_delta.pages.iter().all(|(page, _)| page_end(*page) <= len) => {
+                    memories.push((pos, len, mem_delta))
+                }
+                _ => return Err(Error::Other(format!("memory {} does not match the delta", idx))),
+            }
+        }
+
+        for (pos, len, mem_delta) in memories {
+            let mem = &mut self.memories[pos].1;
+            let data = Rc::make_mut(&mut mem.data);
+            data.resize(len, 0);
+            for (page, bytes) in &mem_delta.pages {
+                data[page_end(*page) - DIRTY_PAGE_SIZE..page_end(*page)].copy_from_slice(bytes);
+            }
+            mem.page_count = mem_delta.page_count;
+        }
+
+        let state = delta.state.clone();
+        self.tables = state.tables;
+        self.globals = state.globals;
+        self.dropped_elems = state.dropped_elems;
+        self.dropped_datas = state.dropped_datas;
+        self.store_addrs = state.store_addrs;
+        Ok(())
+    }
+
+    // write everything except the memories
+    fn write_state(&self, out: &mut Vec<u8>) {
+        out.push(self.store_addrs as u8);
+        write_u32(out, self.tables.len() as u32);
+        for (idx, table) in &self.tables {
+            write_u32(out, *idx);
+            out.push(table.kind.element_type.to_byte());
+            write_u32(out, table.kind.size_initial);
+            write_option(out, table.kind.size_max, write_u32);
+            write_u32(out, table.elements.len() as u32);
+            table.elements.iter().for_each(|e| write_option(out, *e, write_u32));
+        }
+
+        write_u32(out, self.globals.values.len() as u32);
+        for (idx, value) in &self.globals.values {
+            write_u32(out, *idx);
+            out.push(value.val_type().to_byte());
+            match value {
+                WasmValue::I32(v) => write_u64(out, *v as u32 as u64),
+                WasmValue::I64(v) => write_u64(out, *v as u64),
+                WasmValue::F32(v) => write_u64(out, v.to_bits() as u64),
+                WasmValue::F64(v) => write_u64(out, v.to_bits()),
+                WasmValue::RefExtern(addr) | WasmValue::RefFunc(addr) => write_u64(out, *addr as u64 + 1),
+                WasmValue::RefNull(_) => write_u64(out, 0),
+            }
+        }
+
+        for dropped in [&self.dropped_elems, &self.dropped_datas] {
+            write_u32(out, dropped.len() as u32);
+            dropped.iter().for_each(|idx| write_u32(out, *idx));
+        }
+    }
+}
+
+/// The changes to an instance since its last snapshot, see [`ModuleInstance::snapshot_delta`]
+///
+/// Only the pages of memory that have been written are included, while tables and globals
+/// are always included completely, since they are usually small.
+#[derive(Debug, Clone)]
+pub struct SnapshotDelta {
+    pub(crate) memories: Vec<(u32, MemoryDelta)>,
+    // everything else, without any memories
+    pub(crate) state: InstanceSnapshot,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MemoryDelta {
+    pub(crate) kind: MemoryType,
+    pub(crate) page_count: usize,
+    // the dirty pages of `DIRTY_PAGE_SIZE` bytes, by their index
+    pub(crate) pages: Vec<(u32, Vec<u8>)>,
+}
+
+// the end of a dirty page in memory
+fn page_end(page: u32) -> usize {
+    (page as usize + 1) * DIRTY_PAGE_SIZE
+}
+
+impl SnapshotDelta {
+    /// The number of bytes of memory included in the delta
+    pub fn memory_size(&self) -> usize {
+        self.memories.iter().map(|(_, mem)| mem.pages.len() * DIRTY_PAGE_SIZE).sum()
+    }
+
+    /// Serialize the delta, see [`InstanceSnapshot::to_bytes`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(DELTA_MAGIC);
+        out.push(SNAPSHOT_VERSION);
+
+        write_u32(&mut out, self.memories.len() as u32);
+        for (idx, mem) in &self.memories {
+            write_u32(&mut out, *idx);
+            write_memory_type(&mut out, &mem.kind, mem.page_count);
+            write_u32(&mut out, mem.pages.len() as u32);
+            for (page, bytes) in &mem.pages {
+                write_u32(&mut out, *page);
+                out.extend_from_slice(bytes);
+            }
+        }
+
+        self.state.write_state(&mut out);
+        out
+    }
+
+    /// Load a delta serialized with [`SnapshotDelta::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        reader.header(DELTA_MAGIC)?;
+
+        let mut memories = Vec::new();
+        for _ in 0..reader.u32()? {
+            let idx = reader.u32()?;
+            let (kind, page_count) = reader.memory_type()?;
+            let len = pages_to_bytes(page_count as u64).ok_or_else(|| Reader::invalid("memory size"))?;
+            let pages = (0..reader.u32()?).map(|_| match reader.u32()? {
+                page if page_end(page) <= len => Ok((page, reader.bytes(DIRTY_PAGE_SIZE)?.to_vec())),
+                _ => Err(Reader::invalid("memory page")),
+            });
+            let pages = pages.collect::<Result<_>>()?;
+            memories.push((idx, MemoryDelta { kind, page_count, pages }));
+        }
+
+        Ok(Self { memories, state: reader.state()? })
+    }
+}
+
+impl ModuleInstance {
+    /// Take a snapshot of the memories, tables and mutable globals defined by this instance,
+    /// and of which element and data segments have been dropped
+    ///
+    /// Imported memories, tables and globals are not included, like in [`ModuleInstance::snapshot_globals`].
+    /// The execution stack isn't part of the snapshot either, so snapshots taken during a call
+    /// only capture the state the guest has written to memory and globals so far.
+    ///
+    /// Functions in tables are stored by their index in the module, so they still work after restoring
+    /// the snapshot in another store. Fails if a table contains a function of another instance.
+    /// Other references, like those in globals or `externref` tables, are only valid in the same store.
+    ///
+    /// Taking a snapshot also starts tracking which pages of the memories are written,
+    /// so that [`ModuleInstance::snapshot_delta`] can record only the changes since then.
+    pub fn snapshot(&self, store: &Store) -> Result<InstanceSnapshot> {
+        self.snapshot_with(store, false)
+    }
+
+    pub(crate) fn snapshot_with(&self, store: &Store, store_addrs: bool) -> Result<InstanceSnapshot> {
+        let mut memories = Vec::new();
+        for (idx, addr) in self.mem_addrs().iter().enumerate() {
+            let mut mem = store.get_mem(*addr as usize)?.borrow_mut();
+            if mem.owner == self.id() {
+                mem.take_dirty_pages();
+                let snapshot = MemorySnapshot { kind: mem.kind, data: mem.data.clone(), page_count: mem.page_count };
+                memories.push((idx as u32, snapshot));
+            }
+        }
+
+        Ok(InstanceSnapshot { memories, ..self.snapshot_state(store, store_addrs)? })
+    }
+
+    /// Record the changes since the last snapshot or delta of this instance, see [`SnapshotDelta`]
+    ///
+    /// This is much cheaper than [`ModuleInstance::snapshot`] for large memories that are only partially
+    /// written, e.g. to checkpoint an instance frequently. Apply the delta to the last snapshot with
+    /// [`InstanceSnapshot::apply`]. Fails if no snapshot of the instance has been taken.
+    pub fn snapshot_delta(&self, store: &Store) -> Result<SnapshotDelta> {
+        let state = self.snapshot_state(store, false)?;
+
+        // check all memories first, so a failed delta doesn't clear the dirty pages of some of them
+        for (idx, addr) in self.mem_addrs().iter().enumerate() {
+            let mem = store.get_mem(*addr as usize)?.borrow();
+            if mem.owner == self.id() && mem.dirty.is_none() {
+                return Err(Error::Other(format!("memory {} has no snapshot to take a delta of", idx)));
+            }
+        }
+
+        let mut memories = Vec::new();
+        for (idx, addr) in self.mem_addrs().iter().enumerate() {
+            let mut mem = store.get_mem(*addr as usize)?.borrow_mut();
+            if mem.owner != self.id() {
+                continue;
+            }
+
+            let dirty = mem.take_dirty_pages().unwrap_or_default();
+            let pages = dirty.into_iter().map(|page| {
+                let end = page_end(page as u32);
+                (page as u32, mem.data[end - DIRTY_PAGE_SIZE..end].to_vec())
+            });
+            let pages = pages.collect();
+            memories.push((idx as u32, MemoryDelta { kind: mem.kind, page_count: mem.page_count, pages }));
+        }
+
+        Ok(SnapshotDelta { memories, state })
+    }
+
+    // snapshot everything except the memories
+    fn snapshot_state(&self, store: &Store, store_addrs: bool) -> Result<InstanceSnapshot> {
+        let globals = self.snapshot_globals(store)?;
+
+        let mut tables = Vec::new();
+        for (idx, addr) in self.table_addrs().iter().enumerate() {
+            let table = store.get_table(*addr as usize)?.borrow();
+            if table.owner != self.id() {
+                continue;
+            }
+
+            let is_func_table = table.kind.element_type == ValType::RefFunc && !store_addrs;
+            let elements = table.elements.iter().map(|element| match element.addr() {
+                Some(addr) if is_func_table => match self.func_addrs().iter().position(|f| *f == addr) {
+                    Some(func_idx) => Ok(Some(func_idx as u32)),
+                    None => Err(Error::Other(format!("table {} contains a function of another instance", idx))),
+                },
+                addr => Ok(addr),
+            });
+
+            let elements = elements.collect::<Result<_>>()?;
+            tables.push((idx as u32, TableSnapshot { kind: table.kind.clone(), elements }));
+        }
+
+        let mut dropped_elems = Vec::new();
+        for (idx, addr) in self.elem_addrs().iter().enumerate() {
+            if store.get_elem(*addr as usize)?.items.is_none() {
+                dropped_elems.push(idx as u32);
+            }
+        }
+
+        let mut dropped_datas = Vec::new();
+        for (idx, addr) in self.data_addrs().iter().enumerate() {
+            if store.get_data(*addr as usize)?.data.is_none() {
+                dropped_datas.push(idx as u32);
+            }
+        }
+
+        Ok(InstanceSnapshot { memories: Vec::new(), tables, globals, dropped_elems, dropped_datas, store_addrs })
+    }
+
+    /// Restore the state from a snapshot taken with [`ModuleInstance::snapshot`]
+    ///
+    /// The snapshot can also be restored into a new instance of the same module, see [`Module::restore`].
+    /// Fails without changing the instance if the snapshot doesn't match it, or if it has dropped
+    /// a segment that wasn't dropped when the snapshot was taken.
+    /// Like taking a snapshot, this starts a new [`ModuleInstance::snapshot_delta`].
+    pub fn restore(&self, store: &mut Store, snapshot: &InstanceSnapshot) -> Result<()> {
+        self.check_restore(store, snapshot)?.apply(store)
+    }
+
+    // the changes needed to restore `snapshot`, checking that it matches the instance without changing anything
+    pub(crate) fn check_restore<'a>(&self, store: &Store, snapshot: &'a InstanceSnapshot) -> Result<Restore<'a>> {
+        if self.store_id() != store.id() {
+            return Err(Error::InvalidStore);
+        }
+
+        let mut memories = Vec::with_capacity(snapshot.memories.len());
+        for (idx, mem_snapshot) in &snapshot.memories {
+            let mem = self.mem_addrs().get(*idx as usize).map(|addr| store.get_mem(*addr as usize));
+            let Some(Ok(mem)) = mem else {
+                return Err(Error::Other(format!("memory {} not found", idx)));
+            };
+
+            let instance = mem.borrow();
+            let (page_count, len) = (mem_snapshot.page_count, mem_snapshot.data.len());
+            if instance.owner != self.id()
+                || instance.kind != mem_snapshot.kind
+                || pages_to_bytes(page_count as u64) != Some(len)
+                || page_count as u64 > instance.max_pages()
+                || !instance.can_resize_to(page_count, len)
+            {
+                return Err(Error::Other(format!("memory {} does not match the snapshot", idx)));
+            }
+            memories.push((mem.clone(), mem_snapshot));
+        }
+
+        let mut tables = Vec::with_capacity(snapshot.tables.len());
+        for (idx, table_snapshot) in &snapshot.tables {
+            let table = self.table_addrs().get(*idx as usize).map(|addr| store.get_table(*addr as usize));
+            let Some(Ok(table)) = table else {
+                return Err(Error::Other(format!("table {} not found", idx)));
+            };
+
+            let instance = table.borrow();
+            if instance.owner != self.id() || instance.kind != table_snapshot.kind {
+                return Err(Error::Other(format!("table {} does not match the snapshot", idx)));
+            }
+
+            let is_func_table = table_snapshot.kind.element_type == ValType::RefFunc;
+            let elements = table_snapshot.elements.iter().map(|element| match element {
+                None => Ok(TableElement::Uninitialized),
+                Some(func_idx) if is_func_table && !snapshot.store_addrs => {
+                    match self.func_addrs().get(*func_idx as usize) {
+                        Some(addr) => Ok(TableElement::Initialized(*addr)),
+                        None => Err(Error::Other(format!("table {} does not match the snapshot", idx))),
+                    }
+                }
+                Some(addr) if is_func_table && store.get_func(*addr as usize).is_err() => {
+                    Err(Error::Other(format!("table {} contains an unknown function", idx)))
+                }
+                Some(addr) => Ok(TableElement::Initialized(*addr)),
+            });
+            tables.push((table.clone(), elements.collect::<Result<Vec<_>>>()?));
+        }
+
+        let dropped = |addrs: &[u32], snapshot: &[u32], is_dropped: &dyn Fn(u32) -> Result<bool>| {
+            let mut to_drop = Vec::new();
+            for (idx, addr) in addrs.iter().enumerate() {
+                match (is_dropped(*addr)?, snapshot.contains(&(idx as u32))) {
+                    (true, false) => return Err(Error::Other(format!("segment {} has already been dropped", idx))),
+                    (false, true) => to_drop.push(*addr),
+                    _ => {}
+                }
+            }
+            Ok(to_drop)
+        };
+        let elems = dropped(self.elem_addrs(), &snapshot.dropped_elems, &|addr| {
+            Ok(store.get_elem(addr as usize)?.items.is_none())
+        })?;
+        let datas = dropped(self.data_addrs(), &snapshot.dropped_datas, &|addr| {
+            Ok(store.get_data(addr as usize)?.data.is_none())
+        })?;
+
+        let globals = self.check_globals(store, &snapshot.globals)?;
+        Ok(Restore { memories, tables, globals, elems, datas })
+    }
+}
+
+/// The changes to an instance that restore a snapshot, see [`ModuleInstance::check_restore`]
+pub(crate) struct Restore<'a> {
+    memories: Vec<(Rc<RefCell<MemoryInstance>>, &'a MemorySnapshot)>,
+    tables: Vec<(Rc<RefCell<TableInstance>>, Vec<TableElement>)>,
+    globals: Vec<(Rc<RefCell<GlobalInstance>>, WasmValue)>,
+    elems: Vec<u32>,
+    datas: Vec<u32>,
+}
+
+impl Restore<'_> {
+    pub(crate) fn apply(self, store: &mut Store) -> Result<()> {
+        let Self { memories, tables, globals, elems, datas } = self;
+        for (global, value) in globals {
+            global.borrow_mut().set(value)?;
+        }
+        for (mem, mem_snapshot) in memories {
+            let mut mem = mem.borrow_mut();
+            mem.data = mem_snapshot.data.clone();
+            mem.page_count = mem_snapshot.page_count;
+            mem.take_dirty_pages();
+        }
+        for (table, elements) in tables {
+            table.borrow_mut().elements = elements;
+        }
+        for addr in elems {
+            store.get_elem_mut(addr as usize)?.items = None;
+        }
+        for addr in datas {
+            store.get_data_mut(addr as usize)?.drop();
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Instantiate the module and restore a snapshot taken with [`ModuleInstance::snapshot`]
+    ///
+    /// The start function isn't run, since its effects are already part of the snapshot.
+    /// Together with [`InstanceSnapshot::to_bytes`], this can move an instance to another store or
+    /// process, or skip expensive initialization code by restoring a snapshot taken after it ran.
+    pub fn restore(
+        self,
+        store: &mut Store,
+        imports: Option<Imports>,
+        snapshot: &InstanceSnapshot,
+    ) -> Result<ModuleInstance> {
+        let instance = ModuleInstance::instantiate(store, self, imports)?;
+        if let Err(e) = instance.restore(store, snapshot) {
+            store.remove_instance(instance.id())?;
+            return Err(e);
+        }
+        Ok(instance)
+    }
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_memory_type(out: &mut Vec<u8>, kind: &MemoryType, page_count: usize) {
+    out.push(kind.arch as u8);
+    write_u64(out, kind.page_count_initial);
+    write_option(out, kind.page_count_max, write_u64);
+    write_u64(out, page_count as u64);
+}
+
+pub(crate) fn write_option<T>(out: &mut Vec<u8>, value: Option<T>, write: fn(&mut Vec<u8>, T)) {
+    out.push(value.is_some() as u8);
+    if let Some(value) = value {
+        write(out, value);
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn invalid(what: &str) -> Error {
+        Error::Other(format!("invalid snapshot: {}", what))
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Self::invalid("unexpected end"));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().expect("4 bytes")))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().expect("8 bytes")))
+    }
+
+    pub(crate) fn option<T>(&mut self, read: fn(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => read(self).map(Some),
+            _ => Err(Self::invalid("option")),
+        }
+    }
+
+    pub(crate) fn header(&mut self, magic: &[u8; 4]) -> Result<()> {
+        if self.bytes(4)? != magic || self.u8()? != SNAPSHOT_VERSION {
+            return Err(Error::Other("invalid snapshot: unsupported format".into()));
+        }
+        Ok(())
+    }
+
+    fn memory_type(&mut self) -> Result<(MemoryType, usize)> {
+        let arch = match self.u8()? {
+            0 => MemoryArch::I32,
+            1 => MemoryArch::I64,
+            _ => return Err(Self::invalid("memory type")),
+        };
+        let kind = MemoryType { arch, page_count_initial: self.u64()?, page_count_max: self.option(Self::u64)? };
+        let page_count = self.u64()?;
+        if page_count < kind.page_count_initial || page_count > max_pages(&kind) {
+            return Err(Self::invalid("memory size"));
+        }
+        Ok((kind, page_count as usize))
+    }
+
+    // read everything except the memories, see `InstanceSnapshot::write_state`
+    fn state(&mut self) -> Result<InstanceSnapshot> {
+        let store_addrs = match self.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(Self::invalid("snapshot kind")),
+        };
+        let mut tables = Vec::new();
+        for _ in 0..self.u32()? {
+            let idx = self.u32()?;
+            let element_type = ValType::from_byte(self.u8()?).ok_or_else(|| Self::invalid("table type"))?;
+            let kind = TableType { element_type, size_initial: self.u32()?, size_max: self.option(Self::u32)? };
+            let elements = (0..self.u32()?).map(|_| self.option(Self::u32)).collect::<Result<_>>()?;
+            tables.push((idx, TableSnapshot { kind, elements }));
+        }
+
+        let mut globals = GlobalsSnapshot::default();
+        for _ in 0..self.u32()? {
+            let idx = self.u32()?;
+            let ty = ValType::from_byte(self.u8()?).ok_or_else(|| Self::invalid("global type"))?;
+            let bits = self.u64()?;
+            let value = match ty {
+                ValType::I32 => WasmValue::I32(bits as u32 as i32),
+                ValType::I64 => WasmValue::I64(bits as i64),
+                ValType::F32 => WasmValue::F32(f32::from_bits(bits as u32)),
+                ValType::F64 => WasmValue::F64(f64::from_bits(bits)),
+                ValType::RefExtern if bits != 0 => WasmValue::RefExtern((bits - 1) as u32),
+                ValType::RefFunc if bits != 0 => WasmValue::RefFunc((bits - 1) as u32),
+                ValType::RefExtern | ValType::RefFunc => WasmValue::RefNull(ty),
+            };
+            globals.values.push((idx, value));
+        }
+
+        let dropped_elems = (0..self.u32()?).map(|_| self.u32()).collect::<Result<_>>()?;
+        let dropped_datas = (0..self.u32()?).map(|_| self.u32()).collect::<Result<_>>()?;
+        if !self.0.is_empty() {
+            return Err(Self::invalid("trailing data"));
+        }
+
+        Ok(InstanceSnapshot { memories: Vec::new(), tables, globals, dropped_elems, dropped_datas, store_addrs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::PAGE_SIZE;
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_snapshot() -> Result<()> {
+        let module = Module::from(TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            globals: vec![Global {
+                ty: GlobalType { mutable: true, ty: ValType::I32 },
+                init: ConstInstruction::I32Const(0),
+            }]
+            .into(),
+            exports: vec![Export { name: "memory".into(), kind: ExternalKind::Memory, index: 0 }].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let a = Module::from(module.data.clone()).instantiate(&mut store, None)?;
+        let b = module.instantiate(&mut store, None)?;
+        a.exported_memory_mut(&mut store, "memory")?.store(0, 4, b"tiny")?;
+        a.exported_memory_mut(&mut store, "memory")?.grow(1);
+        store.get_global(a.resolve_global_addr(0) as usize)?.borrow_mut().set(WasmValue::I32(7))?;
+
+        let snapshot = a.snapshot(&store)?;
+        a.exported_memory_mut(&mut store, "memory")?.store(0, 4, b"wasm")?;
+        assert_eq!(&snapshot.memory(0).expect("memory 0")[..4], b"tiny");
+        assert_eq!(snapshot.globals().values, vec![(0, WasmValue::I32(7))]);
+
+        b.restore(&mut store, &snapshot)?;
+        {
+            let memory = b.memory(&mut store, 0)?;
+            assert_eq!((memory.load(0, 4)?, memory.instance.page_count), (&b"tiny"[..], 2));
+        }
+        assert_eq!(store.get_global(b.resolve_global_addr(0) as usize)?.borrow().get(), WasmValue::I32(7));
+
+        let other = Module::from(TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, Some(1))].into(),
+            ..Default::default()
+        })
+        .instantiate(&mut store, None)?;
+        assert!(other.restore(&mut store, &snapshot).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_bytes() -> Result<()> {
+        // `get(i32) -> i32` calls the function at index `i` in the table, which is filled by an active segment
+        let mut builder = ModuleBuilder::new();
+        let table = builder.add_table(TableType::new(ValType::RefFunc, 2, None));
+        builder.add_memory(MemoryType::new_32(1, None));
+        let ty = builder.add_type(FuncType { params: Default::default(), results: [ValType::I32].into() });
+        let one = builder.add_function(ty, [], [Instruction::I32Const(1), Instruction::EndFunc]);
+        let two = builder.add_function(ty, [], [Instruction::I32Const(2), Instruction::EndFunc]);
+        let get_ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        let get = [Instruction::LocalGet(0), Instruction::CallIndirect(ty, table as u16), Instruction::EndFunc];
+        let get = builder.add_function(get_ty, [], get);
+        let active = ElementKind::Active { table, offset: ConstInstruction::I32Const(0) };
+        builder.add_element(active, ValType::RefFunc, [ElementItem::Func(one), ElementItem::Func(two)]);
+        builder.add_export("get", ExternalKind::Func, get);
+        let data = builder.finish().expect("valid module");
+
+        let mut store = Store::default();
+        let a = Module::from(&data).instantiate(&mut store, None)?;
+        a.memory_mut(&mut store, 0)?.store(8, 4, b"tiny")?;
+        store.get_table(a.table_addrs()[0] as usize)?.borrow_mut().set(0, a.func_addrs()[two as usize])?;
+        assert_eq!(a.exported_func::<i32, i32>(&store, "get")?.call(&mut store, 0)?, 2);
+
+        let bytes = a.snapshot(&store)?.to_bytes();
+        let snapshot = InstanceSnapshot::from_bytes(&bytes)?;
+        assert_eq!(snapshot.to_bytes(), bytes);
+        assert!(InstanceSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // the function addresses are different in another store
+        let mut other = Store::default();
+        Module::from(&data).instantiate(&mut other, None)?;
+        let b = Module::from(&data).restore(&mut other, None, &snapshot)?;
+        assert_eq!(b.exported_func::<i32, i32>(&other, "get")?.call(&mut other, 0)?, 2);
+        assert_eq!(b.memory(&mut other, 0)?.load(8, 4)?, b"tiny");
+        assert_eq!(snapshot.dropped_elems, vec![0]);
+
+        let mut invalid = snapshot.clone();
+        invalid.tables[0].1.elements[0] = Some(7);
+        assert!(Module::from(&data).restore(&mut other, None, &invalid).is_err());
+
+        // the size of a memory has to match its page count, which has to fit its type
+        let mut invalid = snapshot.clone();
+        invalid.memories[0].1.page_count = 2;
+        assert!(Module::from(&data).restore(&mut other, None, &invalid).is_err());
+        let page_count = 4 + 1 + 4 + 4 + 1 + 8 + 1;
+        for pages in [0u64, 2, 1 << 40] {
+            let mut invalid = bytes.clone();
+            invalid[page_count..page_count + 8].copy_from_slice(&pages.to_le_bytes());
+            assert!(InstanceSnapshot::from_bytes(&invalid).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_delta() -> Result<()> {
+        let module = Module::from(TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let a = module.clone().instantiate(&mut store, None)?;
+        assert!(a.snapshot_delta(&store).is_err());
+
+        a.memory_mut(&mut store, 0)?.store(0, 4, b"tiny")?;
+        let mut snapshot = a.snapshot(&store)?;
+        assert_eq!(a.snapshot_delta(&store)?.memory_size(), 0);
+
+        // crosses into the second page
+        a.memory_mut(&mut store, 0)?.store(DIRTY_PAGE_SIZE - 2, 4, b"wasm")?;
+        a.memory_mut(&mut store, 0)?.grow(1);
+        a.memory_mut(&mut store, 0)?.store(PAGE_SIZE as usize, 1, b"!")?;
+        let delta = a.snapshot_delta(&store)?;
+        assert_eq!(delta.memory_size(), 3 * DIRTY_PAGE_SIZE);
+
+        let bytes = delta.to_bytes();
+        let delta = SnapshotDelta::from_bytes(&bytes)?;
+        assert_eq!(delta.to_bytes(), bytes);
+
+        // pages outside of the memory are rejected
+        let first_page = 4 + 1 + 4 + 4 + 1 + 8 + 1 + 8 + 4;
+        let mut invalid = bytes.clone();
+        invalid[first_page..first_page + 4]
+            .copy_from_slice(&(2 * PAGE_SIZE as u32 / DIRTY_PAGE_SIZE as u32).to_le_bytes());
+        assert!(SnapshotDelta::from_bytes(&invalid).is_err());
+        snapshot.apply(&delta)?;
+
+        let memory = snapshot.memory(0).expect("memory 0");
+        assert_eq!(memory, a.memory(&mut store, 0)?.load(0, 2 * PAGE_SIZE as usize)?);
+        assert_eq!(&memory[..4], b"tiny");
+
+        let b = module.clone().restore(&mut store, None, &snapshot)?;
+        assert_eq!(b.memory(&mut store, 0)?.load(PAGE_SIZE as usize, 1)?, b"!");
+
+        let mut other = Module::from(TinyWasmModule::default()).instantiate(&mut store, None)?.snapshot(&store)?;
+        assert!(other.apply(&delta).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_delta_untracked_memory() -> Result<()> {
+        let module = Module::from(TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, None), MemoryType::new_32(1, None)].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let a = module.instantiate(&mut store, None)?;
+        a.snapshot(&store)?;
+        a.memory_mut(&mut store, 0)?.store(0, 4, b"tiny")?;
+
+        // the second memory isn't tracked, so the delta fails without taking the dirty pages of the first one
+        let second = store.get_mem(a.mem_addrs()[1] as usize)?.clone();
+        second.borrow_mut().dirty = None;
+        assert!(a.snapshot_delta(&store).is_err());
+
+        second.borrow_mut().take_dirty_pages();
+        assert_eq!(a.snapshot_delta(&store)?.memory_size(), DIRTY_PAGE_SIZE);
+        Ok(())
+    }
+}
@@ -15,6 +15,11 @@ impl DataInstance {
         Self { data, _owner: owner }
     }
 
+    /// Create a copy of this data segment for another module instance
+    pub(crate) fn fork(&self, owner: ModuleInstanceAddr) -> Self {
+        Self { data: self.data.clone(), _owner: owner }
+    }
+
     pub(crate) fn drop(&mut self) -> Option<()> {
         match self.data {
             None => None,
@@ -1,5 +1,5 @@
 use crate::TableElement;
-use alloc::vec::Vec;
+use alloc::{collections::BTreeMap, vec::Vec};
 use tinywasm_types::*;
 
 /// A WebAssembly Element Instance
@@ -16,4 +16,14 @@ impl ElementInstance {
     pub(crate) fn new(kind: ElementKind, owner: ModuleInstanceAddr, items: Option<Vec<TableElement>>) -> Self {
         Self { kind, _owner: owner, items }
     }
+
+    /// Create a copy of this element for another module instance, remapping function references
+    pub(crate) fn fork(&self, owner: ModuleInstanceAddr, funcs: &BTreeMap<FuncAddr, FuncAddr>) -> Self {
+        let items = self
+            .items
+            .as_ref()
+            .map(|items| items.iter().map(|item| item.map(|addr| *funcs.get(&addr).unwrap_or(&addr))).collect());
+
+        Self { kind: self.kind, _owner: owner, items }
+    }
 }
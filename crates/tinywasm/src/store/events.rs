@@ -0,0 +1,156 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use tinywasm_types::{MemAddr, ModuleInstanceAddr, TableAddr};
+
+use super::Store;
+use crate::sync::MaybeSendSync;
+use crate::{unlikely, Trap};
+
+/// Something that happened in a [`Store`], see [`Store::subscribe`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StoreEvent<'a> {
+    /// A module instance was added to the store
+    InstanceCreated(ModuleInstanceAddr),
+    /// A module instance was removed using [`Store::remove_instance`]
+    InstanceRemoved(ModuleInstanceAddr),
+    /// A memory was grown by a `memory.grow` instruction
+    MemoryGrown {
+        /// The store address of the memory
+        addr: MemAddr,
+        /// The instance that owns the memory
+        owner: ModuleInstanceAddr,
+        /// The size before growing, in pages
+        old_pages: usize,
+        /// The size after growing, in pages
+        new_pages: usize,
+    },
+    /// A table was grown to fit an element set by a `table.set` instruction
+    TableGrown {
+        /// The store address of the table
+        addr: TableAddr,
+        /// The instance that owns the table
+        owner: ModuleInstanceAddr,
+        /// The size before growing
+        old_size: usize,
+        /// The size after growing
+        new_size: usize,
+    },
+    /// A call into the store trapped
+    Trap(&'a Trap),
+    /// The store ran out of fuel, see [`Store::set_fuel`]
+    ///
+    /// This is also emitted when a [`crate::MeteredCall`] uses up the budget of a slice.
+    FuelExhausted,
+}
+
+/// Identifies a subscription, see [`Store::unsubscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionId(usize);
+
+#[cfg(not(feature = "sync"))]
+type Subscriber = Box<dyn Fn(&StoreEvent<'_>)>;
+#[cfg(feature = "sync")]
+type Subscriber = Box<dyn Fn(&StoreEvent<'_>) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    next_id: usize,
+    subscribers: Vec<(SubscriptionId, Subscriber)>,
+}
+
+impl core::fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Subscribers").field("count", &self.subscribers.len()).finish()
+    }
+}
+
+impl Store {
+    /// Call `callback` for every [`StoreEvent`], e.g. to collect metrics or log traps
+    ///
+    /// Subscribers are called in the order they were added, right after the event happened.
+    /// They can't access the store, so events are meant to be recorded and processed later.
+    pub fn subscribe(&mut self, callback: impl Fn(&StoreEvent<'_>) + MaybeSendSync + 'static) -> SubscriptionId {
+        let subscribers = &mut self.subscribers;
+        let id = SubscriptionId(subscribers.next_id);
+        subscribers.next_id += 1;
+        subscribers.subscribers.push((id, Box::new(callback)));
+        id
+    }
+
+    /// Remove a subscription added with [`Store::subscribe`]
+    ///
+    /// Returns `false` if the subscription doesn't exist.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let subscribers = &mut self.subscribers.subscribers;
+        let len = subscribers.len();
+        subscribers.retain(|(other, _)| *other != id);
+        subscribers.len() != len
+    }
+
+    #[inline]
+    pub(crate) fn emit(&self, event: StoreEvent<'_>) {
+        if unlikely(!self.subscribers.subscribers.is_empty()) {
+            self.subscribers.subscribers.iter().for_each(|(_, callback)| callback(&event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{Rc, RefCell};
+    use crate::{Error, Module, Result};
+    use alloc::format;
+    use alloc::string::String;
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_events() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let mem = builder.add_memory(MemoryType::new_32(1, None));
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        let grow = builder.add_function(
+            ty,
+            [],
+            [Instruction::LocalGet(0), Instruction::MemoryGrow(mem, 0), Instruction::EndFunc],
+        );
+        let empty = builder.add_type(FuncType::default());
+        let trap = builder.add_function(empty, [], [Instruction::Unreachable, Instruction::EndFunc]);
+        builder.add_export("grow", ExternalKind::Func, grow);
+        builder.add_export("trap", ExternalKind::Func, trap);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let mut store = Store::default();
+        let events = Rc::new(RefCell::new(Vec::<String>::new()));
+        let recorded = events.clone();
+        let id = store.subscribe(move |event| recorded.borrow_mut().push(format!("{:?}", event)));
+
+        let instance = module.instantiate(&mut store, None)?;
+        let grow = instance.exported_func::<i32, i32>(&store, "grow")?;
+        grow.call(&mut store, 2)?;
+        let res = instance.exported_func::<(), ()>(&store, "trap")?.call(&mut store, ());
+        assert!(matches!(res, Err(Error::Trap(Trap::Unreachable))));
+
+        store.set_fuel(Some(0));
+        assert!(grow.call(&mut store, 1).is_err());
+        store.set_fuel(None);
+        store.remove_instance(instance.id())?;
+
+        assert!(store.unsubscribe(id) && !store.unsubscribe(id));
+        grow.call(&mut store, 1).ok();
+
+        let id = instance.id();
+        let expected = vec![
+            format!("InstanceCreated({})", id),
+            format!("MemoryGrown {{ addr: 0, owner: {}, old_pages: 1, new_pages: 3 }}", id),
+            format!("Trap({:?})", Trap::Unreachable),
+            "FuelExhausted".into(),
+            format!("Trap({:?})", Trap::OutOfFuel),
+            format!("InstanceRemoved({})", id),
+        ];
+        assert_eq!(*events.borrow(), expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use super::Store;
+use crate::{Result, Trap};
+
+impl Store {
+    /// Limit the number of instructions the store may execute
+    ///
+    /// While fuel is set, every executed instruction consumes one unit. Once no fuel is left,
+    /// execution traps with [`Trap::OutOfFuel`]. Host functions can consume additional fuel
+    /// using [`Store::consume_fuel`]. `None` disables metering.
+    ///
+    /// Calls started without fuel don't check it before every instruction, so fuel set by a host function
+    /// during such a call is only used from the next call or return of a WebAssembly function on.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel_spent = self.fuel_consumed();
+        self.fuel = fuel;
+        self.fuel_set = fuel.unwrap_or(0);
+    }
+
+    /// Get the remaining fuel, or `None` if metering is disabled
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // the fuel consumed since the store was created, including fuel that was replaced by `set_fuel`.
+    // Between two calls of `set_fuel`, fuel is only ever consumed, so this never decreases.
+    pub(crate) fn fuel_consumed(&self) -> u64 {
+        self.fuel_spent.saturating_add(self.fuel.map_or(0, |fuel| self.fuel_set.saturating_sub(fuel)))
+    }
+
+    /// Consume `amount` units of fuel
+    ///
+    /// If less than `amount` fuel is left, the remaining fuel is used up and [`Trap::OutOfFuel`] is returned.
+    /// Does nothing if metering is disabled.
+    pub fn consume_fuel(&mut self, amount: u64) -> Result<()> {
+        let Some(fuel) = self.fuel.as_mut() else { return Ok(()) };
+        match fuel.checked_sub(amount) {
+            Some(remaining) => *fuel = remaining,
+            None => {
+                *fuel = 0;
+                self.emit(super::StoreEvent::FuelExhausted);
+                return Err(Trap::OutOfFuel.into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
+use super::types::TypeId;
+use crate::sync::Rc;
 use crate::Function;
-use alloc::rc::Rc;
 use tinywasm_types::*;
 
 #[derive(Debug, Clone)]
@@ -8,11 +9,12 @@ use tinywasm_types::*;
 /// See <https://webassembly.github.io/spec/core/exec/runtime.html#function-instances>
 pub(crate) struct FunctionInstance {
     pub(crate) func: Function,
+    pub(crate) type_id: TypeId,
     pub(crate) owner: ModuleInstanceAddr, // index into store.module_instances, none for host functions
 }
 
 impl FunctionInstance {
-    pub(crate) fn new_wasm(func: WasmFunction, owner: ModuleInstanceAddr) -> Self {
-        Self { func: Function::Wasm(Rc::new(func)), owner }
+    pub(crate) fn new_wasm(func: Rc<WasmFunction>, type_id: TypeId, owner: ModuleInstanceAddr) -> Self {
+        Self { func: Function::Wasm(func), type_id, owner }
     }
 }
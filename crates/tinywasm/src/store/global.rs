@@ -1,4 +1,4 @@
-use alloc::{format, string::ToString};
+use alloc::{collections::BTreeMap, format, string::ToString};
 use tinywasm_types::*;
 
 use crate::{runtime::RawWasmValue, unlikely, Error, Result};
@@ -10,12 +10,22 @@ use crate::{runtime::RawWasmValue, unlikely, Error, Result};
 pub(crate) struct GlobalInstance {
     pub(crate) value: RawWasmValue,
     pub(crate) ty: GlobalType,
-    pub(crate) _owner: ModuleInstanceAddr, // index into store.module_instances
+    pub(crate) owner: ModuleInstanceAddr, // index into store.module_instances
 }
 
 impl GlobalInstance {
     pub(crate) fn new(ty: GlobalType, value: RawWasmValue, owner: ModuleInstanceAddr) -> Self {
-        Self { ty, value, _owner: owner }
+        Self { ty, value, owner }
+    }
+
+    /// Create a copy of this global for another module instance, remapping function references
+    pub(crate) fn fork(&self, owner: ModuleInstanceAddr, funcs: &BTreeMap<FuncAddr, FuncAddr>) -> Self {
+        let value = match self.get() {
+            WasmValue::RefFunc(addr) => funcs.get(&addr).map_or(self.value, |addr| RawWasmValue::from(*addr)),
+            _ => self.value,
+        };
+
+        Self { ty: self.ty, value, owner }
     }
 
     #[inline]
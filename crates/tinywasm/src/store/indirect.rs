@@ -0,0 +1,139 @@
+use crate::sync::Rc;
+use tinywasm_types::{FuncAddr, ModuleInstanceAddr, WasmFunction};
+
+const CACHE_SIZE: usize = 64;
+
+/// A function called by a `call_indirect` instruction that passed the signature check
+#[derive(Debug, Clone)]
+struct CachedCall {
+    caller: FuncAddr,
+    instr_ptr: usize,
+    callee: FuncAddr,
+    func: Rc<WasmFunction>,
+    owner: ModuleInstanceAddr,
+}
+
+/// The functions recently called by `call_indirect` instructions
+///
+/// Every call site, identified by the calling function and its instruction pointer, maps to a
+/// single entry that remembers the last WebAssembly function it called. The table is still read on
+/// every call, but calling the same function again uses the cached function instead of looking it up
+/// in the store and comparing its type. Host functions are not cached.
+#[derive(Debug, Clone)]
+pub(crate) struct IndirectCallCache {
+    entries: [Option<CachedCall>; CACHE_SIZE],
+}
+
+impl Default for IndirectCallCache {
+    fn default() -> Self {
+        Self { entries: core::array::from_fn(|_| None) }
+    }
+}
+
+impl IndirectCallCache {
+    #[inline(always)]
+    fn slot(caller: FuncAddr, instr_ptr: usize) -> usize {
+        (caller as usize).wrapping_mul(31).wrapping_add(instr_ptr) % CACHE_SIZE
+    }
+
+    /// The function and owner of `callee`, if the call site already called it
+    #[inline(always)]
+    pub(crate) fn get(
+        &self,
+        (caller, instr_ptr): (FuncAddr, usize),
+        callee: FuncAddr,
+    ) -> Option<(Rc<WasmFunction>, ModuleInstanceAddr)> {
+        match &self.entries[Self::slot(caller, instr_ptr)] {
+            Some(entry) if entry.caller == caller && entry.instr_ptr == instr_ptr && entry.callee == callee => {
+                Some((entry.func.clone(), entry.owner))
+            }
+            _ => None,
+        }
+    }
+
+    /// Remember that `callee` has the signature expected by the call site
+    #[inline]
+    pub(crate) fn insert(
+        &mut self,
+        (caller, instr_ptr): (FuncAddr, usize),
+        callee: FuncAddr,
+        func: Rc<WasmFunction>,
+        owner: ModuleInstanceAddr,
+    ) {
+        self.entries[Self::slot(caller, instr_ptr)] = Some(CachedCall { caller, instr_ptr, callee, func, owner });
+    }
+
+    /// Forget all call sites, e.g. when the types or functions of a module instance change
+    pub(crate) fn clear(&mut self) {
+        self.entries = core::array::from_fn(|_| None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Module, Result, Store, Trap};
+    use alloc::string::ToString;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_cache() {
+        let func = Rc::new(WasmFunction {
+            instructions: [Instruction::EndFunc].into(),
+            locals: Default::default(),
+            ty: FuncType { params: Default::default(), results: Default::default() },
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+        });
+        let mut cache = IndirectCallCache::default();
+        assert!(cache.get((1, 4), 7).is_none());
+        cache.insert((1, 4), 7, func.clone(), 2);
+        assert!(cache.get((1, 4), 7).is_some_and(|(f, owner)| Rc::ptr_eq(&f, &func) && owner == 2));
+        assert!(cache.get((1, 4), 8).is_none());
+        assert!(cache.get((2, 4), 7).is_none());
+
+        // call sites that share a slot replace each other
+        cache.insert((1, 4 + CACHE_SIZE), 7, func, 2);
+        assert!(cache.get((1, 4), 7).is_none());
+        cache.clear();
+        assert!(cache.get((1, 4 + CACHE_SIZE), 7).is_none());
+    }
+
+    #[test]
+    fn test_call_indirect() -> Result<()> {
+        // `call(i32) -> i32` calls the function at index `i` in the table
+        let mut builder = ModuleBuilder::new();
+        let table = builder.add_table(TableType::new(ValType::RefFunc, 2, None));
+        let ty = builder.add_type(FuncType { params: Default::default(), results: [ValType::I32].into() });
+        let other_ty = builder.add_type(FuncType { params: Default::default(), results: Default::default() });
+        let one = builder.add_function(ty, [], [Instruction::I32Const(1), Instruction::EndFunc]);
+        let nothing = builder.add_function(other_ty, [], [Instruction::EndFunc]);
+        let call_ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        let call = [Instruction::LocalGet(0), Instruction::CallIndirect(ty, table as u16), Instruction::EndFunc];
+        let call = builder.add_function(call_ty, [], call);
+        let active = ElementKind::Active { table, offset: ConstInstruction::I32Const(0) };
+        builder.add_element(active, ValType::RefFunc, [ElementItem::Func(one), ElementItem::Func(nothing)]);
+        builder.add_export("call", ExternalKind::Func, call).func_names([(nothing, "nothing".into())]);
+
+        let mut store = Store::default();
+        let instance = Module::from(builder.finish().expect("valid module")).instantiate(&mut store, None)?;
+        let func = instance.exported_func::<i32, i32>(&store, "call")?;
+        assert_eq!(func.call(&mut store, 0)?, 1);
+        assert_eq!(store.indirect_calls.entries.iter().flatten().count(), 1);
+        assert_eq!(func.call(&mut store, 0)?, 1);
+
+        // the signature is still checked when the table changes
+        let table = store.get_table(instance.table_addrs()[0] as usize)?.clone();
+        table.borrow_mut().set(0, instance.func_addrs()[nothing as usize])?;
+        let res = func.call(&mut store, 0);
+        assert!(matches!(res, Err(Error::Trap(Trap::IndirectCallTypeMismatch { func: Some((1, _)), .. }))));
+        let message = res.expect_err("type mismatch").to_string();
+        assert!(message.starts_with("trap: indirect call type mismatch calling func[1] 'nothing': expected="));
+        assert!(matches!(func.call(&mut store, 1), Err(Error::Trap(Trap::IndirectCallTypeMismatch { .. }))));
+
+        table.borrow_mut().set(0, instance.func_addrs()[one as usize])?;
+        assert_eq!(func.call(&mut store, 0)?, 1);
+        Ok(())
+    }
+}
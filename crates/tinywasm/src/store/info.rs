@@ -0,0 +1,129 @@
+use tinywasm_types::*;
+
+use super::Store;
+use crate::ModuleInstance;
+
+/// Metadata about a memory in a [`Store`], see [`Store::memories`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryInfo {
+    /// The address of the memory in the store
+    pub addr: MemAddr,
+    /// The module instance that created the memory
+    pub owner: ModuleInstanceAddr,
+    /// The type the memory was created with
+    pub ty: MemoryType,
+    /// The current size of the memory in pages
+    ///
+    /// This is `0` for memories freed by [`Store::remove_instance`]
+    pub pages: usize,
+}
+
+/// Metadata about a table in a [`Store`], see [`Store::tables`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableInfo {
+    /// The address of the table in the store
+    pub addr: TableAddr,
+    /// The module instance that created the table
+    pub owner: ModuleInstanceAddr,
+    /// The type the table was created with
+    pub ty: TableType,
+    /// The current number of elements in the table
+    ///
+    /// This is `0` for tables freed by [`Store::remove_instance`]
+    pub size: u32,
+}
+
+/// Metadata about a global in a [`Store`], see [`Store::globals`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalInfo {
+    /// The address of the global in the store
+    pub addr: GlobalAddr,
+    /// The module instance that created the global
+    pub owner: ModuleInstanceAddr,
+    /// The type of the global
+    pub ty: GlobalType,
+    /// The current value of the global
+    pub value: WasmValue,
+}
+
+impl Store {
+    /// Iterate over all memories in the store
+    ///
+    /// This includes memories of removed instances, which can be recognized by their `owner`
+    /// no longer being returned by [`Store::get_module_instance`].
+    pub fn memories(&self) -> impl Iterator<Item = MemoryInfo> + '_ {
+        self.data.memories.iter().enumerate().map(|(addr, mem)| {
+            let mem = mem.borrow();
+            MemoryInfo { addr: addr as MemAddr, owner: mem.owner, ty: mem.kind, pages: mem.page_count() }
+        })
+    }
+
+    /// Iterate over all tables in the store
+    ///
+    /// Like [`Store::memories`], this includes tables of removed instances.
+    pub fn tables(&self) -> impl Iterator<Item = TableInfo> + '_ {
+        self.data.tables.iter().enumerate().map(|(addr, table)| {
+            let table = table.borrow();
+            TableInfo { addr: addr as TableAddr, owner: table.owner, ty: table.kind.clone(), size: table.size() as u32 }
+        })
+    }
+
+    /// Iterate over all globals in the store
+    ///
+    /// Unlike memories and tables, globals of removed instances are freed and not returned.
+    pub fn globals(&self) -> impl Iterator<Item = GlobalInfo> + '_ {
+        self.data.globals.iter().enumerate().filter_map(|(addr, global)| {
+            let global = global.as_ref()?.borrow();
+            Some(GlobalInfo { addr: addr as GlobalAddr, owner: global.owner, ty: global.ty, value: global.get() })
+        })
+    }
+
+    /// Iterate over all module instances in the store that haven't been removed
+    pub fn instances(&self) -> impl Iterator<Item = &ModuleInstance> + '_ {
+        self.module_instances.iter().flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Module;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_store_info() {
+        let module = TinyWasmModule {
+            memory_types: [MemoryType::new_32(1, Some(2))].into(),
+            table_types: [TableType::new(ValType::RefFunc, 3, None)].into(),
+            globals: [Global {
+                ty: GlobalType { mutable: true, ty: ValType::I32 },
+                init: ConstInstruction::I32Const(7),
+            }]
+            .into(),
+            ..Default::default()
+        };
+
+        let mut store = Store::default();
+        let a = Module::from(&module).instantiate(&mut store, None).unwrap();
+        let b = Module::from(&module).instantiate(&mut store, None).unwrap();
+
+        let instances: Vec<_> = store.instances().map(|i| i.id()).collect();
+        assert_eq!(instances, [a.id(), b.id()]);
+
+        let memories: Vec<_> = store.memories().map(|m| (m.addr, m.owner, m.pages)).collect();
+        assert_eq!(memories, [(0, a.id(), 1), (1, b.id(), 1)]);
+        assert!(store.tables().all(|t| t.size == 3 && t.ty.element_type == ValType::RefFunc));
+
+        let globals: Vec<_> = store.globals().map(|g| (g.value, g.ty.mutable)).collect();
+        assert_eq!(globals, [(WasmValue::I32(7), true), (WasmValue::I32(7), true)]);
+
+        store.remove_instance(a.id()).unwrap();
+        assert_eq!(store.instances().count(), 1);
+        assert_eq!(store.memories().next().map(|m| m.pages), Some(0));
+
+        // the global of the removed instance is freed
+        let globals: Vec<_> = store.globals().map(|g| (g.addr, g.owner)).collect();
+        assert_eq!(globals, [(1, b.id())]);
+        assert!(store.get_global_val(0).is_err());
+    }
+}
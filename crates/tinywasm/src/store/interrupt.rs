@@ -0,0 +1,89 @@
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::Store;
+
+/// A handle to interrupt code running in a [`Store`], see [`Store::interrupt_handle`]
+///
+/// The handle can be sent to other threads or used from an interrupt handler.
+#[derive(Debug, Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Request the store to stop executing
+    ///
+    /// The next yield point executed by the store traps with [`crate::Trap::Interrupted`].
+    /// If no code is running, the next call traps once it reaches a yield point.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Store {
+    /// Get a handle to interrupt code running in this store
+    ///
+    /// Interrupts are only checked at yield points, which the parser inserts at every function entry
+    /// and loop back-edge when enabled with [`crate::parser::Parser::yield_points`].
+    /// Modules parsed without yield points can't be interrupted.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle(self.interrupt.clone())
+    }
+
+    // check and clear a pending interrupt
+    //
+    // the swap reads and clears the flag in one operation, so a request can't be cleared without being
+    // reported. The load first keeps yield points from writing to the flag while no interrupt is pending
+    #[inline]
+    pub(crate) fn take_interrupt(&self) -> bool {
+        self.interrupt.load(Ordering::Relaxed) && self.interrupt.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Module, Trap};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_interrupt() -> crate::Result<()> {
+        // an endless loop with yield points, as inserted by the parser
+        let func = WasmFunction {
+            instructions: vec![
+                Instruction::Yield,
+                Instruction::Loop(BlockArgsPacked::EMPTY, 3),
+                Instruction::Yield,
+                Instruction::Br(0),
+                Instruction::EndBlockFrame,
+                Instruction::EndFunc,
+            ]
+            .into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: FuncType::default(),
+        };
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![func].into(),
+            func_types: vec![FuncType::default()].into(),
+            exports: vec![Export { name: "spin".into(), kind: ExternalKind::Func, index: 0 }].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        let spin = instance.exported_func::<(), ()>(&store, "spin")?;
+
+        // stop the loop after a few iterations using the fuel as a clock
+        store.set_fuel(Some(100));
+        assert!(matches!(spin.call(&mut store, ()), Err(Error::Trap(Trap::OutOfFuel))));
+
+        store.set_fuel(None);
+        store.interrupt_handle().interrupt();
+        assert!(matches!(spin.call(&mut store, ()), Err(Error::Trap(Trap::Interrupted))));
+        assert!(!store.take_interrupt());
+        Ok(())
+    }
+}
@@ -1,42 +1,207 @@
-use alloc::vec;
-use alloc::vec::Vec;
+use alloc::{boxed::Box, format, vec, vec::Vec};
 use tinywasm_types::{MemoryType, ModuleInstanceAddr};
 
+use crate::sync::{MaybeSendSync, Rc};
 use crate::{log, Error, Result};
 
-const PAGE_SIZE: usize = 65536;
-const MAX_PAGES: usize = 65536;
-const MAX_SIZE: u64 = PAGE_SIZE as u64 * MAX_PAGES as u64;
+pub(crate) const PAGE_SIZE: u64 = 65536;
+const MAX_PAGES: u64 = 65536;
+const MAX_SIZE: u64 = PAGE_SIZE * MAX_PAGES;
+
+/// The granularity of dirty page tracking, see [`MemoryInstance::take_dirty_pages`]
+pub(crate) const DIRTY_PAGE_SIZE: usize = 4096;
+
+/// The size of `pages` pages in bytes, or `None` if it doesn't fit in a `usize`
+///
+/// The size is computed using `u64`, so this never overflows.
+#[inline]
+pub(crate) fn pages_to_bytes(pages: u64) -> Option<usize> {
+    pages.checked_mul(PAGE_SIZE).and_then(|bytes| usize::try_from(bytes).ok())
+}
+
+/// The most pages a memory of type `kind` can have
+#[inline]
+pub(crate) fn max_pages(kind: &MemoryType) -> u64 {
+    kind.page_count_max.unwrap_or(MAX_PAGES)
+}
+
+// the initial size of a memory in bytes
+fn initial_size(kind: &MemoryType) -> Result<usize> {
+    assert!(kind.page_count_initial <= max_pages(kind));
+    pages_to_bytes(kind.page_count_initial)
+        .ok_or_else(|| Error::UnsupportedFeature(format!("{} page memories on this target", kind.page_count_initial)))
+}
+
+/// Observes accesses to a linear memory
+///
+/// Observers are called for every access that is in bounds, both from WebAssembly and from the host,
+/// e.g. to track dirty pages or to debug shared-memory protocols.
+/// See [`crate::MemoryRefMut::set_observer`]
+pub trait MemoryObserver: MaybeSendSync {
+    /// Called when `len` bytes are read at `addr`
+    fn on_read(&self, _addr: usize, _len: usize) {}
+
+    /// Called when `len` bytes are written at `addr`
+    fn on_write(&self, _addr: usize, _len: usize) {}
+}
+
+impl<T: MemoryObserver + ?Sized> MemoryObserver for Rc<T> {
+    fn on_read(&self, addr: usize, len: usize) {
+        (**self).on_read(addr, len)
+    }
+
+    fn on_write(&self, addr: usize, len: usize) {
+        (**self).on_write(addr, len)
+    }
+}
+
+pub(crate) struct Observer(Box<dyn MemoryObserver>);
+
+impl core::fmt::Debug for Observer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("MemoryObserver")
+    }
+}
 
 /// A WebAssembly Memory Instance
 ///
+/// The memory's data is reference counted, so forked memories share it
+/// until one of them is written to (copy-on-write).
+///
 /// See <https://webassembly.github.io/spec/core/exec/runtime.html#memory-instances>
 #[derive(Debug)]
 pub(crate) struct MemoryInstance {
     pub(crate) kind: MemoryType,
-    pub(crate) data: Vec<u8>,
+    pub(crate) data: Rc<Vec<u8>>,
     pub(crate) page_count: usize,
-    pub(crate) _owner: ModuleInstanceAddr, // index into store.module_instances
+    // the memory never shrinks below this, so static accesses don't need bounds checks, see `load_static`
+    min_page_count: usize,
+    pub(crate) owner: ModuleInstanceAddr, // index into store.module_instances
+    pub(crate) observer: Option<Observer>,
+    pub(crate) dirty: Option<Vec<u64>>, // bitset of the dirty pages, if they are tracked
 }
 
 impl MemoryInstance {
-    pub(crate) fn new(kind: MemoryType, owner: ModuleInstanceAddr) -> Self {
-        assert!(kind.page_count_initial <= kind.page_count_max.unwrap_or(MAX_PAGES as u64));
+    pub(crate) fn new(kind: MemoryType, owner: ModuleInstanceAddr) -> Result<Self> {
+        let size = initial_size(&kind)?;
         log::debug!("initializing memory with {} pages", kind.page_count_initial);
 
-        Self {
+        Ok(Self {
             kind,
-            data: vec![0; PAGE_SIZE * kind.page_count_initial as usize],
+            data: Rc::new(vec![0; size]),
             page_count: kind.page_count_initial as usize,
-            _owner: owner,
+            min_page_count: kind.page_count_initial as usize,
+            owner,
+            observer: None,
+            dirty: None,
+        })
+    }
+
+    /// Create a new memory, reusing the allocation of `buffer`
+    pub(crate) fn with_buffer(kind: MemoryType, owner: ModuleInstanceAddr, mut buffer: Vec<u8>) -> Result<Self> {
+        let size = initial_size(&kind)?;
+        log::debug!("initializing memory with {} pages from a pooled buffer", kind.page_count_initial);
+
+        buffer.clear();
+        buffer.resize(size, 0);
+        let page_count = kind.page_count_initial as usize;
+        let min_page_count = page_count;
+        Ok(Self { kind, data: Rc::new(buffer), page_count, min_page_count, owner, observer: None, dirty: None })
+    }
+
+    /// Create a copy of this memory for another module instance
+    ///
+    /// The data is only copied once either of the memories is written to.
+    /// Observers and dirty pages are not copied.
+    pub(crate) fn fork(&self, owner: ModuleInstanceAddr) -> Self {
+        Self {
+            kind: self.kind,
+            data: self.data.clone(),
+            page_count: self.page_count,
+            min_page_count: self.min_page_count,
+            owner,
+            observer: None,
+            dirty: None,
+        }
+    }
+
+    pub(crate) fn set_observer(&mut self, observer: Option<Box<dyn MemoryObserver>>) {
+        self.observer = observer.map(Observer);
+    }
+
+    #[inline]
+    fn observe_read(&self, addr: usize, len: usize) {
+        if let Some(observer) = &self.observer {
+            observer.0.on_read(addr, len);
         }
     }
 
+    #[inline]
+    fn observe_write(&mut self, addr: usize, len: usize) {
+        if let Some(observer) = &self.observer {
+            observer.0.on_write(addr, len);
+        }
+
+        if let Some(dirty) = &mut self.dirty {
+            if len > 0 {
+                let last = (addr + len - 1) / DIRTY_PAGE_SIZE;
+                if last / 64 >= dirty.len() {
+                    // the memory has grown since the pages were last taken
+                    dirty.resize(last / 64 + 1, 0);
+                }
+                for page in addr / DIRTY_PAGE_SIZE..=last {
+                    dirty[page / 64] |= 1 << (page % 64);
+                }
+            }
+        }
+    }
+
+    /// Return the pages of [`DIRTY_PAGE_SIZE`] bytes written since the last call, in order,
+    /// and start tracking them if they weren't tracked yet
+    ///
+    /// Returns `None` if the pages weren't tracked.
+    pub(crate) fn take_dirty_pages(&mut self) -> Option<Vec<usize>> {
+        let words = (self.data.len() / DIRTY_PAGE_SIZE).div_ceil(64);
+        let dirty = core::mem::replace(&mut self.dirty, Some(vec![0; words]))?;
+
+        let pages = dirty
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, word)| (0..64).filter(move |bit| word & (1 << bit) != 0).map(move |bit| i * 64 + bit));
+        Some(pages.collect())
+    }
+
+    /// Free the memory's data, returning the buffer if it isn't shared with a forked memory
+    ///
+    /// Only used once no instance using the memory is left, so no static accesses can reach it.
+    pub(crate) fn release(&mut self) -> Option<Vec<u8>> {
+        self.page_count = 0;
+        self.min_page_count = 0;
+        Rc::try_unwrap(core::mem::take(&mut self.data)).ok()
+    }
+
+    // get mutable access to the data, copying it first if it is shared with a forked memory
+    #[inline]
+    fn data_mut(&mut self) -> &mut Vec<u8> {
+        Rc::make_mut(&mut self.data)
+    }
+
     #[cold]
     fn trap_oob(&self, addr: usize, len: usize) -> Error {
         Error::Trap(crate::Trap::MemoryOutOfBounds { offset: addr, len, max: self.data.len() })
     }
 
+    /// Get the effective address of a `len` byte access at `addr + offset`
+    ///
+    /// Traps if the address doesn't fit in a `usize`, in which case the trap's offset is saturated
+    #[inline]
+    pub(crate) fn effective_addr(&self, addr: u32, offset: u64, len: usize) -> Result<usize> {
+        match offset.checked_add(addr as u64).map(usize::try_from) {
+            Some(Ok(addr)) => Ok(addr),
+            _ => Err(self.trap_oob(usize::MAX, len)),
+        }
+    }
+
     pub(crate) fn store(&mut self, addr: usize, len: usize, data: &[u8]) -> Result<()> {
         let Some(end) = addr.checked_add(len) else {
             return Err(self.trap_oob(addr, data.len()));
@@ -48,7 +213,7 @@ impl MemoryInstance {
 
         // WebAssembly doesn't require alignment for stores
         #[cfg(not(feature = "unsafe"))]
-        self.data[addr..end].copy_from_slice(data);
+        self.data_mut()[addr..end].copy_from_slice(data);
 
         #[cfg(feature = "unsafe")]
         // SAFETY: we checked that `end` is in bounds above, this is the same as `copy_from_slice`
@@ -57,14 +222,83 @@ impl MemoryInstance {
         // Both src and dst are properly aligned.
         // The region of memory beginning at src does not overlap with the region of memory beginning at dst with the same size.
         unsafe {
-            core::ptr::copy_nonoverlapping(data.as_ptr(), self.data[addr..end].as_mut_ptr(), len);
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.data_mut()[addr..end].as_mut_ptr(), len);
         }
 
+        self.observe_write(addr, len);
         Ok(())
     }
 
-    pub(crate) fn max_pages(&self) -> usize {
-        self.kind.page_count_max.unwrap_or(MAX_PAGES as u64) as usize
+    // the minimum size in bytes, this always fits in a `usize` since the memory is at least this large
+    #[inline]
+    fn min_size(&self) -> usize {
+        self.min_page_count * PAGE_SIZE as usize
+    }
+
+    /// Prevent the memory from shrinking below `pages` pages, e.g. when it is imported by a module
+    /// with a larger minimum size
+    ///
+    /// Fails if the memory is already smaller than that.
+    pub(crate) fn require_pages(&mut self, pages: u64) -> Result<()> {
+        if pages > self.page_count as u64 {
+            return Err(Error::Other(format!("memory has {} pages, {} are required", self.page_count, pages)));
+        }
+        self.min_page_count = self.min_page_count.max(pages as usize);
+        Ok(())
+    }
+
+    /// Whether the memory can be set to `page_count` pages of data with the given length,
+    /// e.g. when restoring a snapshot, without shrinking below its minimum size
+    pub(crate) fn can_resize_to(&self, page_count: usize, data_len: usize) -> bool {
+        page_count >= self.min_page_count && data_len >= self.min_size()
+    }
+
+    /// Load a value from an address that is in bounds of the memory's minimum size
+    ///
+    /// This is the case for the static memory instructions such as [`tinywasm_types::Instruction::I32LoadStatic`],
+    /// which are only emitted for, and verified to have, such addresses. The data never shrinks below
+    /// the minimum size: the minimum only grows (see `require_pages`), and `grow` and snapshot restores
+    /// reject smaller sizes, so instead of being checked when accessing the memory, the size is checked
+    /// when it changes. The only exception is `release`, after which no instance using the memory is left to run.
+    ///
+    /// So these accesses can't trap, and don't compare the address to the size. With the `unsafe` feature,
+    /// they don't check the bounds at all, otherwise only the slice indexing does, which can't fail.
+    #[inline]
+    pub(crate) fn load_static<const SIZE: usize, T: MemLoadable<SIZE>>(&self, addr: usize) -> T {
+        debug_assert!(addr + SIZE <= self.min_size() && self.min_size() <= self.data.len());
+        self.observe_read(addr, SIZE);
+
+        #[cfg(not(feature = "unsafe"))]
+        let bytes = self.data[addr..addr + SIZE].try_into().expect("slice size mismatch");
+
+        // SAFETY: `addr + SIZE` is at most the minimum size of the memory, which its data never shrinks below.
+        // `[u8; SIZE]` has an alignment of 1, so the read doesn't have to be aligned.
+        #[cfg(feature = "unsafe")]
+        let bytes = unsafe { *(self.data.as_ptr().add(addr) as *const [u8; SIZE]) };
+
+        T::from_le_bytes(bytes)
+    }
+
+    /// Store `data` at an address that is in bounds of the memory's minimum size, see [`MemoryInstance::load_static`]
+    #[inline]
+    pub(crate) fn store_static(&mut self, addr: usize, data: &[u8]) {
+        debug_assert!(addr + data.len() <= self.min_size() && self.min_size() <= self.data.len());
+
+        #[cfg(not(feature = "unsafe"))]
+        self.data_mut()[addr..addr + data.len()].copy_from_slice(data);
+
+        // SAFETY: the destination is in bounds, see `load_static`, and can't overlap with `data`
+        // since that isn't borrowed from the memory
+        #[cfg(feature = "unsafe")]
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), self.data_mut().as_mut_ptr().add(addr), data.len());
+        }
+
+        self.observe_write(addr, data.len());
+    }
+
+    pub(crate) fn max_pages(&self) -> u64 {
+        max_pages(&self.kind)
     }
 
     pub(crate) fn load(&self, addr: usize, len: usize) -> Result<&[u8]> {
@@ -76,10 +310,12 @@ impl MemoryInstance {
             return Err(self.trap_oob(addr, len));
         }
 
+        self.observe_read(addr, len);
         Ok(&self.data[addr..end])
     }
 
     // this is a workaround since we can't use generic const expressions yet (https://github.com/rust-lang/rust/issues/76560)
+    #[cfg_attr(feature = "opt-size", allow(dead_code))] // loads use `load` instead
     pub(crate) fn load_as<const SIZE: usize, T: MemLoadable<SIZE>>(&self, addr: usize) -> Result<T> {
         let Some(end) = addr.checked_add(SIZE) else {
             return Err(self.trap_oob(addr, SIZE));
@@ -89,14 +325,16 @@ impl MemoryInstance {
             return Err(self.trap_oob(addr, SIZE));
         }
 
-        #[cfg(not(feature = "unsafe"))]
+        // WebAssembly memory is always little-endian
+        #[cfg(any(not(feature = "unsafe"), target_endian = "big"))]
         let val = T::from_le_bytes(self.data[addr..end].try_into().expect("slice size mismatch"));
 
-        #[cfg(feature = "unsafe")]
+        #[cfg(all(feature = "unsafe", target_endian = "little"))]
         // SAFETY: we checked that `end` is in bounds above. All types that implement `Into<RawWasmValue>` are valid
         // to load from unaligned addresses.
         let val = unsafe { core::ptr::read_unaligned(self.data[addr..end].as_ptr() as *const T) };
 
+        self.observe_read(addr, SIZE);
         Ok(val)
     }
 
@@ -111,7 +349,8 @@ impl MemoryInstance {
             return Err(self.trap_oob(addr, len));
         }
 
-        self.data[addr..end].fill(val);
+        self.data_mut()[addr..end].fill(val);
+        self.observe_write(addr, len);
         Ok(())
     }
 
@@ -121,7 +360,8 @@ impl MemoryInstance {
             return Err(self.trap_oob(dst, src.len()));
         }
 
-        self.data[dst..end].copy_from_slice(src);
+        self.data_mut()[dst..end].copy_from_slice(src);
+        self.observe_write(dst, src.len());
         Ok(())
     }
 
@@ -139,29 +379,29 @@ impl MemoryInstance {
         }
 
         // Perform the copy
-        self.data.copy_within(src..src_end, dst);
+        self.data_mut().copy_within(src..src_end, dst);
+        self.observe_read(src, len);
+        self.observe_write(dst, len);
         Ok(())
     }
 
-    pub(crate) fn grow(&mut self, pages_delta: i32) -> Option<i32> {
+    // memories never shrink, so the delta is unsigned like the operand of `memory.grow`
+    pub(crate) fn grow(&mut self, pages_delta: u32) -> Option<i32> {
         let current_pages = self.page_count();
-        let new_pages = current_pages as i64 + pages_delta as i64;
+        let new_pages = current_pages as u64 + pages_delta as u64;
 
-        if new_pages < 0 || new_pages > MAX_PAGES as i64 {
+        if new_pages > MAX_PAGES {
             return None;
         }
 
-        if new_pages as usize > self.max_pages() {
+        if new_pages > self.max_pages() || new_pages * PAGE_SIZE > MAX_SIZE {
             return None;
         }
 
-        let new_size = new_pages as usize * PAGE_SIZE;
-        if new_size as u64 > MAX_SIZE {
-            return None;
-        }
+        let new_size = pages_to_bytes(new_pages)?;
 
         // Zero initialize the new pages
-        self.data.resize(new_size, 0);
+        self.data_mut().resize(new_size, 0);
         self.page_count = new_pages as usize;
         debug_assert!(current_pages <= i32::MAX as usize, "page count should never be greater than i32::MAX");
         Some(current_pages as i32)
@@ -213,10 +453,12 @@ mod memory_instance_tests {
     use super::*;
     use tinywasm_types::{MemoryArch, MemoryType, ModuleInstanceAddr};
 
+    const PAGE_SIZE: usize = super::PAGE_SIZE as usize;
+
     fn create_test_memory() -> MemoryInstance {
         let kind = MemoryType { arch: MemoryArch::I32, page_count_initial: 1, page_count_max: Some(2) };
         let owner = ModuleInstanceAddr::default();
-        MemoryInstance::new(kind, owner)
+        MemoryInstance::new(kind, owner).unwrap()
     }
 
     #[test]
@@ -228,6 +470,23 @@ mod memory_instance_tests {
         assert_eq!(loaded_data, &data_to_store);
     }
 
+    #[test]
+    fn test_memory_load_little_endian() {
+        let mut memory = create_test_memory();
+        let bytes = [0x78, 0x56, 0x34, 0x12, 0xf0, 0xde, 0xbc, 0x9a];
+        memory.store(0, bytes.len(), &bytes).unwrap();
+
+        assert_eq!(memory.load_as::<4, u32>(0).unwrap(), 0x12345678);
+        assert_eq!(memory.load_as::<2, u16>(1).unwrap(), 0x3456);
+        assert_eq!(memory.load_as::<8, u64>(0).unwrap(), 0x9abcdef012345678);
+        assert_eq!(memory.load_as::<4, f32>(0).unwrap().to_bits(), 0x12345678);
+
+        // a big-endian host interprets the same bytes in reverse order
+        let mut reversed = bytes;
+        reversed.reverse();
+        assert_eq!(<u64 as MemLoadable<8>>::from_be_bytes(reversed), memory.load_as::<8, u64>(0).unwrap());
+    }
+
     #[test]
     fn test_memory_store_out_of_bounds() {
         let mut memory = create_test_memory();
@@ -262,6 +521,37 @@ mod memory_instance_tests {
         assert!(memory.copy_within(memory.data.len(), 0, 10).is_err());
     }
 
+    #[test]
+    fn test_memory_static_access() {
+        let mut memory = create_test_memory();
+        let mut checked = create_test_memory();
+        for addr in [0, 1, PAGE_SIZE / 2, PAGE_SIZE - 8] {
+            let val = addr as u64 * 0x0101_0101;
+            memory.store_static(addr, &val.to_le_bytes());
+            checked.store(addr, 8, &val.to_le_bytes()).unwrap();
+            assert_eq!(memory.load_static::<8, u64>(addr), checked.load_as::<8, u64>(addr).unwrap());
+            assert_eq!(memory.load_static::<4, i32>(addr + 4), checked.load_as::<4, i32>(addr + 4).unwrap());
+        }
+        assert_eq!(memory.data, checked.data);
+    }
+
+    #[test]
+    fn test_memory_min_pages() {
+        let mut memory = create_test_memory();
+        assert!(memory.require_pages(2).is_err());
+        assert_eq!(memory.grow(1), Some(1));
+        memory.require_pages(2).unwrap();
+
+        // the memory can't shrink below its minimum size, e.g. from a snapshot
+        assert!(!memory.can_resize_to(1, PAGE_SIZE));
+        assert!(memory.can_resize_to(2, 2 * PAGE_SIZE));
+
+        // deltas are unsigned, so growing never shrinks the memory
+        assert_eq!(memory.grow(u32::MAX), None);
+        assert_eq!(memory.fork(0).grow(u32::MAX), None);
+        assert_eq!(memory.page_count(), 2);
+    }
+
     #[test]
     fn test_memory_grow() {
         let mut memory = create_test_memory();
@@ -270,10 +560,17 @@ mod memory_instance_tests {
         assert_eq!(memory.page_count(), original_pages + 1);
     }
 
+    #[test]
+    fn test_pages_to_bytes() {
+        assert_eq!(pages_to_bytes(0), Some(0));
+        assert_eq!(pages_to_bytes(3), Some(3 * PAGE_SIZE));
+        assert_eq!(pages_to_bytes(u64::MAX), None);
+    }
+
     #[test]
     fn test_memory_grow_out_of_bounds() {
         let mut memory = create_test_memory();
-        assert!(memory.grow(MAX_PAGES as i32 + 1).is_none());
+        assert!(memory.grow(MAX_PAGES as u32 + 1).is_none());
     }
 
     #[test]
@@ -282,4 +579,88 @@ mod memory_instance_tests {
         assert_eq!(memory.grow(1), Some(1));
         assert_eq!(memory.grow(1), None);
     }
+
+    #[test]
+    fn test_memory_out_of_bounds_trap() {
+        let mut memory = create_test_memory();
+        fn oob<T: core::fmt::Debug>(res: Result<T>) -> (usize, usize, usize) {
+            match res {
+                Err(Error::Trap(crate::Trap::MemoryOutOfBounds { offset, len, max })) => (offset, len, max),
+                res => panic!("expected out of bounds trap, got {:?}", res),
+            }
+        }
+
+        assert_eq!(oob(memory.load(PAGE_SIZE - 2, 4)), (PAGE_SIZE - 2, 4, PAGE_SIZE));
+        assert_eq!(oob(memory.store(PAGE_SIZE, 8, &[0; 8])), (PAGE_SIZE, 8, PAGE_SIZE));
+        assert_eq!(oob(memory.load_as::<4, u32>(usize::MAX - 1)), (usize::MAX - 1, 4, PAGE_SIZE));
+
+        let addr = memory.effective_addr(u32::MAX, 16, 4).unwrap();
+        assert_eq!(oob(memory.load_as::<4, u32>(addr)), (u32::MAX as usize + 16, 4, PAGE_SIZE));
+        assert_eq!(oob(memory.effective_addr(1, u64::MAX, 4)), (usize::MAX, 4, PAGE_SIZE));
+
+        memory.grow(1).unwrap();
+        assert_eq!(oob(memory.load(2 * PAGE_SIZE, 1)), (2 * PAGE_SIZE, 1, 2 * PAGE_SIZE));
+    }
+
+    #[test]
+    fn test_memory_observer() {
+        use crate::sync::RefCell;
+
+        #[derive(Default)]
+        struct AccessLog(RefCell<Vec<(&'static str, usize, usize)>>);
+        impl MemoryObserver for AccessLog {
+            fn on_read(&self, addr: usize, len: usize) {
+                self.0.borrow_mut().push(("read", addr, len));
+            }
+            fn on_write(&self, addr: usize, len: usize) {
+                self.0.borrow_mut().push(("write", addr, len));
+            }
+        }
+
+        let mut memory = create_test_memory();
+        let log = Rc::new(AccessLog::default());
+        memory.set_observer(Some(Box::new(log.clone())));
+
+        memory.store(8, 4, &[1, 2, 3, 4]).unwrap();
+        memory.load_as::<4, u32>(8).unwrap();
+        memory.copy_within(16, 8, 4).unwrap();
+        memory.fill(0, 2, 0).unwrap();
+        assert!(memory.load(PAGE_SIZE, 1).is_err());
+
+        let expected = [("write", 8, 4), ("read", 8, 4), ("read", 8, 4), ("write", 16, 4), ("write", 0, 2)];
+        assert_eq!(*log.0.borrow(), expected);
+
+        memory.set_observer(None);
+        memory.load(0, 4).unwrap();
+        assert_eq!(log.0.borrow().len(), expected.len());
+    }
+
+    #[test]
+    fn test_memory_dirty_pages() {
+        let mut memory = create_test_memory();
+        memory.store(0, 4, &[1; 4]).unwrap();
+        assert_eq!(memory.take_dirty_pages(), None);
+
+        memory.store(DIRTY_PAGE_SIZE - 1, 2, &[1; 2]).unwrap();
+        memory.fill(3 * DIRTY_PAGE_SIZE, 0, 0).unwrap();
+        memory.grow(1).unwrap();
+        memory.copy_within(PAGE_SIZE, 0, 4).unwrap();
+        let last = PAGE_SIZE / DIRTY_PAGE_SIZE;
+        assert_eq!(memory.take_dirty_pages(), Some(vec![0, 1, last]));
+        assert_eq!(memory.take_dirty_pages(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_memory_fork_copy_on_write() {
+        let mut memory = create_test_memory();
+        memory.fill(0, 4, 1).unwrap();
+
+        let mut forked = memory.fork(1);
+        assert!(Rc::ptr_eq(&memory.data, &forked.data));
+
+        forked.fill(0, 4, 2).unwrap();
+        assert!(!Rc::ptr_eq(&memory.data, &forked.data));
+        assert_eq!(memory.load(0, 4).unwrap(), &[1; 4]);
+        assert_eq!(forked.load(0, 4).unwrap(), &[2; 4]);
+    }
 }
@@ -0,0 +1,64 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use tinywasm_types::ModuleInstanceAddr;
+
+#[cfg(feature = "std")]
+use crate::std::time::Duration;
+
+use super::Store;
+
+/// Usage metrics of an exported function, see [`crate::ModuleInstance::metrics`]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CallMetrics {
+    /// The number of calls to the function
+    pub calls: u64,
+    /// The number of calls that returned an error, e.g. because they trapped
+    pub errors: u64,
+    /// The total wall clock time spent in the function, including nested calls
+    #[cfg(feature = "std")]
+    pub time: Duration,
+}
+
+pub(crate) type InstanceMetrics = BTreeMap<String, CallMetrics>;
+
+impl Store {
+    /// Enable or disable tracking calls to exported functions
+    ///
+    /// While enabled, calls using a [`crate::FuncHandle`] obtained from a module instance's exports
+    /// are counted and timed per instance and export name, see [`crate::ModuleInstance::metrics`].
+    /// Disabling tracking discards the collected metrics.
+    pub fn enable_call_metrics(&mut self, enabled: bool) {
+        self.call_metrics = enabled.then(BTreeMap::new);
+    }
+
+    #[inline]
+    pub(crate) fn call_metrics_enabled(&self) -> bool {
+        self.call_metrics.is_some()
+    }
+
+    pub(crate) fn instance_metrics(&self, addr: ModuleInstanceAddr) -> Option<&InstanceMetrics> {
+        self.call_metrics.as_ref()?.get(&addr)
+    }
+
+    pub(crate) fn record_call(
+        &mut self,
+        addr: ModuleInstanceAddr,
+        name: &str,
+        failed: bool,
+        #[cfg(feature = "std")] time: Duration,
+    ) {
+        let Some(metrics) = self.call_metrics.as_mut() else { return };
+        let instance = metrics.entry(addr).or_default();
+        let metrics = match instance.get_mut(name) {
+            Some(metrics) => metrics,
+            None => instance.entry(name.to_string()).or_default(),
+        };
+
+        metrics.calls += 1;
+        metrics.errors += failed as u64;
+        #[cfg(feature = "std")]
+        {
+            metrics.time += time;
+        }
+    }
+}
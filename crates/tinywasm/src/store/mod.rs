@@ -1,19 +1,46 @@
-use alloc::{boxed::Box, format, rc::Rc, string::ToString, vec::Vec};
-use core::cell::RefCell;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, BTreeSet},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use tinywasm_types::*;
 
-use crate::runtime::{self, InterpreterRuntime, RawWasmValue};
-use crate::{Error, Function, ModuleInstance, Result, Trap};
+use crate::runtime::{self, InterpreterRuntime, RawWasmValue, Stack};
+use crate::sync::{ExternObject, Rc, RefCell};
+use crate::{log, Backtrace, Error, FuncHandle, Function, ModuleInstance, Result, Trap};
 
 mod data;
 mod element;
+mod events;
+mod fuel;
 mod function;
 mod global;
+mod indirect;
+mod info;
+mod interrupt;
 mod memory;
+mod metrics;
+mod pool;
+mod quota;
+mod snapshot;
 mod table;
+mod types;
 
-pub(crate) use {data::*, element::*, function::*, global::*, memory::*, table::*};
+pub(crate) use {data::*, element::*, function::*, global::*, memory::*, metrics::*, pool::*, table::*, types::*};
+pub use {
+    events::{StoreEvent, SubscriptionId},
+    info::*,
+    interrupt::InterruptHandle,
+    memory::MemoryObserver,
+    metrics::CallMetrics,
+    pool::PoolConfig,
+    quota::{ResourceKind, ResourceRequest, ResourceUsage},
+    snapshot::StoreSnapshot,
+};
 
 // global store id counter
 static STORE_ID: AtomicUsize = AtomicUsize::new(0);
@@ -23,18 +50,53 @@ static STORE_ID: AtomicUsize = AtomicUsize::new(0);
 /// Data should only be addressable by the module that owns it
 ///
 /// Note that the state doesn't do any garbage collection - so it will grow
-/// indefinitely if you keep adding modules to it. When calling temporary
-/// functions, you should create a new store and then drop it when you're done (e.g. in a request handler)
+/// indefinitely if you keep adding modules to it. Instances that are no longer needed
+/// can be removed using [`Store::remove_instance`], which frees their memories and tables.
+/// When calling temporary functions, you should create a new store and then drop it
+/// when you're done (e.g. in a request handler)
 ///
 ///  See <https://webassembly.github.io/spec/core/exec/runtime.html#store>
 #[derive(Debug)]
 pub struct Store {
     id: usize,
-    module_instances: Vec<ModuleInstance>,
+    module_instances: Vec<Option<ModuleInstance>>, // none if the instance has been removed
     module_instance_count: usize,
+    registered_instances: BTreeMap<String, ModuleInstanceAddr>,
+    pending_segments: BTreeMap<ModuleInstanceAddr, Vec<PendingSegment>>,
 
     pub(crate) data: StoreData,
     pub(crate) runtime: Runtime,
+    pub(crate) pool: Option<Pool>,
+    // the stack of the last finished call, reused by the next one if the store has no pool
+    spare_stack: Option<Stack>,
+    // the stacks of metered calls dropped before they finished, taken again by the next calls
+    pub(crate) dropped_stacks: Rc<RefCell<Vec<Stack>>>,
+    last_backtrace: Option<Backtrace>,
+    call_metrics: Option<BTreeMap<ModuleInstanceAddr, InstanceMetrics>>,
+    limits: quota::Limits,
+    pub(crate) fuel: Option<u64>,
+    // the fuel given by the last `set_fuel`, and the fuel consumed before it, see `Store::fuel_consumed`
+    fuel_set: u64,
+    fuel_spent: u64,
+    interrupt: Arc<AtomicBool>,
+    pub(crate) coverage: Option<BTreeMap<FuncAddr, BTreeMap<usize, u64>>>,
+    pub(crate) types: types::TypeRegistry,
+    pub(crate) indirect_calls: indirect::IndirectCallCache,
+    subscribers: events::Subscribers,
+    #[cfg(feature = "profiler")]
+    pub(crate) profiler: Option<crate::Profiler>,
+    #[cfg(feature = "opcode-counts")]
+    pub(crate) opcode_counter: Option<crate::opcode_counts::OpcodeCounter>,
+    // functions of modules parsed with `Module::parse_bytes_async` that are translated when they are first called
+    #[cfg(feature = "parallel")]
+    pending_funcs: BTreeMap<FuncAddr, (Arc<tinywasm_parser::LazyFunctions>, usize)>,
+}
+
+/// An active element or data segment whose initialization has been deferred
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum PendingSegment {
+    Element { table: TableAddr, offset: i32, elem: ElemAddr },
+    Data { mem: MemAddr, offset: i32, data: DataAddr },
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -48,13 +110,190 @@ impl Store {
         Self::default()
     }
 
+    /// Create a new store that uses a pooling allocator
+    ///
+    /// This pre-allocates memories, tables and execution stacks and
+    /// reuses them across instantiations and calls, see [`PoolConfig`].
+    /// Fails if the pooled memories don't fit in the address space of the target.
+    pub fn with_pool(config: PoolConfig) -> Result<Self> {
+        let mut store = Self::default();
+        store.pool = Some(Pool::new(config)?);
+        Ok(store)
+    }
+
+    /// Attach a sampling profiler to the store
+    ///
+    /// While attached, functions called in this store publish their current
+    /// location to the profiler, see [`crate::Profiler`]
+    #[cfg(feature = "profiler")]
+    pub fn set_profiler(&mut self, profiler: Option<crate::Profiler>) {
+        self.profiler = profiler;
+    }
+
     /// Get a module instance by the internal id
+    ///
+    /// Returns `None` if the instance doesn't exist or has been removed
     pub fn get_module_instance(&self, addr: ModuleInstanceAddr) -> Option<&ModuleInstance> {
-        self.module_instances.get(addr as usize)
+        self.module_instances.get(addr as usize)?.as_ref()
+    }
+
+    pub(crate) fn get_module_instance_raw(&self, addr: ModuleInstanceAddr) -> Result<ModuleInstance> {
+        self.get_module_instance(addr).cloned().ok_or_else(|| Self::removed_error(addr))
+    }
+
+    #[cold]
+    pub(crate) fn removed_error(addr: ModuleInstanceAddr) -> Error {
+        Error::Other(format!("module instance {} has been removed", addr))
+    }
+
+    /// Register a module instance under a module name
+    ///
+    /// Imports from this module name are resolved from the instance's exports
+    /// when instantiating other modules in this store, unless they are defined in the
+    /// [`crate::Imports`] passed to the instantiation. Registering another instance
+    /// under the same name replaces the previous one.
+    pub fn register_instance(&mut self, name: &str, instance: &ModuleInstance) -> Result<()> {
+        if instance.store_id() != self.id {
+            return Err(Error::InvalidStore);
+        }
+
+        log::debug!("Registering module instance {} as {}", instance.id(), name);
+        self.registered_instances.insert(name.to_string(), instance.id());
+        Ok(())
     }
 
-    pub(crate) fn get_module_instance_raw(&self, addr: ModuleInstanceAddr) -> ModuleInstance {
-        self.module_instances[addr as usize].clone()
+    /// Remove a module name registered with [`Store::register_instance`]
+    ///
+    /// Returns the address of the instance that was registered under the name
+    pub fn unregister_instance(&mut self, name: &str) -> Option<ModuleInstanceAddr> {
+        self.registered_instances.remove(name)
+    }
+
+    /// Get the module instance registered under a module name
+    pub fn registered_instance(&self, name: &str) -> Option<&ModuleInstance> {
+        self.get_module_instance(*self.registered_instances.get(name)?)
+    }
+
+    /// Remove a module instance from the store
+    ///
+    /// This frees the memories, tables, globals and element/data segments owned by the instance,
+    /// unless they are still imported by another instance. If the store uses a pool,
+    /// the freed buffers are returned to it.
+    ///
+    /// The addresses of the removed items are not reused, so any remaining references to them
+    /// (e.g. functions of the instance stored in another instance's table) trap or fail when used.
+    pub fn remove_instance(&mut self, addr: ModuleInstanceAddr) -> Result<()> {
+        let Some(instance) = self.module_instances.get_mut(addr as usize).and_then(Option::take) else {
+            return Err(Self::removed_error(addr));
+        };
+        log::info!("Removing module instance {}", addr);
+        self.emit(StoreEvent::InstanceRemoved(addr));
+        self.registered_instances.retain(|_, registered| *registered != addr);
+        self.pending_segments.remove(&addr);
+        self.limits.remove(addr);
+        if let Some(metrics) = self.call_metrics.as_mut() {
+            metrics.remove(&addr);
+        }
+
+        let live = self.module_instances.iter().flatten();
+        let (mut used_mems, mut used_tables, mut used_globals) = (BTreeSet::new(), BTreeSet::new(), BTreeSet::new());
+        for other in live {
+            used_mems.extend(other.mem_addrs().iter().copied());
+            used_tables.extend(other.table_addrs().iter().copied());
+            used_globals.extend(other.global_addrs().iter().copied());
+        }
+
+        for mem_addr in instance.mem_addrs().iter().filter(|addr| !used_mems.contains(*addr)) {
+            let mut mem = self.data.memories[*mem_addr as usize].borrow_mut();
+            if mem.owner != addr {
+                continue;
+            }
+
+            let buffer = mem.release();
+            if let (Some(pool), Some(buffer)) = (self.pool.as_mut(), buffer) {
+                pool.give_memory(buffer);
+            }
+        }
+
+        for table_addr in instance.table_addrs().iter().filter(|addr| !used_tables.contains(*addr)) {
+            let mut table = self.data.tables[*table_addr as usize].borrow_mut();
+            if table.owner != addr {
+                continue;
+            }
+
+            let buffer = table.release();
+            if let Some(pool) = self.pool.as_mut() {
+                pool.give_table(buffer);
+            }
+        }
+
+        for global_addr in instance.global_addrs().iter().filter(|addr| !used_globals.contains(*addr)) {
+            let slot = &mut self.data.globals[*global_addr as usize];
+            if slot.as_ref().is_some_and(|global| global.borrow().owner == addr) {
+                *slot = None;
+            }
+        }
+
+        for elem_addr in instance.elem_addrs().iter() {
+            self.data.elements[*elem_addr as usize].items = None;
+        }
+
+        for data_addr in instance.data_addrs().iter() {
+            self.data.datas[*data_addr as usize].drop();
+        }
+
+        Ok(())
+    }
+
+    /// Replace the code of a module instance with a new version of its module, keeping its state
+    ///
+    /// The instance keeps its address, imports, memories, tables, globals and element/data segments,
+    /// while its functions, exports and function names are taken from `module`. Function references in the
+    /// instance's tables, globals and element segments are updated to the new function at the same index,
+    /// if it has the same type. The start function of the new module is not run.
+    ///
+    /// This fails if `module` has different imports, a different number or type of memories, tables or globals,
+    /// or if a function exported by both versions has a different type.
+    ///
+    /// Returns the updated instance, so exports have to be looked up again. The replaced functions are freed
+    /// unless they are still used by another instance (e.g. as an import) or referenced by a table, global
+    /// or element segment. Existing [`FuncHandle`]s and function references held by the host that point to
+    /// freed functions fail when used, while the ones that are still used keep running the old code.
+    pub fn hot_swap(&mut self, instance: &ModuleInstance, module: &crate::Module) -> Result<ModuleInstance> {
+        let swapped = instance.hot_swap(self, module)?;
+        // cached calls may use freed functions, or old code that now runs with the types of the new module
+        self.indirect_calls.clear();
+        Ok(swapped)
+    }
+
+    /// Free the functions in `addrs` that no instance, table, global or element segment uses anymore
+    pub(crate) fn free_unused_funcs(&mut self, mut addrs: BTreeSet<FuncAddr>) {
+        for instance in self.module_instances.iter().flatten() {
+            instance.func_addrs().iter().for_each(|addr| _ = addrs.remove(addr));
+        }
+
+        for table in self.data.tables.iter().map(|table| table.borrow()) {
+            if table.kind.element_type == ValType::RefFunc {
+                table.elements.iter().filter_map(TableElement::addr).for_each(|addr| _ = addrs.remove(&addr));
+            }
+        }
+
+        for global in self.data.globals.iter().flatten() {
+            if let WasmValue::RefFunc(addr) = global.borrow().get() {
+                addrs.remove(&addr);
+            }
+        }
+
+        for items in self.data.elements.iter().filter_map(|elem| elem.items.as_ref()) {
+            items.iter().filter_map(TableElement::addr).for_each(|addr| _ = addrs.remove(&addr));
+        }
+
+        log::debug!("freeing {} unused functions", addrs.len());
+        for addr in addrs {
+            self.data.funcs[addr as usize] = None;
+            #[cfg(feature = "parallel")]
+            self.pending_funcs.remove(&addr);
+        }
     }
 
     /// Create a new store with the given runtime
@@ -71,6 +310,31 @@ impl PartialEq for Store {
     }
 }
 
+#[cfg(feature = "std")]
+crate::std::thread_local! {
+    /// The spare stack of the last store dropped on this thread, so stores created for a single request
+    /// don't have to allocate a new stack for their first call
+    static THREAD_STACK: core::cell::RefCell<Option<Stack>> = const { core::cell::RefCell::new(None) };
+}
+
+fn take_thread_stack() -> Option<Stack> {
+    #[cfg(feature = "std")]
+    return THREAD_STACK.try_with(|stack| stack.borrow_mut().take()).ok().flatten();
+    #[cfg(not(feature = "std"))]
+    None
+}
+
+#[cfg(feature = "std")]
+impl Drop for Store {
+    fn drop(&mut self) {
+        if let Some(mut stack) = self.spare_stack.take() {
+            // the stack must not keep the functions and memories of this store alive
+            stack.clear();
+            let _ = THREAD_STACK.try_with(|slot| *slot.borrow_mut() = Some(stack));
+        }
+    }
+}
+
 impl Default for Store {
     fn default() -> Self {
         let id = STORE_ID.fetch_add(1, Ordering::Relaxed);
@@ -79,8 +343,30 @@ impl Default for Store {
             id,
             module_instances: Vec::new(),
             module_instance_count: 0,
+            registered_instances: BTreeMap::new(),
+            pending_segments: BTreeMap::new(),
             data: StoreData::default(),
             runtime: Runtime::Default,
+            pool: None,
+            spare_stack: None,
+            dropped_stacks: Rc::new(RefCell::new(Vec::new())),
+            last_backtrace: None,
+            call_metrics: None,
+            limits: Default::default(),
+            fuel: None,
+            fuel_set: 0,
+            fuel_spent: 0,
+            interrupt: Default::default(),
+            coverage: None,
+            types: Default::default(),
+            indirect_calls: Default::default(),
+            subscribers: Default::default(),
+            #[cfg(feature = "profiler")]
+            profiler: None,
+            #[cfg(feature = "opcode-counts")]
+            opcode_counter: None,
+            #[cfg(feature = "parallel")]
+            pending_funcs: BTreeMap::new(),
         }
     }
 }
@@ -91,12 +377,18 @@ impl Default for Store {
 /// Data should only be addressable by the module that owns it
 /// See <https://webassembly.github.io/spec/core/exec/runtime.html#store>
 pub(crate) struct StoreData {
-    pub(crate) funcs: Vec<FunctionInstance>,
+    /// `None` marks a function freed by [`Store::hot_swap`]
+    pub(crate) funcs: Vec<Option<FunctionInstance>>,
     pub(crate) tables: Vec<Rc<RefCell<TableInstance>>>,
     pub(crate) memories: Vec<Rc<RefCell<MemoryInstance>>>,
-    pub(crate) globals: Vec<Rc<RefCell<GlobalInstance>>>,
+    /// `None` marks a global freed by [`Store::remove_instance`]
+    pub(crate) globals: Vec<Option<Rc<RefCell<GlobalInstance>>>>,
     pub(crate) elements: Vec<ElementInstance>,
     pub(crate) datas: Vec<DataInstance>,
+    /// `None` marks an object released by [`Store::remove_extern_ref`]
+    pub(crate) extern_objects: Vec<Option<ExternObject>>,
+    /// Released slots in `extern_objects`, reused by [`Store::add_extern_ref`]
+    pub(crate) free_extern_objects: Vec<ExternAddr>,
 }
 
 impl Store {
@@ -111,7 +403,8 @@ impl Store {
 
     pub(crate) fn add_instance(&mut self, instance: ModuleInstance) -> Result<()> {
         assert!(instance.id() == self.module_instance_count as ModuleInstanceAddr);
-        self.module_instances.push(instance);
+        self.emit(StoreEvent::InstanceCreated(instance.id()));
+        self.module_instances.push(Some(instance));
         self.module_instance_count += 1;
         Ok(())
     }
@@ -121,10 +414,36 @@ impl Store {
         Error::Other(format!("{} not found", name))
     }
 
+    /// Translate the function at the actual index in the store if it hasn't been translated yet
+    ///
+    /// Only functions of modules parsed with [`crate::Module::parse_bytes_async`] are translated lazily,
+    /// this has to be called before running a function obtained with `get_func`.
+    #[inline]
+    #[cfg_attr(not(feature = "parallel"), allow(unused_variables))]
+    pub(crate) fn translate_func(&mut self, addr: FuncAddr) -> Result<()> {
+        #[cfg(feature = "parallel")]
+        if crate::unlikely(self.pending_funcs.contains_key(&addr)) {
+            return self.translate_pending(addr);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[cold]
+    fn translate_pending(&mut self, addr: FuncAddr) -> Result<()> {
+        let Some((lazy, idx)) = self.pending_funcs.get(&addr) else { return Ok(()) };
+        let func = Rc::new(lazy.get(*idx)?.clone());
+        if let Some(func_inst) = self.data.funcs.get_mut(addr as usize).and_then(Option::as_mut) {
+            func_inst.func = Function::Wasm(func);
+        }
+        self.pending_funcs.remove(&addr);
+        Ok(())
+    }
+
     /// Get the function at the actual index in the store
     #[inline]
     pub(crate) fn get_func(&self, addr: usize) -> Result<&FunctionInstance> {
-        self.data.funcs.get(addr).ok_or_else(|| Self::not_found_error("function"))
+        self.data.funcs.get(addr).and_then(Option::as_ref).ok_or_else(|| Self::not_found_error("function"))
     }
 
     /// Get the memory at the actual index in the store
@@ -157,59 +476,199 @@ impl Store {
         self.data.elements.get(addr).ok_or_else(|| Self::not_found_error("element"))
     }
 
+    /// Get the element at the actual index in the store
+    #[inline]
+    pub(crate) fn get_elem_mut(&mut self, addr: usize) -> Result<&mut ElementInstance> {
+        self.data.elements.get_mut(addr).ok_or_else(|| Self::not_found_error("element"))
+    }
+
     /// Get the global at the actual index in the store
     #[inline]
     pub(crate) fn get_global(&self, addr: usize) -> Result<&Rc<RefCell<GlobalInstance>>> {
-        self.data.globals.get(addr).ok_or_else(|| Self::not_found_error("global"))
+        self.data.globals.get(addr).and_then(Option::as_ref).ok_or_else(|| Self::not_found_error("global"))
     }
 
     /// Get the global at the actual index in the store
     #[inline]
     pub fn get_global_val(&self, addr: usize) -> Result<RawWasmValue> {
-        self.data.globals.get(addr).ok_or_else(|| Self::not_found_error("global")).map(|global| global.borrow().value)
+        self.get_global(addr).map(|global| global.borrow().value)
     }
 
     /// Set the global at the actual index in the store
     #[inline]
     pub(crate) fn set_global_val(&mut self, addr: usize, value: RawWasmValue) -> Result<()> {
-        let global = self.data.globals.get(addr).ok_or_else(|| Self::not_found_error("global"));
-        global.map(|global| global.borrow_mut().value = value)
+        self.get_global(addr).map(|global| global.borrow_mut().value = value)
+    }
+
+    /// Add a host object to the store, returning an `externref` to it
+    ///
+    /// The reference can be passed to WebAssembly functions, stored in tables and globals,
+    /// and turned back into the object with [`Store::get_extern_ref`].
+    /// Objects are kept alive until they are released with [`Store::remove_extern_ref`],
+    /// and the addresses of released objects are reused for new ones.
+    ///
+    /// Fails if the store already holds `u32::MAX + 1` objects.
+    pub fn add_extern_ref(&mut self, object: ExternObject) -> Result<WasmValue> {
+        if let Some(addr) = self.data.free_extern_objects.pop() {
+            self.data.extern_objects[addr as usize] = Some(object);
+            return Ok(WasmValue::RefExtern(addr));
+        }
+
+        let addr = ExternAddr::try_from(self.data.extern_objects.len())
+            .map_err(|_| Error::Other("too many externref objects in the store".to_string()))?;
+        self.data.extern_objects.push(Some(object));
+        Ok(WasmValue::RefExtern(addr))
+    }
+
+    /// Release the host object an `externref` points to, returning it
+    ///
+    /// Returns `None` for null references. The address of the object is reused by the next call to
+    /// [`Store::add_extern_ref`], so the host has to make sure the guest no longer uses the reference,
+    /// e.g. by clearing the table elements and globals it was stored in.
+    pub fn remove_extern_ref(&mut self, value: &WasmValue) -> Result<Option<ExternObject>> {
+        let addr = match value {
+            WasmValue::RefNull(ValType::RefExtern) => return Ok(None),
+            WasmValue::RefExtern(addr) => *addr,
+            _ => return Err(Error::Other(format!("expected an externref, got {:?}", value))),
+        };
+
+        let object = self.data.extern_objects.get_mut(addr as usize).and_then(Option::take);
+        let object = object.ok_or_else(|| Self::not_found_error("externref"))?;
+        self.data.free_extern_objects.push(addr);
+        Ok(Some(object))
+    }
+
+    /// Get the host object an `externref` points to
+    ///
+    /// Returns `None` for null references
+    pub fn get_extern_ref(&self, value: &WasmValue) -> Result<Option<&ExternObject>> {
+        match value {
+            WasmValue::RefNull(ValType::RefExtern) => Ok(None),
+            WasmValue::RefExtern(addr) => self
+                .data
+                .extern_objects
+                .get(*addr as usize)
+                .and_then(Option::as_ref)
+                .map(Some)
+                .ok_or_else(|| Self::not_found_error("externref")),
+            _ => Err(Error::Other(format!("expected an externref, got {:?}", value))),
+        }
+    }
+
+    /// Get a handle to the function a `funcref` points to
+    ///
+    /// This can be used to call functions the guest returned or stored in a table,
+    /// e.g. callbacks, without having to export them. Returns `None` for null references
+    pub fn get_func_ref(&self, value: &WasmValue) -> Result<Option<FuncHandle>> {
+        match value {
+            WasmValue::RefNull(ValType::RefFunc) => Ok(None),
+            WasmValue::RefFunc(addr) => {
+                let func = self.get_func(*addr as usize)?;
+                Ok(Some(FuncHandle { module_addr: func.owner, addr: *addr, ty: func.func.ty().clone(), name: None }))
+            }
+            _ => Err(Error::Other(format!("expected a funcref, got {:?}", value))),
+        }
+    }
+
+    /// Get the name of a function, see [`ModuleInstance::func_name`]
+    pub fn func_name(&self, func_addr: FuncAddr) -> Option<&str> {
+        let owner = self.get_module_instance(self.get_func(func_addr as usize).ok()?.owner)?;
+        owner.func_name(self.func_index(func_addr)?)
+    }
+
+    // the index of a function in the module instance that owns it
+    pub(crate) fn func_index(&self, func_addr: FuncAddr) -> Option<FuncAddr> {
+        let owner = self.get_module_instance(self.get_func(func_addr as usize).ok()?.owner)?;
+        owner.func_addrs().iter().position(|addr| *addr == func_addr).map(|idx| idx as FuncAddr)
+    }
+
+    /// Get the backtrace of the most recent function call that trapped
+    ///
+    /// Backtraces are only recorded for calls into WebAssembly functions
+    /// (e.g. using [`FuncHandle::call`]), not for traps during instantiation.
+    pub fn last_backtrace(&self) -> Option<&Backtrace> {
+        self.last_backtrace.as_ref()
+    }
+
+    pub(crate) fn record_backtrace(&mut self, stack: &Stack) {
+        let backtrace = Backtrace::capture(self, stack.call_stack.frames());
+        log::debug!("trap backtrace:\n{}", backtrace);
+        self.last_backtrace = Some(backtrace);
+    }
+
+    /// Get the byte offset in the original code section of an instruction in a function
+    ///
+    /// `instr_ptr` is the index of the instruction in the translated function, as reported by
+    /// e.g. [`crate::Frame::instr_ptr`]. Returns `None` for host functions and functions that
+    /// were not parsed from a binary.
+    pub fn code_offset(&self, func_addr: FuncAddr, instr_ptr: usize) -> Option<u32> {
+        match &self.get_func(func_addr as usize).ok()?.func {
+            Function::Wasm(func) => func.offsets.get(instr_ptr).copied(),
+            Function::Host(_) => None,
+        }
+    }
+
+    /// Get the host object an `externref` points to, downcast to `T`
+    ///
+    /// Returns `None` for null references and an error if the object is not a `T`
+    pub fn get_extern_ref_as<T: core::any::Any>(&self, value: &WasmValue) -> Result<Option<&T>> {
+        let Some(object) = self.get_extern_ref(value)? else {
+            return Ok(None);
+        };
+
+        object
+            .downcast_ref::<T>()
+            .map(Some)
+            .ok_or_else(|| Error::Other(format!("externref {:?} is not a {}", value, core::any::type_name::<T>())))
     }
 }
 
 // Linking related functions
 impl Store {
     /// Add functions to the store, returning their addresses in the store
-    pub(crate) fn init_funcs(&mut self, funcs: Vec<WasmFunction>, idx: ModuleInstanceAddr) -> Result<Vec<FuncAddr>> {
+    pub(crate) fn init_funcs(
+        &mut self,
+        funcs: impl IntoIterator<Item = Rc<WasmFunction>>,
+        idx: ModuleInstanceAddr,
+    ) -> Result<Vec<FuncAddr>> {
         let func_count = self.data.funcs.len();
         let mut func_addrs = Vec::with_capacity(func_count);
         for (i, func) in funcs.into_iter().enumerate() {
-            self.data.funcs.push(FunctionInstance::new_wasm(func, idx));
+            let type_id = self.types.intern(&func.ty);
+            self.data.funcs.push(Some(FunctionInstance::new_wasm(func, type_id, idx)));
             func_addrs.push((i + func_count) as FuncAddr);
         }
         Ok(func_addrs)
     }
 
+    /// Translate the functions at `addrs` from `lazy` when they are first called, see [`Store::translate_func`]
+    #[cfg(feature = "parallel")]
+    pub(crate) fn defer_translation(&mut self, addrs: &[FuncAddr], lazy: Arc<tinywasm_parser::LazyFunctions>) {
+        self.pending_funcs.extend(addrs.iter().enumerate().map(|(idx, addr)| (*addr, (lazy.clone(), idx))));
+    }
+
     /// Add tables to the store, returning their addresses in the store
-    pub(crate) fn init_tables(&mut self, tables: Vec<TableType>, idx: ModuleInstanceAddr) -> Result<Vec<TableAddr>> {
+    pub(crate) fn init_tables(&mut self, tables: &[TableType], idx: ModuleInstanceAddr) -> Result<Vec<TableAddr>> {
         let table_count = self.data.tables.len();
         let mut table_addrs = Vec::with_capacity(table_count);
-        for (i, table) in tables.into_iter().enumerate() {
-            self.data.tables.push(Rc::new(RefCell::new(TableInstance::new(table, idx))));
+        for (i, table) in tables.iter().enumerate() {
+            let table = self.new_table(table.clone(), idx);
+            self.data.tables.push(Rc::new(RefCell::new(table)));
             table_addrs.push((i + table_count) as TableAddr);
         }
         Ok(table_addrs)
     }
 
     /// Add memories to the store, returning their addresses in the store
-    pub(crate) fn init_memories(&mut self, memories: Vec<MemoryType>, idx: ModuleInstanceAddr) -> Result<Vec<MemAddr>> {
+    pub(crate) fn init_memories(&mut self, memories: &[MemoryType], idx: ModuleInstanceAddr) -> Result<Vec<MemAddr>> {
         let mem_count = self.data.memories.len();
         let mut mem_addrs = Vec::with_capacity(mem_count);
-        for (i, mem) in memories.into_iter().enumerate() {
+        for (i, &mem) in memories.iter().enumerate() {
             if let MemoryArch::I64 = mem.arch {
                 return Err(Error::UnsupportedFeature("64-bit memories".to_string()));
             }
-            self.data.memories.push(Rc::new(RefCell::new(MemoryInstance::new(mem, idx))));
+            let mem = self.new_memory(mem, idx)?;
+            self.data.memories.push(Rc::new(RefCell::new(mem)));
             mem_addrs.push((i + mem_count) as MemAddr);
         }
         Ok(mem_addrs)
@@ -219,7 +678,7 @@ impl Store {
     pub(crate) fn init_globals(
         &mut self,
         mut imported_globals: Vec<GlobalAddr>,
-        new_globals: Vec<Global>,
+        new_globals: &[Global],
         func_addrs: &[FuncAddr],
         idx: ModuleInstanceAddr,
     ) -> Result<Vec<Addr>> {
@@ -228,11 +687,11 @@ impl Store {
         let mut global_addrs = imported_globals;
 
         for (i, global) in new_globals.iter().enumerate() {
-            self.data.globals.push(Rc::new(RefCell::new(GlobalInstance::new(
+            self.data.globals.push(Some(Rc::new(RefCell::new(GlobalInstance::new(
                 global.ty,
                 self.eval_const(&global.init, &global_addrs, func_addrs)?,
                 idx,
-            ))));
+            )))));
             global_addrs.push((i + global_count) as Addr);
         }
 
@@ -251,7 +710,7 @@ impl Store {
                 let addr = globals.get(*addr as usize).copied().ok_or_else(|| {
                     Error::Other(format!("global {} not found. This should have been caught by the validator", addr))
                 })?;
-                let global = self.data.globals[addr as usize].clone();
+                let global = self.get_global(addr as usize)?;
                 let val = i64::from(global.borrow().value);
 
                 // check if the global is actually a null reference
@@ -275,6 +734,7 @@ impl Store {
         global_addrs: &[Addr],
         elements: &[Element],
         idx: ModuleInstanceAddr,
+        mut pending: Option<&mut Vec<PendingSegment>>,
     ) -> Result<(Box<[Addr]>, Option<Trap>)> {
         let elem_count = self.data.elements.len();
         let mut elem_addrs = Vec::with_capacity(elem_count);
@@ -294,7 +754,7 @@ impl Store {
 
                 // this one is active, so we need to initialize it (essentially a `table.init` instruction)
                 ElementKind::Active { offset, table } => {
-                    let offset = self.eval_i32_const(&offset)?;
+                    let offset = self.eval_i32_const(&offset, global_addrs)?;
                     let table_addr = table_addrs
                         .get(table as usize)
                         .copied()
@@ -304,6 +764,14 @@ impl Store {
                         return Err(Error::Other(format!("table {} not found for element {}", table, i)));
                     };
 
+                    if let Some(pending) = pending.as_deref_mut() {
+                        let elem = (i + elem_count) as ElemAddr;
+                        pending.push(PendingSegment::Element { table: table_addr, offset, elem });
+                        self.data.elements.push(ElementInstance::new(element.kind, idx, Some(init)));
+                        elem_addrs.push(elem);
+                        continue;
+                    }
+
                     // In wasm 2.0, it's possible to call a function that hasn't been instantiated yet,
                     // when using a partially initialized active element segments.
                     // This isn't mentioned in the spec, but the "unofficial" testsuite has a test for it:
@@ -326,18 +794,51 @@ impl Store {
         Ok((elem_addrs.into_boxed_slice(), None))
     }
 
+    /// Defer the initialization of a module instance's active segments, see [`Store::init_pending_segments`]
+    pub(crate) fn defer_segments(&mut self, idx: ModuleInstanceAddr, segments: Vec<PendingSegment>) {
+        if !segments.is_empty() {
+            self.pending_segments.insert(idx, segments);
+        }
+    }
+
+    /// Initialize the active segments of a module instance that was instantiated with deferred segments
+    ///
+    /// Segments are applied in order and then dropped, stopping at the first one that traps
+    pub(crate) fn init_pending_segments(&mut self, idx: ModuleInstanceAddr) -> Result<()> {
+        let Some(segments) = self.pending_segments.remove(&idx) else {
+            return Ok(());
+        };
+
+        for segment in segments {
+            match segment {
+                PendingSegment::Element { table, offset, elem } => {
+                    let items = self.data.elements[elem as usize].items.take().unwrap_or_default();
+                    self.data.tables[table as usize].borrow_mut().init_raw(offset, &items)?;
+                }
+                PendingSegment::Data { mem, offset, data } => {
+                    let bytes = self.data.datas[data as usize].data.take().unwrap_or_default();
+                    self.data.memories[mem as usize].borrow_mut().store(offset as usize, bytes.len(), &bytes)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Add data to the store, returning their addresses in the store
     pub(crate) fn init_datas(
         &mut self,
         mem_addrs: &[MemAddr],
-        datas: Vec<Data>,
+        global_addrs: &[Addr],
+        datas: &[Data],
         idx: ModuleInstanceAddr,
+        mut pending: Option<&mut Vec<PendingSegment>>,
     ) -> Result<(Box<[Addr]>, Option<Trap>)> {
         let data_count = self.data.datas.len();
         let mut data_addrs = Vec::with_capacity(data_count);
-        for (i, data) in datas.into_iter().enumerate() {
-            let data_val = match data.kind {
-                tinywasm_types::DataKind::Active { mem: mem_addr, offset } => {
+        for (i, data) in datas.iter().enumerate() {
+            let data_val = match &data.kind {
+                &tinywasm_types::DataKind::Active { mem: mem_addr, ref offset } => {
                     // a. Assert: memidx == 0
                     if mem_addr != 0 {
                         return Err(Error::UnsupportedFeature("data segments for non-zero memories".to_string()));
@@ -347,11 +848,19 @@ impl Store {
                         return Err(Error::Other(format!("memory {} not found for data segment {}", mem_addr, i)));
                     };
 
-                    let offset = self.eval_i32_const(&offset)?;
+                    let offset = self.eval_i32_const(offset, global_addrs)?;
                     let Some(mem) = self.data.memories.get_mut(*mem_addr as usize) else {
                         return Err(Error::Other(format!("memory {} not found for data segment {}", mem_addr, i)));
                     };
 
+                    if let Some(pending) = pending.as_deref_mut() {
+                        let data_addr = (i + data_count) as DataAddr;
+                        pending.push(PendingSegment::Data { mem: *mem_addr, offset, data: data_addr });
+                        self.data.datas.push(DataInstance::new(Some(data.data.to_vec()), idx));
+                        data_addrs.push(data_addr);
+                        continue;
+                    }
+
                     match mem.borrow_mut().store(offset as usize, data.data.len(), &data.data) {
                         Ok(()) => None,
                         Err(Error::Trap(trap)) => return Ok((data_addrs.into_boxed_slice(), Some(trap))),
@@ -370,12 +879,13 @@ impl Store {
     }
 
     pub(crate) fn add_global(&mut self, ty: GlobalType, value: RawWasmValue, idx: ModuleInstanceAddr) -> Result<Addr> {
-        self.data.globals.push(Rc::new(RefCell::new(GlobalInstance::new(ty, value, idx))));
+        self.data.globals.push(Some(Rc::new(RefCell::new(GlobalInstance::new(ty, value, idx)))));
         Ok(self.data.globals.len() as Addr - 1)
     }
 
     pub(crate) fn add_table(&mut self, table: TableType, idx: ModuleInstanceAddr) -> Result<TableAddr> {
-        self.data.tables.push(Rc::new(RefCell::new(TableInstance::new(table, idx))));
+        let table = self.new_table(table, idx);
+        self.data.tables.push(Rc::new(RefCell::new(table)));
         Ok(self.data.tables.len() as TableAddr - 1)
     }
 
@@ -383,22 +893,87 @@ impl Store {
         if let MemoryArch::I64 = mem.arch {
             return Err(Error::UnsupportedFeature("64-bit memories".to_string()));
         }
-        self.data.memories.push(Rc::new(RefCell::new(MemoryInstance::new(mem, idx))));
+        let mem = self.new_memory(mem, idx)?;
+        self.data.memories.push(Rc::new(RefCell::new(mem)));
         Ok(self.data.memories.len() as MemAddr - 1)
     }
 
+    // create a new memory, using a pooled buffer if available
+    fn new_memory(&mut self, mem: MemoryType, idx: ModuleInstanceAddr) -> Result<MemoryInstance> {
+        match self.pool.as_mut().and_then(Pool::take_memory) {
+            Some(buffer) => MemoryInstance::with_buffer(mem, idx, buffer),
+            None => MemoryInstance::new(mem, idx),
+        }
+    }
+
+    // create a new table, using a pooled buffer if available
+    fn new_table(&mut self, table: TableType, idx: ModuleInstanceAddr) -> TableInstance {
+        match self.pool.as_mut().and_then(Pool::take_table) {
+            Some(buffer) => TableInstance::with_buffer(table, idx, buffer),
+            None => TableInstance::new(table, idx),
+        }
+    }
+
+    /// Get an execution stack for a new call, from the pool or the last finished call if available
+    ///
+    /// Call frames, locals and labels are stored in the stack, so calls only allocate
+    /// if they need more space than the calls before them. With `std`, the first call of a store
+    /// without a pool takes the stack of the last store dropped on the same thread, see `THREAD_STACK`.
+    pub(crate) fn take_stack(
+        &mut self,
+        func: Rc<WasmFunction>,
+        func_addr: FuncAddr,
+        owner: ModuleInstanceAddr,
+        params: impl Iterator<Item = RawWasmValue> + ExactSizeIterator,
+    ) -> Stack {
+        let mut stack = self.take_unused_stack();
+        stack.reset(func, func_addr, owner, params);
+        stack
+    }
+
+    /// Get a stack that isn't used by a call, see [`Store::take_stack`]
+    ///
+    /// The stack can still contain the values of the call that used it last.
+    pub(crate) fn take_unused_stack(&mut self) -> Stack {
+        let stack = self.dropped_stacks.borrow_mut().pop().or_else(|| match self.pool.as_mut() {
+            Some(pool) => pool.take_stack(),
+            None => self.spare_stack.take().or_else(take_thread_stack),
+        });
+        stack.unwrap_or_else(Stack::empty)
+    }
+
+    /// Return an execution stack to the pool once a call has finished
+    pub(crate) fn give_stack(&mut self, stack: Stack) {
+        match self.pool.as_mut() {
+            Some(pool) => pool.give_stack(stack),
+            None => self.spare_stack = Some(stack),
+        }
+    }
+
     pub(crate) fn add_func(&mut self, func: Function, idx: ModuleInstanceAddr) -> Result<FuncAddr> {
-        self.data.funcs.push(FunctionInstance { func, owner: idx });
+        let type_id = self.types.intern(func.ty());
+        self.data.funcs.push(Some(FunctionInstance { func, type_id, owner: idx }));
         Ok(self.data.funcs.len() as FuncAddr - 1)
     }
 
     /// Evaluate a constant expression, only supporting i32 globals and i32.const
-    pub(crate) fn eval_i32_const(&self, const_instr: &tinywasm_types::ConstInstruction) -> Result<i32> {
+    ///
+    /// Globals are resolved using the module's global addresses, so offsets can
+    /// depend on imported globals (e.g. `__memory_base` in split modules)
+    pub(crate) fn eval_i32_const(
+        &self,
+        const_instr: &tinywasm_types::ConstInstruction,
+        module_global_addrs: &[Addr],
+    ) -> Result<i32> {
         use tinywasm_types::ConstInstruction::*;
         let val = match const_instr {
             I32Const(i) => *i,
             GlobalGet(addr) => {
-                let global = self.data.globals[*addr as usize].borrow();
+                let addr = module_global_addrs.get(*addr as usize).ok_or_else(|| {
+                    Error::Other(format!("global {} not found. This should have been caught by the validator", addr))
+                })?;
+
+                let global = self.get_global(*addr as usize)?.borrow();
                 i32::from(global.value)
             }
             _ => return Err(Error::Other("expected i32".to_string())),
@@ -424,10 +999,7 @@ impl Store {
                     Error::Other(format!("global {} not found. This should have been caught by the validator", addr))
                 })?;
 
-                let global =
-                    self.data.globals.get(*addr as usize).expect("global not found. This should be unreachable");
-
-                global.borrow().value
+                self.get_global_val(*addr as usize)?
             }
             RefNull(t) => RawWasmValue::from(t.default_value()),
             RefFunc(idx) => RawWasmValue::from(*module_func_addrs.get(*idx as usize).ok_or_else(|| {
@@ -437,3 +1009,434 @@ impl Store {
         Ok(val)
     }
 }
+
+// Forking related functions
+impl Store {
+    /// Copy the functions owned by `owner` to the instance `idx`
+    ///
+    /// Returns the new function addresses and a map from the old to the new addresses
+    pub(crate) fn fork_funcs(
+        &mut self,
+        addrs: &[FuncAddr],
+        owner: ModuleInstanceAddr,
+        idx: ModuleInstanceAddr,
+    ) -> (Box<[FuncAddr]>, BTreeMap<FuncAddr, FuncAddr>) {
+        let mut remap = BTreeMap::new();
+        let funcs = &mut self.data.funcs;
+        let addrs = addrs
+            .iter()
+            .map(|&addr| {
+                let Some(func) = funcs[addr as usize].as_ref().filter(|func| func.owner == owner) else {
+                    return addr;
+                };
+
+                let func = FunctionInstance { func: func.func.clone(), type_id: func.type_id, owner: idx };
+                funcs.push(Some(func));
+                let new_addr = funcs.len() as FuncAddr - 1;
+                remap.insert(addr, new_addr);
+                new_addr
+            })
+            .collect();
+
+        // copies of functions that haven't been translated yet are translated on their own
+        #[cfg(feature = "parallel")]
+        for (old, new) in remap.iter() {
+            if let Some(pending) = self.pending_funcs.get(old).cloned() {
+                self.pending_funcs.insert(*new, pending);
+            }
+        }
+
+        (addrs, remap)
+    }
+
+    /// Copy the tables owned by `owner` to the instance `idx`
+    pub(crate) fn fork_tables(
+        &mut self,
+        addrs: &[TableAddr],
+        owner: ModuleInstanceAddr,
+        idx: ModuleInstanceAddr,
+        funcs: &BTreeMap<FuncAddr, FuncAddr>,
+    ) -> Box<[TableAddr]> {
+        fork_shared(&mut self.data.tables, addrs, |t| (t.owner == owner).then(|| t.fork(idx, funcs)))
+    }
+
+    /// Copy the memories owned by `owner` to the instance `idx` (copy-on-write)
+    pub(crate) fn fork_memories(
+        &mut self,
+        addrs: &[MemAddr],
+        owner: ModuleInstanceAddr,
+        idx: ModuleInstanceAddr,
+    ) -> Box<[MemAddr]> {
+        fork_shared(&mut self.data.memories, addrs, |m| (m.owner == owner).then(|| m.fork(idx)))
+    }
+
+    /// Copy the globals owned by `owner` to the instance `idx`
+    pub(crate) fn fork_globals(
+        &mut self,
+        addrs: &[GlobalAddr],
+        owner: ModuleInstanceAddr,
+        idx: ModuleInstanceAddr,
+        funcs: &BTreeMap<FuncAddr, FuncAddr>,
+    ) -> Box<[GlobalAddr]> {
+        let globals = &mut self.data.globals;
+        addrs
+            .iter()
+            .map(|&addr| {
+                let global = globals[addr as usize].as_ref().map(|g| g.borrow());
+                let Some(global) = global.filter(|g| g.owner == owner).map(|g| g.fork(idx, funcs)) else {
+                    return addr;
+                };
+
+                globals.push(Some(Rc::new(RefCell::new(global))));
+                globals.len() as GlobalAddr - 1
+            })
+            .collect()
+    }
+
+    /// Copy the element segments to the instance `idx`
+    pub(crate) fn fork_elements(
+        &mut self,
+        addrs: &[ElemAddr],
+        idx: ModuleInstanceAddr,
+        funcs: &BTreeMap<FuncAddr, FuncAddr>,
+    ) -> Box<[ElemAddr]> {
+        let elements = &mut self.data.elements;
+        addrs
+            .iter()
+            .map(|&addr| {
+                let elem = elements[addr as usize].fork(idx, funcs);
+                elements.push(elem);
+                elements.len() as ElemAddr - 1
+            })
+            .collect()
+    }
+
+    /// Copy the data segments to the instance `idx`
+    pub(crate) fn fork_datas(&mut self, addrs: &[DataAddr], idx: ModuleInstanceAddr) -> Box<[DataAddr]> {
+        let datas = &mut self.data.datas;
+        addrs
+            .iter()
+            .map(|&addr| {
+                let data = datas[addr as usize].fork(idx);
+                datas.push(data);
+                datas.len() as DataAddr - 1
+            })
+            .collect()
+    }
+
+    /// Point function references in the tables, globals and element segments owned by `owner` to other functions
+    pub(crate) fn remap_funcs(
+        &mut self,
+        owner: ModuleInstanceAddr,
+        addrs: (&[TableAddr], &[GlobalAddr], &[ElemAddr]),
+        funcs: &BTreeMap<FuncAddr, FuncAddr>,
+    ) {
+        let (tables, globals, elems) = addrs;
+        for addr in tables {
+            let mut table = self.data.tables[*addr as usize].borrow_mut();
+            if table.owner == owner {
+                *table = table.fork(owner, funcs);
+            }
+        }
+
+        for global in globals.iter().filter_map(|addr| self.data.globals[*addr as usize].as_ref()) {
+            let mut global = global.borrow_mut();
+            if global.owner == owner {
+                *global = global.fork(owner, funcs);
+            }
+        }
+
+        for addr in elems {
+            let elem = &mut self.data.elements[*addr as usize];
+            *elem = elem.fork(owner, funcs);
+        }
+    }
+
+    /// Replace a module instance with an updated version at the same address
+    pub(crate) fn replace_instance(&mut self, instance: ModuleInstance) -> Result<()> {
+        match self.module_instances.get_mut(instance.id() as usize) {
+            Some(slot @ Some(_)) => {
+                *slot = Some(instance);
+                Ok(())
+            }
+            _ => Err(Self::removed_error(instance.id())),
+        }
+    }
+}
+
+// copy the items for which `fork` returns a new item, keeping the addresses of all others
+fn fork_shared<T>(
+    items: &mut Vec<Rc<RefCell<T>>>,
+    addrs: &[Addr],
+    mut fork: impl FnMut(&T) -> Option<T>,
+) -> Box<[Addr]> {
+    addrs
+        .iter()
+        .map(|&addr| {
+            let Some(item) = fork(&items[addr as usize].borrow()) else {
+                return addr;
+            };
+
+            items.push(Rc::new(RefCell::new(item)));
+            items.len() as Addr - 1
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Extern, Imports, Module};
+    use alloc::vec;
+
+    // a module exporting a single page of memory
+    fn memory_module() -> TinyWasmModule {
+        TinyWasmModule {
+            memory_types: vec![MemoryType::new_32(1, None)].into_boxed_slice(),
+            exports: vec![Export { name: "memory".into(), kind: ExternalKind::Memory, index: 0 }].into_boxed_slice(),
+            ..Default::default()
+        }
+    }
+
+    // a module that imports the memory and writes `data` at `env.__memory_base`
+    fn side_module(data: &[u8]) -> TinyWasmModule {
+        let base_ty = GlobalType { ty: ValType::I32, mutable: false };
+        TinyWasmModule {
+            imports: vec![
+                Import {
+                    module: "env".into(),
+                    name: "memory".into(),
+                    kind: ImportKind::Memory(MemoryType::new_32(1, None)),
+                },
+                Import { module: "env".into(), name: "__memory_base".into(), kind: ImportKind::Global(base_ty) },
+            ]
+            .into_boxed_slice(),
+            data: vec![Data {
+                data: data.into(),
+                range: 0..data.len(),
+                kind: DataKind::Active { mem: 0, offset: ConstInstruction::GlobalGet(0) },
+            }]
+            .into_boxed_slice(),
+            ..Default::default()
+        }
+    }
+
+    fn imports(memory_addr: MemAddr, base: i32) -> Imports {
+        let mut imports = Imports::new();
+        imports.define("env", "__memory_base", Extern::global(WasmValue::I32(base), false)).unwrap();
+        imports.link_module("env", memory_addr).unwrap();
+        imports
+    }
+
+    #[test]
+    fn test_shared_memory_data_segments() {
+        let mut store = Store::new();
+        let main = ModuleInstance::instantiate(&mut store, Module::from(memory_module()), None).unwrap();
+
+        let side = Module::from(side_module(&[1, 2, 3, 4]));
+        ModuleInstance::instantiate(&mut store, side, Some(imports(main.id(), 16))).unwrap();
+        let side = Module::from(side_module(&[5, 6, 7, 8]));
+        ModuleInstance::instantiate(&mut store, side, Some(imports(main.id(), 32))).unwrap();
+
+        {
+            let memory = main.exported_memory(&mut store, "memory").unwrap();
+            assert_eq!(memory.load(16, 4).unwrap(), &[1, 2, 3, 4]);
+            assert_eq!(memory.load(32, 4).unwrap(), &[5, 6, 7, 8]);
+        }
+
+        // segments outside of the shared memory trap during instantiation
+        let side = Module::from(side_module(&[1, 2, 3, 4]));
+        let res = ModuleInstance::instantiate(&mut store, side, Some(imports(main.id(), 65534)));
+        assert!(matches!(res, Err(Error::Trap(Trap::MemoryOutOfBounds { .. }))));
+    }
+
+    #[test]
+    fn test_deferred_segments() {
+        let mut store = Store::new();
+        let main = ModuleInstance::instantiate(&mut store, Module::from(memory_module()), None).unwrap();
+        let side = Module::from(side_module(&[1, 2, 3, 4]));
+        let side = ModuleInstance::instantiate_deferred(&mut store, side, Some(imports(main.id(), 16))).unwrap();
+
+        // the host can modify the memory before the segments are applied
+        {
+            let mut memory = main.exported_memory_mut(&mut store, "memory").unwrap();
+            assert_eq!(memory.load(16, 4).unwrap(), &[0; 4]);
+            memory.store(16, 8, &[9; 8]).unwrap();
+        }
+
+        assert_eq!(side.run_start(&mut store).unwrap(), None);
+        assert_eq!(main.exported_memory(&mut store, "memory").unwrap().load(16, 8).unwrap(), &[1, 2, 3, 4, 9, 9, 9, 9]);
+
+        // segments are only applied once
+        main.exported_memory_mut(&mut store, "memory").unwrap().store(16, 4, &[0; 4]).unwrap();
+        side.run_start(&mut store).unwrap();
+        assert_eq!(main.exported_memory(&mut store, "memory").unwrap().load(16, 4).unwrap(), &[0; 4]);
+    }
+
+    #[test]
+    fn test_stack_reuse() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([]), results: Box::new([ValType::I32]) });
+        let one = builder.add_function(ty, [], [Instruction::I32Const(1), Instruction::EndFunc]);
+        builder.add_export("one", ExternalKind::Func, one);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let mut store = Store::new();
+        let instance = module.instantiate(&mut store, None)?;
+        let one = instance.exported_func::<(), i32>(&store, "one")?;
+        assert_eq!(one.call(&mut store, ())?, 1);
+        assert!(store.spare_stack.is_some());
+
+        // the next call takes the stack of the last one, and gives it back
+        let Function::Wasm(func) = store.get_func(one.func.addr as usize)?.func.clone() else {
+            panic!("not a wasm function")
+        };
+        let stack = store.take_stack(func, one.func.addr, instance.id(), [].into_iter());
+        assert!(store.spare_stack.is_none());
+        store.give_stack(stack);
+        assert_eq!(one.call(&mut store, ())?, 1);
+        assert!(store.spare_stack.is_some());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_thread_stack() -> Result<()> {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([]), results: Box::new([ValType::I32]) });
+        let one = builder.add_function(ty, [], [Instruction::I32Const(1), Instruction::EndFunc]);
+        builder.add_export("one", ExternalKind::Func, one);
+        let module = Module::from(builder.finish().expect("valid module"));
+        let call = |store: &mut Store| -> Result<i32> {
+            let instance = module.clone().instantiate(store, None)?;
+            instance.exported_func::<(), i32>(store, "one")?.call(store, ())
+        };
+
+        // dropping a store leaves its stack to the next store created on the thread
+        let mut store = Store::new();
+        assert_eq!(call(&mut store)?, 1);
+        drop(store);
+        assert!(THREAD_STACK.with(|stack| stack.borrow().as_ref().is_some_and(|s| s.call_stack.is_empty())));
+
+        let mut store = Store::new();
+        assert_eq!(call(&mut store)?, 1);
+        assert!(THREAD_STACK.with(|stack| stack.borrow().is_none()) && store.spare_stack.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_registered_instances() {
+        let mut store = Store::new();
+        let main = ModuleInstance::instantiate(&mut store, Module::from(memory_module()), None).unwrap();
+        store.register_instance("env", &main).unwrap();
+        assert_eq!(store.registered_instance("env").map(ModuleInstance::id), Some(main.id()));
+
+        // `env.memory` is resolved from the registered instance
+        let base = || {
+            let mut imports = Imports::new();
+            imports.define("env", "__memory_base", Extern::global(WasmValue::I32(8), false)).unwrap();
+            imports
+        };
+        ModuleInstance::instantiate(&mut store, Module::from(side_module(&[1, 2])), Some(base())).unwrap();
+        assert_eq!(main.exported_memory(&mut store, "memory").unwrap().load(8, 2).unwrap(), &[1, 2]);
+
+        // instances in other stores can't be registered
+        let mut other = Store::new();
+        assert!(matches!(other.register_instance("env", &main), Err(Error::InvalidStore)));
+
+        // removed instances are unregistered
+        store.remove_instance(main.id()).unwrap();
+        assert!(store.registered_instance("env").is_none());
+        let res = ModuleInstance::instantiate(&mut store, Module::from(side_module(&[1, 2])), Some(base()));
+        assert!(matches!(res, Err(Error::Linker(_))));
+    }
+
+    // a module with an externref table and functions to store, load and check references
+    fn externref_module() -> TinyWasmModule {
+        let func = |params: &[ValType], results: &[ValType], instructions: &[Instruction]| WasmFunction {
+            instructions: instructions.into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: FuncType { params: params.into(), results: results.into() },
+        };
+
+        let (i32, extern_ref) = (ValType::I32, ValType::RefExtern);
+        let funcs = vec![
+            func(
+                &[i32, extern_ref],
+                &[],
+                &[Instruction::LocalGet2(0, 1), Instruction::TableSet(0), Instruction::EndFunc],
+            ),
+            func(&[i32], &[extern_ref], &[Instruction::LocalGet(0), Instruction::TableGet(0), Instruction::EndFunc]),
+            func(&[extern_ref], &[i32], &[Instruction::LocalGet(0), Instruction::RefIsNull, Instruction::EndFunc]),
+        ];
+
+        let export = |name: &str, index| Export { name: name.into(), kind: ExternalKind::Func, index };
+        TinyWasmModule {
+            func_types: funcs.iter().map(|f| f.ty.clone()).collect(),
+            funcs: funcs.into_boxed_slice(),
+            table_types: vec![TableType { element_type: extern_ref, size_initial: 4, size_max: None }].into(),
+            exports: vec![export("store", 0), export("load", 1), export("is_null", 2)].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_extern_ref() -> Result<()> {
+        let mut store = Store::new();
+        let instance = ModuleInstance::instantiate(&mut store, Module::from(externref_module()), None).unwrap();
+        let (store_ref, load_ref) =
+            (instance.exported_func_untyped(&store, "store")?, instance.exported_func_untyped(&store, "load")?);
+        let is_null = instance.exported_func_untyped(&store, "is_null")?;
+
+        let hello = store.add_extern_ref(Rc::new(alloc::string::String::from("hello")))?;
+        let answer = store.add_extern_ref(Rc::new(42u64))?;
+        store_ref.call(&mut store, &[WasmValue::I32(1), hello])?;
+        store_ref.call(&mut store, &[WasmValue::I32(2), answer])?;
+
+        let loaded = load_ref.call(&mut store, &[WasmValue::I32(1)])?;
+        assert_eq!(store.get_extern_ref_as::<alloc::string::String>(&loaded[0])?.unwrap(), "hello");
+        let loaded = load_ref.call(&mut store, &[WasmValue::I32(2)])?;
+        assert_eq!(store.get_extern_ref_as::<u64>(&loaded[0])?, Some(&42));
+        assert!(store.get_extern_ref_as::<u32>(&loaded[0]).is_err());
+
+        // uninitialized table elements are null references
+        let loaded = load_ref.call(&mut store, &[WasmValue::I32(0)])?;
+        assert_eq!(is_null.call(&mut store, &loaded)?, vec![WasmValue::I32(1)]);
+        assert!(store.get_extern_ref(&loaded[0])?.is_none());
+        assert_eq!(is_null.call(&mut store, &[hello])?, vec![WasmValue::I32(0)]);
+
+        // storing null clears the element
+        store_ref.call(&mut store, &[WasmValue::I32(1), WasmValue::RefNull(ValType::RefExtern)])?;
+        let loaded = load_ref.call(&mut store, &[WasmValue::I32(1)])?;
+        assert_eq!(loaded, vec![WasmValue::RefNull(ValType::RefExtern)]);
+
+        // released objects can't be loaded anymore, and their address is reused
+        let released = store.remove_extern_ref(&hello)?.unwrap();
+        assert_eq!(released.downcast_ref::<alloc::string::String>().unwrap(), "hello");
+        assert!(store.get_extern_ref(&hello).is_err());
+        assert!(store.remove_extern_ref(&hello).is_err());
+        assert!(store.remove_extern_ref(&WasmValue::RefNull(ValType::RefExtern))?.is_none());
+        assert_eq!(store.add_extern_ref(Rc::new(7u8))?, hello);
+        assert_eq!(store.get_extern_ref_as::<u8>(&hello)?, Some(&7));
+        assert_eq!(store.data.extern_objects.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_code_offset() {
+        let mut module = externref_module();
+        let mut funcs = module.funcs.into_vec();
+        funcs[0].offsets = vec![10, 12, 15].into();
+        module.funcs = funcs.into();
+
+        let mut store = Store::new();
+        let instance = ModuleInstance::instantiate(&mut store, Module::from(module), None).unwrap();
+        let (store_addr, load_addr) = (instance.resolve_func_addr(0), instance.resolve_func_addr(1));
+        assert_eq!(store.code_offset(store_addr, 1), Some(12));
+        assert_eq!(store.code_offset(store_addr, 3), None);
+        assert_eq!(store.code_offset(load_addr, 0), None);
+    }
+}
@@ -0,0 +1,164 @@
+use alloc::format;
+use alloc::vec::Vec;
+
+use super::{pages_to_bytes, TableElement};
+use crate::runtime::Stack;
+use crate::{log, Error, Result};
+
+/// Configuration for a pooling allocator
+///
+/// Memories, tables and execution stacks are pre-allocated when the store is created
+/// and are recycled once they are no longer in use (e.g. after [`crate::Store::remove_instance`]),
+/// instead of being allocated and freed for every instantiation or call.
+/// If a pool is exhausted, new buffers are allocated as usual.
+///
+/// See [`crate::Store::with_pool`]
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    /// The number of linear memory buffers to keep in the pool
+    pub memory_slots: usize,
+    /// The number of pages to reserve for each linear memory buffer
+    pub memory_pages: usize,
+    /// The number of table buffers to keep in the pool
+    pub table_slots: usize,
+    /// The number of elements to reserve for each table buffer
+    pub table_elements: usize,
+    /// The number of execution stacks to keep in the pool
+    pub stack_slots: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self { memory_slots: 4, memory_pages: 16, table_slots: 4, table_elements: 1024, stack_slots: 4 }
+    }
+}
+
+/// A pool of pre-allocated buffers
+#[derive(Debug)]
+pub(crate) struct Pool {
+    config: PoolConfig,
+    memories: Vec<Vec<u8>>,
+    tables: Vec<Vec<TableElement>>,
+    stacks: Vec<Stack>,
+}
+
+impl Pool {
+    pub(crate) fn new(config: PoolConfig) -> Result<Self> {
+        log::debug!("pre-allocating pool: {:?}", config);
+        let memory_size = pages_to_bytes(config.memory_pages as u64).ok_or_else(|| {
+            Error::UnsupportedFeature(format!("pooled {} page memories on this target", config.memory_pages))
+        })?;
+
+        Ok(Self {
+            memories: (0..config.memory_slots).map(|_| Vec::with_capacity(memory_size)).collect(),
+            tables: (0..config.table_slots).map(|_| Vec::with_capacity(config.table_elements)).collect(),
+            stacks: (0..config.stack_slots).map(|_| Stack::empty()).collect(),
+            config,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn take_memory(&mut self) -> Option<Vec<u8>> {
+        self.memories.pop()
+    }
+
+    #[inline]
+    pub(crate) fn take_table(&mut self) -> Option<Vec<TableElement>> {
+        self.tables.pop()
+    }
+
+    #[inline]
+    pub(crate) fn take_stack(&mut self) -> Option<Stack> {
+        self.stacks.pop()
+    }
+
+    /// Return a memory buffer to the pool, dropping it if the pool is already full
+    pub(crate) fn give_memory(&mut self, mut memory: Vec<u8>) {
+        if self.memories.len() < self.config.memory_slots {
+            memory.clear();
+            self.memories.push(memory);
+        }
+    }
+
+    /// Return a table buffer to the pool, dropping it if the pool is already full
+    pub(crate) fn give_table(&mut self, mut table: Vec<TableElement>) {
+        if self.tables.len() < self.config.table_slots {
+            table.clear();
+            self.tables.push(table);
+        }
+    }
+
+    /// Return an execution stack to the pool, dropping it if the pool is already full
+    pub(crate) fn give_stack(&mut self, stack: Stack) {
+        if self.stacks.len() < self.config.stack_slots {
+            self.stacks.push(stack);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Slice, Store};
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_memory_size() {
+        let config = PoolConfig { memory_pages: usize::MAX, ..Default::default() };
+        assert!(matches!(Store::with_pool(config), Err(Error::UnsupportedFeature(_))));
+        assert!(Store::with_pool(PoolConfig::default()).is_ok());
+    }
+
+    #[test]
+    fn test_stack_reuse() -> Result<()> {
+        // `countdown(n)` loops n times and returns 0
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        let countdown = builder.add_function(
+            ty,
+            [],
+            [
+                Instruction::Loop(BlockArgsPacked::EMPTY, 6),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::LocalTee(0),
+                Instruction::BrIf(0),
+                Instruction::EndBlockFrame,
+                Instruction::LocalGet(0),
+                Instruction::EndFunc,
+            ],
+        );
+        builder.add_export("countdown", ExternalKind::Func, countdown);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let mut store = Store::with_pool(PoolConfig { stack_slots: 1, ..Default::default() })?;
+        let instance = module.clone().instantiate(&mut store, None)?;
+        let func = instance.exported_func_untyped(&store, "countdown")?;
+        let pooled = |store: &Store| store.pool.as_ref().map_or(0, |pool| pool.stacks.len());
+        assert_eq!(func.call(&mut store, &[WasmValue::I32(3)])?, [WasmValue::I32(0)]);
+        assert_eq!(pooled(&store), 1);
+
+        // a metered call dropped before it finished leaves its stack to the next call
+        let mut call = func.call_metered(&mut store, &[WasmValue::I32(100)])?;
+        assert!(matches!(call.run(&mut store, 10)?, Slice::Paused { .. }));
+        assert_eq!(pooled(&store), 0);
+        drop(call);
+        assert_eq!(store.dropped_stacks.borrow().len(), 1);
+
+        let mut call = func.call_metered(&mut store, &[WasmValue::I32(100)])?;
+        assert!(store.dropped_stacks.borrow().is_empty() && pooled(&store) == 0);
+        assert!(matches!(call.run(&mut store, 10)?, Slice::Paused { .. }));
+
+        // resumed calls take a stack from the pool too
+        let suspended = call.suspend(&store)?;
+        assert!(matches!(call.run(&mut store, u64::MAX)?, Slice::Finished { .. }));
+        assert_eq!(pooled(&store), 1);
+
+        let (_, mut resumed) = suspended.resume(module, &mut store, None)?;
+        assert_eq!(pooled(&store), 0);
+        assert!(matches!(resumed.run(&mut store, u64::MAX)?, Slice::Finished { .. }));
+        assert_eq!(pooled(&store), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use alloc::{boxed::Box, collections::BTreeMap};
+use tinywasm_types::ModuleInstanceAddr;
+
+use super::{Store, TableElement};
+use crate::sync::MaybeSendSync;
+
+/// The memory used by a module instance, see [`Store::resource_usage`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The size of the linear memories owned by the instance in bytes
+    pub memory_bytes: usize,
+    /// The size of the tables owned by the instance in bytes
+    pub table_bytes: usize,
+    /// The size of the largest execution stack needed by functions of the instance so far, in bytes
+    ///
+    /// The stack is measured whenever the guest calls a function of the instance, but only
+    /// while the instance has a quota or the store has a limiter, see [`Store::set_limiter`].
+    pub stack_bytes: usize,
+}
+
+impl ResourceUsage {
+    /// The total number of bytes used by the instance
+    pub fn total(&self) -> usize {
+        self.memory_bytes + self.table_bytes + self.stack_bytes
+    }
+}
+
+/// The kind of resource in a [`ResourceRequest`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    /// A linear memory grown by a `memory.grow` instruction
+    Memory,
+    /// A table grown to fit an element set by a `table.set` instruction
+    Table,
+    /// The execution stack of a call into a function of the instance
+    Stack,
+}
+
+/// A request to grow a resource owned by a module instance, see [`Store::set_limiter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceRequest {
+    /// The instance the resource is accounted to
+    pub instance: ModuleInstanceAddr,
+    /// The kind of resource
+    pub kind: ResourceKind,
+    /// The current size of the resource in bytes
+    ///
+    /// For stacks, this is the largest stack the instance has needed so far.
+    pub current: usize,
+    /// The size of the resource after growing, in bytes
+    pub desired: usize,
+}
+
+#[cfg(not(feature = "sync"))]
+type Limiter = Box<dyn FnMut(&ResourceRequest) -> bool>;
+#[cfg(feature = "sync")]
+type Limiter = Box<dyn FnMut(&ResourceRequest) -> bool + Send + Sync>;
+
+/// The quotas and the limiter of a store
+#[derive(Default)]
+pub(crate) struct Limits {
+    quotas: BTreeMap<ModuleInstanceAddr, usize>,
+    limiter: Option<Limiter>,
+    stack_peaks: BTreeMap<ModuleInstanceAddr, usize>,
+}
+
+impl core::fmt::Debug for Limits {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Limits")
+            .field("quotas", &self.quotas)
+            .field("limiter", &self.limiter.is_some())
+            .field("stack_peaks", &self.stack_peaks)
+            .finish()
+    }
+}
+
+impl Limits {
+    /// Forget the quota and the stack usage of a removed instance
+    pub(crate) fn remove(&mut self, instance: ModuleInstanceAddr) {
+        self.quotas.remove(&instance);
+        self.stack_peaks.remove(&instance);
+    }
+}
+
+impl Store {
+    /// Get the memory used by the memories and tables owned by a module instance, and by its execution stacks
+    ///
+    /// Imported memories and tables are accounted to the instance that created them.
+    /// Returns `None` if the instance doesn't exist or has been removed.
+    pub fn resource_usage(&self, instance: ModuleInstanceAddr) -> Option<ResourceUsage> {
+        let instance = self.get_module_instance(instance)?;
+        let stack_bytes = self.limits.stack_peaks.get(&instance.id()).copied().unwrap_or_default();
+        let mut usage = ResourceUsage { stack_bytes, ..Default::default() };
+
+        for addr in instance.mem_addrs() {
+            let mem = self.data.memories[*addr as usize].borrow();
+            if mem.owner == instance.id() {
+                usage.memory_bytes += mem.data.len();
+            }
+        }
+
+        for addr in instance.table_addrs() {
+            let table = self.data.tables[*addr as usize].borrow();
+            if table.owner == instance.id() {
+                usage.table_bytes += table.elements.len() * core::mem::size_of::<TableElement>();
+            }
+        }
+
+        Some(usage)
+    }
+
+    /// Limit the number of bytes a module instance may use, see [`Store::resource_usage`]
+    ///
+    /// Once the quota is reached, `memory.grow` instructions executed by the guest fail (returning `-1`),
+    /// `table.set` instructions that would grow a table trap and so do calls that need a larger execution stack.
+    /// Memory the instance already uses is not affected, and the host can still grow memories
+    /// using [`crate::MemoryRefMut::grow`]. `None` removes the quota.
+    pub fn set_quota(&mut self, instance: ModuleInstanceAddr, bytes: Option<usize>) {
+        match bytes {
+            Some(bytes) => self.limits.quotas.insert(instance, bytes),
+            None => self.limits.quotas.remove(&instance),
+        };
+    }
+
+    /// Decide whether the guest may grow a memory, a table or the execution stack
+    ///
+    /// `limiter` is called for every [`ResourceRequest`] that is within the quota of its instance (if any),
+    /// and the resource only grows if it returns `true`. Denied requests are handled like an exceeded quota,
+    /// see [`Store::set_quota`]. This replaces the previous limiter.
+    pub fn set_limiter(&mut self, limiter: impl FnMut(&ResourceRequest) -> bool + MaybeSendSync + 'static) {
+        self.limits.limiter = Some(Box::new(limiter));
+    }
+
+    /// Remove the limiter set with [`Store::set_limiter`]
+    pub fn remove_limiter(&mut self) {
+        self.limits.limiter = None;
+    }
+
+    /// Check if resources are limited at all, so the checks can be skipped otherwise
+    #[inline(always)]
+    pub(crate) fn has_limits(&self) -> bool {
+        !self.limits.quotas.is_empty() || self.limits.limiter.is_some()
+    }
+
+    /// Check if a resource may grow without exceeding the quota of its instance and ask the limiter
+    pub(crate) fn allow_growth(&mut self, request: ResourceRequest) -> bool {
+        if !self.has_limits() {
+            return true;
+        }
+
+        if let (Some(quota), Some(usage)) =
+            (self.limits.quotas.get(&request.instance).copied(), self.resource_usage(request.instance))
+        {
+            let total = usage.total().saturating_sub(request.current).saturating_add(request.desired);
+            if total > quota {
+                return false;
+            }
+        }
+
+        self.limits.limiter.as_mut().map_or(true, |limiter| limiter(&request))
+    }
+
+    /// Check if the execution stack may grow to `bytes` for a call into a function of `instance`
+    ///
+    /// Only stacks larger than the largest one the instance needed so far are requested.
+    pub(crate) fn allow_stack(&mut self, instance: ModuleInstanceAddr, bytes: usize) -> bool {
+        let current = self.limits.stack_peaks.get(&instance).copied().unwrap_or_default();
+        if bytes <= current {
+            return true;
+        }
+
+        let allowed =
+            self.allow_growth(ResourceRequest { instance, kind: ResourceKind::Stack, current, desired: bytes });
+        if allowed {
+            self.limits.stack_peaks.insert(instance, bytes);
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::{Rc, RefCell};
+    use crate::{Error, Module, Result, Trap};
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use tinywasm_types::*;
+
+    const PAGE_SIZE: usize = super::super::PAGE_SIZE as usize;
+
+    #[test]
+    fn test_quota() -> Result<()> {
+        let ty = FuncType { params: vec![ValType::I32].into(), results: vec![ValType::I32].into() };
+        let grow = WasmFunction {
+            instructions: vec![Instruction::LocalGet(0), Instruction::MemoryGrow(0, 0), Instruction::EndFunc].into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+        let module = Module::from(TinyWasmModule {
+            funcs: vec![grow].into(),
+            func_types: vec![ty].into(),
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            table_types: vec![TableType::new(ValType::RefFunc, 2, None)].into(),
+            exports: vec![Export { name: "grow".into(), kind: ExternalKind::Func, index: 0 }].into(),
+            ..Default::default()
+        });
+
+        let mut store = Store::default();
+        let instance = module.instantiate(&mut store, None)?;
+        let grow = instance.exported_func::<i32, i32>(&store, "grow")?;
+
+        let usage = store.resource_usage(instance.id()).expect("instance exists");
+        assert_eq!(usage.memory_bytes, PAGE_SIZE);
+        assert_eq!(usage.table_bytes, 2 * core::mem::size_of::<TableElement>());
+
+        store.set_quota(instance.id(), Some(usage.total() + PAGE_SIZE));
+        assert_eq!(grow.call(&mut store, 1)?, 1);
+        assert_eq!(grow.call(&mut store, 1)?, -1);
+        assert_eq!(store.resource_usage(instance.id()).map(|u| u.memory_bytes), Some(2 * PAGE_SIZE));
+
+        store.set_quota(instance.id(), None);
+        assert_eq!(grow.call(&mut store, 1)?, 2);
+
+        // the delta is unsigned, so -1 pages is too much to grow by, and doesn't shrink the memory
+        assert_eq!(grow.call(&mut store, -1)?, -1);
+        assert_eq!(grow.call(&mut store, 0)?, 3);
+
+        store.remove_instance(instance.id())?;
+        assert_eq!(store.resource_usage(instance.id()), None);
+        Ok(())
+    }
+
+    // a module with a table of two elements and `set(index)`, which stores a reference to itself in the table
+    fn table_module() -> Module {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I32]), results: Box::new([]) });
+        builder.add_table(TableType::new(ValType::RefFunc, 2, None));
+        let instructions = [Instruction::LocalGet(0), Instruction::RefFunc(0), Instruction::TableSet(0)];
+        let set = builder.add_function(ty, [], [instructions.as_slice(), &[Instruction::EndFunc]].concat());
+        builder.add_export("set", ExternalKind::Func, set);
+        Module::from(builder.finish().expect("valid module"))
+    }
+
+    #[test]
+    fn test_table_quota() -> Result<()> {
+        const ELEMENT: usize = core::mem::size_of::<TableElement>();
+        let mut store = Store::default();
+        let instance = table_module().instantiate(&mut store, None)?;
+        let set = instance.exported_func::<i32, ()>(&store, "set")?;
+
+        store.set_quota(instance.id(), Some(4 * ELEMENT));
+        set.call(&mut store, 1)?;
+        set.call(&mut store, 3)?;
+        assert_eq!(store.resource_usage(instance.id()).map(|u| u.table_bytes), Some(4 * ELEMENT));
+
+        // setting an element past the end would grow the table beyond the quota
+        let res = set.call(&mut store, 4);
+        assert!(matches!(res, Err(Error::Trap(Trap::TableOutOfBounds { offset: 4, .. }))));
+        assert_eq!(store.resource_usage(instance.id()).map(|u| u.table_bytes), Some(4 * ELEMENT));
+        Ok(())
+    }
+
+    // a module with `recurse(n)`, which calls itself until `n` is zero
+    fn recursive_module() -> Module {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: Box::new([ValType::I32]), results: Box::new([]) });
+        let recurse = builder.add_function(
+            ty,
+            [],
+            [
+                Instruction::LocalGet(0),
+                Instruction::If(BlockArgsPacked::EMPTY, 5),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::Call(0),
+                Instruction::EndBlockFrame,
+                Instruction::EndFunc,
+            ],
+        );
+        builder.add_export("recurse", ExternalKind::Func, recurse);
+        Module::from(builder.finish().expect("valid module"))
+    }
+
+    #[test]
+    fn test_stack_quota() -> Result<()> {
+        let mut store = Store::default();
+        let instance = recursive_module().instantiate(&mut store, None)?;
+        let recurse = instance.exported_func::<i32, ()>(&store, "recurse")?;
+
+        // without a quota, stacks aren't measured
+        recurse.call(&mut store, 10)?;
+        assert_eq!(store.resource_usage(instance.id()).map(|u| u.stack_bytes), Some(0));
+
+        store.set_quota(instance.id(), Some(usize::MAX));
+        recurse.call(&mut store, 10)?;
+        let ten = store.resource_usage(instance.id()).expect("instance exists").stack_bytes;
+        recurse.call(&mut store, 5)?;
+        assert_eq!(store.resource_usage(instance.id()).map(|u| u.stack_bytes), Some(ten));
+        recurse.call(&mut store, 20)?;
+        let twenty = store.resource_usage(instance.id()).expect("instance exists").stack_bytes;
+        assert!(twenty > ten && ten > 0);
+
+        // deeper recursion needs a larger stack than the quota allows
+        store.set_quota(instance.id(), Some(twenty));
+        recurse.call(&mut store, 20)?;
+        assert!(matches!(recurse.call(&mut store, 21), Err(Error::Trap(Trap::CallStackOverflow))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limiter() -> Result<()> {
+        let mut store = Store::default();
+        let tables = table_module().instantiate(&mut store, None)?;
+        let calls = recursive_module().instantiate(&mut store, None)?;
+        let set = tables.exported_func::<i32, ()>(&store, "set")?;
+        let recurse = calls.exported_func::<i32, ()>(&store, "recurse")?;
+
+        let requests = Rc::new(RefCell::new(Vec::new()));
+        let recorded = requests.clone();
+        store.set_limiter(move |request| {
+            recorded.borrow_mut().push(*request);
+            request.kind != ResourceKind::Table || request.desired <= 3 * core::mem::size_of::<TableElement>()
+        });
+
+        set.call(&mut store, 2)?;
+        assert!(set.call(&mut store, 3).is_err());
+        recurse.call(&mut store, 1)?;
+
+        let requests = requests.borrow();
+        let table = |current, desired| ResourceRequest {
+            instance: tables.id(),
+            kind: ResourceKind::Table,
+            current: current * core::mem::size_of::<TableElement>(),
+            desired: desired * core::mem::size_of::<TableElement>(),
+        };
+        assert_eq!(requests[..2], [table(2, 3), table(3, 4)]);
+
+        // only the calls made by the guest are checked, and each larger stack is requested once
+        assert_eq!(requests.len(), 3);
+        assert_eq!((requests[2].instance, requests[2].kind, requests[2].current), (calls.id(), ResourceKind::Stack, 0));
+        assert_eq!(store.resource_usage(calls.id()).map(|u| u.stack_bytes), Some(requests[2].desired));
+
+        store.remove_limiter();
+        set.call(&mut store, 3)?;
+        Ok(())
+    }
+}
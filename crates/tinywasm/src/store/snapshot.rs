@@ -0,0 +1,177 @@
+use alloc::{format, vec::Vec};
+use tinywasm_types::{ImportKind, ModuleInstanceAddr};
+
+use super::Store;
+use crate::snapshot::{write_u32, write_u64, Reader, SNAPSHOT_VERSION};
+use crate::{Error, InstanceSnapshot, Result};
+
+const STORE_MAGIC: &[u8; 4] = b"TWST";
+
+/// A snapshot of all module instances in a store, see [`Store::snapshot`]
+#[derive(Debug, Clone)]
+pub struct StoreSnapshot {
+    instances: Vec<(ModuleInstanceAddr, InstanceSnapshot)>,
+}
+
+impl StoreSnapshot {
+    /// The snapshot of the instance with the address `addr`, if it was part of the store
+    pub fn instance(&self, addr: ModuleInstanceAddr) -> Option<&InstanceSnapshot> {
+        self.instances.iter().find(|(a, _)| *a == addr).map(|(_, snapshot)| snapshot)
+    }
+
+    /// Serialize the snapshot, see [`InstanceSnapshot::to_bytes`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(STORE_MAGIC);
+        out.push(SNAPSHOT_VERSION);
+
+        write_u32(&mut out, self.instances.len() as u32);
+        for (addr, snapshot) in &self.instances {
+            let bytes = snapshot.to_bytes();
+            write_u32(&mut out, *addr);
+            write_u64(&mut out, bytes.len() as u64);
+            out.extend_from_slice(&bytes);
+        }
+        out
+    }
+
+    /// Load a snapshot serialized with [`StoreSnapshot::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        reader.header(STORE_MAGIC)?;
+
+        let mut instances = Vec::new();
+        for _ in 0..reader.u32()? {
+            let addr = reader.u32()?;
+            let len = reader.u64()? as usize;
+            instances.push((addr, InstanceSnapshot::from_bytes(reader.bytes(len)?)?));
+        }
+
+        if !reader.0.is_empty() {
+            return Err(Reader::invalid("trailing data"));
+        }
+        Ok(Self { instances })
+    }
+}
+
+impl Store {
+    /// Take a snapshot of all module instances in the store
+    ///
+    /// Unlike [`crate::ModuleInstance::snapshot`], this also covers instances that are linked
+    /// to each other: shared memories and tables are included once, by the instance that defines
+    /// them, and tables can reference functions of other instances.
+    /// Globals imported from the host are provided externally and are not part of the snapshot,
+    /// while host memories and tables are, since the instances can write to them.
+    pub fn snapshot(&self) -> Result<StoreSnapshot> {
+        let mut instances = Vec::new();
+        for instance in self.module_instances.iter().flatten() {
+            let mut snapshot = instance.snapshot_with(self, true)?;
+
+            // imported globals owned by the instance were provided by the host
+            let imports = instance.imports().iter();
+            let imported_globals = imports.filter(|import| matches!(import.kind, ImportKind::Global(_))).count();
+            snapshot.globals.values.retain(|(idx, _)| *idx as usize >= imported_globals);
+            instances.push((instance.id(), snapshot));
+        }
+
+        Ok(StoreSnapshot { instances })
+    }
+
+    /// Restore a snapshot taken with [`Store::snapshot`]
+    ///
+    /// To rehydrate a snapshot in a new store, first create the same instances in the same order
+    /// with the same imports, re-registering any host functions and globals, so that they get the
+    /// same addresses. Use [`crate::ModuleInstance::instantiate`] to skip their start functions.
+    /// Fails without changing the store if an instance of the snapshot doesn't exist or doesn't match its snapshot,
+    /// since all instances are checked before any of them are restored.
+    pub fn restore(&mut self, snapshot: &StoreSnapshot) -> Result<()> {
+        let mut restores = Vec::with_capacity(snapshot.instances.len());
+        for (addr, instance_snapshot) in &snapshot.instances {
+            if !instance_snapshot.store_addrs {
+                return Err(Error::Other(format!("snapshot of instance {} is not part of a store snapshot", addr)));
+            }
+            restores.push(self.get_module_instance_raw(*addr)?.check_restore(self, instance_snapshot)?);
+        }
+
+        for restore in restores {
+            restore.apply(self)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Extern, Imports, Module, ModuleInstance};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_store_snapshot() -> Result<()> {
+        // `a` defines a memory and a table, which `b` imports together with a global from the host
+        let mut a = ModuleBuilder::new();
+        a.add_memory(MemoryType::new_32(1, None));
+        a.add_table(TableType::new(ValType::RefFunc, 2, None));
+        a.add_export("memory", ExternalKind::Memory, 0).add_export("table", ExternalKind::Table, 0);
+        let a = a.finish().expect("valid module");
+
+        let mut b = ModuleBuilder::new();
+        b.add_import("a", "memory", ImportKind::Memory(MemoryType::new_32(1, None)));
+        b.add_import("a", "table", ImportKind::Table(TableType::new(ValType::RefFunc, 2, None)));
+        b.add_import("env", "counter", ImportKind::Global(GlobalType { mutable: true, ty: ValType::I32 }));
+        b.add_global(GlobalType { mutable: true, ty: ValType::I32 }, ConstInstruction::I32Const(0));
+        let ty = b.add_type(FuncType { params: Default::default(), results: [ValType::I32].into() });
+        let two = b.add_function(ty, [], [Instruction::I32Const(2), Instruction::EndFunc]);
+        let b = b.finish().expect("valid module");
+
+        let instantiate = |store: &mut Store, counter: i32| -> Result<(ModuleInstance, ModuleInstance)> {
+            let a = ModuleInstance::instantiate(store, Module::from(&a), None)?;
+            let mut imports = Imports::new();
+            imports.link_module("a", a.id())?.define(
+                "env",
+                "counter",
+                Extern::global(WasmValue::I32(counter), true),
+            )?;
+            Ok((a.clone(), ModuleInstance::instantiate(store, Module::from(&b), Some(imports))?))
+        };
+
+        let mut store = Store::default();
+        let (a1, b1) = instantiate(&mut store, 1)?;
+        a1.memory_mut(&mut store, 0)?.store(0, 4, b"tiny")?;
+        store.get_table(a1.table_addrs()[0] as usize)?.borrow_mut().set(0, b1.func_addrs()[two as usize])?;
+        store.get_global(b1.resolve_global_addr(0) as usize)?.borrow_mut().set(WasmValue::I32(9))?;
+        store.get_global(b1.resolve_global_addr(1) as usize)?.borrow_mut().set(WasmValue::I32(5))?;
+
+        let bytes = store.snapshot()?.to_bytes();
+        let snapshot = StoreSnapshot::from_bytes(&bytes)?;
+        assert_eq!(snapshot.to_bytes(), bytes);
+        assert!(StoreSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert_eq!(snapshot.instance(b1.id()).expect("instance b").globals().values, vec![(1, WasmValue::I32(5))]);
+        assert!(snapshot.instance(b1.id()).expect("instance b").memory(0).is_none());
+
+        let mut other = Store::default();
+        assert!(other.restore(&snapshot).is_err());
+        let (a2, b2) = instantiate(&mut other, 3)?;
+        other.restore(&snapshot)?;
+
+        assert_eq!(b2.memory(&mut other, 0)?.load(0, 4)?, b"tiny");
+        let table = other.get_table(a2.table_addrs()[0] as usize)?.borrow().elements[0].addr();
+        assert_eq!(table, Some(b2.func_addrs()[two as usize]));
+        assert_eq!(other.get_global(b2.resolve_global_addr(0) as usize)?.borrow().get(), WasmValue::I32(3));
+        assert_eq!(other.get_global(b2.resolve_global_addr(1) as usize)?.borrow().get(), WasmValue::I32(5));
+
+        // nothing is restored if any of the instances doesn't match its snapshot
+        a2.memory_mut(&mut other, 0)?.store(0, 4, b"wasm")?;
+        let mut invalid = snapshot.clone();
+        let instance_b = invalid.instances.iter_mut().find(|(addr, _)| *addr == b1.id()).expect("instance b");
+        instance_b.1.globals.values[0].1 = WasmValue::I64(5);
+        assert!(other.restore(&invalid).is_err());
+        assert_eq!(b2.memory(&mut other, 0)?.load(0, 4)?, b"wasm");
+
+        // instance snapshots reference functions by their index in the module instead
+        assert!(b2.restore(&mut other, snapshot.instance(b1.id()).expect("instance b")).is_ok());
+        assert!(other.restore(&StoreSnapshot { instances: vec![(b2.id(), b2.snapshot(&other)?)] }).is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{log, unlikely};
 use crate::{Error, Result, Trap};
-use alloc::{vec, vec::Vec};
+use alloc::{collections::BTreeMap, vec, vec::Vec};
 use tinywasm_types::*;
 
 const MAX_TABLE_SIZE: u32 = 10000000;
@@ -12,12 +12,36 @@ const MAX_TABLE_SIZE: u32 = 10000000;
 pub(crate) struct TableInstance {
     pub(crate) elements: Vec<TableElement>,
     pub(crate) kind: TableType,
-    pub(crate) _owner: ModuleInstanceAddr, // index into store.module_instances
+    pub(crate) owner: ModuleInstanceAddr, // index into store.module_instances
 }
 
 impl TableInstance {
     pub(crate) fn new(kind: TableType, owner: ModuleInstanceAddr) -> Self {
-        Self { elements: vec![TableElement::Uninitialized; kind.size_initial as usize], kind, _owner: owner }
+        Self { elements: vec![TableElement::Uninitialized; kind.size_initial as usize], kind, owner }
+    }
+
+    /// Create a new table, reusing the allocation of `buffer`
+    pub(crate) fn with_buffer(kind: TableType, owner: ModuleInstanceAddr, mut buffer: Vec<TableElement>) -> Self {
+        buffer.clear();
+        buffer.resize(kind.size_initial as usize, TableElement::Uninitialized);
+        Self { elements: buffer, kind, owner }
+    }
+
+    /// Create a copy of this table for another module instance, remapping function references
+    pub(crate) fn fork(&self, owner: ModuleInstanceAddr, funcs: &BTreeMap<FuncAddr, FuncAddr>) -> Self {
+        let elements = match self.kind.element_type {
+            ValType::RefFunc => {
+                self.elements.iter().map(|e| e.map(|addr| *funcs.get(&addr).unwrap_or(&addr))).collect()
+            }
+            _ => self.elements.clone(),
+        };
+
+        Self { elements, kind: self.kind.clone(), owner }
+    }
+
+    /// Free the table's elements, returning the buffer
+    pub(crate) fn release(&mut self) -> Vec<TableElement> {
+        core::mem::take(&mut self.elements)
     }
 
     pub(crate) fn get_wasm_val(&self, addr: usize) -> Result<WasmValue> {
@@ -34,8 +58,9 @@ impl TableInstance {
         self.elements.get(addr).ok_or_else(|| Error::Trap(Trap::UndefinedElement { index: addr }))
     }
 
-    pub(crate) fn set(&mut self, table_idx: usize, value: Addr) -> Result<()> {
-        self.grow_to_fit(table_idx + 1).map(|_| self.elements[table_idx] = TableElement::Initialized(value))
+    pub(crate) fn set(&mut self, table_idx: usize, value: impl Into<TableElement>) -> Result<()> {
+        let value = value.into();
+        self.grow_to_fit(table_idx + 1).map(|_| self.elements[table_idx] = value)
     }
 
     pub(crate) fn grow_to_fit(&mut self, new_size: usize) -> Result<()> {
@@ -92,6 +117,12 @@ pub(crate) enum TableElement {
     Initialized(TableAddr),
 }
 
+impl From<Addr> for TableElement {
+    fn from(addr: Addr) -> Self {
+        TableElement::Initialized(addr)
+    }
+}
+
 impl From<Option<Addr>> for TableElement {
     fn from(addr: Option<Addr>) -> Self {
         match addr {
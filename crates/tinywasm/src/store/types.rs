@@ -0,0 +1,79 @@
+use alloc::{boxed::Box, collections::BTreeMap};
+use tinywasm_types::FuncType;
+
+/// The canonical ID of a function type in a store
+///
+/// Two functions have the same signature exactly if their type IDs are equal.
+pub(crate) type TypeId = u32;
+
+/// The function types of all functions and module instances in a store
+///
+/// Every distinct type is assigned a [`TypeId`] once, so signature checks, e.g. by `call_indirect`,
+/// compare two integers instead of the parameter and result types.
+#[derive(Debug, Default)]
+pub(crate) struct TypeRegistry {
+    ids: BTreeMap<FuncType, TypeId>,
+}
+
+impl TypeRegistry {
+    /// Get the ID of a type, assigning a new one if the type hasn't been seen before
+    pub(crate) fn intern(&mut self, ty: &FuncType) -> TypeId {
+        if let Some(id) = self.ids.get(ty) {
+            return *id;
+        }
+
+        let id = self.ids.len() as TypeId;
+        self.ids.insert(ty.clone(), id);
+        id
+    }
+
+    /// Get the IDs of the types of a module, indexed by their [`TypeAddr`](tinywasm_types::TypeAddr)
+    pub(crate) fn intern_all(&mut self, types: &[FuncType]) -> Box<[TypeId]> {
+        types.iter().map(|ty| self.intern(ty)).collect()
+    }
+
+    /// Get the ID of a type, if any function in the store has it
+    pub(crate) fn get(&self, ty: &FuncType) -> Option<TypeId> {
+        self.ids.get(ty).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Module, Result, Store};
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_registry() {
+        let ty = |params: &[ValType]| FuncType { params: params.into(), results: [ValType::I32].into() };
+        let mut types = TypeRegistry::default();
+        assert_eq!(types.get(&ty(&[])), None);
+
+        let ids = types.intern_all(&[ty(&[]), ty(&[ValType::I64]), ty(&[])]);
+        assert_eq!(&*ids, &[0, 1, 0]);
+        assert_eq!(types.intern(&ty(&[ValType::I64])), 1);
+        assert_eq!(types.get(&ty(&[])), Some(0));
+        assert_eq!(types.get(&ty(&[ValType::I32])), None);
+    }
+
+    #[test]
+    fn test_modules_share_ids() -> Result<()> {
+        // a function returning 1, with its type after `types` in the module
+        let module = |types: &[FuncType]| {
+            let mut builder = ModuleBuilder::new();
+            types.iter().for_each(|ty| _ = builder.add_type(ty.clone()));
+            let ty = builder.add_type(FuncType { params: [].into(), results: [ValType::I32].into() });
+            builder.add_function(ty, [], [Instruction::I32Const(1), Instruction::EndFunc]);
+            Module::from(builder.finish().expect("valid module"))
+        };
+
+        let mut store = Store::default();
+        let a = module(&[]).instantiate(&mut store, None)?;
+        let other = FuncType { params: [ValType::I64].into(), results: [].into() };
+        let b = module(&[other]).instantiate(&mut store, None)?;
+        assert_eq!(a.type_id(0), b.type_id(1));
+        assert_ne!(b.type_id(0), b.type_id(1));
+        Ok(())
+    }
+}
@@ -0,0 +1,482 @@
+use alloc::vec::Vec;
+
+use crate::lockstep::State;
+use crate::runtime::{BlockFrame, BlockType, CallFrame, RawWasmValue, Stack};
+use crate::snapshot::{write_u32, write_u64, Reader};
+use crate::{
+    Error, FuncHandle, Function, Imports, InstanceSnapshot, MeteredCall, Module, ModuleInstance, Result, Store,
+};
+use tinywasm_types::{Instruction, WasmFunction};
+
+/// A paused [`MeteredCall`] together with the state of its instance, see [`MeteredCall::suspend`]
+///
+/// Unlike the call itself, this doesn't depend on the store, so it can be serialized and resumed
+/// in another store or process, e.g. by durable-function platforms that move work between hosts.
+#[derive(Debug, Clone)]
+pub struct SuspendedCall {
+    snapshot: InstanceSnapshot,
+    func: u32, // the called function's index in the module
+    consumed: u64,
+    state: SuspendedState,
+}
+
+#[derive(Debug, Clone)]
+enum SuspendedState {
+    // a host function that hasn't been called yet
+    Host(Vec<u64>),
+    Wasm { values: Vec<u64>, blocks: Vec<SuspendedBlock>, frames: Vec<SuspendedFrame> },
+}
+
+// a `BlockFrame`, with the type as a byte
+#[derive(Debug, Clone)]
+struct SuspendedBlock([u64; 5], u8);
+
+// a `CallFrame`, with its function by its index in the module
+#[derive(Debug, Clone)]
+struct SuspendedFrame {
+    func: u32,
+    instr_ptr: u64,
+    block_ptr: u64,
+    locals: Vec<u64>,
+}
+
+const SUSPENDED_MAGIC: &[u8; 4] = b"TWSC";
+
+impl MeteredCall {
+    /// Capture the paused call and a snapshot of its instance, see [`SuspendedCall`]
+    ///
+    /// Only calls within one instance can be suspended: fails if the call has finished, or if it
+    /// is currently inside a function of another instance. Host functions called by the guest
+    /// always run to completion, so they are never part of a suspended call.
+    pub fn suspend(&self, store: &Store) -> Result<SuspendedCall> {
+        let instance = store.get_module_instance_raw(self.func.module_addr)?;
+        let func_idx = |addr: u32| {
+            let idx = instance.func_addrs().iter().position(|f| *f == addr);
+            idx.map(|idx| idx as u32).ok_or_else(|| Error::Other("call is in a function of another instance".into()))
+        };
+
+        let state = match &self.state {
+            State::Done => return Err(Error::Other("metered call has already finished".into())),
+            State::Host(params) => SuspendedState::Host(params.iter().map(|v| RawWasmValue::from(*v).into()).collect()),
+            State::Wasm(stack) => {
+                let values = stack.values.last_n(stack.values.len())?.iter().map(|v| (*v).into()).collect();
+                let blocks = stack.blocks.frames().iter().map(|b| {
+                    let ty = match b.ty {
+                        BlockType::Loop => 0,
+                        BlockType::If => 1,
+                        BlockType::Else => 2,
+                        BlockType::Block => 3,
+                    };
+                    let ptrs = [b.instr_ptr, b.end_instr_ptr, b.stack_ptr, b.results, b.params];
+                    SuspendedBlock(ptrs.map(|p| p as u64), ty)
+                });
+
+                let mut frames = Vec::new();
+                for frame in stack.call_stack.frames() {
+                    if frame.func_instance.1 != instance.id() {
+                        return Err(Error::Other("call is in a function of another instance".into()));
+                    }
+                    frames.push(SuspendedFrame {
+                        func: func_idx(frame.func_addr)?,
+                        instr_ptr: frame.instr_ptr as u64,
+                        block_ptr: frame.block_ptr as u64,
+                        locals: frame.locals(&stack.locals).iter().map(|v| (*v).into()).collect(),
+                    });
+                }
+
+                SuspendedState::Wasm { values, blocks: blocks.collect(), frames }
+            }
+        };
+
+        let snapshot = instance.snapshot(store)?;
+        Ok(SuspendedCall { snapshot, func: func_idx(self.func.addr)?, consumed: self.consumed, state })
+    }
+}
+
+impl SuspendedCall {
+    /// The snapshot of the instance the call was suspended in
+    pub fn snapshot(&self) -> &InstanceSnapshot {
+        &self.snapshot
+    }
+
+    /// Restore the instance in `store` and continue the call there
+    ///
+    /// `module` has to be the module the call was suspended in, and the imports have to be compatible
+    /// with the original ones, like for [`Module::restore`]. The resumed call continues with the
+    /// instruction it was paused at, and [`MeteredCall::consumed`] includes the fuel consumed before.
+    pub fn resume(
+        &self,
+        module: Module,
+        store: &mut Store,
+        imports: Option<Imports>,
+    ) -> Result<(ModuleInstance, MeteredCall)> {
+        let instance = module.restore(store, imports, &self.snapshot)?;
+        match self.resume_in(&instance, store) {
+            Ok(call) => Ok((instance, call)),
+            Err(e) => {
+                store.remove_instance(instance.id())?;
+                Err(e)
+            }
+        }
+    }
+
+    fn resume_in(&self, instance: &ModuleInstance, store: &mut Store) -> Result<MeteredCall> {
+        let invalid = || Error::Other("suspended call does not match the module".into());
+        let func_addr = |idx: u32| instance.func_addrs().get(idx as usize).copied().ok_or_else(invalid);
+
+        let addr = func_addr(self.func)?;
+        let ty = store.get_func(addr as usize)?.func.ty().clone();
+        let func = FuncHandle { module_addr: instance.id(), addr, ty, name: None };
+
+        let state = match &self.state {
+            SuspendedState::Host(params) if params.len() == func.ty.params.len() => {
+                let params = params.iter().zip(func.ty.params.iter());
+                State::Host(params.map(|(v, ty)| RawWasmValue::from(*v).attach_type(*ty)).collect())
+            }
+            SuspendedState::Host(_) => return Err(invalid()),
+            SuspendedState::Wasm { values, blocks, frames } => {
+                // the stack goes back to the store if the call doesn't match the module
+                let mut stack = store.take_unused_stack();
+                stack.clear();
+                if let Err(e) = self.restore_stack(instance, store, &mut stack, values, blocks, frames) {
+                    store.give_stack(stack);
+                    return Err(e);
+                }
+                State::Wasm(stack)
+            }
+        };
+
+        let dropped_stacks = store.dropped_stacks.clone();
+        Ok(MeteredCall { func, state, consumed: self.consumed, dropped_stacks })
+    }
+
+    // push the blocks and frames of the suspended call to an empty stack
+    fn restore_stack(
+        &self,
+        instance: &ModuleInstance,
+        store: &mut Store,
+        stack: &mut Stack,
+        values: &[u64],
+        blocks: &[SuspendedBlock],
+        frames: &[SuspendedFrame],
+    ) -> Result<()> {
+        let invalid = || Error::Other("suspended call does not match the module".into());
+        let func_addr = |idx: u32| instance.func_addrs().get(idx as usize).copied().ok_or_else(invalid);
+
+        stack.values.extend_from_slice(&values.iter().map(|v| RawWasmValue::from(*v)).collect::<Vec<_>>());
+
+        for SuspendedBlock(ptrs, ty) in blocks {
+            let ty = match ty {
+                0 => BlockType::Loop,
+                1 => BlockType::If,
+                2 => BlockType::Else,
+                3 => BlockType::Block,
+                _ => return Err(invalid()),
+            };
+            let [instr_ptr, end_instr_ptr, stack_ptr, results, params] = ptrs.map(|p| p as usize);
+            stack.blocks.push(BlockFrame { instr_ptr, end_instr_ptr, stack_ptr, results, params, ty });
+        }
+
+        for (i, frame) in frames.iter().enumerate() {
+            let addr = func_addr(frame.func)?;
+            store.translate_func(addr)?;
+            let func_inst = store.get_func(addr as usize)?;
+            let Function::Wasm(wasm_func) = &func_inst.func else {
+                return Err(invalid());
+            };
+
+            // callers continue after the call instruction, the innermost frame anywhere in its function
+            let instr_ptr = frame.instr_ptr as usize;
+            let calling = match instr_ptr.checked_sub(1).and_then(|ip| wasm_func.instructions.get(ip)) {
+                Some(Instruction::Call(_) | Instruction::CallIndirect(..)) => true,
+                _ => i + 1 == frames.len(),
+            };
+            let locals = wasm_func.ty.params.len() + wasm_func.locals.len();
+            if instr_ptr >= wasm_func.instructions.len()
+                || !calling
+                || (i == 0 && frame.func != self.func)
+                || frame.locals.len() != locals
+            {
+                return Err(invalid());
+            }
+
+            // the blocks of the frame are nested and entered by the instructions they start at
+            let block_ptr = frame.block_ptr as usize;
+            let block_end = frames.get(i + 1).map_or(blocks.len(), |next| next.block_ptr as usize);
+            let mut outer: Option<&BlockFrame> = None;
+            for block in &stack.blocks.frames()[block_ptr..block_end] {
+                let nested = outer.map_or(true, |outer| {
+                    outer.instr_ptr < block.instr_ptr && block.end_instr_ptr <= outer.end_instr_ptr
+                });
+                if !nested
+                    || !(block.instr_ptr..=block.end_instr_ptr).contains(&instr_ptr)
+                    || !check_block(wasm_func, block, instance)
+                {
+                    return Err(invalid());
+                }
+                outer = Some(block);
+            }
+
+            let params = frame.locals.iter().map(|v| RawWasmValue::from(*v));
+            let mut cf = CallFrame::new(wasm_func.clone(), addr, func_inst.owner, params, block_ptr, &mut stack.locals);
+            cf.instr_ptr = instr_ptr;
+            stack.call_stack.push(cf)?;
+        }
+
+        if stack.call_stack.is_empty() {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// Serialize the suspended call including the snapshot of its instance
+    ///
+    /// Load it again with [`SuspendedCall::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(SUSPENDED_MAGIC);
+        out.push(crate::snapshot::SNAPSHOT_VERSION);
+        write_u32(&mut out, self.func);
+        write_u64(&mut out, self.consumed);
+
+        let snapshot = self.snapshot.to_bytes();
+        write_u64(&mut out, snapshot.len() as u64);
+        out.extend_from_slice(&snapshot);
+
+        let write_values = |out: &mut Vec<u8>, values: &[u64]| {
+            write_u64(out, values.len() as u64);
+            values.iter().for_each(|v| write_u64(out, *v));
+        };
+
+        match &self.state {
+            SuspendedState::Host(params) => {
+                out.push(0);
+                write_values(&mut out, params);
+            }
+            SuspendedState::Wasm { values, blocks, frames } => {
+                out.push(1);
+                write_values(&mut out, values);
+
+                write_u64(&mut out, blocks.len() as u64);
+                for SuspendedBlock(ptrs, ty) in blocks {
+                    ptrs.iter().for_each(|p| write_u64(&mut out, *p));
+                    out.push(*ty);
+                }
+
+                write_u64(&mut out, frames.len() as u64);
+                for frame in frames {
+                    write_u32(&mut out, frame.func);
+                    write_u64(&mut out, frame.instr_ptr);
+                    write_u64(&mut out, frame.block_ptr);
+                    write_values(&mut out, &frame.locals);
+                }
+            }
+        }
+        out
+    }
+
+    /// Load a suspended call serialized with [`SuspendedCall::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader(bytes);
+        reader.header(SUSPENDED_MAGIC)?;
+        let func = reader.u32()?;
+        let consumed = reader.u64()?;
+
+        let len = reader.u64()? as usize;
+        let snapshot = InstanceSnapshot::from_bytes(reader.bytes(len)?)?;
+
+        // the lengths are checked against the remaining bytes, so invalid lengths can't allocate too much
+        let len = |reader: &mut Reader<'_>, size: usize| match reader.u64()? as usize {
+            len if len.saturating_mul(size) <= reader.0.len() => Ok(len),
+            _ => Err(Reader::invalid("unexpected end")),
+        };
+        let values = |reader: &mut Reader<'_>| (0..len(reader, 8)?).map(|_| reader.u64()).collect::<Result<Vec<_>>>();
+
+        let state = match reader.u8()? {
+            0 => SuspendedState::Host(values(&mut reader)?),
+            1 => {
+                let values = values(&mut reader)?;
+
+                let mut blocks = Vec::new();
+                for _ in 0..len(&mut reader, 41)? {
+                    let mut ptrs = [0; 5];
+                    for ptr in &mut ptrs {
+                        *ptr = reader.u64()?;
+                    }
+                    blocks.push(SuspendedBlock(ptrs, reader.u8()?));
+                }
+
+                let mut frames = Vec::new();
+                for _ in 0..len(&mut reader, 28)? {
+                    let (func, instr_ptr, block_ptr) = (reader.u32()?, reader.u64()?, reader.u64()?);
+                    let locals = (0..len(&mut reader, 8)?).map(|_| reader.u64()).collect::<Result<_>>()?;
+                    frames.push(SuspendedFrame { func, instr_ptr, block_ptr, locals });
+                }
+
+                // blocks and frames are pushed in order, and blocks keep the values below them on the stack
+                let mut stack_ptr = 0;
+                for SuspendedBlock([_, _, block_stack_ptr, _, params], _) in &blocks {
+                    if *block_stack_ptr < stack_ptr
+                        || *block_stack_ptr > values.len() as u64
+                        || params > block_stack_ptr
+                    {
+                        return Err(Reader::invalid("block stack pointer"));
+                    }
+                    stack_ptr = *block_stack_ptr;
+                }
+                let mut block_ptr = 0;
+                for frame in &frames {
+                    if frame.block_ptr < block_ptr || frame.block_ptr > blocks.len() as u64 {
+                        return Err(Reader::invalid("frame block pointer"));
+                    }
+                    block_ptr = frame.block_ptr;
+                }
+                if frames.first().map_or(true, |frame| frame.block_ptr != 0) {
+                    return Err(Reader::invalid("call frames"));
+                }
+
+                SuspendedState::Wasm { values, blocks, frames }
+            }
+            _ => return Err(Reader::invalid("call state")),
+        };
+
+        if !reader.0.is_empty() {
+            return Err(Reader::invalid("trailing data"));
+        }
+
+        Ok(Self { snapshot, func, consumed, state })
+    }
+}
+
+// whether the instruction at the start of `block` enters it, like the interpreter does
+fn check_block(func: &WasmFunction, block: &BlockFrame, module: &ModuleInstance) -> bool {
+    let instructions = &func.instructions;
+    let ip = block.instr_ptr;
+    let (args, end) = match (block.ty, instructions.get(ip)) {
+        (BlockType::Loop, Some(Instruction::Loop(args, end))) => (args, ip + *end as usize),
+        (BlockType::Block, Some(Instruction::Block(args, end))) => (args, ip + *end as usize),
+        (BlockType::If, Some(Instruction::If(args, offset))) => match instructions.get(ip + *offset as usize) {
+            Some(Instruction::Else(end)) => (args, ip + *offset as usize + *end as usize),
+            _ => (args, ip + *offset as usize),
+        },
+        // the block type is the one of the `if` that jumped to this `else`
+        (BlockType::Else, Some(Instruction::Else(end))) => {
+            let args = instructions[..ip].iter().enumerate().find_map(|(if_ip, instr)| match instr {
+                Instruction::If(args, offset) if if_ip + *offset as usize == ip => Some(args),
+                _ => None,
+            });
+            match args {
+                Some(args) => (args, ip + *end as usize),
+                None => return false,
+            }
+        }
+        _ => return false,
+    };
+
+    let Some(args) = args.try_unpack() else { return false };
+    let expected = BlockFrame::new(ip, end, block.stack_ptr, block.ty, &args, module, &func.constants);
+    expected.end_instr_ptr == block.end_instr_ptr
+        && expected.params == block.params
+        && expected.results == block.results
+        && end < instructions.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Slice;
+    use tinywasm_types::*;
+
+    #[test]
+    fn test_suspend_resume() -> Result<()> {
+        // `count(n)` calls `step` n times, which adds 1 to the value in memory at 0, and returns it
+        let mut builder = ModuleBuilder::new();
+        builder.add_memory(MemoryType::new_32(1, None));
+        let empty = builder.add_type(FuncType::default());
+        let step = builder.add_function_with_constants(
+            empty,
+            [],
+            [
+                Instruction::I32Const(0),
+                Instruction::I32Const(0),
+                Instruction::I32Load { offset: 0, mem_addr: 0 },
+                Instruction::I32Const(1),
+                Instruction::I32Add,
+                Instruction::I32Store { offset: 0, mem_addr: 0 },
+                Instruction::EndFunc,
+            ],
+            [0],
+        );
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        let count = builder.add_function_with_constants(
+            ty,
+            [],
+            [
+                Instruction::Loop(BlockArgsPacked::EMPTY, 7),
+                Instruction::Call(step),
+                Instruction::LocalGet(0),
+                Instruction::I32Const(1),
+                Instruction::I32Sub,
+                Instruction::LocalTee(0),
+                Instruction::BrIf(0),
+                Instruction::EndBlockFrame,
+                Instruction::I32Const(0),
+                Instruction::I32Load { offset: 0, mem_addr: 0 },
+                Instruction::EndFunc,
+            ],
+            [0],
+        );
+        builder.add_export("count", ExternalKind::Func, count);
+        let module = Module::from(builder.finish().expect("valid module"));
+
+        let mut store = Store::default();
+        let instance = module.clone().instantiate(&mut store, None)?;
+        let mut call =
+            instance.exported_func_untyped(&store, "count")?.call_metered(&mut store, &[WasmValue::I32(5)])?;
+        assert!(matches!(call.run(&mut store, 20)?, Slice::Paused { .. }));
+
+        let bytes = call.suspend(&store)?.to_bytes();
+        let suspended = SuspendedCall::from_bytes(&bytes)?;
+        assert_eq!(suspended.to_bytes(), bytes);
+        assert!(SuspendedCall::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        // frames and blocks that don't match the module are rejected instead of breaking the interpreter
+        type Mutation = fn(&mut [SuspendedBlock], &mut [SuspendedFrame]);
+        let mutations: [Mutation; 5] = [
+            |blocks, _| blocks[0].0[1] += 1,     // end of the loop
+            |blocks, _| blocks[0].1 = 3,         // the loop as a block
+            |blocks, _| blocks[0].0[4] = 1,      // params of the loop
+            |_, frames| frames[0].instr_ptr = 3, // `count` wasn't calling `step`
+            |_, frames| frames[1].func = 1,      // `count` called itself
+        ];
+        for mutate in mutations {
+            let mut corrupted = suspended.clone();
+            let SuspendedState::Wasm { blocks, frames, .. } = &mut corrupted.state else {
+                panic!("call should be paused in `step`");
+            };
+            assert_eq!((blocks.len(), frames.len()), (1, 2));
+            mutate(blocks, frames);
+            assert!(corrupted.resume(module.clone(), &mut Store::default(), None).is_err());
+        }
+
+        let mut corrupted = suspended.clone();
+        if let SuspendedState::Wasm { blocks, values, .. } = &mut corrupted.state {
+            blocks[0].0[2] = values.len() as u64 + 1;
+        }
+        assert!(SuspendedCall::from_bytes(&corrupted.to_bytes()).is_err());
+
+        // the suspended call continues where it was paused, in another store
+        let mut other = Store::default();
+        let (restored, mut resumed) = suspended.resume(module, &mut other, None)?;
+        let Slice::Finished { results, .. } = resumed.run(&mut other, u64::MAX)? else {
+            panic!("call should finish");
+        };
+        assert_eq!(results, [WasmValue::I32(5)]);
+        assert_eq!(restored.memory(&mut other, 0)?.load(0, 4)?, 5i32.to_le_bytes());
+
+        assert!(matches!(call.run(&mut store, u64::MAX)?, Slice::Finished { .. }));
+        assert_eq!(resumed.consumed(), call.consumed());
+        assert!(call.suspend(&store).is_err());
+        Ok(())
+    }
+}
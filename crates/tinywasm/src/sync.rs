@@ -0,0 +1,343 @@
+// Shared ownership and interior mutability primitives used throughout the store.
+//
+// By default, these are `Rc` and `RefCell`. With the `sync` feature, `Arc` and a
+// lock based cell are used instead, which makes `Store` and all handles into it `Send + Sync`.
+// With the `critical-section` feature, the cell tracks borrows inside critical sections instead,
+// which also works without `std`, e.g. to share a store with interrupt handlers. `Arc` is still used, so
+// the target has to support atomic operations on pointers (which e.g. AVR, MSP430 and `thumbv6m` don't).
+
+#[cfg(not(feature = "sync"))]
+pub(crate) use alloc::rc::Rc;
+#[cfg(not(feature = "sync"))]
+pub(crate) use core::cell::{Ref, RefCell, RefMut};
+
+#[cfg(feature = "sync")]
+pub(crate) use alloc::sync::Arc as Rc;
+#[cfg(feature = "critical-section")]
+pub(crate) use cs::{Ref, RefCell, RefMut};
+#[cfg(all(feature = "sync", not(feature = "critical-section")))]
+pub(crate) use lock::{Ref, RefCell, RefMut};
+
+/// The error returned when a value is already borrowed in a way that conflicts with a new borrow
+#[cfg(feature = "sync")]
+#[derive(Debug)]
+pub(crate) struct BorrowError;
+
+#[cfg(all(feature = "sync", not(feature = "std"), not(feature = "critical-section")))]
+compile_error!("the `sync` feature requires either the `std` or the `critical-section` feature");
+
+/// A marker trait for values that can be stored in a [`crate::Store`]
+///
+/// With the `sync` feature enabled, this requires `Send + Sync`, otherwise it is implemented for all types.
+/// Host functions and other callbacks passed to tinywasm need to implement this trait.
+#[cfg(feature = "sync")]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(feature = "sync")]
+impl<T: Send + Sync + ?Sized> MaybeSendSync for T {}
+
+/// A marker trait for values that can be stored in a [`crate::Store`]
+///
+/// With the `sync` feature enabled, this requires `Send + Sync`, otherwise it is implemented for all types.
+/// Host functions and other callbacks passed to tinywasm need to implement this trait.
+#[cfg(not(feature = "sync"))]
+pub trait MaybeSendSync {}
+#[cfg(not(feature = "sync"))]
+impl<T: ?Sized> MaybeSendSync for T {}
+
+/// A host object that can be passed to WebAssembly as an `externref`
+///
+/// With the `sync` feature enabled, this is an `Arc<dyn Any + Send + Sync>`.
+/// See [`crate::Store::add_extern_ref`]
+#[cfg(feature = "sync")]
+pub type ExternObject = Rc<dyn core::any::Any + Send + Sync>;
+
+/// A host object that can be passed to WebAssembly as an `externref`
+///
+/// With the `sync` feature enabled, this is an `Arc<dyn Any + Send + Sync>`.
+/// See [`crate::Store::add_extern_ref`]
+#[cfg(not(feature = "sync"))]
+pub type ExternObject = Rc<dyn core::any::Any>;
+
+#[cfg(all(feature = "sync", not(feature = "critical-section")))]
+mod lock {
+    use super::BorrowError;
+    use crate::std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    pub(crate) type Ref<'a, T> = RwLockReadGuard<'a, T>;
+    pub(crate) type RefMut<'a, T> = RwLockWriteGuard<'a, T>;
+
+    /// A `RefCell` that can be shared between threads
+    ///
+    /// Like `RefCell`, borrowing panics instead of blocking if the value is already borrowed.
+    /// The store is only ever accessed through `&mut Store` while executing, so this can
+    /// only happen when a handle (e.g. a `GlobalRef`) is used from another thread at the same time.
+    #[derive(Debug, Default)]
+    pub(crate) struct RefCell<T>(RwLock<T>);
+
+    impl<T> RefCell<T> {
+        #[inline]
+        pub(crate) fn new(value: T) -> Self {
+            Self(RwLock::new(value))
+        }
+
+        #[inline]
+        pub(crate) fn borrow(&self) -> Ref<'_, T> {
+            self.try_borrow().expect("already mutably borrowed")
+        }
+
+        #[inline]
+        pub(crate) fn borrow_mut(&self) -> RefMut<'_, T> {
+            self.try_borrow_mut().expect("already borrowed")
+        }
+
+        #[inline]
+        pub(crate) fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+            match self.0.try_read() {
+                Ok(value) => Ok(value),
+                Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+
+        #[inline]
+        pub(crate) fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowError> {
+            match self.0.try_write() {
+                Ok(value) => Ok(value),
+                Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "critical-section")]
+#[allow(unsafe_code)]
+mod cs {
+    use super::BorrowError;
+    use core::cell::{Cell, UnsafeCell};
+    use core::ops::{Deref, DerefMut};
+    use critical_section::Mutex;
+
+    /// A `RefCell` that can be shared between threads and interrupt handlers
+    ///
+    /// The borrow state is only updated inside of a critical section. Like `RefCell`, borrowing panics
+    /// instead of blocking if the value is already borrowed, since blocking in an interrupt handler could
+    /// never make progress. Values are never accessed outside of a successful borrow.
+    pub(crate) struct RefCell<T> {
+        // the number of shared borrows, or -1 if mutably borrowed
+        borrows: Mutex<Cell<isize>>,
+        value: UnsafeCell<T>,
+    }
+
+    // SAFETY: the borrow state ensures there is either a single mutable borrow or only shared borrows,
+    // so this has the same requirements as `RwLock`
+    unsafe impl<T: Send> Send for RefCell<T> {}
+    unsafe impl<T: Send + Sync> Sync for RefCell<T> {}
+
+    impl<T> core::fmt::Debug for RefCell<T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.write_str("RefCell { .. }")
+        }
+    }
+
+    // `critical_section::Mutex` doesn't implement `Default`
+    impl<T: Default> Default for RefCell<T> {
+        fn default() -> Self {
+            Self::new(T::default())
+        }
+    }
+
+    impl<T> RefCell<T> {
+        #[inline]
+        pub(crate) fn new(value: T) -> Self {
+            Self { borrows: Mutex::new(Cell::new(0)), value: UnsafeCell::new(value) }
+        }
+
+        // update the borrow state, returning false if the update isn't allowed
+        #[inline]
+        fn update(&self, f: impl FnOnce(isize) -> Option<isize>) -> bool {
+            critical_section::with(|cs| {
+                let borrows = self.borrows.borrow(cs);
+                f(borrows.get()).map(|new| borrows.set(new)).is_some()
+            })
+        }
+
+        #[inline]
+        pub(crate) fn borrow(&self) -> Ref<'_, T> {
+            self.try_borrow().expect("already mutably borrowed")
+        }
+
+        #[inline]
+        pub(crate) fn borrow_mut(&self) -> RefMut<'_, T> {
+            self.try_borrow_mut().expect("already borrowed")
+        }
+
+        #[inline]
+        pub(crate) fn try_borrow(&self) -> Result<Ref<'_, T>, BorrowError> {
+            match self.update(|borrows| (borrows >= 0).then_some(borrows + 1)) {
+                true => Ok(Ref(self)),
+                false => Err(BorrowError),
+            }
+        }
+
+        #[inline]
+        pub(crate) fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, BorrowError> {
+            match self.update(|borrows| (borrows == 0).then_some(-1)) {
+                true => Ok(RefMut(self)),
+                false => Err(BorrowError),
+            }
+        }
+    }
+
+    pub(crate) struct Ref<'a, T>(&'a RefCell<T>);
+    pub(crate) struct RefMut<'a, T>(&'a RefCell<T>);
+
+    impl<T: core::fmt::Debug> core::fmt::Debug for Ref<'_, T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            (**self).fmt(f)
+        }
+    }
+
+    impl<T: core::fmt::Debug> core::fmt::Debug for RefMut<'_, T> {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            (**self).fmt(f)
+        }
+    }
+
+    impl<T> Deref for Ref<'_, T> {
+        type Target = T;
+
+        #[inline]
+        fn deref(&self) -> &T {
+            // SAFETY: there are no mutable borrows while this borrow exists
+            unsafe { &*self.0.value.get() }
+        }
+    }
+
+    impl<T> Drop for Ref<'_, T> {
+        #[inline]
+        fn drop(&mut self) {
+            self.0.update(|borrows| Some(borrows - 1));
+        }
+    }
+
+    impl<T> Deref for RefMut<'_, T> {
+        type Target = T;
+
+        #[inline]
+        fn deref(&self) -> &T {
+            // SAFETY: this is the only borrow
+            unsafe { &*self.0.value.get() }
+        }
+    }
+
+    impl<T> DerefMut for RefMut<'_, T> {
+        #[inline]
+        fn deref_mut(&mut self) -> &mut T {
+            // SAFETY: this is the only borrow
+            unsafe { &mut *self.0.value.get() }
+        }
+    }
+
+    impl<T> Drop for RefMut<'_, T> {
+        #[inline]
+        fn drop(&mut self) {
+            self.0.update(|_| Some(0));
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_borrows() {
+            let cell = RefCell::new(1);
+            {
+                let (a, b) = (cell.borrow(), cell.borrow());
+                assert_eq!(*a + *b, 2);
+                assert!(cell.try_borrow_mut().is_err());
+            }
+
+            *cell.borrow_mut() += 1;
+            let value = cell.borrow_mut();
+            assert!(cell.try_borrow().is_err());
+            assert_eq!(*value, 2);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sync", feature = "std"))]
+mod tests {
+    use crate::std::{sync::Arc, thread, vec::Vec};
+    use crate::{FuncHandle, GlobalRef, Imports, Module, ModuleInstance, Store, TableRef};
+    use alloc::vec;
+    use tinywasm_types::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<Module>();
+        assert_send_sync::<Store>();
+        assert_send_sync::<ModuleInstance>();
+        assert_send_sync::<Imports>();
+        assert_send_sync::<FuncHandle>();
+        assert_send_sync::<GlobalRef>();
+        assert_send_sync::<TableRef>();
+    }
+
+    // a module exporting `add(i32, i32) -> i32`
+    fn add_module() -> Module {
+        let ty = FuncType { params: vec![ValType::I32, ValType::I32].into(), results: vec![ValType::I32].into() };
+        let func = WasmFunction {
+            instructions: vec![Instruction::LocalGet2(0, 1), Instruction::I32Add, Instruction::EndFunc].into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+
+        Module::from(TinyWasmModule {
+            funcs: vec![func].into(),
+            func_types: vec![ty].into(),
+            exports: vec![Export { name: "add".into(), kind: ExternalKind::Func, index: 0 }].into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_parallel_stores() {
+        let module = Arc::new(add_module());
+
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let module = module.clone();
+                thread::spawn(move || {
+                    let mut store = Store::default();
+                    let instance = Module::clone(&module).instantiate(&mut store, None).unwrap();
+                    let add = instance.exported_func::<(i32, i32), i32>(&store, "add").unwrap();
+                    (0..1000).fold(0, |acc, _| add.call(&mut store, (acc, i)).unwrap())
+                })
+            })
+            .collect();
+
+        for (i, thread) in threads.into_iter().enumerate() {
+            assert_eq!(thread.join().unwrap(), i as i32 * 1000);
+        }
+    }
+
+    #[test]
+    fn test_move_store() {
+        let mut store = Store::default();
+        let instance = add_module().instantiate(&mut store, None).unwrap();
+
+        let res = thread::spawn(move || {
+            let add = instance.exported_func::<(i32, i32), i32>(&store, "add").unwrap();
+            add.call(&mut store, (1, 2)).unwrap()
+        });
+
+        assert_eq!(res.join().unwrap(), 3);
+    }
+}
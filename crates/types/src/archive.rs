@@ -1,39 +1,130 @@
 use core::fmt::{Display, Formatter};
 
-use crate::TinyWasmModule;
+use crate::{ModuleFrontend, TinyWasmModule, VerifyError};
 use rkyv::{
     check_archived_root,
     ser::{serializers::AllocSerializer, Serializer},
     Deserialize,
 };
 
+mod portable;
+
+// The header of an archive:
+// | magic (4) | format version (2) | tinywasm-types version (3 x u16 LE) | CRC-32 of the payload (u32 LE) |
 const TWASM_MAGIC_PREFIX: &[u8; 4] = b"TWAS";
-const TWASM_VERSION: &[u8; 2] = b"01";
-#[rustfmt::skip]
-const TWASM_MAGIC: [u8; 16] = [ TWASM_MAGIC_PREFIX[0], TWASM_MAGIC_PREFIX[1], TWASM_MAGIC_PREFIX[2], TWASM_MAGIC_PREFIX[3], TWASM_VERSION[0], TWASM_VERSION[1], 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+const TWASM_VERSION: &[u8; 2] = b"02";
+const TWASM_HEADER_LEN: usize = 16;
+
+// The layout of the archived types can change between any two releases
+const TYPES_VERSION: [u16; 3] = [
+    parse_version(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_version(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_version(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+const fn parse_version(s: &str) -> u16 {
+    let (bytes, mut i, mut n) = (s.as_bytes(), 0, 0);
+    while i < bytes.len() {
+        n = n * 10 + (bytes[i] - b'0') as u16;
+        i += 1;
+    }
+    n
+}
+
+const fn header() -> [u8; TWASM_HEADER_LEN] {
+    let mut header = [0; TWASM_HEADER_LEN];
+    let mut i = 0;
+    while i < 4 {
+        header[i] = TWASM_MAGIC_PREFIX[i];
+        i += 1;
+    }
+    header[4] = TWASM_VERSION[0];
+    header[5] = TWASM_VERSION[1];
+
+    let mut i = 0;
+    while i < 3 {
+        let bytes = TYPES_VERSION[i].to_le_bytes();
+        header[6 + i * 2] = bytes[0];
+        header[7 + i * 2] = bytes[1];
+        i += 1;
+    }
+    header
+}
+
+const TWASM_HEADER: [u8; TWASM_HEADER_LEN] = header();
+
+// Compressed archives are a whole archive (including its header), compressed with lz4 or DEFLATE:
+// | magic (4) | uncompressed size (u32 LE) | lz4 block or raw DEFLATE stream |
+const TWASM_COMPRESSED_MAGIC: &[u8; 4] = b"TWLZ";
+const TWASM_DEFLATE_MAGIC: &[u8; 4] = b"TWDF";
+
+// The most data can grow when decompressed: lz4 stores up to 255 more bytes of a match in each
+// length byte, DEFLATE up to 258 bytes in a single bit, plus the few bits it needs per block.
+// Larger sizes in the header of a compressed archive are rejected before anything is allocated.
+#[cfg(feature = "compression")]
+const MAX_RATIO_LZ4: usize = 255;
+#[cfg(feature = "compression")]
+const MAX_RATIO_DEFLATE: usize = 1032;
+
+// CRC-32 (IEEE), the same checksum used by zip and png
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
 
 pub use rkyv::AlignedVec;
 
-fn validate_magic(wasm: &[u8]) -> Result<usize, TwasmError> {
-    if wasm.len() < TWASM_MAGIC.len() || &wasm[..TWASM_MAGIC_PREFIX.len()] != TWASM_MAGIC_PREFIX {
+fn validate_header(wasm: &[u8]) -> Result<usize, TwasmError> {
+    if wasm.len() < TWASM_HEADER_LEN || &wasm[..4] != TWASM_MAGIC_PREFIX {
         return Err(TwasmError::InvalidMagic);
     }
-    if &wasm[TWASM_MAGIC_PREFIX.len()..TWASM_MAGIC_PREFIX.len() + TWASM_VERSION.len()] != TWASM_VERSION {
+    if &wasm[4..6] != TWASM_VERSION {
         return Err(TwasmError::InvalidVersion);
     }
-    if wasm[TWASM_MAGIC_PREFIX.len() + TWASM_VERSION.len()..TWASM_MAGIC.len()] != [0; 10] {
-        return Err(TwasmError::InvalidPadding);
+
+    let found = core::array::from_fn(|i| u16::from_le_bytes([wasm[6 + i * 2], wasm[7 + i * 2]]));
+    if found != TYPES_VERSION {
+        return Err(TwasmError::VersionMismatch { expected: TYPES_VERSION, found });
     }
 
-    Ok(TWASM_MAGIC.len())
+    let checksum = u32::from_le_bytes([wasm[12], wasm[13], wasm[14], wasm[15]]);
+    if checksum != crc32(&wasm[TWASM_HEADER_LEN..]) {
+        return Err(TwasmError::ChecksumMismatch);
+    }
+
+    Ok(TWASM_HEADER_LEN)
 }
 
 #[derive(Debug)]
 pub enum TwasmError {
     InvalidMagic,
     InvalidVersion,
-    InvalidPadding,
+    /// The archive was created by a different version of `tinywasm-types`, as `[major, minor, patch]`
+    VersionMismatch {
+        expected: [u16; 3],
+        found: [u16; 3],
+    },
+    /// The archive's contents don't match the checksum in its header, e.g. because the file is corrupted
+    ChecksumMismatch,
     InvalidArchive,
+    /// The archive is compressed, which requires the `compression` feature
+    UnsupportedCompression,
+    InvalidModule(VerifyError),
 }
 
 impl Display for TwasmError {
@@ -41,8 +132,15 @@ impl Display for TwasmError {
         match self {
             TwasmError::InvalidMagic => write!(f, "Invalid twasm: invalid magic number"),
             TwasmError::InvalidVersion => write!(f, "Invalid twasm: invalid version"),
-            TwasmError::InvalidPadding => write!(f, "Invalid twasm: invalid padding"),
+            TwasmError::VersionMismatch { expected: [a, b, c], found: [x, y, z] } => {
+                write!(f, "Invalid twasm: created by tinywasm-types {}.{}.{}, expected {}.{}.{}", x, y, z, a, b, c)
+            }
+            TwasmError::ChecksumMismatch => write!(f, "Invalid twasm: checksum mismatch"),
+            TwasmError::UnsupportedCompression => {
+                write!(f, "Invalid twasm: compressed archives require the `compression` feature")
+            }
             TwasmError::InvalidArchive => write!(f, "Invalid twasm: invalid archive"),
+            TwasmError::InvalidModule(e) => write!(f, "Invalid twasm: {}", e),
         }
     }
 }
@@ -52,8 +150,19 @@ impl std::error::Error for TwasmError {}
 
 impl TinyWasmModule {
     /// Creates a TinyWasmModule from a slice of bytes.
+    ///
+    /// Archives compressed with [`TinyWasmModule::serialize_twasm_compressed`] are decompressed first,
+    /// which requires the `compression` feature. Portable archives created with
+    /// [`TinyWasmModule::serialize_twasm_portable`] are loaded as well.
     pub fn from_twasm(wasm: &[u8]) -> Result<TinyWasmModule, TwasmError> {
-        let len = validate_magic(wasm)?;
+        if is_compressed(wasm) {
+            return Self::from_twasm(&decompress(wasm)?);
+        }
+        if wasm.starts_with(portable::PORTABLE_MAGIC) {
+            return portable::deserialize(wasm);
+        }
+
+        let len = validate_header(wasm)?;
         let root = check_archived_root::<Self>(&wasm[len..]).map_err(|_e| {
             crate::log::error!("Invalid archive: {}", _e);
             TwasmError::InvalidArchive
@@ -62,6 +171,29 @@ impl TinyWasmModule {
         Ok(root.deserialize(&mut rkyv::Infallible).unwrap())
     }
 
+    /// Creates a TinyWasmModule from a slice of bytes and checks it using [`TinyWasmModule::verify`].
+    ///
+    /// Use this instead of [`TinyWasmModule::from_twasm`] for archives from a source you don't fully trust.
+    pub fn from_twasm_verified(wasm: &[u8]) -> Result<TinyWasmModule, TwasmError> {
+        let module = Self::from_twasm(wasm)?;
+        module.verify().map_err(TwasmError::InvalidModule)?;
+        Ok(module)
+    }
+
+    /// Creates a TinyWasmModule from a slice of bytes that doesn't have to be aligned.
+    ///
+    /// Archives are read in place, so [`TinyWasmModule::from_twasm`] needs them to be aligned to
+    /// [`AlignedVec::ALIGNMENT`]. This copies them into an [`AlignedVec`] first if they aren't,
+    /// e.g. for archives read into a `Vec<u8>`.
+    pub fn from_twasm_unaligned(wasm: &[u8]) -> Result<TinyWasmModule, TwasmError> {
+        with_aligned(wasm, Self::from_twasm)
+    }
+
+    /// Like [`TinyWasmModule::from_twasm_unaligned`], but checks the module using [`TinyWasmModule::verify`].
+    pub fn from_twasm_unaligned_verified(wasm: &[u8]) -> Result<TinyWasmModule, TwasmError> {
+        with_aligned(wasm, Self::from_twasm_verified)
+    }
+
     #[cfg(feature = "unsafe")]
     #[allow(unsafe_code)]
     /// Creates a TinyWasmModule from a slice of bytes.
@@ -70,7 +202,14 @@ impl TinyWasmModule {
     /// This function is only safe to call if the bytes have been created by
     /// a trusted source. Otherwise, it may cause undefined behavior.
     pub unsafe fn from_twasm_unchecked(wasm: &[u8]) -> Self {
-        let len = validate_magic(wasm).unwrap();
+        if is_compressed(wasm) {
+            return Self::from_twasm_unchecked(&decompress(wasm).unwrap());
+        }
+        if wasm.starts_with(portable::PORTABLE_MAGIC) {
+            return portable::deserialize(wasm).unwrap();
+        }
+
+        let len = validate_header(wasm).unwrap();
         rkyv::archived_root::<TinyWasmModule>(&wasm[len..]).deserialize(&mut rkyv::Infallible).unwrap()
     }
 
@@ -79,14 +218,142 @@ impl TinyWasmModule {
     /// implements io::Write when the `std` feature is enabled.
     pub fn serialize_twasm(&self) -> rkyv::AlignedVec {
         let mut serializer = AllocSerializer::<0>::default();
-        serializer.pad(TWASM_MAGIC.len()).unwrap();
+        serializer.pad(TWASM_HEADER_LEN).unwrap();
         serializer.serialize_value(self).unwrap();
         let mut out = serializer.into_serializer().into_inner();
-        out[..TWASM_MAGIC.len()].copy_from_slice(&TWASM_MAGIC);
+        out[..TWASM_HEADER_LEN].copy_from_slice(&TWASM_HEADER);
+        let checksum = crc32(&out[TWASM_HEADER_LEN..]);
+        out[12..TWASM_HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    /// Serializes the TinyWasmModule into a portable archive
+    ///
+    /// Archives created with [`TinyWasmModule::serialize_twasm`] can only be loaded by the same version
+    /// of `tinywasm-types`, since they store the in-memory layout of the module to load it without decoding.
+    /// Portable archives use a stable, versioned encoding instead, so they can be loaded by later releases,
+    /// e.g. for artifacts shipped to devices that are updated separately. Loading them decodes the whole module.
+    /// They are also considerably smaller, which helps on devices with little flash.
+    pub fn serialize_twasm_portable(&self) -> alloc::vec::Vec<u8> {
+        portable::serialize(self)
+    }
+}
+
+/// How [`TinyWasmModule::serialize_twasm_compressed_with`] compresses an archive
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// lz4, which is fast to compress and decompress
+    #[default]
+    Lz4,
+    /// DEFLATE, which is slower but creates smaller archives
+    Deflate {
+        /// From 0 (no compression) to 10 (smallest archive, slowest). Higher levels are treated as 10.
+        level: u8,
+    },
+}
+
+#[cfg(feature = "compression")]
+impl TinyWasmModule {
+    /// Serializes the TinyWasmModule into a compressed archive
+    ///
+    /// Translated instructions compress well, so this is usually a fraction of the size of
+    /// [`TinyWasmModule::serialize_twasm`], e.g. for precompiled modules stored in flash.
+    /// Loading a compressed archive needs enough memory for the decompressed archive on top of the module.
+    ///
+    /// This uses lz4, see [`TinyWasmModule::serialize_twasm_compressed_with`] to create smaller archives.
+    pub fn serialize_twasm_compressed(&self) -> alloc::vec::Vec<u8> {
+        self.serialize_twasm_compressed_with(Compression::Lz4)
+    }
+
+    /// Serializes the TinyWasmModule into an archive compressed with the given [`Compression`]
+    pub fn serialize_twasm_compressed_with(&self, compression: Compression) -> alloc::vec::Vec<u8> {
+        let archive = self.serialize_twasm();
+        let size = (archive.len() as u32).to_le_bytes();
+        let (magic, data) = match compression {
+            Compression::Lz4 => (TWASM_COMPRESSED_MAGIC, lz4_flex::compress(&archive)),
+            Compression::Deflate { level } => {
+                (TWASM_DEFLATE_MAGIC, miniz_oxide::deflate::compress_to_vec(&archive, level.min(10)))
+            }
+        };
+
+        let mut out = alloc::vec::Vec::with_capacity(8 + data.len());
+        out.extend_from_slice(magic);
+        out.extend_from_slice(&size);
+        out.extend_from_slice(&data);
         out
     }
 }
 
+fn with_aligned<T>(wasm: &[u8], f: impl FnOnce(&[u8]) -> T) -> T {
+    if wasm.as_ptr() as usize % AlignedVec::ALIGNMENT == 0 {
+        return f(wasm);
+    }
+
+    let mut aligned = AlignedVec::with_capacity(wasm.len());
+    aligned.extend_from_slice(wasm);
+    f(&aligned)
+}
+
+fn is_compressed(wasm: &[u8]) -> bool {
+    wasm.starts_with(TWASM_COMPRESSED_MAGIC) || wasm.starts_with(TWASM_DEFLATE_MAGIC)
+}
+
+// decompress into an aligned buffer, since archives are read in place
+#[cfg(feature = "compression")]
+fn decompress(wasm: &[u8]) -> Result<AlignedVec, TwasmError> {
+    let invalid = |_e: &dyn core::fmt::Debug| {
+        crate::log::error!("Invalid compressed archive: {:?}", _e);
+        TwasmError::InvalidArchive
+    };
+
+    let (magic, rest) = wasm.split_at(4);
+    if rest.len() < 4 {
+        return Err(invalid(&"missing size"));
+    }
+    let (size, data) = rest.split_at(4);
+    let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+
+    let max_ratio = if magic == TWASM_COMPRESSED_MAGIC { MAX_RATIO_LZ4 } else { MAX_RATIO_DEFLATE };
+    if size > data.len().saturating_mul(max_ratio) {
+        return Err(invalid(&"size too large for the compressed data"));
+    }
+
+    let mut aligned = AlignedVec::with_capacity(size);
+    aligned.resize(size, 0);
+    let written = match magic == TWASM_COMPRESSED_MAGIC {
+        true => lz4_flex::decompress_into(data, &mut aligned).map_err(|e| invalid(&e))?,
+        false => {
+            miniz_oxide::inflate::decompress_slice_iter_to_slice(&mut aligned, core::iter::once(data), false, false)
+                .map_err(|e| invalid(&e))?
+        }
+    };
+
+    match written == size {
+        true => Ok(aligned),
+        false => Err(invalid(&"size doesn't match the decompressed data")),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_wasm: &[u8]) -> Result<AlignedVec, TwasmError> {
+    Err(TwasmError::UnsupportedCompression)
+}
+
+/// A [`ModuleFrontend`] for loading `.twasm` archives
+///
+/// Archives are checked using [`TinyWasmModule::from_twasm_verified`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TwasmFrontend;
+
+impl ModuleFrontend for TwasmFrontend {
+    type Error = TwasmError;
+
+    fn parse_module_bytes(&self, bytes: &[u8]) -> Result<TinyWasmModule, TwasmError> {
+        TinyWasmModule::from_twasm_verified(bytes)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,6 +366,96 @@ mod tests {
         assert_eq!(wasm, wasm2);
     }
 
+    #[test]
+    fn test_version_mismatch() {
+        let mut twasm = TinyWasmModule::default().serialize_twasm();
+        assert_eq!(&twasm[..6], b"TWAS02");
+
+        twasm[6] = twasm[6].wrapping_add(1);
+        let err = TinyWasmModule::from_twasm(&twasm).unwrap_err();
+        assert!(matches!(err, TwasmError::VersionMismatch { expected, .. } if expected == TYPES_VERSION));
+
+        twasm[4..6].copy_from_slice(b"01");
+        assert!(matches!(TinyWasmModule::from_twasm(&twasm), Err(TwasmError::InvalidVersion)));
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut twasm = TinyWasmModule::default().serialize_twasm();
+        let last = twasm.len() - 1;
+        twasm[last] ^= 1;
+        assert!(matches!(TinyWasmModule::from_twasm(&twasm), Err(TwasmError::ChecksumMismatch)));
+    }
+
+    #[test]
+    fn test_serialize_verified() {
+        let mut wasm = TinyWasmModule::default();
+        assert!(TinyWasmModule::from_twasm_verified(&wasm.serialize_twasm()).is_ok());
+
+        wasm.start_func = Some(0);
+        let err = TinyWasmModule::from_twasm_verified(&wasm.serialize_twasm()).unwrap_err();
+        assert!(matches!(err, TwasmError::InvalidModule(VerifyError::Module(_))));
+    }
+
+    #[test]
+    fn test_serialize_unaligned() {
+        let wasm = TinyWasmModule { start_func: Some(0), ..Default::default() };
+        let mut twasm = AlignedVec::new();
+        twasm.push(0);
+        twasm.extend_from_slice(&wasm.serialize_twasm());
+
+        assert_eq!(TinyWasmModule::from_twasm_unaligned(&twasm[1..]).unwrap(), wasm);
+        let err = TinyWasmModule::from_twasm_unaligned_verified(&twasm[1..]).unwrap_err();
+        assert!(matches!(err, TwasmError::InvalidModule(VerifyError::Module(_))));
+    }
+
+    #[test]
+    fn test_serialize_portable() {
+        let wasm = TinyWasmModule { start_func: Some(0), ..Default::default() };
+        let twasm = wasm.serialize_twasm_portable();
+        assert_eq!(TinyWasmModule::from_twasm(&twasm).unwrap(), wasm);
+        assert!(matches!(TinyWasmModule::from_twasm_verified(&twasm), Err(TwasmError::InvalidModule(_))));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_serialize_compressed() {
+        let mut wasm = TinyWasmModule::default();
+        wasm.funcs = alloc::vec![crate::WasmFunction {
+            instructions: alloc::vec![crate::Instruction::Nop; 1000].into(),
+            locals: Default::default(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: Default::default(),
+        }]
+        .into();
+
+        let compressed = wasm.serialize_twasm_compressed();
+        assert!(compressed.len() < wasm.serialize_twasm().len() / 4);
+        assert_eq!(TinyWasmModule::from_twasm(&compressed).unwrap(), wasm);
+
+        let truncated = &compressed[..compressed.len() / 2];
+        assert!(matches!(TinyWasmModule::from_twasm(truncated), Err(TwasmError::InvalidArchive)));
+
+        // sizes the compressed data can't expand to are rejected
+        let mut oversized = compressed.clone();
+        oversized[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(TinyWasmModule::from_twasm(&oversized), Err(TwasmError::InvalidArchive)));
+        oversized[4..8].copy_from_slice(&(wasm.serialize_twasm().len() as u32 + 1).to_le_bytes());
+        assert!(matches!(TinyWasmModule::from_twasm(&oversized), Err(TwasmError::InvalidArchive)));
+
+        // level 0 only stores the archive
+        let stored = wasm.serialize_twasm_compressed_with(Compression::Deflate { level: 0 });
+        let small = wasm.serialize_twasm_compressed_with(Compression::Deflate { level: 10 });
+        assert!(small.len() < stored.len() / 4);
+        assert_eq!(TinyWasmModule::from_twasm(&stored).unwrap(), wasm);
+        assert_eq!(TinyWasmModule::from_twasm(&small).unwrap(), wasm);
+        assert!(matches!(TinyWasmModule::from_twasm(&small[..small.len() - 1]), Err(TwasmError::InvalidArchive)));
+    }
+
     #[cfg(feature = "unsafe")]
     #[test]
     fn test_serialize_unchecked() {
@@ -0,0 +1,789 @@
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::ops::Range;
+
+use super::{crc32, TwasmError};
+use crate::bytecode::{read_sleb, read_uleb, write_sleb, write_uleb, BytecodeError, Decoder};
+use crate::*;
+
+// Portable archives use a stable encoding instead of the in-memory layout of the types,
+// so they stay loadable across releases of `tinywasm-types`:
+// | magic (4) | format version (u16 LE) | CRC-32 of the payload (u32 LE) | sections |
+//
+// Every section is | id (u8) | length | contents |, and readers skip sections they don't know.
+// The items in a section are records prefixed with their length, so new fields can be
+// appended to them: readers ignore the rest of a record after the fields they know.
+// Instructions use the stable bytecode encoding, see `encode_bytecode`.
+//
+// * Version 1: integers are fixed-size little endian, strings and function types are stored inline
+// * Version 2: integers are LEB128 (signed for constants), strings are stored once in the string
+//   table and referenced by their index, function types are deduplicated, instruction offsets are
+//   delta encoded, and the function section starts with its bytecode version
+pub(super) const PORTABLE_MAGIC: &[u8; 4] = b"TWPM";
+const PORTABLE_VERSION: u16 = 2;
+const PORTABLE_HEADER_LEN: usize = 10;
+
+// section ids are never reused
+const SECTION_TYPES: u8 = 1;
+const SECTION_IMPORTS: u8 = 2;
+const SECTION_FUNCS: u8 = 3;
+const SECTION_TABLES: u8 = 4;
+const SECTION_MEMORIES: u8 = 5;
+const SECTION_GLOBALS: u8 = 6;
+const SECTION_EXPORTS: u8 = 7;
+const SECTION_START: u8 = 8;
+const SECTION_ELEMENTS: u8 = 9;
+const SECTION_DATA: u8 = 10;
+const SECTION_FUNC_NAMES: u8 = 11;
+const SECTION_TARGET_FEATURES: u8 = 12;
+const SECTION_STRINGS: u8 = 13;
+
+pub(super) fn serialize(module: &TinyWasmModule) -> Vec<u8> {
+    let mut strings = Vec::new();
+    strings.extend(module.imports.iter().flat_map(|import| [&*import.module, &*import.name]));
+    strings.extend(module.exports.iter().map(|export| &*export.name));
+    strings.extend(module.func_names.iter().map(|(_, name)| &**name));
+    strings.extend(module.target_features.iter().map(|feature| &**feature));
+    strings.sort_unstable();
+    strings.dedup();
+
+    let mut types: Vec<&FuncType> = Vec::new();
+    for ty in module.func_types.iter().chain(module.funcs.iter().map(|func| &func.ty)) {
+        if !types.contains(&ty) {
+            types.push(ty);
+        }
+    }
+
+    let mut w = Writer {
+        out: PORTABLE_MAGIC.to_vec(),
+        strings: strings.iter().enumerate().map(|(idx, s)| (*s, idx as u32)).collect(),
+        types: types.clone(),
+    };
+    w.out.extend_from_slice(&PORTABLE_VERSION.to_le_bytes());
+    w.out.extend_from_slice(&[0; 4]);
+
+    if !strings.is_empty() {
+        w.u8(SECTION_STRINGS);
+        w.framed(|w| w.list(&strings, |w, s| w.raw_str(s)));
+    }
+    if !types.is_empty() {
+        w.u8(SECTION_TYPES);
+        w.framed(|w| {
+            w.records(&types, |w, ty| w.func_type(ty));
+            w.list(&module.func_types, Writer::type_ref);
+        });
+    }
+    w.section(SECTION_IMPORTS, &module.imports, |w, import| {
+        w.str(&import.module);
+        w.str(&import.name);
+        match &import.kind {
+            ImportKind::Function(ty) => {
+                w.u8(0);
+                w.u32(*ty);
+            }
+            ImportKind::Table(ty) => {
+                w.u8(1);
+                w.table_type(ty);
+            }
+            ImportKind::Memory(ty) => {
+                w.u8(2);
+                w.memory_type(ty);
+            }
+            ImportKind::Global(ty) => {
+                w.u8(3);
+                w.global_type(ty);
+            }
+        }
+    });
+    if !module.funcs.is_empty() {
+        w.u8(SECTION_FUNCS);
+        w.framed(|w| {
+            w.u32(BYTECODE_VERSION as u32);
+            w.records(&module.funcs, |w, func| {
+                w.type_ref(&func.ty);
+                w.list(&func.locals, |w, ty| w.u8(ty.to_byte()));
+                w.u32(func.instructions.len() as u32);
+                func.instructions.iter().for_each(|instr| instr.encode(&mut w.out));
+
+                // offsets are mostly increasing, so only the difference to the previous one is stored
+                let mut prev = 0;
+                w.list(&func.offsets, |w, offset| {
+                    w.i64(*offset as i64 - prev);
+                    prev = *offset as i64;
+                });
+                w.list(&func.br_table_targets, |w, target| w.u32(*target));
+                w.list(&func.constants, |w, constant| w.u64(*constant));
+            });
+        });
+    }
+    w.section(SECTION_TABLES, &module.table_types, Writer::table_type);
+    w.section(SECTION_MEMORIES, &module.memory_types, Writer::memory_type);
+    w.section(SECTION_GLOBALS, &module.globals, |w, global| {
+        w.global_type(&global.ty);
+        w.const_instr(&global.init);
+    });
+    w.section(SECTION_EXPORTS, &module.exports, |w, export| {
+        w.str(&export.name);
+        w.u8(export.kind.clone() as u8);
+        w.u32(export.index);
+    });
+    if let Some(start) = module.start_func {
+        w.u8(SECTION_START);
+        w.framed(|w| w.u32(start));
+    }
+    w.section(SECTION_ELEMENTS, &module.elements, |w, element| {
+        match &element.kind {
+            ElementKind::Passive => w.u8(0),
+            ElementKind::Active { table, offset } => {
+                w.u8(1);
+                w.u32(*table);
+                w.const_instr(offset);
+            }
+            ElementKind::Declared => w.u8(2),
+        }
+        w.list(&element.items, |w, item| match item {
+            ElementItem::Func(func) => {
+                w.u8(0);
+                w.u32(*func);
+            }
+            ElementItem::Expr(expr) => {
+                w.u8(1);
+                w.const_instr(expr);
+            }
+        });
+        w.range(&element.range);
+        w.u8(element.ty.to_byte());
+    });
+    w.section(SECTION_DATA, &module.data, |w, data| {
+        match &data.kind {
+            DataKind::Active { mem, offset } => {
+                w.u8(0);
+                w.u32(*mem);
+                w.const_instr(offset);
+            }
+            DataKind::Passive => w.u8(1),
+        }
+        w.u32(data.data.len() as u32);
+        w.out.extend_from_slice(&data.data);
+        w.range(&data.range);
+    });
+    w.section(SECTION_FUNC_NAMES, &module.func_names, |w, (func, name)| {
+        w.u32(*func);
+        w.str(name);
+    });
+    w.section(SECTION_TARGET_FEATURES, &module.target_features, |w, feature| w.str(feature));
+
+    let mut out = w.out;
+    let checksum = crc32(&out[PORTABLE_HEADER_LEN..]);
+    out[6..PORTABLE_HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+pub(super) fn deserialize(bytes: &[u8]) -> Result<TinyWasmModule, TwasmError> {
+    if bytes.len() < PORTABLE_HEADER_LEN || !bytes.starts_with(PORTABLE_MAGIC) {
+        return Err(TwasmError::InvalidMagic);
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if !(1..=PORTABLE_VERSION).contains(&version) {
+        return Err(TwasmError::InvalidVersion);
+    }
+
+    let checksum = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    if checksum != crc32(&bytes[PORTABLE_HEADER_LEN..]) {
+        return Err(TwasmError::ChecksumMismatch);
+    }
+
+    let mut sections = Vec::new();
+    let mut reader = Reader::new(&bytes[PORTABLE_HEADER_LEN..], version);
+    while !reader.bytes.is_empty() {
+        let id = reader.u8()?;
+        sections.push((id, reader.framed()?.bytes));
+    }
+    let section = |id| sections.iter().rev().find(|(i, _)| *i == id).map(|(_, bytes)| Reader::new(bytes, version));
+
+    // the string table and the types are referenced by the other sections
+    let strings = match section(SECTION_STRINGS) {
+        Some(mut reader) => reader.list(Reader::raw_str)?,
+        None => Box::default(),
+    };
+
+    let mut module = TinyWasmModule::default();
+    let types = match section(SECTION_TYPES) {
+        Some(mut reader) => {
+            let types = reader.items(Reader::func_type)?;
+            module.func_types = match version {
+                1 => types.clone(),
+                _ => Reader { types: &types, ..reader }.list(Reader::type_ref)?,
+            };
+            types
+        }
+        None => Box::default(),
+    };
+
+    for (id, bytes) in &sections {
+        let mut section = Reader { strings: &strings, types: &types, ..Reader::new(bytes, version) };
+        match *id {
+            SECTION_IMPORTS => module.imports = section.items(Reader::import)?,
+            SECTION_FUNCS => {
+                if version > 1 {
+                    section.bytecode_version = u16::try_from(section.u32()?).map_err(|_| TwasmError::InvalidArchive)?;
+                }
+                module.funcs = section.items(Reader::func)?
+            }
+            SECTION_TABLES => module.table_types = section.items(Reader::table_type)?,
+            SECTION_MEMORIES => module.memory_types = section.items(Reader::memory_type)?,
+            SECTION_GLOBALS => {
+                module.globals = section.items(|r| Ok(Global { ty: r.global_type()?, init: r.const_instr()? }))?
+            }
+            SECTION_EXPORTS => module.exports = section.items(Reader::export)?,
+            SECTION_START => module.start_func = Some(section.u32()?),
+            SECTION_ELEMENTS => module.elements = section.items(Reader::element)?,
+            SECTION_DATA => module.data = section.items(Reader::data)?,
+            SECTION_FUNC_NAMES => module.func_names = section.items(|r| Ok((r.u32()?, r.str()?)))?,
+            SECTION_TARGET_FEATURES => module.target_features = section.items(Reader::str)?,
+            // read above, or added by a newer version
+            _ => {}
+        }
+    }
+
+    Ok(module)
+}
+
+// writes the current version
+struct Writer<'a> {
+    out: Vec<u8>,
+    strings: BTreeMap<&'a str, u32>,
+    types: Vec<&'a FuncType>,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) {
+        self.out.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        write_uleb(&mut self.out, value as u64);
+    }
+
+    fn u64(&mut self, value: u64) {
+        write_uleb(&mut self.out, value);
+    }
+
+    fn i64(&mut self, value: i64) {
+        write_sleb(&mut self.out, value);
+    }
+
+    fn raw_str(&mut self, value: &str) {
+        self.u32(value.len() as u32);
+        self.out.extend_from_slice(value.as_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        let idx = self.strings[value];
+        self.u32(idx);
+    }
+
+    fn list<T>(&mut self, items: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        self.u32(items.len() as u32);
+        items.iter().for_each(|item| write(self, item));
+    }
+
+    // write the contents prefixed with their length
+    fn framed(&mut self, write: impl FnOnce(&mut Self)) {
+        let outer = core::mem::take(&mut self.out);
+        write(self);
+        let contents = core::mem::replace(&mut self.out, outer);
+        self.u32(contents.len() as u32);
+        self.out.extend_from_slice(&contents);
+    }
+
+    fn records<T>(&mut self, items: &[T], mut write: impl FnMut(&mut Self, &T)) {
+        self.list(items, |w, item| w.framed(|w| write(w, item)));
+    }
+
+    // write a section with a record for each item, empty sections are left out
+    fn section<T>(&mut self, id: u8, items: &[T], write: impl FnMut(&mut Self, &T)) {
+        if items.is_empty() {
+            return;
+        }
+        self.u8(id);
+        self.framed(|w| w.records(items, write));
+    }
+
+    fn option<T: Copy>(&mut self, value: Option<T>, write: fn(&mut Self, T)) {
+        match value {
+            None => self.u8(0),
+            Some(value) => {
+                self.u8(1);
+                write(self, value);
+            }
+        }
+    }
+
+    fn range(&mut self, range: &Range<usize>) {
+        self.u64(range.start as u64);
+        self.u64(range.end as u64);
+    }
+
+    fn func_type(&mut self, ty: &FuncType) {
+        self.list(&ty.params, |w, ty| w.u8(ty.to_byte()));
+        self.list(&ty.results, |w, ty| w.u8(ty.to_byte()));
+    }
+
+    fn type_ref(&mut self, ty: &FuncType) {
+        let idx = self.types.iter().position(|t| *t == ty).expect("type is in the type table");
+        self.u32(idx as u32);
+    }
+
+    fn table_type(&mut self, ty: &TableType) {
+        self.u8(ty.element_type.to_byte());
+        self.u32(ty.size_initial);
+        self.option(ty.size_max, Self::u32);
+    }
+
+    fn memory_type(&mut self, ty: &MemoryType) {
+        self.u8(ty.arch as u8);
+        self.u64(ty.page_count_initial);
+        self.option(ty.page_count_max, Self::u64);
+    }
+
+    fn global_type(&mut self, ty: &GlobalType) {
+        self.u8(ty.mutable as u8);
+        self.u8(ty.ty.to_byte());
+    }
+
+    fn const_instr(&mut self, instr: &ConstInstruction) {
+        match instr {
+            ConstInstruction::I32Const(v) => {
+                self.u8(0);
+                self.i64(*v as i64);
+            }
+            ConstInstruction::I64Const(v) => {
+                self.u8(1);
+                self.i64(*v);
+            }
+            ConstInstruction::F32Const(v) => {
+                self.u8(2);
+                self.out.extend_from_slice(&v.to_le_bytes());
+            }
+            ConstInstruction::F64Const(v) => {
+                self.u8(3);
+                self.out.extend_from_slice(&v.to_le_bytes());
+            }
+            ConstInstruction::GlobalGet(global) => {
+                self.u8(4);
+                self.u32(*global);
+            }
+            ConstInstruction::RefNull(ty) => {
+                self.u8(5);
+                self.u8(ty.to_byte());
+            }
+            ConstInstruction::RefFunc(func) => {
+                self.u8(6);
+                self.u32(*func);
+            }
+        }
+    }
+}
+
+// reads any supported version
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    version: u16,
+    bytecode_version: u16,
+    strings: &'a [Box<str>],
+    types: &'a [FuncType],
+}
+
+type ReadResult<T> = Result<T, TwasmError>;
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8], version: u16) -> Self {
+        // version 1 doesn't store the bytecode version
+        Self { bytes, version, bytecode_version: 1, strings: &[], types: &[] }
+    }
+
+    fn bytes(&mut self, len: usize) -> ReadResult<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(TwasmError::InvalidArchive);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    fn fixed<const N: usize>(&mut self) -> ReadResult<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().expect("N bytes"))
+    }
+
+    fn u8(&mut self) -> ReadResult<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn uleb(&mut self, bits: u32) -> ReadResult<u64> {
+        read_uleb(&mut self.bytes, bits).ok().flatten().ok_or(TwasmError::InvalidArchive)
+    }
+
+    fn sleb(&mut self, bits: u32) -> ReadResult<i64> {
+        read_sleb(&mut self.bytes, bits).ok().flatten().ok_or(TwasmError::InvalidArchive)
+    }
+
+    fn u32(&mut self) -> ReadResult<u32> {
+        match self.version {
+            1 => Ok(u32::from_le_bytes(self.fixed()?)),
+            _ => Ok(self.uleb(32)? as u32),
+        }
+    }
+
+    fn u64(&mut self) -> ReadResult<u64> {
+        match self.version {
+            1 => Ok(u64::from_le_bytes(self.fixed()?)),
+            _ => self.uleb(64),
+        }
+    }
+
+    fn i32(&mut self) -> ReadResult<i32> {
+        match self.version {
+            1 => Ok(i32::from_le_bytes(self.fixed()?)),
+            _ => Ok(self.sleb(32)? as i32),
+        }
+    }
+
+    fn i64(&mut self) -> ReadResult<i64> {
+        match self.version {
+            1 => Ok(i64::from_le_bytes(self.fixed()?)),
+            _ => self.sleb(64),
+        }
+    }
+
+    fn raw_str(&mut self) -> ReadResult<Box<str>> {
+        let len = self.u32()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map(Into::into).map_err(|_| TwasmError::InvalidArchive)
+    }
+
+    fn str(&mut self) -> ReadResult<Box<str>> {
+        match self.version {
+            1 => self.raw_str(),
+            _ => self.strings.get(self.u32()? as usize).cloned().ok_or(TwasmError::InvalidArchive),
+        }
+    }
+
+    fn list<T>(&mut self, mut read: impl FnMut(&mut Self) -> ReadResult<T>) -> ReadResult<Box<[T]>> {
+        // every item is at least one byte, so this can't allocate more than the input
+        let count = self.u32()? as usize;
+        let mut items = Vec::with_capacity(count.min(self.bytes.len()));
+        for _ in 0..count {
+            items.push(read(self)?);
+        }
+        Ok(items.into_boxed_slice())
+    }
+
+    // the contents prefixed with their length
+    fn framed(&mut self) -> ReadResult<Reader<'a>> {
+        let len = self.u32()? as usize;
+        Ok(Reader { bytes: self.bytes(len)?, ..*self })
+    }
+
+    // a list of records, ignoring fields added by newer versions
+    fn items<T>(&mut self, read: impl Fn(&mut Self) -> ReadResult<T>) -> ReadResult<Box<[T]>> {
+        self.list(|r| read(&mut r.framed()?))
+    }
+
+    fn option<T>(&mut self, read: fn(&mut Self) -> ReadResult<T>) -> ReadResult<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(read(self)?)),
+            _ => Err(TwasmError::InvalidArchive),
+        }
+    }
+
+    fn range(&mut self) -> ReadResult<Range<usize>> {
+        Ok(self.u64()? as usize..self.u64()? as usize)
+    }
+
+    fn val_type(&mut self) -> ReadResult<ValType> {
+        ValType::from_byte(self.u8()?).ok_or(TwasmError::InvalidArchive)
+    }
+
+    fn func_type(&mut self) -> ReadResult<FuncType> {
+        Ok(FuncType { params: self.list(Self::val_type)?, results: self.list(Self::val_type)? })
+    }
+
+    // a function type, which is stored inline in version 1
+    fn type_ref(&mut self) -> ReadResult<FuncType> {
+        match self.version {
+            1 => self.func_type(),
+            _ => self.types.get(self.u32()? as usize).cloned().ok_or(TwasmError::InvalidArchive),
+        }
+    }
+
+    fn table_type(&mut self) -> ReadResult<TableType> {
+        Ok(TableType { element_type: self.val_type()?, size_initial: self.u32()?, size_max: self.option(Self::u32)? })
+    }
+
+    fn memory_type(&mut self) -> ReadResult<MemoryType> {
+        let arch = match self.u8()? {
+            0 => MemoryArch::I32,
+            1 => MemoryArch::I64,
+            _ => return Err(TwasmError::InvalidArchive),
+        };
+        Ok(MemoryType { arch, page_count_initial: self.u64()?, page_count_max: self.option(Self::u64)? })
+    }
+
+    fn global_type(&mut self) -> ReadResult<GlobalType> {
+        let mutable = match self.u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(TwasmError::InvalidArchive),
+        };
+        Ok(GlobalType { mutable, ty: self.val_type()? })
+    }
+
+    fn const_instr(&mut self) -> ReadResult<ConstInstruction> {
+        Ok(match self.u8()? {
+            0 => ConstInstruction::I32Const(self.i32()?),
+            1 => ConstInstruction::I64Const(self.i64()?),
+            2 => ConstInstruction::F32Const(f32::from_le_bytes(self.fixed()?)),
+            3 => ConstInstruction::F64Const(f64::from_le_bytes(self.fixed()?)),
+            4 => ConstInstruction::GlobalGet(self.u32()?),
+            5 => ConstInstruction::RefNull(self.val_type()?),
+            6 => ConstInstruction::RefFunc(self.u32()?),
+            _ => return Err(TwasmError::InvalidArchive),
+        })
+    }
+
+    fn import(&mut self) -> ReadResult<Import> {
+        let (module, name) = (self.str()?, self.str()?);
+        let kind = match self.u8()? {
+            0 => ImportKind::Function(self.u32()?),
+            1 => ImportKind::Table(self.table_type()?),
+            2 => ImportKind::Memory(self.memory_type()?),
+            3 => ImportKind::Global(self.global_type()?),
+            _ => return Err(TwasmError::InvalidArchive),
+        };
+        Ok(Import { module, name, kind })
+    }
+
+    fn func(&mut self) -> ReadResult<WasmFunction> {
+        let ty = self.type_ref()?;
+        let locals = self.list(Self::val_type)?;
+        let count = self.u32()? as usize;
+        let invalid = |_e: BytecodeError| {
+            crate::log::error!("Invalid archive: {}", _e);
+            TwasmError::InvalidArchive
+        };
+        let mut decoder = Decoder::new(self.bytecode_version);
+        let instructions = decoder.instructions(&mut self.bytes, count).map_err(invalid)?;
+
+        let offsets = match self.version {
+            1 => self.list(Self::u32)?,
+            _ => {
+                let mut prev = 0;
+                self.list(|r| {
+                    prev += r.i64()?;
+                    u32::try_from(prev).map_err(|_| TwasmError::InvalidArchive)
+                })?
+            }
+        };
+
+        // in bytecode version 1, the targets and constants were part of the instructions
+        let (targets, constants) = match self.bytecode_version {
+            1 => (Vec::new(), Vec::new()),
+            _ => (self.list(Self::u32)?.into_vec(), self.list(Self::u64)?.into_vec()),
+        };
+        let bytecode = decoder.finish(instructions, targets, constants).map_err(invalid)?;
+        Ok(WasmFunction {
+            instructions: bytecode.instructions.into(),
+            locals,
+            ty,
+            offsets,
+            br_table_targets: bytecode.br_table_targets.into(),
+            constants: bytecode.constants.into(),
+        })
+    }
+
+    fn export(&mut self) -> ReadResult<Export> {
+        let name = self.str()?;
+        let kind = match self.u8()? {
+            0 => ExternalKind::Func,
+            1 => ExternalKind::Table,
+            2 => ExternalKind::Memory,
+            3 => ExternalKind::Global,
+            _ => return Err(TwasmError::InvalidArchive),
+        };
+        Ok(Export { name, kind, index: self.u32()? })
+    }
+
+    fn element(&mut self) -> ReadResult<Element> {
+        let kind = match self.u8()? {
+            0 => ElementKind::Passive,
+            1 => ElementKind::Active { table: self.u32()?, offset: self.const_instr()? },
+            2 => ElementKind::Declared,
+            _ => return Err(TwasmError::InvalidArchive),
+        };
+        let items = self.list(|r| match r.u8()? {
+            0 => Ok(ElementItem::Func(r.u32()?)),
+            1 => Ok(ElementItem::Expr(r.const_instr()?)),
+            _ => Err(TwasmError::InvalidArchive),
+        })?;
+        Ok(Element { kind, items, range: self.range()?, ty: self.val_type()? })
+    }
+
+    fn data(&mut self) -> ReadResult<Data> {
+        let kind = match self.u8()? {
+            0 => DataKind::Active { mem: self.u32()?, offset: self.const_instr()? },
+            1 => DataKind::Passive,
+            _ => return Err(TwasmError::InvalidArchive),
+        };
+        let data = match self.version {
+            1 => self.list(Self::u8)?,
+            _ => {
+                let len = self.u32()? as usize;
+                self.bytes(len)?.into()
+            }
+        };
+        Ok(Data { data, range: self.range()?, kind })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn module() -> TinyWasmModule {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I64].into() });
+        let init_ty = builder.add_type(FuncType::default());
+        builder.add_import("env", "log", ImportKind::Function(ty));
+        builder.add_import("env", "memory", ImportKind::Memory(MemoryType::new_32(1, Some(2))));
+        let table = builder.add_table(TableType::new(ValType::RefFunc, 1, None));
+        let global =
+            builder.add_global(GlobalType { mutable: true, ty: ValType::F64 }, ConstInstruction::F64Const(1.5));
+        let instructions = [
+            Instruction::Block(BlockArgsPacked::new(BlockArgs::Type(ValType::I64)).unwrap(), 3),
+            Instruction::LocalGet(0),
+            Instruction::I64Load { offset: 0, mem_addr: 0 },
+            Instruction::EndBlockFrame,
+            Instruction::GlobalGet(global),
+            Instruction::Drop,
+            Instruction::EndFunc,
+        ];
+        let run = builder.add_function_with_constants(ty, [ValType::F32], instructions, [4]);
+        let init = builder.add_function(init_ty, [], [Instruction::EndFunc]);
+        let active = ElementKind::Active { table, offset: ConstInstruction::I32Const(0) };
+        builder.add_element(active, ValType::RefFunc, [ElementItem::Func(run)]);
+        builder.add_data(DataKind::Active { mem: 0, offset: ConstInstruction::I32Const(16) }, *b"tiny");
+        builder.add_export("run", ExternalKind::Func, run).start(init).func_names([(run, "run".into())]);
+
+        let mut module = builder.finish().expect("valid module");
+        module.target_features = vec!["bulk-memory".into()].into();
+        module
+    }
+
+    #[test]
+    fn test_portable() {
+        let module = module();
+        let bytes = serialize(&module);
+        assert_eq!(&bytes[..6], b"TWPM\x02\x00");
+        assert_eq!(deserialize(&bytes).unwrap(), module);
+
+        let mut corrupted = bytes.clone();
+        corrupted[PORTABLE_HEADER_LEN + 1] ^= 1;
+        assert!(matches!(deserialize(&corrupted), Err(TwasmError::ChecksumMismatch)));
+
+        let mut future = bytes.clone();
+        future[4] = 3;
+        assert!(matches!(deserialize(&future), Err(TwasmError::InvalidVersion)));
+    }
+
+    #[test]
+    fn test_portable_v1() {
+        // `run(i32) -> i32` with an `i64` local calls the imported `env.double`
+        #[rustfmt::skip]
+        let v1: &[u8] = &[
+            0x54, 0x57, 0x50, 0x4d, 0x01, 0x00, 0xdb, 0x44, 0xe9, 0xed, 0x01, 0x12, 0x00, 0x00, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x00,
+            0x7f, 0x02, 0x1e, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x16, 0x00, 0x00, 0x00, 0x03, 0x00,
+            0x00, 0x00, 0x65, 0x6e, 0x76, 0x06, 0x00, 0x00, 0x00, 0x64, 0x6f, 0x75, 0x62, 0x6c, 0x65, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x03, 0x2a, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x22, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x00, 0x7f, 0x01, 0x00, 0x00, 0x00, 0x7e,
+            0x03, 0x00, 0x00, 0x00, 0x18, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x00,
+            0x00, 0x00, 0x00, 0x07, 0x14, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00, 0x72, 0x75, 0x6e, 0x00, 0x01, 0x00, 0x00, 0x00,
+        ];
+
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: [ValType::I32].into(), results: [ValType::I32].into() });
+        builder.add_import("env", "double", ImportKind::Function(ty));
+        let instructions = [Instruction::LocalGet(0), Instruction::Call(0), Instruction::EndFunc];
+        let run = builder.add_function(ty, [ValType::I64], instructions);
+        builder.add_export("run", ExternalKind::Func, run);
+        let module = builder.finish().expect("valid module");
+
+        assert_eq!(deserialize(v1).unwrap(), module);
+        assert!(serialize(&module).len() <= v1.len() / 2);
+    }
+
+    #[test]
+    fn test_portable_dedup() {
+        let mut module = module();
+        module.func_types = [&module.func_types[..], &module.func_types[..]].concat().into();
+        module.funcs[0].offsets = vec![10, 12, 11, 300, 301, 302, 303].into();
+        let bytes = serialize(&module);
+        assert_eq!(deserialize(&bytes).unwrap(), module);
+
+        // strings and types are only stored once
+        assert_eq!(bytes.windows(3).filter(|w| w == b"env").count(), 1);
+        assert_eq!(bytes.windows(3).filter(|w| w == b"run").count(), 1);
+        let mut types = Reader::new(&bytes[PORTABLE_HEADER_LEN..], PORTABLE_VERSION);
+        while types.u8().unwrap() != SECTION_TYPES {
+            types.framed().unwrap();
+        }
+        assert_eq!(types.framed().unwrap().items(Reader::func_type).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_portable_br_table() {
+        let mut module = module();
+        module.funcs[0].br_table_targets = vec![1, 0, 0].into();
+        module.funcs[0].constants = vec![4, u64::MAX].into();
+        assert_eq!(deserialize(&serialize(&module)).unwrap(), module);
+    }
+
+    #[test]
+    fn test_portable_evolution() {
+        let module = module();
+        let bytes = serialize(&module);
+
+        // a newer version appends a field to every export and adds a section
+        let mut reader = Reader::new(&bytes[PORTABLE_HEADER_LEN..], PORTABLE_VERSION);
+        let mut w = Writer { out: bytes[..PORTABLE_HEADER_LEN].to_vec(), strings: BTreeMap::new(), types: Vec::new() };
+        while !reader.bytes.is_empty() {
+            let id = reader.u8().unwrap();
+            let mut section = reader.framed().unwrap();
+            w.u8(id);
+            if id != SECTION_EXPORTS {
+                w.framed(|w| w.out.extend_from_slice(section.bytes));
+                continue;
+            }
+
+            let exports = section.list(|r| Ok(r.framed()?.bytes)).unwrap();
+            w.framed(|w| {
+                w.records(&exports, |w, export| {
+                    w.out.extend_from_slice(export);
+                    w.raw_str("new field");
+                })
+            });
+        }
+        w.u8(0xf0);
+        w.framed(|w| w.raw_str("new section"));
+        let mut out = w.out;
+        let checksum = crc32(&out[PORTABLE_HEADER_LEN..]);
+        out[6..PORTABLE_HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+
+        assert_ne!(out, bytes);
+        assert_eq!(deserialize(&out).unwrap(), module);
+    }
+}
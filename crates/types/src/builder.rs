@@ -0,0 +1,266 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use crate::*;
+
+// a function's type index, locals and instructions
+type PendingFunc = (TypeAddr, Box<[ValType]>, Box<[Instruction]>, Box<[u64]>);
+
+/// A builder for constructing a [`TinyWasmModule`] in code
+///
+/// Items are added one by one and their index in the module is returned, so they
+/// can be referenced by instructions and exports. Imported items come before the items defined
+/// in the module, so imports have to be added before the first defined item of the same kind.
+/// The module is checked using [`TinyWasmModule::verify`] when calling [`ModuleBuilder::finish`].
+///
+/// ```
+/// use tinywasm_types::*;
+///
+/// let mut builder = ModuleBuilder::new();
+/// let ty = builder.add_type(FuncType { params: Box::new([ValType::I32; 2]), results: Box::new([ValType::I32]) });
+/// let add = builder.add_function(
+///     ty,
+///     [],
+///     [Instruction::LocalGet2(0, 1), Instruction::I32Add, Instruction::EndFunc],
+/// );
+/// builder.add_export("add", ExternalKind::Func, add);
+/// let module = builder.finish().unwrap();
+/// # assert_eq!(module.funcs.len(), 1);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ModuleBuilder {
+    module: TinyWasmModule,
+    types: Vec<FuncType>,
+    funcs: Vec<PendingFunc>,
+    imports: Vec<Import>,
+    exports: Vec<Export>,
+    globals: Vec<Global>,
+    tables: Vec<TableType>,
+    memories: Vec<MemoryType>,
+    data: Vec<Data>,
+    elements: Vec<Element>,
+    imported: [u32; 4],
+    late_import: bool,
+}
+
+impl ModuleBuilder {
+    /// Create a new, empty module builder
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function type, returning its type index
+    ///
+    /// Identical types are only added once.
+    pub fn add_type(&mut self, ty: FuncType) -> TypeAddr {
+        match self.types.iter().position(|t| *t == ty) {
+            Some(index) => index as TypeAddr,
+            None => {
+                self.types.push(ty);
+                self.types.len() as TypeAddr - 1
+            }
+        }
+    }
+
+    /// Add an import, returning its index in the index space of its kind
+    pub fn add_import(&mut self, module: &str, name: &str, kind: ImportKind) -> Addr {
+        let (space, defined) = match kind {
+            ImportKind::Function(_) => (0, self.funcs.len()),
+            ImportKind::Table(_) => (1, self.tables.len()),
+            ImportKind::Memory(_) => (2, self.memories.len()),
+            ImportKind::Global(_) => (3, self.globals.len()),
+        };
+
+        // defined items would change their index
+        self.late_import |= defined != 0;
+        self.imports.push(Import { module: module.into(), name: name.into(), kind });
+        self.imported[space] += 1;
+        self.imported[space] - 1
+    }
+
+    /// Add a function with the type `ty`, returning its function index
+    ///
+    /// `locals` are the types of the locals following the function's params. The instructions
+    /// have to end with [`Instruction::EndFunc`], and blocks need their end offsets set.
+    pub fn add_function(
+        &mut self,
+        ty: TypeAddr,
+        locals: impl Into<Box<[ValType]>>,
+        instructions: impl Into<Box<[Instruction]>>,
+    ) -> FuncAddr {
+        self.add_function_with_constants(ty, locals, instructions, [])
+    }
+
+    /// Like [`ModuleBuilder::add_function`], for instructions that refer to 64-bit constants
+    /// or memory offsets, see [`WasmFunction::constants`]
+    pub fn add_function_with_constants(
+        &mut self,
+        ty: TypeAddr,
+        locals: impl Into<Box<[ValType]>>,
+        instructions: impl Into<Box<[Instruction]>>,
+        constants: impl Into<Box<[u64]>>,
+    ) -> FuncAddr {
+        self.funcs.push((ty, locals.into(), instructions.into(), constants.into()));
+        self.imported[0] + self.funcs.len() as FuncAddr - 1
+    }
+
+    /// Add a table, returning its table index
+    pub fn add_table(&mut self, ty: TableType) -> TableAddr {
+        self.tables.push(ty);
+        self.imported[1] + self.tables.len() as TableAddr - 1
+    }
+
+    /// Add a memory, returning its memory index
+    pub fn add_memory(&mut self, ty: MemoryType) -> MemAddr {
+        self.memories.push(ty);
+        self.imported[2] + self.memories.len() as MemAddr - 1
+    }
+
+    /// Add a global, returning its global index
+    pub fn add_global(&mut self, ty: GlobalType, init: ConstInstruction) -> GlobalAddr {
+        self.globals.push(Global { ty, init });
+        self.imported[3] + self.globals.len() as GlobalAddr - 1
+    }
+
+    /// Add a data segment, returning its index
+    pub fn add_data(&mut self, kind: DataKind, data: impl Into<Box<[u8]>>) -> DataAddr {
+        let data = data.into();
+        self.data.push(Data { range: 0..data.len(), data, kind });
+        self.data.len() as DataAddr - 1
+    }
+
+    /// Add an element segment, returning its index
+    pub fn add_element(&mut self, kind: ElementKind, ty: ValType, items: impl Into<Box<[ElementItem]>>) -> ElemAddr {
+        let items = items.into();
+        self.elements.push(Element { kind, range: 0..items.len(), items, ty });
+        self.elements.len() as ElemAddr - 1
+    }
+
+    /// Export the item with the index `index` of the given kind
+    pub fn add_export(&mut self, name: &str, kind: ExternalKind, index: Addr) -> &mut Self {
+        self.exports.push(Export { name: name.into(), kind, index });
+        self
+    }
+
+    /// Set the start function
+    pub fn start(&mut self, func: FuncAddr) -> &mut Self {
+        self.module.start_func = Some(func);
+        self
+    }
+
+    /// Set the names of functions, by their function index
+    pub fn func_names(&mut self, names: impl IntoIterator<Item = (FuncAddr, Box<str>)>) -> &mut Self {
+        self.module.func_names = names.into_iter().collect();
+        self
+    }
+
+    /// Build the module and check it using [`TinyWasmModule::verify`]
+    pub fn finish(self) -> Result<TinyWasmModule, VerifyError> {
+        if self.late_import {
+            return Err(VerifyError::Module("imports have to be added before other items of the same kind"));
+        }
+
+        let funcs = self.funcs.into_iter().enumerate().map(|(i, (ty, locals, instructions, constants))| {
+            let ty = self.types.get(ty as usize).cloned();
+            let ty = ty.ok_or(VerifyError::Function { func: i, instr: 0, reason: "function type out of range" })?;
+            Ok(WasmFunction {
+                instructions,
+                locals,
+                ty,
+                offsets: Default::default(),
+                br_table_targets: Default::default(),
+                constants,
+            })
+        });
+
+        let module = TinyWasmModule {
+            funcs: funcs.collect::<Result<_, VerifyError>>()?,
+            func_types: self.types.into(),
+            imports: self.imports.into(),
+            exports: self.exports.into(),
+            globals: self.globals.into(),
+            table_types: self.tables.into(),
+            memory_types: self.memories.into(),
+            data: self.data.into(),
+            elements: self.elements.into(),
+            ..self.module
+        };
+
+        module.verify()?;
+        Ok(module)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_builder() {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: vec![ValType::I32].into(), results: vec![ValType::I32].into() });
+        assert_eq!(
+            builder.add_type(FuncType { params: vec![ValType::I32].into(), results: vec![ValType::I32].into() }),
+            ty
+        );
+
+        let log = builder.add_import("env", "log", ImportKind::Function(ty));
+        let mem = builder.add_memory(MemoryType::new_32(1, None));
+        let counter = builder.add_global(GlobalType { mutable: true, ty: ValType::I32 }, ConstInstruction::I32Const(0));
+        let func = builder.add_function(
+            ty,
+            [],
+            [
+                Instruction::LocalGet(0),
+                Instruction::Call(log),
+                Instruction::GlobalSet(counter),
+                Instruction::MemorySize(mem, 0),
+                Instruction::EndFunc,
+            ],
+        );
+        builder.add_data(DataKind::Active { mem, offset: ConstInstruction::I32Const(8) }, *b"hello");
+        builder.add_export("run", ExternalKind::Func, func).add_export("memory", ExternalKind::Memory, mem);
+
+        let module = builder.finish().unwrap();
+        assert_eq!((log, func), (0, 1));
+        assert_eq!(module.funcs[0].ty, module.func_types[0]);
+        assert_eq!(module.data[0].range, 0..5);
+        assert_eq!(module.exports.len(), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType { params: vec![ValType::F64].into(), results: vec![ValType::F64].into() });
+        let mem = builder.add_memory(MemoryType::new_32(1, Some(2)));
+        let func = builder.add_function_with_constants(
+            ty,
+            [ValType::I64],
+            [Instruction::LocalGet(0), Instruction::F64Const(0), Instruction::F64Mul, Instruction::EndFunc],
+            [0.5f64.to_bits()],
+        );
+        builder.add_data(DataKind::Active { mem, offset: ConstInstruction::I32Const(0) }, *b"tiny");
+        builder.add_export("half", ExternalKind::Func, func);
+        let module = builder.finish().unwrap();
+
+        let json = serde_json::to_string(&module).unwrap();
+        assert_eq!(serde_json::from_str::<TinyWasmModule>(&json).unwrap(), module);
+    }
+
+    #[test]
+    fn test_builder_invalid() {
+        let mut builder = ModuleBuilder::new();
+        let ty = builder.add_type(FuncType::default());
+        builder.add_function(ty, [], [Instruction::GlobalGet(0), Instruction::EndFunc]);
+        assert!(matches!(builder.finish(), Err(VerifyError::Function { func: 0, instr: 0, .. })));
+
+        let mut builder = ModuleBuilder::new();
+        let func = builder.add_function(0, [], [Instruction::EndFunc]);
+        builder.add_import("env", "f", ImportKind::Function(0));
+        builder.add_type(FuncType::default());
+        assert_eq!(func, 0);
+        assert!(matches!(builder.finish(), Err(VerifyError::Module(_))));
+    }
+}
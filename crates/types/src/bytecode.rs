@@ -0,0 +1,963 @@
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use crate::{BlockArgs, BlockArgsPacked, ConstAddr, Instruction, LabelAddr, ValType};
+
+/// The version of the bytecode encoding, see [`encode_bytecode`]
+///
+/// The encoding of existing instructions only changes together with the version, and bytecode of
+/// all previous versions can still be decoded. New instructions get new opcodes without changing
+/// the version, and opcodes are never reused.
+///
+/// * Version 1: integer immediates are fixed-size little endian, `br_table` is followed by a
+///   `br_label` instruction for each target, and 64-bit constants and memory offsets are immediates
+/// * Version 2: integer immediates are LEB128, like in the WebAssembly binary format, `br_table`
+///   targets and 64-bit constants and memory offsets are stored after the instructions, block types
+///   can be the number of params and results, and `if` only stores the offset of its `else` or end
+pub const BYTECODE_VERSION: u16 = 2;
+
+// in version 1, `br_table` was followed by a `br_label` instruction for each target
+const LEGACY_BR_TABLE: u8 = 0x12;
+const LEGACY_BR_LABEL: u8 = 0x00;
+const LEGACY_IF: u8 = 0x0c;
+const LEGACY_I32_STORE_LOCAL: u8 = 0xd1;
+
+// in version 1, these instructions had their 64-bit immediates and offsets inline, see `Decoder`
+fn is_legacy(op: u8, version: u16) -> bool {
+    version < 2
+        && matches!(
+            op,
+            0x01 | LEGACY_IF | LEGACY_BR_TABLE | 0x1d..=0x33 | 0x37 | 0x39 | LEGACY_I32_STORE_LOCAL | 0xd4 | 0xd5
+        )
+}
+
+/// Decoded bytecode, see [`decode_bytecode`]
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Bytecode {
+    pub instructions: Vec<Instruction>,
+    /// See [`crate::WasmFunction::br_table_targets`]
+    pub br_table_targets: Vec<LabelAddr>,
+    /// See [`crate::WasmFunction::constants`]
+    pub constants: Vec<u64>,
+}
+
+const BYTECODE_MAGIC: &[u8; 4] = b"TWBC";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BytecodeError {
+    InvalidMagic,
+    /// The bytecode was encoded with a version this release doesn't support
+    UnsupportedVersion(u16),
+    UnknownOpcode(u8),
+    /// An immediate of the instruction with the given opcode is invalid
+    InvalidImmediate(u8),
+    UnexpectedEnd,
+    TrailingData,
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BytecodeError::InvalidMagic => write!(f, "Invalid bytecode: invalid magic number"),
+            BytecodeError::UnsupportedVersion(v) => write!(f, "Invalid bytecode: unsupported version {}", v),
+            BytecodeError::UnknownOpcode(op) => write!(f, "Invalid bytecode: unknown opcode {:#04x}", op),
+            BytecodeError::InvalidImmediate(op) => {
+                write!(f, "Invalid bytecode: invalid immediate for opcode {:#04x}", op)
+            }
+            BytecodeError::UnexpectedEnd => write!(f, "Invalid bytecode: unexpected end"),
+            BytecodeError::TrailingData => write!(f, "Invalid bytecode: trailing data"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BytecodeError {}
+
+/// Encode a stream of instructions with their `br_table` targets and constants, e.g. of a [`crate::WasmFunction`]
+///
+/// Unlike archives, which depend on the exact version of `tinywasm-types`, this encoding is stable,
+/// so other tools can generate or analyze tinywasm bytecode:
+///
+/// ```text
+/// | magic `TWBC` (4) | version (u16 LE) | instruction count | instructions |
+/// | target count | br_table targets | constant count | constants |
+/// ```
+///
+/// Every instruction is its opcode (see [`Instruction::opcode`]) followed by its immediates in the order they
+/// are declared in. Integers are unsigned LEB128, except for the signed `i32` and `i64` constants,
+/// floats are fixed-size little endian, value types use their byte in the WebAssembly binary format,
+/// and `Option<ValType>` and `BlockArgs` start with a tag byte: `0` for none/empty, `1` followed by
+/// a value type, or (`BlockArgs` only) `2` followed by a type index, `3` followed by the number of
+/// params and results or `4` followed by the constant holding them.
+pub fn encode_bytecode(instructions: &[Instruction], br_table_targets: &[LabelAddr], constants: &[u64]) -> Vec<u8> {
+    let mut out = BYTECODE_MAGIC.to_vec();
+    out.extend_from_slice(&BYTECODE_VERSION.to_le_bytes());
+    (instructions.len() as u32).write(&mut out);
+    instructions.iter().for_each(|instr| instr.encode(&mut out));
+    (br_table_targets.len() as u32).write(&mut out);
+    br_table_targets.iter().for_each(|target| target.write(&mut out));
+    (constants.len() as u32).write(&mut out);
+    constants.iter().for_each(|constant| constant.write(&mut out));
+    out
+}
+
+/// Decode a stream of instructions with their `br_table` targets and constants encoded with
+/// [`encode_bytecode`] by this or an earlier version
+///
+/// The instructions are only decoded, not validated, see [`crate::TinyWasmModule::verify`].
+pub fn decode_bytecode(mut bytes: &[u8]) -> Result<Bytecode, BytecodeError> {
+    if !bytes.starts_with(BYTECODE_MAGIC) {
+        return Err(BytecodeError::InvalidMagic);
+    }
+    bytes = &bytes[BYTECODE_MAGIC.len()..];
+
+    let version = u16::from_le_bytes(take(&mut bytes)?);
+    if !(1..=BYTECODE_VERSION).contains(&version) {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+
+    let count = u32::read(&mut bytes, version)?.ok_or(BytecodeError::UnexpectedEnd)? as usize;
+    let mut decoder = Decoder::new(version);
+    let instructions = decoder.instructions(&mut bytes, count)?;
+    let (targets, constants) = match version {
+        1 => (Vec::new(), Vec::new()),
+        _ => (read_list(&mut bytes, version)?, read_list(&mut bytes, version)?),
+    };
+
+    if !bytes.is_empty() {
+        return Err(BytecodeError::TrailingData);
+    }
+    decoder.finish(instructions, targets, constants)
+}
+
+pub(crate) fn read_list<T: Immediate>(bytes: &mut &[u8], version: u16) -> Result<Vec<T>, BytecodeError> {
+    let count = u32::read(bytes, version)?.ok_or(BytecodeError::UnexpectedEnd)? as usize;
+    // every item is at least one byte, so this can't allocate more than the input
+    let mut items = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        items.push(T::read(bytes, version)?.ok_or(BytecodeError::UnexpectedEnd)?);
+    }
+    Ok(items)
+}
+
+// Decodes the instructions of a function and converts the ones of version 1:
+// * the targets of `br_table` instructions are moved from the `br_label` instructions after
+//   them to the targets, and the labels are replaced with `nop`s, so the relative offsets of
+//   the blocks stay the same
+// * 64-bit immediates and offsets are moved to the constants
+pub(crate) struct Decoder {
+    version: u16,
+    constants: Vec<u64>,
+    // the index, default label and the range of the labels in `br_labels` of each `br_table`
+    br_tables: Vec<(usize, u32, u32, u32)>,
+    // the labels of the `br_label` instructions
+    br_labels: Vec<LabelAddr>,
+}
+
+impl Decoder {
+    pub(crate) fn new(version: u16) -> Self {
+        Self { version, constants: Vec::new(), br_tables: Vec::new(), br_labels: Vec::new() }
+    }
+
+    // decode `count` instructions
+    pub(crate) fn instructions(&mut self, bytes: &mut &[u8], count: usize) -> Result<Vec<Instruction>, BytecodeError> {
+        // every instruction is at least one byte, so this can't allocate more than the input
+        let mut instructions = Vec::with_capacity(count.min(bytes.len()));
+        while instructions.len() < count {
+            match bytes.first() {
+                Some(&op) if is_legacy(op, self.version) => {
+                    *bytes = &bytes[1..];
+                    self.legacy(bytes, op, count, &mut instructions)?;
+                }
+                _ => instructions.push(Instruction::decode_versioned(bytes, self.version)?),
+            }
+        }
+        Ok(instructions)
+    }
+
+    fn legacy(
+        &mut self,
+        bytes: &mut &[u8],
+        op: u8,
+        count: usize,
+        instructions: &mut Vec<Instruction>,
+    ) -> Result<(), BytecodeError> {
+        let version = self.version;
+        let instr = match op {
+            LEGACY_IF => {
+                let args = read::<BlockArgsPacked>(bytes, version, op)?;
+                let (else_offset, end_offset) = (read::<u32>(bytes, version, op)?, read::<u32>(bytes, version, op)?);
+                Instruction::If(args, if else_offset != 0 { else_offset } else { end_offset })
+            }
+            LEGACY_BR_TABLE => {
+                let default = read::<u32>(bytes, version, op)?;
+                let len = read::<u32>(bytes, version, op)?;
+                if len as usize >= count - instructions.len() {
+                    return Err(BytecodeError::InvalidImmediate(op));
+                }
+
+                self.br_tables.push((instructions.len(), default, self.br_labels.len() as u32, len));
+                instructions.push(Instruction::BrTable(0));
+                for _ in 0..len {
+                    if take::<1>(bytes)?[0] != LEGACY_BR_LABEL {
+                        return Err(BytecodeError::InvalidImmediate(op));
+                    }
+                    self.br_labels.push(read::<u32>(bytes, version, LEGACY_BR_LABEL)?);
+                    instructions.push(Instruction::Nop);
+                }
+                return Ok(());
+            }
+            LEGACY_I32_STORE_LOCAL => {
+                let local = read::<u16>(bytes, version, op)?;
+                let offset = read::<u32>(bytes, version, op)?;
+                let Ok(mem_addr) = u8::try_from(read::<u32>(bytes, version, op)?) else {
+                    return Err(BytecodeError::InvalidImmediate(op));
+                };
+                Instruction::I32StoreLocal { local, offset, mem_addr }
+            }
+            0x1d..=0x33 => {
+                // re-encode the memory instruction with its offset in the constants
+                let offset = self.constant(read::<u64>(bytes, version, op)?);
+                let mem_addr = read::<u32>(bytes, version, op)?;
+                let mut current = Vec::from([op]);
+                offset.write(&mut current);
+                mem_addr.write(&mut current);
+                Instruction::decode(&mut &current[..])?
+            }
+            0x39 => Instruction::F64Const(self.constant(read::<f64>(bytes, version, op)?.to_bits())),
+            _ => {
+                let constant = self.constant(read::<i64>(bytes, version, op)? as u64);
+                match op {
+                    0x01 => Instruction::I64XorConstRotl(constant),
+                    0x37 => Instruction::I64Const(constant),
+                    0xd4 => Instruction::I64AddConst(constant),
+                    _ => Instruction::I64SubConst(constant),
+                }
+            }
+        };
+        instructions.push(instr);
+        Ok(())
+    }
+
+    fn constant(&mut self, value: u64) -> ConstAddr {
+        self.constants.push(value);
+        self.constants.len() as ConstAddr - 1
+    }
+
+    // combine the decoded instructions with the targets and constants read after them
+    pub(crate) fn finish(
+        self,
+        mut instructions: Vec<Instruction>,
+        targets: Vec<LabelAddr>,
+        constants: Vec<u64>,
+    ) -> Result<Bytecode, BytecodeError> {
+        if self.version >= 2 {
+            return Ok(Bytecode { instructions, br_table_targets: targets, constants });
+        }
+
+        let mut br_table_targets = Vec::with_capacity(self.br_labels.len() + self.br_tables.len() * 2);
+        for (idx, default, start, len) in self.br_tables {
+            let labels = self.br_labels.get(start as usize..start as usize + len as usize);
+            let labels = labels.ok_or(BytecodeError::InvalidImmediate(LEGACY_BR_TABLE))?;
+            instructions[idx] = Instruction::BrTable(br_table_targets.len() as u32);
+            br_table_targets.push(len);
+            br_table_targets.extend_from_slice(labels);
+            br_table_targets.push(default);
+        }
+        Ok(Bytecode { instructions, br_table_targets, constants: self.constants })
+    }
+}
+
+fn take<const N: usize>(bytes: &mut &[u8]) -> Result<[u8; N], BytecodeError> {
+    if bytes.len() < N {
+        return Err(BytecodeError::UnexpectedEnd);
+    }
+    let (head, rest) = bytes.split_at(N);
+    *bytes = rest;
+    Ok(head.try_into().expect("N bytes"))
+}
+
+pub(crate) fn write_uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return out.push(byte);
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn write_sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            return out.push(byte);
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// `None` if the value doesn't fit into `bits` bits
+pub(crate) fn read_uleb(bytes: &mut &[u8], bits: u32) -> Result<Option<u64>, BytecodeError> {
+    let (mut value, mut shift) = (0u64, 0);
+    loop {
+        let byte = take::<1>(bytes)?[0];
+        let part = (byte & 0x7f) as u64;
+        if shift >= 64 || (part << shift) >> shift != part {
+            return Ok(None);
+        }
+        value |= part << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok((bits == 64 || value >> bits == 0).then_some(value));
+        }
+    }
+}
+
+// `None` if the value doesn't fit into `bits` bits
+pub(crate) fn read_sleb(bytes: &mut &[u8], bits: u32) -> Result<Option<i64>, BytecodeError> {
+    let (mut value, mut shift) = (0i64, 0);
+    loop {
+        let byte = take::<1>(bytes)?[0];
+        if shift >= 64 {
+            return Ok(None);
+        }
+        value |= ((byte & 0x7f) as i64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                value |= -1 << shift;
+            }
+            let sign = value >> (bits - 1);
+            return Ok((sign == 0 || sign == -1).then_some(value));
+        }
+    }
+}
+
+// an immediate of an instruction, `None` if the bytes are invalid
+pub(crate) trait Immediate: Sized {
+    // always writes the current version
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(bytes: &mut &[u8], version: u16) -> Result<Option<Self>, BytecodeError>;
+}
+
+macro_rules! impl_immediate_int {
+    ($($t:ty => $write:ident($as:ty), $read:ident),*) => {
+        $(impl Immediate for $t {
+            fn write(&self, out: &mut Vec<u8>) {
+                $write(out, *self as $as);
+            }
+            fn read(bytes: &mut &[u8], version: u16) -> Result<Option<Self>, BytecodeError> {
+                match version {
+                    1 => Ok(Some(<$t>::from_le_bytes(take(bytes)?))),
+                    _ => Ok($read(bytes, <$t>::BITS)?.map(|v| v as $t)),
+                }
+            }
+        })*
+    };
+}
+impl_immediate_int!(
+    u32 => write_uleb(u64), read_uleb,
+    u64 => write_uleb(u64), read_uleb,
+    i32 => write_sleb(i64), read_sleb,
+    i64 => write_sleb(i64), read_sleb
+);
+
+// narrowed indices, which were `u32` in version 1
+impl Immediate for u16 {
+    fn write(&self, out: &mut Vec<u8>) {
+        write_uleb(out, *self as u64);
+    }
+    fn read(bytes: &mut &[u8], version: u16) -> Result<Option<Self>, BytecodeError> {
+        match version {
+            1 => Ok(u32::from_le_bytes(take(bytes)?).try_into().ok()),
+            _ => Ok(read_uleb(bytes, u16::BITS)?.map(|v| v as u16)),
+        }
+    }
+}
+
+macro_rules! impl_immediate_le {
+    ($($t:ty),*) => {
+        $(impl Immediate for $t {
+            fn write(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+            fn read(bytes: &mut &[u8], _version: u16) -> Result<Option<Self>, BytecodeError> {
+                Ok(Some(<$t>::from_le_bytes(take(bytes)?)))
+            }
+        })*
+    };
+}
+impl_immediate_le!(u8, f32, f64);
+
+impl Immediate for ValType {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.to_byte());
+    }
+    fn read(bytes: &mut &[u8], _version: u16) -> Result<Option<Self>, BytecodeError> {
+        Ok(ValType::from_byte(take::<1>(bytes)?[0]))
+    }
+}
+
+impl Immediate for Option<ValType> {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(ty) => {
+                out.push(1);
+                ty.write(out);
+            }
+        }
+    }
+    fn read(bytes: &mut &[u8], version: u16) -> Result<Option<Self>, BytecodeError> {
+        match take::<1>(bytes)?[0] {
+            0 => Ok(Some(None)),
+            1 => Ok(ValType::read(bytes, version)?.map(Some)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Immediate for BlockArgs {
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            BlockArgs::Empty => out.push(0),
+            BlockArgs::Type(ty) => {
+                out.push(1);
+                ty.write(out);
+            }
+            BlockArgs::FuncType(ty) => {
+                out.push(2);
+                ty.write(out);
+            }
+            BlockArgs::Arity { params, results } => {
+                out.push(3);
+                (*params as u16).write(out);
+                (*results as u16).write(out);
+            }
+            BlockArgs::ArityConst(c) => {
+                out.push(4);
+                c.write(out);
+            }
+        }
+    }
+    fn read(bytes: &mut &[u8], version: u16) -> Result<Option<Self>, BytecodeError> {
+        match take::<1>(bytes)?[0] {
+            0 => Ok(Some(BlockArgs::Empty)),
+            1 => Ok(ValType::read(bytes, version)?.map(BlockArgs::Type)),
+            2 => Ok(u32::read(bytes, version)?.map(BlockArgs::FuncType)),
+            3 if version >= 2 => {
+                let (Some(params), Some(results)) = (u16::read(bytes, version)?, u16::read(bytes, version)?) else {
+                    return Ok(None);
+                };
+                let (Ok(params), Ok(results)) = (u8::try_from(params), u8::try_from(results)) else {
+                    return Ok(None);
+                };
+                Ok(Some(BlockArgs::Arity { params, results }))
+            }
+            4 if version >= 2 => Ok(u32::read(bytes, version)?.map(BlockArgs::ArityConst)),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl Immediate for BlockArgsPacked {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.unpack().write(out);
+    }
+    fn read(bytes: &mut &[u8], version: u16) -> Result<Option<Self>, BytecodeError> {
+        Ok(BlockArgs::read(bytes, version)?.and_then(BlockArgsPacked::new))
+    }
+}
+
+// read an immediate, failing with the opcode of the instruction if it is invalid
+fn read<T: Immediate>(bytes: &mut &[u8], version: u16, op: u8) -> Result<T, BytecodeError> {
+    T::read(bytes, version)?.ok_or(BytecodeError::InvalidImmediate(op))
+}
+
+macro_rules! opcodes {
+    ($($op:literal => $name:ident $(($($arg:ident: $ty:ty),*))? $({ $($field:ident: $fty:ty),* })?,)*) => {
+        /// The opcodes of the instructions, named like their variants, see [`Instruction::opcode`]
+        #[allow(non_upper_case_globals)]
+        pub mod opcode {
+            $(#[doc = concat!("The opcode of [`Instruction::", stringify!($name), "`](crate::Instruction::", stringify!($name), ")")]
+            pub const $name: u8 = $op;)*
+
+            /// The name of the instruction with the given opcode, like its variant
+            pub fn name(op: u8) -> Option<&'static str> {
+                match op {
+                    $($op => Some(stringify!($name)),)*
+                    _ => None,
+                }
+            }
+        }
+
+        impl Instruction {
+            /// The opcode of the instruction in the bytecode encoding, see [`encode_bytecode`]
+            #[inline]
+            pub fn opcode(&self) -> u8 {
+                match self {
+                    $(Instruction::$name { .. } => $op,)*
+                }
+            }
+
+            /// Append the encoding of the instruction to `out`, see [`encode_bytecode`]
+            pub fn encode(&self, out: &mut Vec<u8>) {
+                match self {
+                    $(Instruction::$name $(($($arg),*))? $({ $($field),* })? => {
+                        out.push($op);
+                        $($($arg.write(out);)*)?
+                        $($($field.write(out);)*)?
+                    })*
+                }
+            }
+
+            /// Decode an instruction encoded with [`Instruction::encode`], advancing `bytes` past it
+            pub fn decode(bytes: &mut &[u8]) -> Result<Self, BytecodeError> {
+                Self::decode_versioned(bytes, BYTECODE_VERSION)
+            }
+
+            /// Like [`Instruction::decode`], for an instruction encoded by the given [`BYTECODE_VERSION`]
+            ///
+            /// In version 1, instructions with 64-bit immediates or offsets, `if` and `br_table`
+            /// refer to the other instructions or are stored differently, so they can only be decoded
+            /// as part of a stream with [`decode_bytecode`].
+            pub fn decode_versioned(bytes: &mut &[u8], version: u16) -> Result<Self, BytecodeError> {
+                if !(1..=BYTECODE_VERSION).contains(&version) {
+                    return Err(BytecodeError::UnsupportedVersion(version));
+                }
+
+                let op = take::<1>(bytes)?[0];
+                if is_legacy(op, version) {
+                    return Err(BytecodeError::UnknownOpcode(op));
+                }
+                Ok(match op {
+                    $($op => Instruction::$name
+                        $(($(read::<$ty>(bytes, version, op)?),*))?
+                        $({ $($field: read::<$fty>(bytes, version, op)?),* })?,)*
+                    _ => return Err(BytecodeError::UnknownOpcode(op)),
+                })
+            }
+        }
+    };
+}
+
+// The opcodes are part of the stable bytecode encoding: new instructions are added at the end
+// and removed instructions leave a gap, so existing opcodes never change.
+opcodes! {
+    // 0x00 was `br_label`, see `decode_instructions`
+    0x01 => I64XorConstRotl(a: u32),
+    0x02 => LocalTeeGet(a: u16, b: u16),
+    0x03 => LocalGet2(a: u16, b: u16),
+    0x04 => LocalGet3(a: u16, b: u16, c: u16),
+    0x05 => LocalGetSet(a: u16, b: u16),
+    0x06 => Unreachable,
+    0x07 => Nop,
+    0x08 => Yield,
+    0x09 => Probe,
+    0x0a => Block(a: BlockArgsPacked, b: u32),
+    0x0b => Loop(a: BlockArgsPacked, b: u32),
+    0x0c => If(a: BlockArgsPacked, b: u32),
+    0x0d => Else(a: u32),
+    0x0e => EndBlockFrame,
+    0x0f => EndFunc,
+    0x10 => Br(a: u32),
+    0x11 => BrIf(a: u32),
+    0x12 => BrTable(a: u32),
+    0x13 => Return,
+    0x14 => Call(a: u32),
+    0x15 => CallIndirect(a: u32, b: u16),
+    0x16 => Drop,
+    0x17 => Select(a: Option<ValType>),
+    0x18 => LocalGet(a: u32),
+    0x19 => LocalSet(a: u32),
+    0x1a => LocalTee(a: u32),
+    0x1b => GlobalGet(a: u32),
+    0x1c => GlobalSet(a: u32),
+    0x1d => I32Load { offset: u32, mem_addr: u16 },
+    0x1e => I64Load { offset: u32, mem_addr: u16 },
+    0x1f => F32Load { offset: u32, mem_addr: u16 },
+    0x20 => F64Load { offset: u32, mem_addr: u16 },
+    0x21 => I32Load8S { offset: u32, mem_addr: u16 },
+    0x22 => I32Load8U { offset: u32, mem_addr: u16 },
+    0x23 => I32Load16S { offset: u32, mem_addr: u16 },
+    0x24 => I32Load16U { offset: u32, mem_addr: u16 },
+    0x25 => I64Load8S { offset: u32, mem_addr: u16 },
+    0x26 => I64Load8U { offset: u32, mem_addr: u16 },
+    0x27 => I64Load16S { offset: u32, mem_addr: u16 },
+    0x28 => I64Load16U { offset: u32, mem_addr: u16 },
+    0x29 => I64Load32S { offset: u32, mem_addr: u16 },
+    0x2a => I64Load32U { offset: u32, mem_addr: u16 },
+    0x2b => I32Store { offset: u32, mem_addr: u16 },
+    0x2c => I64Store { offset: u32, mem_addr: u16 },
+    0x2d => F32Store { offset: u32, mem_addr: u16 },
+    0x2e => F64Store { offset: u32, mem_addr: u16 },
+    0x2f => I32Store8 { offset: u32, mem_addr: u16 },
+    0x30 => I32Store16 { offset: u32, mem_addr: u16 },
+    0x31 => I64Store8 { offset: u32, mem_addr: u16 },
+    0x32 => I64Store16 { offset: u32, mem_addr: u16 },
+    0x33 => I64Store32 { offset: u32, mem_addr: u16 },
+    0x34 => MemorySize(a: u32, b: u8),
+    0x35 => MemoryGrow(a: u32, b: u8),
+    0x36 => I32Const(a: i32),
+    0x37 => I64Const(a: u32),
+    0x38 => F32Const(a: f32),
+    0x39 => F64Const(a: u32),
+    0x3a => RefNull(a: ValType),
+    0x3b => RefFunc(a: u32),
+    0x3c => RefIsNull,
+    0x3d => I32Eqz,
+    0x3e => I32Eq,
+    0x3f => I32Ne,
+    0x40 => I32LtS,
+    0x41 => I32LtU,
+    0x42 => I32GtS,
+    0x43 => I32GtU,
+    0x44 => I32LeS,
+    0x45 => I32LeU,
+    0x46 => I32GeS,
+    0x47 => I32GeU,
+    0x48 => I64Eqz,
+    0x49 => I64Eq,
+    0x4a => I64Ne,
+    0x4b => I64LtS,
+    0x4c => I64LtU,
+    0x4d => I64GtS,
+    0x4e => I64GtU,
+    0x4f => I64LeS,
+    0x50 => I64LeU,
+    0x51 => I64GeS,
+    0x52 => I64GeU,
+    0x53 => F32Eq,
+    0x54 => F32Ne,
+    0x55 => F32Lt,
+    0x56 => F32Gt,
+    0x57 => F32Le,
+    0x58 => F32Ge,
+    0x59 => F64Eq,
+    0x5a => F64Ne,
+    0x5b => F64Lt,
+    0x5c => F64Gt,
+    0x5d => F64Le,
+    0x5e => F64Ge,
+    0x5f => I32Clz,
+    0x60 => I32Ctz,
+    0x61 => I32Popcnt,
+    0x62 => I32Add,
+    0x63 => I32Sub,
+    0x64 => I32Mul,
+    0x65 => I32DivS,
+    0x66 => I32DivU,
+    0x67 => I32RemS,
+    0x68 => I32RemU,
+    0x69 => I32And,
+    0x6a => I32Or,
+    0x6b => I32Xor,
+    0x6c => I32Shl,
+    0x6d => I32ShrS,
+    0x6e => I32ShrU,
+    0x6f => I32Rotl,
+    0x70 => I32Rotr,
+    0x71 => I64Clz,
+    0x72 => I64Ctz,
+    0x73 => I64Popcnt,
+    0x74 => I64Add,
+    0x75 => I64Sub,
+    0x76 => I64Mul,
+    0x77 => I64DivS,
+    0x78 => I64DivU,
+    0x79 => I64RemS,
+    0x7a => I64RemU,
+    0x7b => I64And,
+    0x7c => I64Or,
+    0x7d => I64Xor,
+    0x7e => I64Shl,
+    0x7f => I64ShrS,
+    0x80 => I64ShrU,
+    0x81 => I64Rotl,
+    0x82 => I64Rotr,
+    0x83 => F32Abs,
+    0x84 => F32Neg,
+    0x85 => F32Ceil,
+    0x86 => F32Floor,
+    0x87 => F32Trunc,
+    0x88 => F32Nearest,
+    0x89 => F32Sqrt,
+    0x8a => F32Add,
+    0x8b => F32Sub,
+    0x8c => F32Mul,
+    0x8d => F32Div,
+    0x8e => F32Min,
+    0x8f => F32Max,
+    0x90 => F32Copysign,
+    0x91 => F64Abs,
+    0x92 => F64Neg,
+    0x93 => F64Ceil,
+    0x94 => F64Floor,
+    0x95 => F64Trunc,
+    0x96 => F64Nearest,
+    0x97 => F64Sqrt,
+    0x98 => F64Add,
+    0x99 => F64Sub,
+    0x9a => F64Mul,
+    0x9b => F64Div,
+    0x9c => F64Min,
+    0x9d => F64Max,
+    0x9e => F64Copysign,
+    0x9f => I32WrapI64,
+    0xa0 => I32TruncF32S,
+    0xa1 => I32TruncF32U,
+    0xa2 => I32TruncF64S,
+    0xa3 => I32TruncF64U,
+    0xa4 => I32Extend8S,
+    0xa5 => I32Extend16S,
+    0xa6 => I64Extend8S,
+    0xa7 => I64Extend16S,
+    0xa8 => I64Extend32S,
+    0xa9 => I64ExtendI32S,
+    0xaa => I64ExtendI32U,
+    0xab => I64TruncF32S,
+    0xac => I64TruncF32U,
+    0xad => I64TruncF64S,
+    0xae => I64TruncF64U,
+    0xaf => F32ConvertI32S,
+    0xb0 => F32ConvertI32U,
+    0xb1 => F32ConvertI64S,
+    0xb2 => F32ConvertI64U,
+    0xb3 => F32DemoteF64,
+    0xb4 => F64ConvertI32S,
+    0xb5 => F64ConvertI32U,
+    0xb6 => F64ConvertI64S,
+    0xb7 => F64ConvertI64U,
+    0xb8 => F64PromoteF32,
+    0xb9 => I32ReinterpretF32,
+    0xba => I64ReinterpretF64,
+    0xbb => F32ReinterpretI32,
+    0xbc => F64ReinterpretI64,
+    0xbd => I32TruncSatF32S,
+    0xbe => I32TruncSatF32U,
+    0xbf => I32TruncSatF64S,
+    0xc0 => I32TruncSatF64U,
+    0xc1 => I64TruncSatF32S,
+    0xc2 => I64TruncSatF32U,
+    0xc3 => I64TruncSatF64S,
+    0xc4 => I64TruncSatF64U,
+    0xc5 => TableInit(a: u32, b: u16),
+    0xc6 => TableGet(a: u32),
+    0xc7 => TableSet(a: u32),
+    0xc8 => TableCopy { from: u16, to: u16 },
+    0xc9 => TableGrow(a: u32),
+    0xca => TableSize(a: u32),
+    0xcb => TableFill(a: u32),
+    0xcc => MemoryInit(a: u32, b: u16),
+    0xcd => MemoryCopy(a: u16, b: u16),
+    0xce => MemoryFill(a: u32),
+    0xcf => DataDrop(a: u32),
+    0xd0 => I32LocalGetConstAdd(a: u16, b: i32),
+    0xd1 => I32StoreLocal { local: u16, offset: u32, mem_addr: u8 },
+    0xd2 => I32AddConst(a: i32),
+    0xd3 => I32SubConst(a: i32),
+    0xd4 => I64AddConst(a: u32),
+    0xd5 => I64SubConst(a: u32),
+    0xd6 => I32EqConst(a: i32),
+    0xd7 => I32NeConst(a: i32),
+    0xd8 => I32LtSConst(a: i32),
+    0xd9 => I32LtUConst(a: i32),
+    0xda => I32GtSConst(a: i32),
+    0xdb => I32GtUConst(a: i32),
+    0xdc => I32EqzBrIf(a: u32),
+    0xdd => I32LoadStatic { addr: u32, mem_addr: u16 },
+    0xde => I64LoadStatic { addr: u32, mem_addr: u16 },
+    0xdf => F32LoadStatic { addr: u32, mem_addr: u16 },
+    0xe0 => F64LoadStatic { addr: u32, mem_addr: u16 },
+    0xe1 => I32StoreStatic { addr: u32, mem_addr: u16 },
+    0xe2 => I64StoreStatic { addr: u32, mem_addr: u16 },
+    0xe3 => F32StoreStatic { addr: u32, mem_addr: u16 },
+    0xe4 => F64StoreStatic { addr: u32, mem_addr: u16 },
+    0xe5 => I32AddLocals(a: u16, b: u16),
+    0xe6 => I32SubLocals(a: u16, b: u16),
+    0xe7 => I32LtSLocals(a: u16, b: u16),
+    0xe8 => I32LtULocals(a: u16, b: u16),
+    0xe9 => I32LtSLocalConst(a: u16, b: i32),
+    0xea => I32LtULocalConst(a: u16, b: i32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_encoding() {
+        // the encoding is stable, so it should never change
+        let instructions = vec![
+            Instruction::I32Const(-2),
+            Instruction::Block(BlockArgsPacked::new(BlockArgs::Type(ValType::I64)).unwrap(), 3),
+            Instruction::I32Load { offset: 0, mem_addr: 1 },
+            Instruction::Select(None),
+            Instruction::EndFunc,
+        ];
+        let bytecode = Bytecode { instructions: instructions.clone(), br_table_targets: vec![], constants: vec![200] };
+        let bytes = encode_bytecode(&instructions, &[], &[200]);
+        let expected: &[u8] = &[
+            b'T', b'W', b'B', b'C', 2, 0, 5, // header
+            0x36, 0x7e, // i32.const -2
+            0x0a, 1, 0x7e, 3, // block (result i64), end offset 3
+            0x1d, 0, 1, // i32.load offset=constants[0] memory 1
+            0x17, 0,    // select
+            0x0f, // end
+            0,    // br_table targets
+            1, 0xc8, 0x01, // constants
+        ];
+        assert_eq!(bytes, expected);
+        assert_eq!(decode_bytecode(&bytes), Ok(bytecode.clone()));
+
+        // version 1 is still supported
+        let v1: &[u8] = &[
+            b'T', b'W', b'B', b'C', 1, 0, 5, 0, 0, 0, // header
+            0x36, 0xfe, 0xff, 0xff, 0xff, // i32.const -2
+            0x0a, 1, 0x7e, 3, 0, 0, 0, // block (result i64), end offset 3
+            0x1d, 200, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, // i32.load offset=200 memory 1
+            0x17, 0,    // select
+            0x0f, // end
+        ];
+        assert_eq!(decode_bytecode(v1), Ok(bytecode));
+
+        assert_eq!(decode_bytecode(&bytes[..bytes.len() - 1]), Err(BytecodeError::UnexpectedEnd));
+        assert_eq!(decode_bytecode(&[&bytes[..], &[0x0f]].concat()), Err(BytecodeError::TrailingData));
+        let mut future = bytes.clone();
+        future[4] = 3;
+        assert_eq!(decode_bytecode(&future), Err(BytecodeError::UnsupportedVersion(3)));
+        assert_eq!(decode_bytecode(b"TWAS"), Err(BytecodeError::InvalidMagic));
+    }
+
+    #[test]
+    fn test_block_arity() {
+        let block = Instruction::Loop(BlockArgsPacked::new(BlockArgs::Arity { params: 2, results: 200 }).unwrap(), 1);
+        let mut bytes = Vec::new();
+        block.encode(&mut bytes);
+        assert_eq!(bytes, [0x0b, 3, 2, 0xc8, 0x01, 1]);
+        assert_eq!(Instruction::decode(&mut &bytes[..]), Ok(block));
+
+        // arities were added in version 2
+        assert_eq!(Instruction::decode_versioned(&mut &bytes[..], 1), Err(BytecodeError::InvalidImmediate(0x0b)));
+        let large = [0x0b, 3, 2, 0xac, 0x02, 1];
+        assert_eq!(Instruction::decode(&mut &large[..]), Err(BytecodeError::InvalidImmediate(0x0b)));
+
+        // larger arities are stored in a constant
+        let block = Instruction::Block(BlockArgsPacked::new(BlockArgs::ArityConst(300)).unwrap(), 1);
+        let mut bytes = Vec::new();
+        block.encode(&mut bytes);
+        assert_eq!(bytes, [0x0a, 4, 0xac, 0x02, 1]);
+        assert_eq!(Instruction::decode(&mut &bytes[..]), Ok(block));
+    }
+
+    #[test]
+    fn test_br_table() {
+        let instructions = vec![Instruction::BrTable(0), Instruction::EndFunc];
+        let bytecode = Bytecode { instructions, br_table_targets: vec![2, 0, 1, 2], constants: vec![] };
+        let bytes = encode_bytecode(&bytecode.instructions, &bytecode.br_table_targets, &[]);
+        assert_eq!(bytes[7..], [0x12, 0, 0x0f, 4, 2, 0, 1, 2, 0]);
+        assert_eq!(decode_bytecode(&bytes), Ok(bytecode.clone()));
+
+        // in version 1, the targets were `br_label` instructions
+        let v1: &[u8] = &[
+            b'T', b'W', b'B', b'C', 1, 0, 4, 0, 0, 0, // header
+            0x12, 2, 0, 0, 0, 2, 0, 0, 0, // br_table, default 2, 2 targets
+            0x00, 0, 0, 0, 0, 0x00, 1, 0, 0, 0,    // br_label 0, br_label 1
+            0x0f, // end
+        ];
+        let instructions = vec![Instruction::BrTable(0), Instruction::Nop, Instruction::Nop, Instruction::EndFunc];
+        let legacy = Bytecode { instructions, br_table_targets: vec![2, 0, 1, 2], constants: vec![] };
+        assert_eq!(decode_bytecode(v1), Ok(legacy));
+
+        let missing = [&v1[..6], &[2, 0, 0, 0], &v1[10..24]].concat();
+        assert_eq!(decode_bytecode(&missing), Err(BytecodeError::InvalidImmediate(LEGACY_BR_TABLE)));
+        assert_eq!(Instruction::decode_versioned(&mut &v1[10..], 1), Err(BytecodeError::UnknownOpcode(0x12)));
+    }
+
+    #[test]
+    fn test_constants() {
+        // in version 1, 64-bit constants were immediates, and `if` had an else and an end offset
+        let v1: &[u8] = &[
+            b'T', b'W', b'B', b'C', 1, 0, 6, 0, 0, 0, // header
+            0x37, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // i64.const -1
+            0x39, 0, 0, 0, 0, 0, 0, 0xe0, 0x3f, // f64.const 0.5
+            0x0c, 0, 0, 0, 0, 0, 2, 0, 0, 0, // if, without an else
+            0x0c, 0, 1, 0, 0, 0, 2, 0, 0, 0, // if, with an else
+            0xd1, 1, 0, 0, 0, 8, 0, 0, 0, 0x80, 0, 0, 0,    // i32.store_local memory 128
+            0x0f, // end
+        ];
+        let instructions = vec![
+            Instruction::I64Const(0),
+            Instruction::F64Const(1),
+            Instruction::If(BlockArgsPacked::EMPTY, 2),
+            Instruction::If(BlockArgsPacked::EMPTY, 1),
+            Instruction::I32StoreLocal { local: 1, offset: 8, mem_addr: 128 },
+            Instruction::EndFunc,
+        ];
+        let constants = vec![u64::MAX, 0.5f64.to_bits()];
+        let bytecode = Bytecode { instructions, br_table_targets: vec![], constants };
+        assert_eq!(decode_bytecode(v1), Ok(bytecode.clone()));
+        assert_eq!(Instruction::decode_versioned(&mut &v1[10..], 1), Err(BytecodeError::UnknownOpcode(0x37)));
+
+        let bytes = encode_bytecode(&bytecode.instructions, &[], &bytecode.constants);
+        assert_eq!(decode_bytecode(&bytes), Ok(bytecode));
+
+        let large_memory = [&v1[..57], &[0, 1, 0, 0, 0x0f]].concat();
+        assert_eq!(decode_bytecode(&large_memory), Err(BytecodeError::InvalidImmediate(LEGACY_I32_STORE_LOCAL)));
+    }
+
+    #[test]
+    fn test_leb128() {
+        for value in [0, 1, 63, 64, 127, 128, u32::MAX as u64, u64::MAX] {
+            let mut out = Vec::new();
+            write_uleb(&mut out, value);
+            assert_eq!(read_uleb(&mut &out[..], 64), Ok(Some(value)));
+            assert_eq!(read_uleb(&mut &out[..], 32), Ok((value <= u32::MAX as u64).then_some(value)));
+        }
+        for value in [0, 1, -1, 63, 64, -64, -65, i32::MIN as i64, i32::MAX as i64, i64::MIN, i64::MAX] {
+            let mut out = Vec::new();
+            write_sleb(&mut out, value);
+            assert_eq!(read_sleb(&mut &out[..], 64), Ok(Some(value)));
+            let fits = i32::try_from(value).is_ok();
+            assert_eq!(read_sleb(&mut &out[..], 32), Ok(fits.then_some(value)));
+        }
+        assert_eq!(read_uleb(&mut &[0x80, 0x80][..], 32), Err(BytecodeError::UnexpectedEnd));
+        assert_eq!(read_uleb(&mut &[0xff; 11][..], 64), Ok(None));
+    }
+
+    #[test]
+    fn test_opcodes() {
+        // every opcode decodes with immediates of zeros, or `0x7f` where a value type is expected
+        let mut count = 0;
+        for op in 0..=u8::MAX {
+            let decoded = [0, 0x7f].iter().map(|fill| {
+                let bytes = [&[op][..], &[*fill; 16]].concat();
+                let mut rest = &bytes[..];
+                Instruction::decode(&mut rest).map(|instr| (instr, bytes.len() - rest.len()))
+            });
+
+            match decoded.clone().find_map(Result::ok) {
+                Some((instr, len)) => {
+                    let mut out = Vec::new();
+                    instr.encode(&mut out);
+                    assert_eq!((instr.opcode(), out.len()), (op, len));
+                    assert!(format!("{:?}", instr).starts_with(opcode::name(op).expect("named opcode")));
+                    count += 1;
+                }
+                None => {
+                    assert!(decoded.into_iter().all(|e| e.err() == Some(BytecodeError::UnknownOpcode(op))));
+                    assert_eq!(opcode::name(op), None);
+                }
+            }
+        }
+        assert_eq!(count, 234);
+        assert_eq!((opcode::I32EqzBrIf, opcode::I32LtULocalConst, opcode::LocalGet2), (0xdc, 0xea, 0x03));
+
+        let invalid = [0x0a, 4];
+        assert_eq!(Instruction::decode(&mut &invalid[..]), Err(BytecodeError::InvalidImmediate(0x0a)));
+    }
+}
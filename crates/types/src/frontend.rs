@@ -0,0 +1,14 @@
+use crate::TinyWasmModule;
+
+/// A frontend that translates modules into [`TinyWasmModule`]s
+///
+/// The runtime only depends on this trait, so alternative frontends (e.g. a decoder for
+/// embedded targets, a WAT frontend or a precompiled archive loader) can be used in place of
+/// [`tinywasm_parser`](https://docs.rs/tinywasm_parser), see `Module::parse_with` in the `tinywasm` crate.
+pub trait ModuleFrontend {
+    /// The error returned if a module can't be translated
+    type Error;
+
+    /// Translate a module from its encoded form
+    fn parse_module_bytes(&self, bytes: &[u8]) -> Result<TinyWasmModule, Self::Error>;
+}
@@ -1,23 +1,51 @@
-use super::{FuncAddr, GlobalAddr, LabelAddr, LocalAddr, TableAddr, TypeAddr, ValType};
+use super::{ConstAddr, FuncAddr, GlobalAddr, LabelAddr, LocalAddr, TableAddr, TypeAddr, ValType};
 use crate::{DataAddr, ElemAddr, MemAddr};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BlockArgs {
     Empty,
     Type(ValType),
     FuncType(u32),
+    /// The number of params and results of a [`BlockArgs::FuncType`], resolved by the parser
+    /// so entering a block doesn't need to look up its type
+    Arity {
+        params: u8,
+        results: u8,
+    },
+    /// Like [`BlockArgs::Arity`], for blocks with more than 255 params or results.
+    /// The arity is stored in the function's constants, see [`BlockArgs::pack_arity`]
+    ArityConst(ConstAddr),
+}
+
+impl BlockArgs {
+    /// Pack the number of params and results of a block into the constant of a [`BlockArgs::ArityConst`]
+    pub fn pack_arity(params: u32, results: u32) -> u64 {
+        (params as u64) << 32 | results as u64
+    }
+
+    /// The number of params and results in the constant of a [`BlockArgs::ArityConst`]
+    pub fn unpack_arity(constant: u64) -> (u32, u32) {
+        ((constant >> 32) as u32, constant as u32)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 /// A packed representation of BlockArgs
 /// This is needed to keep the size of the Instruction enum small.
 /// Sadly, using #[repr(u8)] on BlockArgs itself is not possible because of the FuncType variant.
-pub struct BlockArgsPacked([u8; 5]); // Modifying this directly can cause runtime errors, but no UB
+pub struct BlockArgsPacked([u8; 3]); // Modifying this directly can cause runtime errors, but no UB
 impl BlockArgsPacked {
-    pub fn new(args: BlockArgs) -> Self {
-        let mut packed = [0; 5];
+    /// The packed [`BlockArgs::Empty`]
+    pub const EMPTY: Self = Self([0; 3]);
+
+    /// Pack the block type, or return `None` for a [`BlockArgs::FuncType`] or [`BlockArgs::ArityConst`]
+    /// with an index that doesn't fit in 16 bits
+    pub fn new(args: BlockArgs) -> Option<Self> {
+        let mut packed = [0; 3];
         match args {
             BlockArgs::Empty => packed[0] = 0,
             BlockArgs::Type(t) => {
@@ -26,17 +54,29 @@ impl BlockArgsPacked {
             }
             BlockArgs::FuncType(t) => {
                 packed[0] = 2;
-                packed[1..].copy_from_slice(&t.to_le_bytes());
+                packed[1..].copy_from_slice(&u16::try_from(t).ok()?.to_le_bytes());
+            }
+            BlockArgs::Arity { params, results } => packed = [3, params, results],
+            BlockArgs::ArityConst(c) => {
+                packed[0] = 4;
+                packed[1..].copy_from_slice(&u16::try_from(c).ok()?.to_le_bytes());
             }
         }
-        Self(packed)
+        Some(Self(packed))
     }
     pub fn unpack(&self) -> BlockArgs {
+        self.try_unpack().unwrap()
+    }
+
+    /// Like [`BlockArgsPacked::unpack`], but returns `None` instead of panicking on invalid bytes
+    pub fn try_unpack(&self) -> Option<BlockArgs> {
         match self.0[0] {
-            0 => BlockArgs::Empty,
-            1 => BlockArgs::Type(ValType::from_byte(self.0[1]).unwrap()),
-            2 => BlockArgs::FuncType(u32::from_le_bytes(self.0[1..].try_into().unwrap())),
-            _ => unreachable!(),
+            0 => Some(BlockArgs::Empty),
+            1 => ValType::from_byte(self.0[1]).map(BlockArgs::Type),
+            2 => Some(BlockArgs::FuncType(u16::from_le_bytes([self.0[1], self.0[2]]) as u32)),
+            3 => Some(BlockArgs::Arity { params: self.0[1], results: self.0[2] }),
+            4 => Some(BlockArgs::ArityConst(u16::from_le_bytes([self.0[1], self.0[2]]) as u32)),
+            _ => None,
         }
     }
 }
@@ -44,18 +84,25 @@ impl BlockArgsPacked {
 /// Represents a memory immediate in a WebAssembly memory instruction.
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemoryArg {
     pub offset: u64,
     pub mem_addr: MemAddr,
 }
 
-type BrTableDefault = u32;
-type BrTableLen = u32;
+type BrTableStart = u32;
 type EndOffset = u32;
 type ElseOffset = u32;
 
+// Indices in fused instructions and of memories and tables are smaller to keep instructions at 8 bytes.
+// The parser doesn't allow more than 50000 locals and 100 memories and tables, so they always fit.
+type SmallLocalAddr = u16;
+type SmallMemAddr = u16;
+type SmallTableAddr = u16;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConstInstruction {
     I32Const(i32),
     I64Const(i64),
@@ -72,7 +119,9 @@ pub enum ConstInstruction {
 /// Wasm Bytecode can map to multiple of these instructions.
 ///
 /// # Differences to the spec
-/// * `br_table` stores the jump lables in the following `br_label` instructions to keep this enum small.
+/// * `br_table` stores the jump lables in the `br_table_targets` of the function to keep this enum small.
+/// * 64-bit constants and memory offsets are stored in the `constants` of the function, the instructions
+///   only contain their index ([`ConstAddr`]).
 /// * Lables/Blocks: we store the label end offset in the instruction itself and
 ///   have seperate EndBlockFrame and EndFunc instructions to mark the end of a block or function.
 ///   This makes it easier to implement the label stack iteratively.
@@ -80,54 +129,85 @@ pub enum ConstInstruction {
 /// See <https://webassembly.github.io/spec/core/binary/instructions.html>
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
-// should be kept as small as possible (16 bytes max)
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+// should be kept as small as possible (8 bytes max, see the assertion below)
 pub enum Instruction {
     // Custom Instructions
-    BrLabel(LabelAddr),
-
-    // Not implemented yet
     // LocalGet + I32Const + I32Add
     // One of the most common patterns in the Rust compiler output
-    // I32LocalGetConstAdd(LocalAddr, i32),
+    I32LocalGetConstAdd(SmallLocalAddr, i32),
 
-    // Not implemented yet
-    // LocalGet + I32Const + I32Store => I32LocalGetConstStore + I32Const
+    // LocalGet + I32Const + I32Store => I32StoreLocal + I32Const
     // Also common, helps us skip the stack entirely.
-    // Has to be followed by an I32Const instruction
-    // I32StoreLocal { local: LocalAddr, offset: i32, mem_addr: MemAddr },
+    // Has to be followed by an I32Const instruction with the value to store
+    I32StoreLocal { local: SmallLocalAddr, offset: u32, mem_addr: u8 },
 
     // I64Xor + I64Const + I64RotL
     // Commonly used by a few crypto libraries
-    I64XorConstRotl(i64),
+    I64XorConstRotl(ConstAddr),
 
     // LocalTee + LocalGet
-    LocalTeeGet(LocalAddr, LocalAddr),
-    LocalGet2(LocalAddr, LocalAddr),
-    LocalGet3(LocalAddr, LocalAddr, LocalAddr),
-    LocalGetSet(LocalAddr, LocalAddr),
+    LocalTeeGet(SmallLocalAddr, SmallLocalAddr),
+    LocalGet2(SmallLocalAddr, SmallLocalAddr),
+    LocalGet3(SmallLocalAddr, SmallLocalAddr, SmallLocalAddr),
+    LocalGetSet(SmallLocalAddr, SmallLocalAddr),
+
+    // Arithmetic and comparisons with a constant second operand
+    I32AddConst(i32),
+    I32SubConst(i32),
+    I64AddConst(ConstAddr),
+    I64SubConst(ConstAddr),
+    I32EqConst(i32),
+    I32NeConst(i32),
+    I32LtSConst(i32),
+    I32LtUConst(i32),
+    I32GtSConst(i32),
+    I32GtUConst(i32),
+
+    // I32Eqz + BrIf
+    I32EqzBrIf(LabelAddr),
 
-    // Not implemented yet
-    // I32AddConst(i32),
-    // I32SubConst(i32),
-    // I64AddConst(i64),
-    // I64SubConst(i64),
+    // I32Const + Load/Store with an effective address that is in bounds of the memory's minimum size,
+    // so the runtime can skip the bounds check. Stores only take their value from the stack.
+    I32LoadStatic { addr: u32, mem_addr: SmallMemAddr },
+    I64LoadStatic { addr: u32, mem_addr: SmallMemAddr },
+    F32LoadStatic { addr: u32, mem_addr: SmallMemAddr },
+    F64LoadStatic { addr: u32, mem_addr: SmallMemAddr },
+    I32StoreStatic { addr: u32, mem_addr: SmallMemAddr },
+    I64StoreStatic { addr: u32, mem_addr: SmallMemAddr },
+    F32StoreStatic { addr: u32, mem_addr: SmallMemAddr },
+    F64StoreStatic { addr: u32, mem_addr: SmallMemAddr },
+
+    // Arithmetic and comparisons of two locals, or a local and a constant,
+    // which dominate the conditions of loops
+    I32AddLocals(SmallLocalAddr, SmallLocalAddr),
+    I32SubLocals(SmallLocalAddr, SmallLocalAddr),
+    I32LtSLocals(SmallLocalAddr, SmallLocalAddr),
+    I32LtULocals(SmallLocalAddr, SmallLocalAddr),
+    I32LtSLocalConst(SmallLocalAddr, i32),
+    I32LtULocalConst(SmallLocalAddr, i32),
 
     // Control Instructions
     // See <https://webassembly.github.io/spec/core/binary/instructions.html#control-instructions>
     Unreachable,
     Nop,
-    Block(BlockArgs, EndOffset),
-    Loop(BlockArgs, EndOffset),
-    If(BlockArgsPacked, ElseOffset, EndOffset), // If else offset is 0 if there is no else block
+    // Inserted by the parser at function entries and loop back-edges if yield points are enabled
+    Yield,
+    // Inserted by the parser at the start of every basic block if coverage is enabled
+    Probe,
+    Block(BlockArgsPacked, EndOffset),
+    Loop(BlockArgsPacked, EndOffset),
+    If(BlockArgsPacked, ElseOffset), // points to the `else`, or to the end of the block if there is none
     Else(EndOffset),
     EndBlockFrame,
     EndFunc,
     Br(LabelAddr),
     BrIf(LabelAddr),
-    BrTable(BrTableDefault, BrTableLen), // has to be followed by multiple BrLabel instructions
+    // `br_table_targets[start]` is the number of labels, followed by the labels and the default label
+    BrTable(BrTableStart),
     Return,
     Call(FuncAddr),
-    CallIndirect(TypeAddr, TableAddr),
+    CallIndirect(TypeAddr, SmallTableAddr),
 
     // Parametric Instructions
     // See <https://webassembly.github.io/spec/core/binary/instructions.html#parametric-instructions>
@@ -143,37 +223,37 @@ pub enum Instruction {
     GlobalSet(GlobalAddr),
 
     // Memory Instructions
-    I32Load { offset: u64, mem_addr: MemAddr },
-    I64Load { offset: u64, mem_addr: MemAddr },
-    F32Load { offset: u64, mem_addr: MemAddr },
-    F64Load { offset: u64, mem_addr: MemAddr },
-    I32Load8S { offset: u64, mem_addr: MemAddr },
-    I32Load8U { offset: u64, mem_addr: MemAddr },
-    I32Load16S { offset: u64, mem_addr: MemAddr },
-    I32Load16U { offset: u64, mem_addr: MemAddr },
-    I64Load8S { offset: u64, mem_addr: MemAddr },
-    I64Load8U { offset: u64, mem_addr: MemAddr },
-    I64Load16S { offset: u64, mem_addr: MemAddr },
-    I64Load16U { offset: u64, mem_addr: MemAddr },
-    I64Load32S { offset: u64, mem_addr: MemAddr },
-    I64Load32U { offset: u64, mem_addr: MemAddr },
-    I32Store { offset: u64, mem_addr: MemAddr },
-    I64Store { offset: u64, mem_addr: MemAddr },
-    F32Store { offset: u64, mem_addr: MemAddr },
-    F64Store { offset: u64, mem_addr: MemAddr },
-    I32Store8 { offset: u64, mem_addr: MemAddr },
-    I32Store16 { offset: u64, mem_addr: MemAddr },
-    I64Store8 { offset: u64, mem_addr: MemAddr },
-    I64Store16 { offset: u64, mem_addr: MemAddr },
-    I64Store32 { offset: u64, mem_addr: MemAddr },
+    I32Load { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load { offset: ConstAddr, mem_addr: SmallMemAddr },
+    F32Load { offset: ConstAddr, mem_addr: SmallMemAddr },
+    F64Load { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Load8S { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Load8U { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Load16S { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Load16U { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load8S { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load8U { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load16S { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load16U { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load32S { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Load32U { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Store { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Store { offset: ConstAddr, mem_addr: SmallMemAddr },
+    F32Store { offset: ConstAddr, mem_addr: SmallMemAddr },
+    F64Store { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Store8 { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I32Store16 { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Store8 { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Store16 { offset: ConstAddr, mem_addr: SmallMemAddr },
+    I64Store32 { offset: ConstAddr, mem_addr: SmallMemAddr },
     MemorySize(MemAddr, u8),
     MemoryGrow(MemAddr, u8),
 
     // Constants
     I32Const(i32),
-    I64Const(i64),
+    I64Const(ConstAddr),
     F32Const(f32),
-    F64Const(f64),
+    F64Const(ConstAddr),
 
     // Reference Types
     RefNull(ValType),
@@ -320,21 +400,23 @@ pub enum Instruction {
     I64TruncSatF64U,
 
     // Table Instructions
-    TableInit(TableAddr, ElemAddr),
+    TableInit(ElemAddr, SmallTableAddr),
     TableGet(TableAddr),
     TableSet(TableAddr),
-    TableCopy { from: TableAddr, to: TableAddr },
+    TableCopy { from: SmallTableAddr, to: SmallTableAddr },
     TableGrow(TableAddr),
     TableSize(TableAddr),
     TableFill(TableAddr),
 
     // Bulk Memory Instructions
-    MemoryInit(MemAddr, DataAddr),
-    MemoryCopy(MemAddr, MemAddr),
+    MemoryInit(DataAddr, SmallMemAddr),
+    MemoryCopy(SmallMemAddr, SmallMemAddr),
     MemoryFill(MemAddr),
     DataDrop(DataAddr),
 }
 
+const _: () = assert!(core::mem::size_of::<Instruction>() <= 8, "instructions are too large");
+
 #[cfg(test)]
 mod test_blockargs_packed {
     use super::*;
@@ -342,35 +424,35 @@ mod test_blockargs_packed {
     #[test]
     fn test_empty() {
         let args = BlockArgs::Empty;
-        let packed = BlockArgsPacked::new(args);
+        let packed = BlockArgsPacked::new(args).unwrap();
         assert_eq!(packed.unpack(), BlockArgs::Empty);
     }
 
     #[test]
     fn test_val_type_i32() {
         let args = BlockArgs::Type(ValType::I32);
-        let packed = BlockArgsPacked::new(args);
+        let packed = BlockArgsPacked::new(args).unwrap();
         assert_eq!(packed.unpack(), BlockArgs::Type(ValType::I32));
     }
 
     #[test]
     fn test_val_type_i64() {
         let args = BlockArgs::Type(ValType::I64);
-        let packed = BlockArgsPacked::new(args);
+        let packed = BlockArgsPacked::new(args).unwrap();
         assert_eq!(packed.unpack(), BlockArgs::Type(ValType::I64));
     }
 
     #[test]
     fn test_val_type_f32() {
         let args = BlockArgs::Type(ValType::F32);
-        let packed = BlockArgsPacked::new(args);
+        let packed = BlockArgsPacked::new(args).unwrap();
         assert_eq!(packed.unpack(), BlockArgs::Type(ValType::F32));
     }
 
     #[test]
     fn test_val_type_f64() {
         let args = BlockArgs::Type(ValType::F64);
-        let packed = BlockArgsPacked::new(args);
+        let packed = BlockArgsPacked::new(args).unwrap();
         assert_eq!(packed.unpack(), BlockArgs::Type(ValType::F64));
     }
 
@@ -378,7 +460,29 @@ mod test_blockargs_packed {
     fn test_func_type() {
         let func_type = 123; // Use an arbitrary u32 value
         let args = BlockArgs::FuncType(func_type);
-        let packed = BlockArgsPacked::new(args);
+        let packed = BlockArgsPacked::new(args).unwrap();
         assert_eq!(packed.unpack(), BlockArgs::FuncType(func_type));
     }
+
+    #[test]
+    fn test_arity() {
+        let args = BlockArgs::Arity { params: 2, results: 0x12 };
+        let packed = BlockArgsPacked::new(args).unwrap();
+        assert_eq!(packed.unpack(), args);
+        assert!(BlockArgsPacked::new(BlockArgs::FuncType(0x10000)).is_none());
+    }
+
+    #[test]
+    fn test_arity_const() {
+        let args = BlockArgs::ArityConst(0x1234);
+        let packed = BlockArgsPacked::new(args).unwrap();
+        assert_eq!(packed.unpack(), args);
+        assert!(BlockArgsPacked::new(BlockArgs::ArityConst(0x10000)).is_none());
+        assert_eq!(BlockArgs::unpack_arity(BlockArgs::pack_arity(1000, 3)), (1000, 3));
+    }
+
+    #[test]
+    fn test_instruction_size() {
+        assert_eq!(core::mem::size_of::<Instruction>(), 8);
+    }
 }
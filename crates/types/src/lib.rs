@@ -25,10 +25,18 @@ pub(crate) mod log {
     pub(crate) use error;
 }
 
+mod builder;
+mod bytecode;
+mod frontend;
 mod instructions;
 mod value;
+mod verify;
+pub use builder::ModuleBuilder;
+pub use bytecode::{decode_bytecode, encode_bytecode, opcode, Bytecode, BytecodeError, BYTECODE_VERSION};
+pub use frontend::ModuleFrontend;
 pub use instructions::*;
 pub use value::*;
+pub use verify::VerifyError;
 
 #[cfg(feature = "archive")]
 pub mod archive;
@@ -37,9 +45,11 @@ pub mod archive;
 ///
 /// This is the internal representation of a WebAssembly module in TinyWasm.
 /// TinyWasmModules are validated before being created, so they are guaranteed to be valid (as long as they were created by TinyWasm).
-/// This means you should not trust a TinyWasmModule created by a third party to be valid.
+/// This means you should not trust a TinyWasmModule created by a third party to be valid,
+/// unless it has been checked using [`TinyWasmModule::verify`].
 #[derive(Debug, Clone, Default, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TinyWasmModule {
     /// Optional address of the start function
     ///
@@ -90,6 +100,16 @@ pub struct TinyWasmModule {
     ///
     /// Corresponds to the `elem` section of the original WebAssembly module.
     pub elements: Box<[Element]>,
+
+    /// Names of functions, by their index in the module (including imported functions).
+    ///
+    /// Corresponds to the function names in the `name` custom section of the original WebAssembly module.
+    pub func_names: Box<[(FuncAddr, Box<str>)]>,
+
+    /// Features the module was compiled with.
+    ///
+    /// Corresponds to the features marked as used or required in the `target_features` custom section.
+    pub target_features: Box<[Box<str>]>,
 }
 
 /// A WebAssembly External Kind.
@@ -97,6 +117,7 @@ pub struct TinyWasmModule {
 /// See <https://webassembly.github.io/spec/core/syntax/types.html#external-types>
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ExternalKind {
     /// A WebAssembly Function.
     Func,
@@ -128,6 +149,7 @@ pub type ExternAddr = Addr;
 pub type TypeAddr = Addr;
 pub type LocalAddr = Addr;
 pub type LabelAddr = Addr;
+pub type ConstAddr = Addr;
 pub type ModuleInstanceAddr = Addr;
 
 /// A WebAssembly External Value.
@@ -166,8 +188,9 @@ impl ExternVal {
 /// The type of a WebAssembly Function.
 ///
 /// See <https://webassembly.github.io/spec/core/syntax/types.html#function-types>
-#[derive(Debug, Clone, PartialEq, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncType {
     pub params: Box<[ValType]>,
     pub results: Box<[ValType]>,
@@ -175,15 +198,26 @@ pub struct FuncType {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WasmFunction {
     pub instructions: Box<[Instruction]>,
     pub locals: Box<[ValType]>,
     pub ty: FuncType,
+    /// The byte offset of the original instruction in the code section, for each instruction
+    ///
+    /// Fused instructions have the offset of the first instruction they were translated from.
+    /// This is empty if the function was not parsed from a binary.
+    pub offsets: Box<[u32]>,
+    /// The labels of all `br_table` instructions, see [`Instruction::BrTable`]
+    pub br_table_targets: Box<[LabelAddr]>,
+    /// The 64-bit constants and memory offsets of the instructions, as raw bits
+    pub constants: Box<[u64]>,
 }
 
 /// A WebAssembly Module Export
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Export {
     /// The name of the export.
     pub name: Box<str>,
@@ -195,6 +229,7 @@ pub struct Export {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Global {
     pub ty: GlobalType,
     pub init: ConstInstruction,
@@ -202,6 +237,7 @@ pub struct Global {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GlobalType {
     pub mutable: bool,
     pub ty: ValType,
@@ -209,6 +245,7 @@ pub struct GlobalType {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TableType {
     pub element_type: ValType,
     pub size_initial: u32,
@@ -228,6 +265,7 @@ impl TableType {
 /// Represents a memory's type.
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MemoryType {
     pub arch: MemoryArch,
     pub page_count_initial: u64,
@@ -238,10 +276,19 @@ impl MemoryType {
     pub fn new_32(page_count_initial: u64, page_count_max: Option<u64>) -> Self {
         Self { arch: MemoryArch::I32, page_count_initial, page_count_max }
     }
+
+    /// The size of a page in bytes
+    pub const PAGE_SIZE: u64 = 65536;
+
+    /// The initial size of the memory in bytes, saturating at `u64::MAX`
+    pub fn initial_size(&self) -> u64 {
+        self.page_count_initial.saturating_mul(Self::PAGE_SIZE)
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MemoryArch {
     I32,
     I64,
@@ -249,6 +296,7 @@ pub enum MemoryArch {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Import {
     pub module: Box<str>,
     pub name: Box<str>,
@@ -257,6 +305,7 @@ pub struct Import {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ImportKind {
     Function(TypeAddr),
     Table(TableType),
@@ -278,6 +327,7 @@ impl From<&ImportKind> for ExternalKind {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Data {
     pub data: Box<[u8]>,
     pub range: Range<usize>,
@@ -286,6 +336,7 @@ pub struct Data {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataKind {
     Active { mem: MemAddr, offset: ConstInstruction },
     Passive,
@@ -293,6 +344,7 @@ pub enum DataKind {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Element {
     pub kind: ElementKind,
     pub items: Box<[ElementItem]>,
@@ -302,6 +354,7 @@ pub struct Element {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ElementKind {
     Passive,
     Active { table: TableAddr, offset: ConstInstruction },
@@ -310,6 +363,7 @@ pub enum ElementKind {
 
 #[derive(Debug, Clone, PartialEq)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ElementItem {
     Func(FuncAddr),
     Expr(ConstInstruction),
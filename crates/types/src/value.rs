@@ -117,8 +117,9 @@ impl WasmValue {
 }
 
 /// Type of a WebAssembly value.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(feature = "archive", derive(rkyv::Archive, rkyv::Serialize, rkyv::Deserialize), archive(check_bytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ValType {
     /// A 32-bit integer.
     I32,
@@ -142,7 +143,8 @@ impl ValType {
         WasmValue::default_for(*self)
     }
 
-    pub(crate) fn to_byte(self) -> u8 {
+    /// The byte encoding this type in the WebAssembly binary format
+    pub fn to_byte(self) -> u8 {
         match self {
             ValType::I32 => 0x7F,
             ValType::I64 => 0x7E,
@@ -153,7 +155,8 @@ impl ValType {
         }
     }
 
-    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+    /// Decode a type from its byte in the WebAssembly binary format
+    pub fn from_byte(byte: u8) -> Option<Self> {
         match byte {
             0x7F => Some(ValType::I32),
             0x7E => Some(ValType::I64),
@@ -0,0 +1,1005 @@
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+
+use crate::*;
+
+/// An error returned by [`TinyWasmModule::verify`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// A module level item (import, export, global, data or element segment) is invalid
+    Module(&'static str),
+
+    /// An instruction of a function is invalid
+    Function {
+        /// The index of the function in [`TinyWasmModule::funcs`]
+        func: usize,
+        /// The index of the instruction in the function
+        instr: usize,
+        /// A description of the problem
+        reason: &'static str,
+    },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        match self {
+            VerifyError::Module(reason) => write!(f, "invalid module: {}", reason),
+            VerifyError::Function { func, instr, reason } => {
+                write!(f, "invalid function {} at instruction {}: {}", func, instr, reason)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for VerifyError {}
+
+impl TinyWasmModule {
+    /// Check the structure of the module
+    ///
+    /// Modules created by [`tinywasm_parser`](https://docs.rs/tinywasm_parser) are always valid,
+    /// but modules deserialized from an untrusted source (e.g. a `.twasm` archive from a shared cache)
+    /// might not be. This re-validates everything the runtime relies on:
+    /// * all type, function, table, memory, global, local, data and element indices are in range
+    /// * block, loop, if and else instructions point to their matching ends
+    /// * branch targets exist and `br_table` is followed by its labels
+    /// * memory accesses with a constant address are in bounds of the memory's initial size
+    /// * every instruction has operands of the right types on the stack, and blocks and functions
+    ///   leave values of their result types on the stack
+    /// * constant expressions, globals and element segments have the types they are used as, and
+    ///   `call_indirect` only calls through tables of function references
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let ctx = Context::new(self)?;
+
+        for global in self.globals.iter() {
+            ctx.check_const(&global.init, global.ty.ty).map_err(VerifyError::Module)?;
+        }
+
+        for export in self.exports.iter() {
+            let count = match export.kind {
+                ExternalKind::Func => ctx.funcs.len(),
+                ExternalKind::Table => ctx.tables.len(),
+                ExternalKind::Memory => ctx.memories.len(),
+                ExternalKind::Global => ctx.globals.len(),
+            };
+            if export.index as usize >= count {
+                return Err(VerifyError::Module("export index out of range"));
+            }
+        }
+
+        if self.start_func.is_some_and(|f| f as usize >= ctx.funcs.len()) {
+            return Err(VerifyError::Module("start function out of range"));
+        }
+
+        for data in self.data.iter() {
+            if let DataKind::Active { mem, offset } = &data.kind {
+                let offset_ty = match ctx.memories.get(*mem as usize).map(|ty| ty.arch) {
+                    Some(MemoryArch::I32) => ValType::I32,
+                    Some(MemoryArch::I64) => ValType::I64,
+                    None => return Err(VerifyError::Module("data segment memory out of range")),
+                };
+                ctx.check_const(offset, offset_ty).map_err(VerifyError::Module)?;
+            }
+        }
+
+        for elem in self.elements.iter() {
+            if let ElementKind::Active { table, offset } = &elem.kind {
+                match ctx.tables.get(*table as usize) {
+                    None => return Err(VerifyError::Module("element segment table out of range")),
+                    Some(ty) if *ty != elem.ty => {
+                        return Err(VerifyError::Module("element segment type doesn't match the table"))
+                    }
+                    Some(_) => {}
+                }
+                ctx.check_const(offset, ValType::I32).map_err(VerifyError::Module)?;
+            }
+
+            for item in elem.items.iter() {
+                match item {
+                    ElementItem::Func(f) if *f as usize >= ctx.funcs.len() => {
+                        return Err(VerifyError::Module("element segment function out of range"))
+                    }
+                    ElementItem::Func(_) if elem.ty != ValType::RefFunc => {
+                        return Err(VerifyError::Module("element segment function in a segment of other references"))
+                    }
+                    ElementItem::Func(_) => {}
+                    ElementItem::Expr(expr) => ctx.check_const(expr, elem.ty).map_err(VerifyError::Module)?,
+                }
+            }
+        }
+
+        for (i, func) in self.funcs.iter().enumerate() {
+            if !func.offsets.is_empty() && func.offsets.len() != func.instructions.len() {
+                return Err(VerifyError::Function { func: i, instr: 0, reason: "offsets don't match instructions" });
+            }
+
+            FuncVerifier::new(&ctx, func).verify().map_err(|(instr, reason)| VerifyError::Function {
+                func: i,
+                instr,
+                reason,
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+// The types of values on the stack, `None` for values of any type in unreachable code
+// and for block params and results of which only the number is known yet
+type Types = Vec<Option<ValType>>;
+
+// The index spaces of a module, including imports
+struct Context<'a> {
+    module: &'a TinyWasmModule,
+    funcs: Vec<&'a FuncType>,
+    globals: Vec<GlobalType>,
+    // the element types of the tables
+    tables: Vec<ValType>,
+    memories: Vec<&'a MemoryType>,
+}
+
+impl<'a> Context<'a> {
+    fn new(module: &'a TinyWasmModule) -> Result<Self, VerifyError> {
+        let mut ctx = Self { module, funcs: Vec::new(), globals: Vec::new(), tables: Vec::new(), memories: Vec::new() };
+
+        for import in module.imports.iter() {
+            match &import.kind {
+                ImportKind::Function(ty) => {
+                    let ty = module.func_types.get(*ty as usize);
+                    ctx.funcs.push(ty.ok_or(VerifyError::Module("import type out of range"))?);
+                }
+                ImportKind::Global(ty) => ctx.globals.push(*ty),
+                ImportKind::Table(ty) => ctx.tables.push(ty.element_type),
+                ImportKind::Memory(ty) => ctx.memories.push(ty),
+            }
+        }
+
+        ctx.funcs.extend(module.funcs.iter().map(|f| &f.ty));
+        ctx.globals.extend(module.globals.iter().map(|g| g.ty));
+        ctx.tables.extend(module.table_types.iter().map(|t| t.element_type));
+        ctx.memories.extend(module.memory_types.iter());
+        Ok(ctx)
+    }
+
+    // check that a constant expression is in range and evaluates to a value of type `ty`
+    fn check_const(&self, instr: &ConstInstruction, ty: ValType) -> Result<(), &'static str> {
+        let actual = match instr {
+            ConstInstruction::I32Const(_) => ValType::I32,
+            ConstInstruction::I64Const(_) => ValType::I64,
+            ConstInstruction::F32Const(_) => ValType::F32,
+            ConstInstruction::F64Const(_) => ValType::F64,
+            ConstInstruction::GlobalGet(g) => self.globals.get(*g as usize).ok_or("global out of range")?.ty,
+            ConstInstruction::RefNull(ty) => *ty,
+            ConstInstruction::RefFunc(f) if *f as usize >= self.funcs.len() => return Err("function out of range"),
+            ConstInstruction::RefFunc(_) => ValType::RefFunc,
+        };
+        match actual == ty {
+            true => Ok(()),
+            false => Err("constant expression has the wrong type"),
+        }
+    }
+
+    // the types of the params and results of a block, `None` where only their number is known
+    fn block_types(&self, args: BlockArgs, constants: &[u64]) -> Result<(Types, Types), &'static str> {
+        match args {
+            BlockArgs::Empty => Ok((Vec::new(), Vec::new())),
+            BlockArgs::Type(ty) => Ok((Vec::new(), alloc::vec![Some(ty)])),
+            BlockArgs::FuncType(t) => match self.module.func_types.get(t as usize) {
+                Some(ty) => {
+                    Ok((ty.params.iter().copied().map(Some).collect(), ty.results.iter().copied().map(Some).collect()))
+                }
+                None => Err("block type out of range"),
+            },
+            BlockArgs::Arity { params, results } => {
+                Ok((alloc::vec![None; params as usize], alloc::vec![None; results as usize]))
+            }
+            BlockArgs::ArityConst(c) => {
+                let constant = constants.get(c as usize).ok_or("constant out of range")?;
+                let (params, results) = BlockArgs::unpack_arity(*constant);
+                // function types are limited to 1000 params and results
+                if params > 1000 || results > 1000 {
+                    return Err("block arity out of range");
+                }
+                Ok((alloc::vec![None; params as usize], alloc::vec![None; results as usize]))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FrameKind {
+    Func,
+    Block,
+    Loop,
+    If { else_ptr: Option<usize> },
+    Else,
+}
+
+struct Frame {
+    kind: FrameKind,
+    // the instruction pointer of the matching `EndBlockFrame` or `EndFunc`
+    end_ptr: usize,
+    // the stack height at the start of the frame, excluding its params
+    height: usize,
+    // blocks with only an arity get the types of the values they are entered or left with
+    params: Types,
+    results: Types,
+    unreachable: bool,
+}
+
+impl Frame {
+    fn label_types(&mut self) -> &mut Types {
+        match self.kind {
+            FrameKind::Loop => &mut self.params,
+            _ => &mut self.results,
+        }
+    }
+}
+
+struct FuncVerifier<'a> {
+    ctx: &'a Context<'a>,
+    func: &'a WasmFunction,
+    frames: Vec<Frame>,
+    values: Types,
+}
+
+type VerifyResult<T = ()> = Result<T, &'static str>;
+
+impl<'a> FuncVerifier<'a> {
+    fn new(ctx: &'a Context<'a>, func: &'a WasmFunction) -> Self {
+        let end_ptr = func.instructions.len().saturating_sub(1);
+        let results = func.ty.results.iter().copied().map(Some).collect();
+        let frame =
+            Frame { kind: FrameKind::Func, end_ptr, height: 0, params: Vec::new(), results, unreachable: false };
+        Self { ctx, func, frames: alloc::vec![frame], values: Vec::new() }
+    }
+
+    fn frame(&self) -> &Frame {
+        // the function frame is only popped by the last `EndFunc`
+        self.frames.last().expect("frame stack is empty")
+    }
+
+    fn push(&mut self, ty: impl Into<Option<ValType>>) {
+        self.values.push(ty.into());
+    }
+
+    // pop a value, which has to be of type `expected` if it is given, and return its type
+    fn pop(&mut self, expected: impl Into<Option<ValType>>) -> VerifyResult<Option<ValType>> {
+        let (expected, frame) = (expected.into(), self.frame());
+        if self.values.len() == frame.height {
+            // the stack is polymorphic after an unconditional branch
+            return match frame.unreachable {
+                true => Ok(expected),
+                false => Err("stack underflow"),
+            };
+        }
+
+        match (self.values.pop().expect("value above the frame"), expected) {
+            (Some(actual), Some(expected)) if actual != expected => Err("type mismatch"),
+            (actual, expected) => Ok(actual.or(expected)),
+        }
+    }
+
+    // pop the params and push the results of an instruction
+    fn op(&mut self, params: &[ValType], results: &[ValType]) -> VerifyResult {
+        for ty in params.iter().rev() {
+            self.pop(*ty)?;
+        }
+        results.iter().for_each(|ty| self.push(*ty));
+        Ok(())
+    }
+
+    // pop values of the given types, filling in the unknown ones with the types of the values
+    fn pop_types(&mut self, types: &mut [Option<ValType>]) -> VerifyResult {
+        for ty in types.iter_mut().rev() {
+            *ty = self.pop(*ty)?;
+        }
+        Ok(())
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().expect("frame stack is empty");
+        frame.unreachable = true;
+        self.values.truncate(frame.height);
+    }
+
+    fn label_types(&mut self, depth: LabelAddr) -> VerifyResult<&mut Types> {
+        let frame = self.frames.iter_mut().rev().nth(depth as usize).ok_or("branch depth out of range")?;
+        Ok(frame.label_types())
+    }
+
+    fn branch(&mut self, depth: LabelAddr) -> VerifyResult {
+        let mut types = core::mem::take(self.label_types(depth)?);
+        let res = self.pop_types(&mut types);
+        self.values.extend_from_slice(&types);
+        *self.label_types(depth)? = types;
+        res
+    }
+
+    fn local(&self, local: impl Into<LocalAddr>) -> VerifyResult<ValType> {
+        let (local, params) = (local.into() as usize, &self.func.ty.params);
+        match local.checked_sub(params.len()) {
+            None => Ok(params[local]),
+            Some(i) => self.func.locals.get(i).copied().ok_or("local out of range"),
+        }
+    }
+
+    // a local that is read by a fused instruction as an `i32`
+    fn i32_local(&self, local: impl Into<LocalAddr>) -> VerifyResult {
+        match self.local(local)? {
+            ValType::I32 => Ok(()),
+            _ => Err("type mismatch"),
+        }
+    }
+
+    fn global(&self, global: GlobalAddr) -> VerifyResult<GlobalType> {
+        self.ctx.globals.get(global as usize).copied().ok_or("global out of range")
+    }
+
+    fn func_type(&self, func: FuncAddr) -> VerifyResult<&'a FuncType> {
+        self.ctx.funcs.get(func as usize).copied().ok_or("function out of range")
+    }
+
+    // the element type of a table
+    fn table(&self, table: impl Into<TableAddr>) -> VerifyResult<ValType> {
+        self.ctx.tables.get(table.into() as usize).copied().ok_or("table out of range")
+    }
+
+    // the type of the addresses of a memory
+    fn memory(&self, mem: impl Into<MemAddr>) -> VerifyResult<ValType> {
+        match self.ctx.memories.get(mem.into() as usize).ok_or("memory out of range")?.arch {
+            MemoryArch::I32 => Ok(ValType::I32),
+            MemoryArch::I64 => Ok(ValType::I64),
+        }
+    }
+
+    // static accesses have to be in bounds of the memory's minimum size, which it never shrinks below
+    fn static_access(&self, mem: u16, addr: u32, len: u64) -> VerifyResult {
+        let ty = self.ctx.memories.get(mem as usize).ok_or("memory out of range")?;
+        match ty.arch == MemoryArch::I32 && addr as u64 + len <= ty.initial_size() {
+            true => Ok(()),
+            false => Err("static memory access out of bounds"),
+        }
+    }
+
+    fn static_load(&mut self, mem: u16, addr: u32, ty: ValType) -> VerifyResult {
+        self.static_access(mem, addr, value_size(ty))?;
+        self.push(ty);
+        Ok(())
+    }
+
+    fn static_store(&mut self, mem: u16, addr: u32, ty: ValType) -> VerifyResult {
+        self.static_access(mem, addr, value_size(ty))?;
+        self.pop(ty)?;
+        Ok(())
+    }
+
+    fn load(&mut self, offset: ConstAddr, mem: u16, ty: ValType) -> VerifyResult {
+        self.constant(offset)?;
+        self.pop(self.memory(mem)?)?;
+        self.push(ty);
+        Ok(())
+    }
+
+    fn store(&mut self, offset: ConstAddr, mem: u16, ty: ValType) -> VerifyResult {
+        self.constant(offset)?;
+        self.pop(ty)?;
+        self.pop(self.memory(mem)?)?;
+        Ok(())
+    }
+
+    fn data(&self, data: DataAddr) -> VerifyResult {
+        match (data as usize) < self.ctx.module.data.len() {
+            true => Ok(()),
+            false => Err("data segment out of range"),
+        }
+    }
+
+    fn constant(&self, constant: ConstAddr) -> VerifyResult {
+        match (constant as usize) < self.func.constants.len() {
+            true => Ok(()),
+            false => Err("constant out of range"),
+        }
+    }
+
+    // the type of the references in an element segment
+    fn elem(&self, elem: ElemAddr) -> VerifyResult<ValType> {
+        self.ctx.module.elements.get(elem as usize).map(|elem| elem.ty).ok_or("element segment out of range")
+    }
+
+    fn end_ptr(&self, ip: usize, offset: u32) -> VerifyResult<usize> {
+        match ip.checked_add(offset as usize) {
+            Some(end) if offset != 0 && end < self.func.instructions.len() => Ok(end),
+            _ => Err("block end offset out of range"),
+        }
+    }
+
+    fn enter(&mut self, kind: FrameKind, args: BlockArgsPacked, end_ptr: usize) -> VerifyResult {
+        let args = args.try_unpack().ok_or("invalid block type")?;
+        let (mut params, results) = self.ctx.block_types(args, &self.func.constants)?;
+        self.pop_types(&mut params)?;
+        let height = self.values.len();
+        self.values.extend_from_slice(&params);
+        self.frames.push(Frame { kind, end_ptr, height, params, results, unreachable: false });
+        Ok(())
+    }
+
+    // pop the results of the current frame, which has to leave exactly its results on the stack
+    fn end_frame(&mut self) -> VerifyResult<Types> {
+        let frame = self.frame();
+        let values = self.values.len() - frame.height;
+        if values != frame.results.len() && !(frame.unreachable && values <= frame.results.len()) {
+            return Err("wrong number of values on the stack at the end of a block");
+        }
+
+        let mut results = core::mem::take(&mut self.frames.last_mut().expect("frame stack is empty").results);
+        self.pop_types(&mut results)?;
+        Ok(results)
+    }
+
+    fn verify(mut self) -> Result<(), (usize, &'static str)> {
+        let mut ip = 0;
+        while !self.frames.is_empty() {
+            let Some(instr) = self.func.instructions.get(ip) else {
+                return Err((ip, "function doesn't end with `end`"));
+            };
+            ip = self.verify_instr(ip, instr).map_err(|reason| (ip, reason))?;
+        }
+
+        match ip == self.func.instructions.len() {
+            true => Ok(()),
+            false => Err((ip, "instructions after the end of the function")),
+        }
+    }
+
+    // returns the instruction pointer of the next instruction
+    fn verify_instr(&mut self, ip: usize, instr: &Instruction) -> VerifyResult<usize> {
+        use Instruction::*;
+        use ValType::{F32, F64, I32, I64};
+
+        match instr {
+            I64XorConstRotl(constant) => {
+                self.constant(*constant)?;
+                self.op(&[I64, I64], &[I64])?;
+            }
+            I32LocalGetConstAdd(local, _) => {
+                self.i32_local(*local)?;
+                self.push(I32);
+            }
+            I64AddConst(constant) | I64SubConst(constant) => {
+                self.constant(*constant)?;
+                self.op(&[I64], &[I64])?;
+            }
+            I32AddConst(_) | I32SubConst(_) | I32EqConst(_) | I32NeConst(_) | I32LtSConst(_) | I32LtUConst(_)
+            | I32GtSConst(_) | I32GtUConst(_) => self.op(&[I32], &[I32])?,
+            I32EqzBrIf(depth) => {
+                self.pop(I32)?;
+                self.branch(*depth)?;
+            }
+            I32StoreLocal { local, mem_addr, .. } => {
+                self.i32_local(*local)?;
+                self.memory(*mem_addr)?;
+                let Some(I32Const(_)) = self.func.instructions.get(ip + 1) else {
+                    return Err("`i32.store_local` is missing its value");
+                };
+                return Ok(ip + 2);
+            }
+            LocalTeeGet(a, b) => {
+                let ty = self.local(*a)?;
+                self.pop(ty)?;
+                self.push(ty);
+                self.push(self.local(*b)?);
+            }
+            LocalGet2(a, b) => {
+                self.push(self.local(*a)?);
+                self.push(self.local(*b)?);
+            }
+            LocalGet3(a, b, c) => {
+                self.push(self.local(*a)?);
+                self.push(self.local(*b)?);
+                self.push(self.local(*c)?);
+            }
+            LocalGetSet(a, b) => {
+                self.push(self.local(*a)?);
+                self.pop(self.local(*b)?)?;
+            }
+            I32AddLocals(a, b) | I32SubLocals(a, b) | I32LtSLocals(a, b) | I32LtULocals(a, b) => {
+                self.i32_local(*a)?;
+                self.i32_local(*b)?;
+                self.push(I32);
+            }
+            I32LtSLocalConst(local, _) | I32LtULocalConst(local, _) => {
+                self.i32_local(*local)?;
+                self.push(I32);
+            }
+
+            Unreachable => self.set_unreachable(),
+            Nop | Yield | Probe => {}
+            Block(args, end) => {
+                let end_ptr = self.end_ptr(ip, *end)?;
+                self.enter(FrameKind::Block, *args, end_ptr)?;
+            }
+            Loop(args, end) => {
+                let end_ptr = self.end_ptr(ip, *end)?;
+                self.enter(FrameKind::Loop, *args, end_ptr)?;
+            }
+            If(args, offset) => {
+                // the offset points to the `else` if there is one, which points to the end
+                let ptr = self.end_ptr(ip, *offset)?;
+                let (else_ptr, end_ptr) = match self.func.instructions[ptr] {
+                    Else(end) => (Some(ptr), self.end_ptr(ptr, end)?),
+                    _ => (None, ptr),
+                };
+                self.pop(I32)?;
+                self.enter(FrameKind::If { else_ptr }, *args, end_ptr)?;
+            }
+            Else(end) => {
+                let FrameKind::If { else_ptr } = self.frame().kind else {
+                    return Err("`else` outside of an `if` block");
+                };
+                if else_ptr != Some(ip) {
+                    return Err("`if` else offset doesn't point to its `else`");
+                }
+                if self.end_ptr(ip, *end)? != self.frame().end_ptr {
+                    return Err("`else` end offset doesn't match its `if`");
+                }
+
+                let results = self.end_frame()?;
+                let frame = self.frames.last_mut().expect("frame stack is empty");
+                (frame.kind, frame.results, frame.unreachable) = (FrameKind::Else, results, false);
+                self.values.extend_from_slice(&frame.params);
+            }
+            EndBlockFrame => {
+                let frame = self.frame();
+                match frame.kind {
+                    FrameKind::Func => return Err("`end` of a block outside of a block"),
+                    FrameKind::If { else_ptr: Some(_) } => return Err("`if` block ended before its `else`"),
+                    FrameKind::If { else_ptr: None } if frame.params.len() != frame.results.len() => {
+                        return Err("`if` without `else` must have matching params and results")
+                    }
+                    _ if frame.end_ptr != ip => return Err("block end offset doesn't point to its `end`"),
+                    _ => {}
+                }
+
+                let results = self.end_frame()?;
+                let frame = self.frames.pop().expect("frame stack is empty");
+                if let FrameKind::If { else_ptr: None } = frame.kind {
+                    // without an `else`, the params are passed through as the results
+                    let mut params = frame.params;
+                    for (param, result) in params.iter_mut().zip(&results) {
+                        match (*param, *result) {
+                            (Some(a), Some(b)) if a != b => return Err("type mismatch"),
+                            _ => *param = param.or(*result),
+                        }
+                    }
+                    self.values.extend_from_slice(&params);
+                } else {
+                    self.values.extend_from_slice(&results);
+                }
+            }
+            EndFunc => {
+                if self.frame().kind != FrameKind::Func {
+                    return Err("function ended inside of a block");
+                }
+                self.end_frame()?;
+                self.frames.pop();
+            }
+            Br(depth) => {
+                self.branch(*depth)?;
+                self.set_unreachable();
+            }
+            BrIf(depth) => {
+                self.pop(I32)?;
+                self.branch(*depth)?;
+            }
+            BrTable(start) => {
+                self.pop(I32)?;
+                // the number of labels, the labels and the default label
+                let (start, targets) = (*start as usize, &self.func.br_table_targets);
+                let labels = targets.get(start).and_then(|len| {
+                    let end = start.checked_add(*len as usize)?.checked_add(1)?;
+                    targets.get(start + 1..=end)
+                });
+                let labels = labels.ok_or("`br_table` targets out of range")?;
+                let (default, labels) = labels.split_last().expect("the default label");
+                let arity = self.label_types(*default)?.len();
+                for depth in labels {
+                    if self.label_types(*depth)?.len() != arity {
+                        return Err("`br_table` labels have different arities");
+                    }
+                    // every label has to accept the values on the stack
+                    self.branch(*depth)?;
+                }
+
+                self.branch(*default)?;
+                self.set_unreachable();
+            }
+            Return => {
+                self.branch(self.frames.len() as u32 - 1)?;
+                self.set_unreachable();
+            }
+            Call(func) => {
+                let ty = self.func_type(*func)?;
+                self.op(&ty.params, &ty.results)?;
+            }
+            CallIndirect(ty, table) => {
+                if self.table(*table)? != ValType::RefFunc {
+                    return Err("`call_indirect` on a table without function references");
+                }
+                let ty = self.ctx.module.func_types.get(*ty as usize).ok_or("type out of range")?;
+                self.pop(I32)?;
+                self.op(&ty.params, &ty.results)?;
+            }
+
+            Drop => {
+                self.pop(None)?;
+            }
+            Select(declared) => {
+                self.pop(I32)?;
+                let ty = self.pop(*declared)?;
+                let ty = self.pop(ty)?;
+                if declared.is_none() && ty.is_some_and(is_ref) {
+                    return Err("`select` without a type on references");
+                }
+                self.push(ty);
+            }
+
+            LocalGet(local) => self.push(self.local(*local)?),
+            LocalSet(local) => {
+                self.pop(self.local(*local)?)?;
+            }
+            LocalTee(local) => {
+                let ty = self.local(*local)?;
+                self.op(&[ty], &[ty])?;
+            }
+            GlobalGet(global) => self.push(self.global(*global)?.ty),
+            GlobalSet(global) => {
+                let global = self.global(*global)?;
+                if !global.mutable {
+                    return Err("global is immutable");
+                }
+                self.pop(global.ty)?;
+            }
+
+            I32Load { offset, mem_addr }
+            | I32Load8S { offset, mem_addr }
+            | I32Load8U { offset, mem_addr }
+            | I32Load16S { offset, mem_addr }
+            | I32Load16U { offset, mem_addr } => self.load(*offset, *mem_addr, I32)?,
+            I64Load { offset, mem_addr }
+            | I64Load8S { offset, mem_addr }
+            | I64Load8U { offset, mem_addr }
+            | I64Load16S { offset, mem_addr }
+            | I64Load16U { offset, mem_addr }
+            | I64Load32S { offset, mem_addr }
+            | I64Load32U { offset, mem_addr } => self.load(*offset, *mem_addr, I64)?,
+            F32Load { offset, mem_addr } => self.load(*offset, *mem_addr, F32)?,
+            F64Load { offset, mem_addr } => self.load(*offset, *mem_addr, F64)?,
+            I32Store { offset, mem_addr } | I32Store8 { offset, mem_addr } | I32Store16 { offset, mem_addr } => {
+                self.store(*offset, *mem_addr, I32)?
+            }
+            I64Store { offset, mem_addr }
+            | I64Store8 { offset, mem_addr }
+            | I64Store16 { offset, mem_addr }
+            | I64Store32 { offset, mem_addr } => self.store(*offset, *mem_addr, I64)?,
+            F32Store { offset, mem_addr } => self.store(*offset, *mem_addr, F32)?,
+            F64Store { offset, mem_addr } => self.store(*offset, *mem_addr, F64)?,
+            I32LoadStatic { addr, mem_addr } => self.static_load(*mem_addr, *addr, I32)?,
+            I64LoadStatic { addr, mem_addr } => self.static_load(*mem_addr, *addr, I64)?,
+            F32LoadStatic { addr, mem_addr } => self.static_load(*mem_addr, *addr, F32)?,
+            F64LoadStatic { addr, mem_addr } => self.static_load(*mem_addr, *addr, F64)?,
+            I32StoreStatic { addr, mem_addr } => self.static_store(*mem_addr, *addr, I32)?,
+            I64StoreStatic { addr, mem_addr } => self.static_store(*mem_addr, *addr, I64)?,
+            F32StoreStatic { addr, mem_addr } => self.static_store(*mem_addr, *addr, F32)?,
+            F64StoreStatic { addr, mem_addr } => self.static_store(*mem_addr, *addr, F64)?,
+            MemorySize(mem, _) => self.push(self.memory(*mem)?),
+            MemoryGrow(mem, _) => {
+                let ty = self.memory(*mem)?;
+                self.op(&[ty], &[ty])?;
+            }
+
+            I32Const(_) => self.push(I32),
+            F32Const(_) => self.push(F32),
+            I64Const(constant) => {
+                self.constant(*constant)?;
+                self.push(I64);
+            }
+            F64Const(constant) => {
+                self.constant(*constant)?;
+                self.push(F64);
+            }
+            RefNull(ty) if is_ref(*ty) => self.push(*ty),
+            RefNull(_) => return Err("`ref.null` of a non-reference type"),
+            RefFunc(func) => {
+                self.func_type(*func)?;
+                self.push(ValType::RefFunc);
+            }
+            RefIsNull => {
+                if self.pop(None)?.is_some_and(|ty| !is_ref(ty)) {
+                    return Err("`ref.is_null` on a non-reference value");
+                }
+                self.push(I32);
+            }
+
+            I32Eqz | I32Clz | I32Ctz | I32Popcnt | I32Extend8S | I32Extend16S => self.op(&[I32], &[I32])?,
+            I64Eqz | I32WrapI64 => self.op(&[I64], &[I32])?,
+            I64Clz | I64Ctz | I64Popcnt | I64Extend8S | I64Extend16S | I64Extend32S => self.op(&[I64], &[I64])?,
+            F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt => self.op(&[F32], &[F32])?,
+            F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => self.op(&[F64], &[F64])?,
+            I32TruncF32S | I32TruncF32U | I32TruncSatF32S | I32TruncSatF32U | I32ReinterpretF32 => {
+                self.op(&[F32], &[I32])?
+            }
+            I32TruncF64S | I32TruncF64U | I32TruncSatF64S | I32TruncSatF64U => self.op(&[F64], &[I32])?,
+            I64ExtendI32S | I64ExtendI32U => self.op(&[I32], &[I64])?,
+            I64TruncF32S | I64TruncF32U | I64TruncSatF32S | I64TruncSatF32U => self.op(&[F32], &[I64])?,
+            I64TruncF64S | I64TruncF64U | I64TruncSatF64S | I64TruncSatF64U | I64ReinterpretF64 => {
+                self.op(&[F64], &[I64])?
+            }
+            F32ConvertI32S | F32ConvertI32U | F32ReinterpretI32 => self.op(&[I32], &[F32])?,
+            F32ConvertI64S | F32ConvertI64U => self.op(&[I64], &[F32])?,
+            F32DemoteF64 => self.op(&[F64], &[F32])?,
+            F64ConvertI32S | F64ConvertI32U => self.op(&[I32], &[F64])?,
+            F64ConvertI64S | F64ConvertI64U | F64ReinterpretI64 => self.op(&[I64], &[F64])?,
+            F64PromoteF32 => self.op(&[F32], &[F64])?,
+
+            I32Eq | I32Ne | I32LtS | I32LtU | I32GtS | I32GtU | I32LeS | I32LeU | I32GeS | I32GeU | I32Add | I32Sub
+            | I32Mul | I32DivS | I32DivU | I32RemS | I32RemU | I32And | I32Or | I32Xor | I32Shl | I32ShrS | I32ShrU
+            | I32Rotl | I32Rotr => self.op(&[I32, I32], &[I32])?,
+            I64Eq | I64Ne | I64LtS | I64LtU | I64GtS | I64GtU | I64LeS | I64LeU | I64GeS | I64GeU => {
+                self.op(&[I64, I64], &[I32])?
+            }
+            I64Add | I64Sub | I64Mul | I64DivS | I64DivU | I64RemS | I64RemU | I64And | I64Or | I64Xor | I64Shl
+            | I64ShrS | I64ShrU | I64Rotl | I64Rotr => self.op(&[I64, I64], &[I64])?,
+            F32Eq | F32Ne | F32Lt | F32Gt | F32Le | F32Ge => self.op(&[F32, F32], &[I32])?,
+            F64Eq | F64Ne | F64Lt | F64Gt | F64Le | F64Ge => self.op(&[F64, F64], &[I32])?,
+            F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Copysign => self.op(&[F32, F32], &[F32])?,
+            F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Copysign => self.op(&[F64, F64], &[F64])?,
+
+            TableInit(elem, table) => {
+                if self.table(*table)? != self.elem(*elem)? {
+                    return Err("element segment type doesn't match the table");
+                }
+                self.op(&[I32, I32, I32], &[])?;
+            }
+            TableGet(table) => {
+                let ty = self.table(*table)?;
+                self.op(&[I32], &[ty])?;
+            }
+            TableSet(table) => {
+                let ty = self.table(*table)?;
+                self.op(&[I32, ty], &[])?;
+            }
+            TableCopy { from, to } => {
+                if self.table(*from)? != self.table(*to)? {
+                    return Err("table types don't match");
+                }
+                self.op(&[I32, I32, I32], &[])?;
+            }
+            TableGrow(table) => {
+                let ty = self.table(*table)?;
+                self.op(&[ty, I32], &[I32])?;
+            }
+            TableSize(table) => {
+                self.table(*table)?;
+                self.push(I32);
+            }
+            TableFill(table) => {
+                let ty = self.table(*table)?;
+                self.op(&[I32, ty, I32], &[])?;
+            }
+
+            MemoryInit(data, mem) => {
+                let ty = self.memory(*mem)?;
+                self.data(*data)?;
+                self.op(&[ty, I32, I32], &[])?;
+            }
+            MemoryCopy(from, to) => {
+                // the length is only an `i64` if both memories are 64-bit
+                let (from, to) = (self.memory(*from)?, self.memory(*to)?);
+                self.op(&[to, from, if from == to { to } else { I32 }], &[])?;
+            }
+            MemoryFill(mem) => {
+                let ty = self.memory(*mem)?;
+                self.op(&[ty, I32, ty], &[])?;
+            }
+            DataDrop(data) => self.data(*data)?,
+        }
+
+        Ok(ip + 1)
+    }
+}
+
+fn is_ref(ty: ValType) -> bool {
+    matches!(ty, ValType::RefFunc | ValType::RefExtern)
+}
+
+// the number of bytes a value of a numeric type takes up in memory
+fn value_size(ty: ValType) -> u64 {
+    match ty {
+        ValType::I64 | ValType::F64 => 8,
+        _ => 4,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use Instruction::*;
+
+    fn module(instructions: Vec<Instruction>) -> TinyWasmModule {
+        let ty = FuncType { params: vec![ValType::I32].into(), results: vec![ValType::I32].into() };
+        let func = WasmFunction {
+            instructions: instructions.into(),
+            locals: vec![ValType::I32].into(),
+            offsets: Default::default(),
+            br_table_targets: Default::default(),
+            constants: Default::default(),
+            ty: ty.clone(),
+        };
+
+        TinyWasmModule {
+            funcs: vec![func].into(),
+            func_types: vec![ty].into(),
+            memory_types: vec![MemoryType::new_32(1, None)].into(),
+            exports: vec![Export { name: "f".into(), kind: ExternalKind::Func, index: 0 }].into(),
+            ..Default::default()
+        }
+    }
+
+    fn error(module: &TinyWasmModule) -> &'static str {
+        match module.verify() {
+            Err(VerifyError::Function { reason, .. }) | Err(VerifyError::Module(reason)) => reason,
+            Ok(()) => panic!("module should be invalid"),
+        }
+    }
+
+    #[test]
+    fn test_verify_valid() {
+        let instructions = vec![
+            Block(BlockArgsPacked::new(BlockArgs::Type(ValType::I32)).unwrap(), 11),
+            LocalGet(0),
+            If(BlockArgsPacked::EMPTY, 3),
+            LocalGet(1),
+            LocalSet(1),
+            Else(2),
+            Nop,
+            EndBlockFrame,
+            LocalGet(0),
+            LocalGet(0),
+            BrTable(0),
+            EndBlockFrame,
+            I32Load { offset: 0, mem_addr: 0 },
+            Call(0),
+            I64Const(1),
+            Drop,
+            I32LocalGetConstAdd(0, 1),
+            I32Add,
+            I32StoreLocal { local: 1, offset: 4, mem_addr: 0 },
+            I32Const(7),
+            I32LoadStatic { addr: 65532, mem_addr: 0 },
+            I32StoreStatic { addr: 0, mem_addr: 0 },
+            I32AddLocals(0, 1),
+            I32LtULocalConst(0, 4),
+            I32LtS,
+            Drop,
+            Block(BlockArgsPacked::EMPTY, 4),
+            LocalGet(0),
+            I32AddConst(1),
+            I32EqzBrIf(0),
+            EndBlockFrame,
+            EndFunc,
+        ];
+        let mut valid = module(instructions);
+        valid.funcs[0].br_table_targets = vec![1, 0, 0].into();
+        valid.funcs[0].constants = vec![0, -1i64 as u64].into();
+        assert_eq!(valid.verify(), Ok(()));
+
+        let unreachable =
+            vec![Loop(BlockArgsPacked::EMPTY, 4), Br(0), I32Add, Drop, EndBlockFrame, Unreachable, EndFunc];
+        assert_eq!(module(unreachable).verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_invalid() {
+        assert_eq!(error(&module(vec![LocalGet(2), EndFunc])), "local out of range");
+        assert_eq!(error(&module(vec![I32LocalGetConstAdd(2, 1), EndFunc])), "local out of range");
+        assert_eq!(error(&module(vec![I32SubLocals(0, 2), EndFunc])), "local out of range");
+        assert_eq!(
+            error(&module(vec![I32StoreLocal { local: 0, offset: 0, mem_addr: 0 }, EndFunc])),
+            "`i32.store_local` is missing its value"
+        );
+        assert_eq!(
+            error(&module(vec![I32LoadStatic { addr: 65533, mem_addr: 0 }, EndFunc])),
+            "static memory access out of bounds"
+        );
+        assert_eq!(error(&module(vec![F64LoadStatic { addr: 0, mem_addr: 1 }, EndFunc])), "memory out of range");
+        assert_eq!(error(&module(vec![I32Const(1)])), "function doesn't end with `end`");
+        assert_eq!(error(&module(vec![I32Add, EndFunc])), "stack underflow");
+        assert_eq!(
+            error(&module(vec![I32Const(1), I32Const(2), EndFunc])),
+            "wrong number of values on the stack at the end of a block"
+        );
+        assert_eq!(
+            error(&module(vec![Block(BlockArgsPacked::EMPTY, 2), Nop, Nop, EndBlockFrame, LocalGet(0), EndFunc])),
+            "block end offset doesn't point to its `end`"
+        );
+        assert_eq!(error(&module(vec![LocalGet(0), BrTable(0), EndFunc])), "`br_table` targets out of range");
+        assert_eq!(error(&module(vec![I64Const(0), Drop, EndFunc])), "constant out of range");
+        let mut invalid = module(vec![LocalGet(0), LocalGet(0), BrTable(0), EndFunc]);
+        invalid.funcs[0].br_table_targets = vec![1, 0].into();
+        assert_eq!(error(&invalid), "`br_table` targets out of range");
+        assert_eq!(
+            error(&module(vec![LocalGet(0), If(BlockArgsPacked::EMPTY, 2), Nop, Nop, EndBlockFrame, EndFunc])),
+            "block end offset doesn't point to its `end`"
+        );
+        assert_eq!(error(&module(vec![Br(1), EndFunc])), "branch depth out of range");
+        assert_eq!(error(&module(vec![LocalGet(0), EndFunc, Nop])), "instructions after the end of the function");
+
+        let mut invalid = module(vec![LocalGet(0), EndFunc]);
+        invalid.exports = vec![Export { name: "g".into(), kind: ExternalKind::Global, index: 0 }].into();
+        assert_eq!(invalid.verify(), Err(VerifyError::Module("export index out of range")));
+    }
+
+    #[test]
+    fn test_verify_types() {
+        assert_eq!(error(&module(vec![F32Const(1.0), EndFunc])), "type mismatch");
+        assert_eq!(error(&module(vec![LocalGet(0), F32Const(1.0), I32Add, EndFunc])), "type mismatch");
+        assert_eq!(error(&module(vec![F32Const(1.0), LocalSet(1), LocalGet(0), EndFunc])), "type mismatch");
+        assert_eq!(error(&module(vec![F32Const(1.0), LocalTeeGet(0, 1), Drop, EndFunc])), "type mismatch");
+        let select = vec![RefNull(ValType::RefFunc), RefNull(ValType::RefFunc), LocalGet(0), Select(None), EndFunc];
+        assert_eq!(error(&module(select)), "`select` without a type on references");
+        let select = vec![LocalGet(0), F32Const(1.0), LocalGet(0), Select(None), EndFunc];
+        assert_eq!(error(&module(select)), "type mismatch");
+        assert_eq!(error(&module(vec![LocalGet(0), RefIsNull, EndFunc])), "`ref.is_null` on a non-reference value");
+
+        // the unknown results of a block are taken from the values it is left with
+        let arity = BlockArgsPacked::new(BlockArgs::Arity { params: 0, results: 1 }).unwrap();
+        let valid = vec![Block(arity, 3), LocalGet(0), Br(0), EndBlockFrame, EndFunc];
+        assert_eq!(module(valid).verify(), Ok(()));
+        let invalid = vec![Block(arity, 3), F32Const(1.0), Br(0), EndBlockFrame, EndFunc];
+        assert_eq!(error(&module(invalid)), "type mismatch");
+        let invalid = vec![Block(arity, 6), F32Const(1.0), LocalGet(0), BrIf(0), Drop, LocalGet(0), EndBlockFrame];
+        assert_eq!(error(&module([invalid, vec![EndFunc]].concat())), "type mismatch");
+
+        let mut global = module(vec![LocalGet(0), GlobalSet(0), LocalGet(0), EndFunc]);
+        let ty = GlobalType { mutable: true, ty: ValType::I64 };
+        global.globals = vec![Global { ty, init: ConstInstruction::I64Const(0) }].into();
+        assert_eq!(error(&global), "type mismatch");
+        global.globals = vec![Global { ty, init: ConstInstruction::I32Const(0) }].into();
+        assert_eq!(error(&global), "constant expression has the wrong type");
+    }
+
+    #[test]
+    fn test_verify_tables() {
+        let with_table = |ty, instructions| {
+            let mut module = module(instructions);
+            module.table_types = vec![TableType::new(ty, 1, None)].into();
+            module
+        };
+
+        // calls through a table of external references would run into the interpreter's assertions
+        let call = vec![LocalGet(0), LocalGet(0), CallIndirect(0, 0), EndFunc];
+        assert_eq!(with_table(ValType::RefFunc, call.clone()).verify(), Ok(()));
+        assert_eq!(
+            error(&with_table(ValType::RefExtern, call)),
+            "`call_indirect` on a table without function references"
+        );
+
+        // storing an integer in a table would forge a function reference
+        let mut forged =
+            with_table(ValType::RefFunc, vec![I32Const(0), I64Const(0), TableSet(0), LocalGet(0), EndFunc]);
+        forged.funcs[0].constants = vec![0].into();
+        assert_eq!(error(&forged), "type mismatch");
+        let set = vec![I32Const(0), RefNull(ValType::RefExtern), TableSet(0), LocalGet(0), EndFunc];
+        assert_eq!(error(&with_table(ValType::RefFunc, set)), "type mismatch");
+        let set = vec![I32Const(0), RefFunc(0), TableSet(0), LocalGet(0), EndFunc];
+        assert_eq!(with_table(ValType::RefFunc, set).verify(), Ok(()));
+
+        let mut elem = with_table(ValType::RefExtern, vec![LocalGet(0), EndFunc]);
+        let kind = ElementKind::Active { table: 0, offset: ConstInstruction::I32Const(0) };
+        let items = vec![ElementItem::Func(0)].into();
+        elem.elements = vec![Element { kind, items, range: 0..0, ty: ValType::RefFunc }].into();
+        assert_eq!(elem.verify(), Err(VerifyError::Module("element segment type doesn't match the table")));
+    }
+}